@@ -0,0 +1,96 @@
+use std::fs::{copy, read_to_string, write, File};
+use std::path::PathBuf;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use lvisweb_ediparser::config::{Config, ConfigSource};
+use lvisweb_ediparser::db;
+use lvisweb_ediparser::edi::parser::EXPL_SEQ_PRODUCT;
+use lvisweb_ediparser::edi::{edi_line_iter, is_header_line, EdiFields, EdiFileKind, ImportOptions};
+use lvisweb_ediparser::files::IMPORT_LOG_NAME;
+use lvisweb_ediparser::fixture::{generate, FixtureSpec, DEFAULT_SELLER};
+use lvisweb_ediparser::import_file::import_file;
+
+const ROWS: usize = 1000;
+
+// Data dir with a config writing only the given targets, and product and
+// price fixtures of the configured seller
+fn data_dir(json: bool, sqlite: bool) -> (tempfile::TempDir, Config, Vec<PathBuf>) {
+    let dir = tempfile::tempdir().unwrap();
+
+    write(dir.path().join("config.toml"), format!(
+        "vat_percent = 25.5\nlang_codes = [\"fin\"]\n\n[import]\njson = {}\nsqlite = {}\nsearch = false\n\n\
+        [[price_group]]\nid = \"01\"\nlabel = \"List price VAT 0%\"\nis_list_price = true\n\n\
+        [[seller]]\nid = \"{}\"\nname = \"Bench\"\n", json, sqlite, DEFAULT_SELLER
+    )).unwrap();
+
+    let source = ConfigSource { dir: Some(dir.path().to_string_lossy().into_owned()), file: None, container: true };
+    let config = Config::new(&source).unwrap();
+
+    let files = generate(&FixtureSpec {
+        kinds: vec![EdiFileKind::Product, EdiFileKind::Price],
+        rows: ROWS,
+        sellers: vec![DEFAULT_SELLER.to_string()],
+        out: dir.path().join("fixtures"),
+        ..Default::default()
+    }).unwrap();
+
+    (dir, config, files)
+}
+
+fn line_fields(c: &mut Criterion) {
+    let (_dir, _, files) = data_dir(true, false);
+    let text = read_to_string(&files[0]).unwrap();
+    let line = text.lines().skip(2).find(|l| !is_header_line(l)).unwrap().to_owned();
+
+    let mut group = c.benchmark_group("product line fields");
+
+    group.bench_function("edi_line_iter", |b| b.iter(|| {
+        let chars = line.chars();
+        let mut pointer = 0;
+
+        for w in EXPL_SEQ_PRODUCT.iter() {
+            let (_, p) = edi_line_iter(pointer, &chars, w).unwrap();
+            pointer = p;
+        }
+    }));
+    group.bench_function("EdiFields", |b| b.iter(|| {
+        let mut fields = EdiFields::new(&line);
+
+        for w in EXPL_SEQ_PRODUCT.iter() {
+            fields.next_field(*w).unwrap();
+        }
+    }));
+
+    group.finish();
+}
+
+// Whole imports of the fixtures with one writer enabled, the file is copied
+// for each run as imports move it
+fn writers(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!("import {} rows", ROWS));
+    group.sample_size(10);
+
+    for (target, json, sqlite) in [("json", true, false), ("db", false, true)] {
+        let (dir, config, files) = data_dir(json, sqlite);
+        let (mut db_sellers, mut db_buyers) = db::init(&config).unwrap();
+        let mut log = File::create(dir.path().join(IMPORT_LOG_NAME)).unwrap();
+        let opts = ImportOptions { force: true, ..Default::default() };
+
+        for (kind, fixture) in [("products", &files[0]), ("prices", &files[1])] {
+            group.bench_function(format!("{} {}", kind, target), |b| b.iter_batched(
+                || {
+                    let path = dir.path().join(fixture.file_name().unwrap());
+                    copy(fixture, &path).unwrap();
+                    path
+                },
+                |path| import_file(&config, &path, &opts, None, &mut db_sellers, &mut db_buyers, &mut log).unwrap(),
+                BatchSize::PerIteration,
+            ));
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, line_fields, writers);
+criterion_main!(benches);
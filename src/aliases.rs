@@ -0,0 +1,237 @@
+use std::fs::{create_dir_all, read_dir, read_to_string, remove_dir_all, remove_file, rename, write};
+use std::path::PathBuf;
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+use rusqlite::{params, Connection};
+use serde_json::Value;
+
+use crate::config::Config;
+use crate::edi::{buyer_discounts_aggregate, buyer_discounts_dir, buyer_json, envelope};
+use crate::search::search_table;
+use crate::utils::Category;
+
+
+// Copy rows of the alias to the seller with ids replaced, rows the seller has
+// already win. Alias rows are deleted after, so the outbox gets an insert and
+// a delete and replicas follow. Alias is ?1 and seller ?2 in the SQL, filter
+// only has the alias.
+fn move_rows(conn: &Connection, table: &str, filter: &str, replace: &[(&str, &str)], alias: &str, seller: &str)
+-> Result<usize> {
+    let mut stm = conn.prepare("select name from pragma_table_info(?1)")?;
+    let columns = stm.query_map(params![table], |r| r.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    let values = columns.iter()
+        .map(|c| match replace.iter().find(|(n, _)| n.eq(c)) {
+            Some((_, expr)) => expr.to_string(),
+            None => c.to_owned(),
+        })
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    conn.execute(
+        &format!("insert or ignore into {table} ({}) select {values} from {table} where {filter}", columns.join(", ")),
+        params![alias, seller]
+    )?;
+
+    Ok(conn.execute(&format!("delete from {table} where {filter}"), params![alias])?)
+}
+
+fn merge_sellers_db(conn: &mut Connection, config: &Config, alias: &str, seller: &str) -> Result<usize> {
+    let tx = conn.transaction()?;
+
+    let mut moved = move_rows(&tx, "products_entries", "seller_id = ?1",
+        &[("id", "?2 || product_id"), ("seller_id", "?2")], alias, seller)?;
+    moved += move_rows(&tx, "prices_entries", "id = ?1 || product_id", &[("id", "?2 || product_id")], alias, seller)?;
+
+    for (k, _) in Category::mapper() {
+        // Translation id is seller id + product id + ':' + lang index
+        moved += move_rows(&tx, &format!("product_{k}_t"), "seller_id = ?1",
+            &[("id", "?2 || substr(id, length(?1) + 1)"), ("seller_id", "?2")], alias, seller)?;
+
+        // Rebuilt for the seller after the merge
+        if config.import.search {
+            for l in config.lang_codes.iter() {
+                tx.execute(&format!("delete from {} where seller_id = ?1", search_table(k, l)), params![alias])?;
+            }
+        }
+    }
+
+    move_rows(&tx, "freshness", "seller_id = ?1", &[("seller_id", "?2")], alias, seller)?;
+    tx.execute("delete from sellers where id = ?1", params![alias])?;
+
+    tx.commit()?;
+
+    Ok(moved)
+}
+
+fn merge_buyers_db(conn: &mut Connection, alias: &str, seller: &str) -> Result<usize> {
+    let tx = conn.transaction()?;
+
+    // Buyer is customer number + seller id, uuid stays with the buyer
+    tx.execute("update or ignore buyers set id = substr(id, 1, length(id) - length(?1)) || ?2 \
+        where id = buyer_id || ?1", params![alias, seller])?;
+    tx.execute("delete from buyers where id = buyer_id || ?1", params![alias])?;
+
+    let buyer = "substr(buyer_id, 1, length(buyer_id) - length(?1)) || ?2";
+    let replace = [("id", format!("{buyer} || discount_group")), ("buyer_id", buyer.to_string()),
+        ("seller_id", String::from("?2"))];
+    let replace = replace.iter().map(|(n, e)| (*n, e.as_str())).collect::<Vec<(&str, &str)>>();
+
+    let mut moved = move_rows(&tx, "discounts", "seller_id = ?1", &replace, alias, seller)?;
+    moved += move_rows(&tx, "pending_discounts", "seller_id = ?1", &replace, alias, seller)?;
+
+    tx.commit()?;
+
+    Ok(moved)
+}
+
+// Objects are merged by key and discount lists by discount group, entries
+// the seller has already win
+fn merge_json(from: Value, into: Value) -> Value {
+    match (from, into) {
+        (Value::Object(f), Value::Object(mut i)) => {
+            for (k, v) in f {
+                i.entry(k).or_insert(v);
+            }
+
+            Value::Object(i)
+        },
+        (Value::Array(f), Value::Array(mut i)) => {
+            for v in f {
+                if !i.iter().any(|e| e.get("disc").is_some() && e.get("disc").eq(&v.get("disc"))) {
+                    i.push(v);
+                }
+            }
+
+            Value::Array(i)
+        },
+        (_, i) => i,
+    }
+}
+
+// Move files of the alias dir into the seller's dir. Discount files named by
+// the alias are renamed, JSON files both have are merged and for other files
+// the seller's is kept.
+fn merge_dir(from: &PathBuf, into: &PathBuf, alias: &str, seller: &str) -> Result<()> {
+    create_dir_all(into)?;
+
+    for e in read_dir(from)? {
+        let path = e?.path();
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let target = into.join(match name.eq(&format!("{alias}.json")) {
+            true => format!("{seller}.json"),
+            false => name,
+        });
+
+        if path.is_dir() {
+            merge_dir(&path, &target, alias, seller)?;
+            continue
+        }
+
+        merge_file(&path, &target)?;
+    }
+
+    Ok(())
+}
+
+// Move a file to target, merging JSON files when both exist
+fn merge_file(path: &PathBuf, target: &PathBuf) -> Result<()> {
+    if !target.exists() {
+        rename(path, target)?;
+        return Ok(())
+    }
+
+    if path.extension().is_some_and(|e| e.eq("json")) {
+        let read = |p: &PathBuf| -> Result<Value> {
+            serde_json::from_str(&read_to_string(p)?).map_err(|e| anyhow!("Failed to parse {:?}: {}", p, e))
+        };
+
+        // Data is merged, the envelope of the seller's file kept
+        let from = envelope::into_data(read(path)?);
+        let mut into = read(target)?;
+        let data = envelope::data_mut(&mut into);
+        *data = merge_json(from, data.take());
+
+        write(target, serde_json::to_string(&into)?)?;
+    }
+
+    remove_file(path)?;
+
+    Ok(())
+}
+
+// Merge the JSON tree of the alias to the seller's and collect buyers' discount
+// aggregates again. Returns buyers of the merged dir.
+fn merge_tree(config: &Config, alias: &str, seller: &str) -> Result<Vec<String>> {
+    let sellers_dir = config.dir.join("sellers");
+    let from = sellers_dir.join(alias);
+    let into = sellers_dir.join(seller);
+
+    merge_dir(&from, &into, alias, seller)?;
+    remove_dir_all(&from)?;
+
+    let mut buyers = vec![];
+
+    // Buyer dirs stay in the seller tree for their EDI files, discount files
+    // of a separate buyers dir are named by the alias there
+    if into.join("buyers").is_dir() {
+        for e in read_dir(into.join("buyers"))? {
+            let buyer = e?.file_name().to_string_lossy().into_owned();
+
+            if config.json_output.buyers_dir.is_some() {
+                let dir = buyer_discounts_dir(config, seller, &buyer);
+                let path = dir.join(format!("{alias}.json"));
+
+                if path.is_file() {
+                    merge_file(&path, &dir.join(format!("{seller}.json")))?;
+                }
+            }
+
+            if buyer_json(config) {
+                buyer_discounts_aggregate(config, &buyer)?;
+            }
+
+            buyers.push(buyer);
+        }
+    }
+
+    Ok(buyers)
+}
+
+/// Merge data and files imported under seller aliases to the seller's id, in
+/// the databases and the JSON tree. Nothing to do once merged. Returns true if
+/// something was merged and the search index needs updating.
+pub fn merge_aliases(config: &Config, db_sellers: &mut Connection, db_buyers: &mut Connection) -> Result<bool> {
+    let mut merged = false;
+
+    for s in config.seller.iter() {
+        for alias in s.aliases.iter() {
+            let imported: bool = db_sellers.query_row(
+                "select exists (select 1 from sellers where id = ?1) \
+                    or exists (select 1 from products_entries where seller_id = ?1)",
+                params![alias], |r| r.get(0)
+            )?;
+            let has_dir = config.dir.join("sellers").join(alias).is_dir();
+
+            if !imported && !has_dir {
+                continue
+            }
+
+            let rows = merge_sellers_db(db_sellers, config, alias, &s.id)?
+                + merge_buyers_db(db_buyers, alias, &s.id)?;
+
+            let buyers = match has_dir {
+                true => merge_tree(config, alias, &s.id)?,
+                false => vec![],
+            };
+
+            debug!("Discount aggregates of buyers {:?} rewritten", buyers);
+            info!("Merged {} rows and the files of alias {} to seller {}", rows, alias, s.id);
+
+            merged = true;
+        }
+    }
+
+    Ok(merged)
+}
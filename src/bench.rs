@@ -0,0 +1,120 @@
+use std::env::temp_dir;
+use std::fs::{remove_dir_all, File};
+use std::time::{Duration, Instant};
+use std::path::PathBuf;
+use anyhow::Result;
+use log::{info, warn};
+use rand::distributions::{Alphanumeric, DistString};
+
+use crate::config::Config;
+use crate::db;
+use crate::edi::{EdiFileKind, ImportOptions};
+use crate::edi::parser::parse_lines;
+use crate::files::IMPORT_LOG_NAME;
+use crate::fixture::{generate, FixtureSpec, DEFAULT_SELLER};
+use crate::import_file::import_file;
+use crate::search::search_index_builder;
+use crate::utils::Lang;
+
+
+// Scratch data dir of a run, removed on drop unless kept so failed steps
+// don't leave it behind
+struct Scratch {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        match self.keep {
+            true => info!("Benchmark files kept in {:?}", self.path),
+            false => if let Err(e) = remove_dir_all(&self.path) {
+                warn!("Failed to remove {:?}: {}", self.path, e);
+            },
+        }
+    }
+}
+
+// Timed step of the pipeline
+struct Timing {
+    step: &'static str,
+    rows: usize,
+    took: Duration,
+}
+
+impl Timing {
+    fn measure<F, T>(step: &'static str, rows: usize, f: F) -> Result<(Self, T)> where F: FnOnce() -> Result<T> {
+        let start = Instant::now();
+        let out = f()?;
+
+        Ok((Self { step, rows, took: start.elapsed() }, out))
+    }
+    fn per_second(&self) -> f64 {
+        match self.took.as_secs_f64() > 0.0 {
+            true => self.rows as f64 / self.took.as_secs_f64(),
+            false => 0.0,
+        }
+    }
+}
+
+/// Time line parsing, DB inserts and search index building on generated
+/// fixtures. Runs in a scratch copy of the data dir so real databases stay
+/// untouched, scratch dir is removed unless `keep` is set.
+pub fn bench(config: &Config, rows: usize, keep: bool) -> Result<()> {
+    let randy = Alphanumeric.sample_string(&mut rand::thread_rng(), 10);
+    let scratch = Scratch { path: temp_dir().join(format!("lvisweb-bench-{}", randy)), keep };
+
+    // Fixture seller has to be configured or its rows are skipped
+    let seller = match config.seller.first() {
+        Some(s) => s.id.to_owned(),
+        None => DEFAULT_SELLER.to_string(),
+    };
+
+    let mut bench_config = config.clone();
+    bench_config.dir = scratch.path.to_owned();
+
+    let spec = FixtureSpec {
+        kinds: vec![EdiFileKind::Product, EdiFileKind::Price, EdiFileKind::Discount],
+        rows,
+        sellers: vec![seller],
+        out: scratch.path.join("fixtures"),
+        ..Default::default()
+    };
+
+    info!("Benchmarking {} rows in {:?}", rows, scratch.path);
+
+    let files = generate(&spec)?;
+    let (mut db_sellers, mut db_buyers) = db::init(&bench_config)?;
+    let mut log = File::create(scratch.path.join(IMPORT_LOG_NAME))?;
+
+    let lang = bench_config.lang_codes.first().cloned().unwrap_or(Lang::Fin);
+    let opts = ImportOptions { force: true, ..Default::default() };
+    let mut timings = vec![];
+
+    let (t, _) = Timing::measure("Parse product lines", rows, || {
+        parse_lines(&files[0], EdiFileKind::Product, &bench_config, &lang)
+    })?;
+    timings.push(t);
+
+    for (step, path) in [("Import products", &files[0]), ("Import prices", &files[1]), ("Import discounts", &files[2])] {
+        let (t, _) = Timing::measure(step, rows, || {
+            import_file(&bench_config, path, &opts, None, &mut db_sellers, &mut db_buyers, &mut log)
+        })?;
+        timings.push(t);
+    }
+
+    if bench_config.import.search {
+        let (t, _) = Timing::measure("Build search index", rows, || {
+            search_index_builder(&bench_config, &mut db_sellers)
+        })?;
+        timings.push(t);
+    }
+
+    println!("{:<22}{:>10}{:>14}{:>14}", "Step", "Rows", "Time (ms)", "Rows / s");
+
+    for t in timings.iter() {
+        println!("{:<22}{:>10}{:>14.1}{:>14.0}", t.step, t.rows, t.took.as_secs_f64() * 1000.0, t.per_second());
+    }
+
+    Ok(())
+}
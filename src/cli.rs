@@ -0,0 +1,465 @@
+use std::env;
+use std::path::PathBuf;
+use std::io::Write;
+use anyhow::{bail, Result};
+use chrono::Utc;
+
+use crate::completions::SHELLS;
+use crate::config::ConfigSource;
+use crate::edi::EdiFileKind;
+use crate::fixture::FixtureSpec;
+use crate::registry::Registry;
+use crate::utils::Category;
+
+
+// Limits the pipeline run to some sellers and / or categories. Empty means all.
+#[derive(Debug, Default, Clone)]
+pub struct RunFilter {
+    pub sellers: Vec<String>,
+    pub categories: Vec<Category>,
+}
+
+impl RunFilter {
+    pub fn is_active(&self) -> bool {
+        !self.sellers.is_empty() || !self.categories.is_empty()
+    }
+    pub fn has_seller(&self, id: &str) -> bool {
+        self.sellers.is_empty() || self.sellers.iter().any(|s| s.eq(id))
+    }
+    pub fn has_category(&self, category: &Category) -> bool {
+        self.categories.is_empty() || self.categories.contains(category)
+    }
+}
+
+pub enum Command {
+    // Download, unzip and import everything, the default.
+    Run(RunFilter),
+    Reimport { seller: String, file: Option<String> },
+    ImportFile { path: String, kind: Option<EdiFileKind>, seller: Option<String>, remote_addr: Option<String> },
+    GenFixture(FixtureSpec),
+    Bench { rows: usize, keep: bool },
+    Order { input: String, out: Option<String> },
+    ImportEtim { path: String, seller: Option<String> },
+    ImportRegistry { path: String, registry: Option<Registry> },
+    Ean { code: String, buyer: Option<String> },
+    Prices { input: String },
+    Price { buyer: String, seller: String, product: String, quantity: f64 },
+    Show { key: String },
+    Search { text: String, lang: Option<String>, limit: usize },
+    Stats,
+    Reindex(RunFilter),
+    Maintain,
+    Verify,
+    Completions { shell: String },
+    Schema,
+    Health { ready: bool },
+    Demo { out: Option<String>, rows: usize },
+    Help,
+}
+
+impl Command {
+    /// Command writes imported data to the databases.
+    pub fn is_import(&self) -> bool {
+        matches!(self, Self::Run(_) | Self::Reimport { .. } | Self::ImportFile { .. } | Self::ImportEtim { .. }
+            | Self::ImportRegistry { .. })
+    }
+    /// Command only reads the databases, they are opened read only.
+    pub fn is_read_only(&self) -> bool {
+        matches!(self, Self::Ean { .. } | Self::Prices { .. } | Self::Price { .. } | Self::Show { .. } | Self::Order { .. }
+            | Self::Search { .. } | Self::Verify | Self::Stats)
+    }
+    fn from_name(val: &str) -> Option<Self> {
+        match val {
+            "run" => Some(Self::Run(RunFilter::default())),
+            "reimport" => Some(Self::Reimport { seller: String::new(), file: None }),
+            "import-file" => Some(Self::ImportFile { path: String::new(), kind: None, seller: None, remote_addr: None }),
+            "import-registry" => Some(Self::ImportRegistry { path: String::new(), registry: None }),
+            "import-etim" => Some(Self::ImportEtim { path: String::new(), seller: None }),
+            "ean" => Some(Self::Ean { code: String::new(), buyer: None }),
+            "prices" => Some(Self::Prices { input: String::new() }),
+            "price" => Some(Self::Price {
+                buyer: String::new(), seller: String::new(), product: String::new(), quantity: 1.0
+            }),
+            "show" => Some(Self::Show { key: String::new() }),
+            "search" => Some(Self::Search { text: String::new(), lang: None, limit: 20 }),
+            "stats" => Some(Self::Stats),
+            "reindex" => Some(Self::Reindex(RunFilter::default())),
+            "maintain" => Some(Self::Maintain),
+            "verify" => Some(Self::Verify),
+            "order" => Some(Self::Order { input: String::new(), out: None }),
+            "bench" => Some(Self::Bench { rows: 10000, keep: false }),
+            "gen-fixture" => Some(Self::GenFixture(FixtureSpec::default())),
+            "completions" => Some(Self::Completions { shell: String::new() }),
+            "schema" => Some(Self::Schema),
+            "health" => Some(Self::Health { ready: false }),
+            "demo" => Some(Self::Demo { out: None, rows: 100 }),
+            "help" => Some(Self::Help),
+            _ => None,
+        }
+    }
+}
+
+fn number(opt: &str, val: &str) -> Result<usize> {
+    match val.parse() {
+        Ok(n) => Ok(n),
+        Err(_) => bail!("Option --{} expects a number, got '{}'", opt, val),
+    }
+}
+
+fn decimal(opt: &str, val: &str) -> Result<f64> {
+    match val.parse::<f64>() {
+        Ok(n) if n.is_finite() && n > 0.0 => Ok(n),
+        _ => bail!("Option --{} expects a positive number, got '{}'", opt, val),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    fn from_name(val: &str) -> Result<Self> {
+        match val {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => bail!("Unknown log format '{}', expected text or json", val),
+        }
+    }
+}
+
+pub struct Args {
+    pub source: ConfigSource,
+    pub command: Command,
+    // Negative is quiet, 0 default (info), 1 debug and 2+ trace
+    pub verbosity: i8,
+    pub log_format: LogFormat,
+    // Per stage timings of imports are collected and printed
+    pub timings: bool,
+}
+
+impl Args {
+    /// Reads command line as `[dir] [command] [--options]`. Plain directory
+    /// argument (or 'example') keeps working as before.
+    pub fn parse() -> Result<Self> {
+        Self::from_iter(env::args().skip(1))
+    }
+    fn from_iter<I>(iter: I) -> Result<Self> where I: Iterator<Item = String> {
+        let mut args = Self {
+            source: ConfigSource::default(),
+            command: Command::Run(RunFilter::default()),
+            verbosity: 0,
+            log_format: LogFormat::Text,
+            timings: false,
+        };
+        let mut command_set = false;
+        let mut iter = iter;
+
+        while let Some(a) = iter.next() {
+            match a.as_str() {
+                "-q" | "--quiet" => { args.verbosity = -1; continue },
+                "-v" | "--verbose" => { args.verbosity = args.verbosity.max(0) + 1; continue },
+                "-vv" => { args.verbosity = 2; continue },
+                "--timings" => { args.timings = true; continue },
+                "--container" => { args.source.container = true; continue },
+                "-h" | "--help" => { args.command = Command::Help; command_set = true; continue },
+                _ => (),
+            }
+
+            if let Some(opt) = a.strip_prefix("--") {
+                let mut value = || match iter.next() {
+                    Some(v) => Ok(v),
+                    None => bail!("Option --{} requires a value", opt),
+                };
+
+                match (opt, &mut args.command) {
+                    ("dir", _) => args.source.dir = Some(value()?),
+                    ("config", _) => args.source.file = Some(value()?),
+                    ("log-format", _) => args.log_format = LogFormat::from_name(&value()?)?,
+                    ("seller", Command::Run(f)) => f.sellers.push(value()?),
+                    ("category", Command::Run(f)) => f.categories.push(Category::from_name(&value()?)?),
+                    ("seller", Command::Reindex(f)) => f.sellers.push(value()?),
+                    ("category", Command::Reindex(f)) => f.categories.push(Category::from_name(&value()?)?),
+                    ("seller", Command::Reimport { seller, .. }) => *seller = value()?,
+                    ("file", Command::Reimport { file, .. }) => *file = Some(value()?),
+                    ("type", Command::ImportFile { kind, .. }) => *kind = Some(EdiFileKind::from_name(&value()?)?),
+                    ("seller", Command::ImportFile { seller, .. }) => *seller = Some(value()?),
+                    ("remote-addr", Command::ImportFile { remote_addr, .. }) => *remote_addr = Some(value()?),
+                    ("type", Command::GenFixture(f)) => f.kinds.push(EdiFileKind::from_name(&value()?)?),
+                    ("rows", Command::GenFixture(f)) => f.rows = number(opt, &value()?)?,
+                    ("seller", Command::GenFixture(f)) => f.sellers.push(value()?),
+                    ("buyer", Command::GenFixture(f)) => f.buyer = value()?,
+                    ("category", Command::GenFixture(f)) => f.category = Category::from_name(&value()?)?,
+                    ("defects", Command::GenFixture(f)) => f.defects = number(opt, &value()?)?,
+                    ("out", Command::GenFixture(f)) => f.out = PathBuf::from(value()?),
+                    ("out", Command::Order { out, .. }) => *out = Some(value()?),
+                    ("buyer", Command::Ean { buyer, .. }) => *buyer = Some(value()?),
+                    ("buyer", Command::Price { buyer, .. }) => *buyer = value()?,
+                    ("seller", Command::Price { seller, .. }) => *seller = value()?,
+                    ("product", Command::Price { product, .. }) => *product = value()?,
+                    ("qty", Command::Price { quantity, .. }) => *quantity = decimal(opt, &value()?)?,
+                    ("lang", Command::Search { lang, .. }) => *lang = Some(value()?),
+                    ("limit", Command::Search { limit, .. }) => *limit = number(opt, &value()?)?,
+                    ("seller", Command::ImportEtim { seller, .. }) => *seller = Some(value()?),
+                    ("registry", Command::ImportRegistry { registry, .. }) => *registry = Some(Registry::from_name(&value()?)?),
+                    ("rows", Command::Bench { rows, .. }) => *rows = number(opt, &value()?)?,
+                    ("keep", Command::Bench { keep, .. }) => *keep = true,
+                    ("ready", Command::Health { ready }) => *ready = true,
+                    ("out", Command::Demo { out, .. }) => *out = Some(value()?),
+                    ("rows", Command::Demo { rows, .. }) => *rows = number(opt, &value()?)?,
+                    _ => bail!("Unknown option --{}", opt),
+                }
+
+                continue;
+            }
+
+            if !command_set {
+                if let Some(c) = Command::from_name(&a) {
+                    args.command = c;
+                    command_set = true;
+                    continue;
+                }
+            }
+
+            match &mut args.command {
+                Command::ImportFile { path, .. } if command_set && path.is_empty() => *path = a,
+                Command::Completions { shell } if shell.is_empty() => *shell = a,
+                Command::Order { input, .. } if input.is_empty() => *input = a,
+                Command::Ean { code, .. } if code.is_empty() => *code = a,
+                Command::Prices { input } if input.is_empty() => *input = a,
+                Command::Show { key } if key.is_empty() => *key = a,
+                Command::Search { text, .. } if text.is_empty() => *text = a,
+                Command::ImportEtim { path, .. } if path.is_empty() => *path = a,
+                Command::ImportRegistry { path, .. } if path.is_empty() => *path = a,
+                _ => match args.source.dir.is_none() && !command_set {
+                    true => args.source.dir = Some(a),
+                    false => bail!("Unexpected argument '{}'", a),
+                },
+            }
+        }
+
+        match &args.command {
+            Command::Reimport { seller, .. } if seller.is_empty() => {
+                bail!("reimport requires --seller <id>")
+            },
+            Command::ImportFile { path, .. } if path.is_empty() => {
+                bail!("import-file requires a file path")
+            },
+            Command::ImportRegistry { path, registry } if path.is_empty() || registry.is_none() => {
+                bail!("import-registry requires a file path and --registry <lvi|sahko>")
+            },
+            Command::ImportEtim { path, .. } if path.is_empty() => {
+                bail!("import-etim requires a file path")
+            },
+            Command::Ean { code, .. } if code.is_empty() => {
+                bail!("ean requires an EAN code")
+            },
+            Command::Show { key } if key.is_empty() => {
+                bail!("show requires a product id or EAN code")
+            },
+            Command::Search { text, .. } if text.is_empty() => {
+                bail!("search requires a text, product id or EAN code")
+            },
+            Command::Prices { input } if input.is_empty() => {
+                bail!("prices requires a request file path, or '-' for stdin")
+            },
+            Command::Order { input, .. } if input.is_empty() => {
+                bail!("order requires an order JSON file path")
+            },
+            Command::Price { buyer, seller, product, .. } if buyer.is_empty() || seller.is_empty()
+                || product.is_empty() => {
+                bail!("price requires --buyer <uuid>, --seller <id> and --product <id>")
+            },
+            Command::Completions { shell } if shell.is_empty() => {
+                bail!("completions requires a shell: {}", SHELLS.join(", "))
+            },
+            _ => (),
+        }
+
+        let stdin_input = match &args.command {
+            Command::ImportFile { path: p, .. } | Command::Prices { input: p } | Command::Order { input: p, .. } => {
+                p.eq("-")
+            },
+            _ => false,
+        };
+
+        if stdin_input && args.source.file.as_deref() == Some("-") {
+            bail!("Config and command input can't both be read from stdin")
+        }
+
+        Ok(args)
+    }
+
+    /// Console logging by verbosity flags. RUST_LOG still overrides the level.
+    pub fn init_logger(&self) {
+        let level = match self.verbosity {
+            v if v < 0 => "error",
+            0 => "info",
+            1 => "debug",
+            _ => "trace",
+        };
+
+        let mut builder = env_logger::Builder::from_env(env_logger::Env::new().default_filter_or(level));
+
+        if self.log_format == LogFormat::Json {
+            builder.format(|buf, record| {
+                let line = serde_json::json!({
+                    "time": Utc::now().to_rfc3339(),
+                    "level": record.level().to_string(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                });
+
+                writeln!(buf, "{}", line)
+            });
+        }
+
+        builder.init();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<Args> {
+        Args::from_iter(line.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn plain_dir_runs_everything() {
+        let args = parse("example").unwrap();
+
+        assert_eq!(args.source.dir.as_deref(), Some("example"));
+        assert!(matches!(args.command, Command::Run(ref f) if !f.is_active()));
+    }
+
+    #[test]
+    fn global_options() {
+        let args = parse("--dir data --config - -v --timings --container --log-format json stats").unwrap();
+
+        assert_eq!(args.source.dir.as_deref(), Some("data"));
+        assert_eq!(args.source.file.as_deref(), Some("-"));
+        assert!(args.source.container && args.timings);
+        assert_eq!(args.verbosity, 1);
+        assert_eq!(args.log_format, LogFormat::Json);
+        assert!(matches!(args.command, Command::Stats));
+
+        assert_eq!(parse("-q").unwrap().verbosity, -1);
+        assert!(parse("--log-format xml").is_err());
+        assert!(parse("--dir").is_err());
+    }
+
+    #[test]
+    fn run_and_reindex_filters() {
+        for (name, args) in [("run", parse("data run --seller 003700000001 --category lv").unwrap()),
+            ("reindex", parse("data reindex --seller 003700000001 --category lv").unwrap())] {
+            let f = match args.command {
+                Command::Run(f) | Command::Reindex(f) => f,
+                _ => panic!("{} parsed as another command", name),
+            };
+
+            assert_eq!(f.sellers, ["003700000001"]);
+            assert_eq!(f.categories, [Category::WaterAndHeating]);
+        }
+
+        assert!(parse("data reindex --category xx").is_err());
+    }
+
+    #[test]
+    fn import_commands() {
+        let args = parse("data import-file in.txt --type price --seller 003700000001 --remote-addr 10.0.0.1").unwrap();
+
+        assert!(matches!(args.command, Command::ImportFile { ref path, kind: Some(EdiFileKind::Price),
+            seller: Some(ref s), remote_addr: Some(ref a) } if path == "in.txt" && s == "003700000001" && a == "10.0.0.1"));
+
+        let args = parse("data reimport --seller 003700000001 --file a.txt").unwrap();
+
+        assert!(matches!(args.command, Command::Reimport { ref seller, file: Some(ref f) }
+            if seller == "003700000001" && f == "a.txt"));
+
+        let args = parse("data import-registry numbers.csv --registry sähkö").unwrap();
+
+        assert!(matches!(args.command, Command::ImportRegistry { ref path, registry: Some(Registry::Sahko) }
+            if path == "numbers.csv"));
+
+        let args = parse("data import-etim etim.csv --seller 003700000001").unwrap();
+
+        assert!(matches!(args.command, Command::ImportEtim { ref path, seller: Some(_) } if path == "etim.csv"));
+
+        for line in ["data reimport", "data import-file", "data import-registry numbers.csv", "data import-etim",
+            "data import-file in.txt --type invoice"] {
+            assert!(parse(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn lookup_commands() {
+        let args = parse("data price --buyer b --seller s --product p --qty 2.5").unwrap();
+
+        assert!(matches!(args.command, Command::Price { ref buyer, ref seller, ref product, quantity }
+            if buyer == "b" && seller == "s" && product == "p" && quantity == 2.5));
+
+        let args = parse("data search kupariputki --lang swe --limit 5").unwrap();
+
+        assert!(matches!(args.command, Command::Search { ref text, lang: Some(ref l), limit: 5 }
+            if text == "kupariputki" && l == "swe"));
+        assert!(matches!(parse("data search putki").unwrap().command, Command::Search { limit: 20, .. }));
+
+        assert!(matches!(parse("data ean 6411234567890 --buyer b").unwrap().command,
+            Command::Ean { ref code, buyer: Some(_) } if code == "6411234567890"));
+        assert!(matches!(parse("data show P1").unwrap().command, Command::Show { ref key } if key == "P1"));
+        assert!(matches!(parse("data prices -").unwrap().command, Command::Prices { ref input } if input == "-"));
+        assert!(matches!(parse("data order o.json --out r.json").unwrap().command,
+            Command::Order { ref input, out: Some(_) } if input == "o.json"));
+
+        for line in ["data price --buyer b --seller s", "data price --buyer b --seller s --product p --qty 0",
+            "data search", "data search putki --limit many", "data ean", "data show", "data prices", "data order",
+            "--config - data prices -"] {
+            assert!(parse(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn tool_commands() {
+        let args = parse("gen-fixture --type product --type price --rows 50 --seller a --seller b --buyer c \
+            --category sa --defects 3 --out fx").unwrap();
+
+        match args.command {
+            Command::GenFixture(f) => {
+                assert_eq!(f.kinds, [EdiFileKind::Product, EdiFileKind::Price]);
+                assert_eq!((f.rows, f.defects), (50, 3));
+                assert_eq!(f.sellers, ["a", "b"]);
+                assert_eq!(f.buyer, "c");
+                assert_eq!(f.category, Category::Electricity);
+                assert_eq!(f.out, PathBuf::from("fx"));
+            },
+            _ => panic!("gen-fixture parsed as another command"),
+        }
+
+        assert!(matches!(parse("bench --rows 10 --keep").unwrap().command, Command::Bench { rows: 10, keep: true }));
+        assert!(matches!(parse("demo --out d --rows 7").unwrap().command, Command::Demo { out: Some(_), rows: 7 }));
+        assert!(matches!(parse("data health --ready").unwrap().command, Command::Health { ready: true }));
+        assert!(matches!(parse("completions bash").unwrap().command, Command::Completions { ref shell }
+            if shell == "bash"));
+
+        for (line, command) in [("data maintain", "maintain"), ("data verify", "verify"), ("data schema", "schema"),
+            ("--help", "help")] {
+            let parsed = parse(line).unwrap().command;
+
+            assert!(matches!((command, parsed), ("maintain", Command::Maintain) | ("verify", Command::Verify)
+                | ("schema", Command::Schema) | ("help", Command::Help)), "{}", line);
+        }
+
+        assert!(parse("completions").is_err());
+    }
+
+    #[test]
+    fn options_belong_to_their_command() {
+        for line in ["data stats --seller 003700000001", "data verify --rows 5", "data search putki --keep",
+            "data stats extra"] {
+            assert!(parse(line).is_err(), "{}", line);
+        }
+    }
+}
@@ -0,0 +1,502 @@
+use anyhow::{bail, Result};
+
+pub const BIN_NAME: &str = "lvisweb-ediparser";
+
+// What kind of value an option takes, drives the shell completion.
+pub enum Value {
+    Flag,
+    Any(&'static str),
+    Dir,
+    File,
+    Choice(&'static [&'static str]),
+}
+
+pub struct Opt {
+    pub name: &'static str,
+    pub short: Option<char>,
+    pub value: Value,
+    pub help: &'static str,
+}
+
+pub struct Cmd {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub opts: &'static [Opt],
+    // Positional argument after the command, if any
+    pub positional: Option<Value>,
+}
+
+pub const SHELLS: &[&str] = &["bash", "zsh", "fish"];
+const CATEGORIES: &[&str] = &["lv", "iv", "sa", "te", "ky"];
+const FILE_TYPES: &[&str] = &["product", "price", "discount"];
+
+// Keep in sync with cli::Args::from_iter
+pub const GLOBAL_OPTS: &[Opt] = &[
+    Opt { name: "dir", short: None, value: Value::Dir, help: "Data directory, 'example' for the example setup" },
+    Opt { name: "config", short: None, value: Value::File, help: "Config file instead of config.toml of the data dir, '-' reads stdin" },
+    Opt { name: "container", short: None, value: Value::Flag, help: "Data dir required and created, no current dir fallback nor example setup" },
+    Opt { name: "log-format", short: None, value: Value::Choice(&["text", "json"]), help: "Console log format" },
+    Opt { name: "quiet", short: Some('q'), value: Value::Flag, help: "Print errors only" },
+    Opt { name: "verbose", short: Some('v'), value: Value::Flag, help: "Debug output, -vv for trace" },
+    Opt { name: "timings", short: None, value: Value::Flag, help: "Print time spent in each import stage" },
+    Opt { name: "help", short: Some('h'), value: Value::Flag, help: "Print help" },
+];
+
+pub const COMMANDS: &[Cmd] = &[
+    Cmd {
+        name: "run",
+        help: "Download, unzip and import everything (default)",
+        opts: &[
+            Opt { name: "seller", short: None, value: Value::Any("id"), help: "Limit run to seller, repeatable" },
+            Opt { name: "category", short: None, value: Value::Choice(CATEGORIES), help: "Limit run to category, repeatable" },
+        ],
+        positional: None,
+    },
+    Cmd {
+        name: "reimport",
+        help: "Import files already imported for a seller again",
+        opts: &[
+            Opt { name: "seller", short: None, value: Value::Any("id"), help: "Seller id (required)" },
+            Opt { name: "file", short: None, value: Value::Any("name"), help: "Only this file" },
+        ],
+        positional: None,
+    },
+    Cmd {
+        name: "import-file",
+        help: "Import a single EDI file or zip archive, '-' reads stdin",
+        opts: &[
+            Opt { name: "type", short: None, value: Value::Choice(FILE_TYPES), help: "Skip file type detection" },
+            Opt { name: "seller", short: None, value: Value::Any("id"), help: "Require file header to match seller" },
+            Opt { name: "remote-addr", short: None, value: Value::Any("addr"), help: "Address of the upload's sender for the audit log" },
+        ],
+        positional: Some(Value::File),
+    },
+    Cmd {
+        name: "import-registry",
+        help: "Import LVI-numero or Sähkönumero registry file",
+        opts: &[
+            Opt { name: "registry", short: None, value: Value::Choice(&["lvi", "sahko"]), help: "Registry of the file (required)" },
+        ],
+        positional: Some(Value::File),
+    },
+    Cmd {
+        name: "import-etim",
+        help: "Import ETIM classes, features or product classifications",
+        opts: &[
+            Opt { name: "seller", short: None, value: Value::Any("id"), help: "Seller of product classifications" },
+        ],
+        positional: Some(Value::File),
+    },
+    Cmd {
+        name: "reindex",
+        help: "Drop and rebuild search indexes from the catalog",
+        opts: &[
+            Opt { name: "category", short: None, value: Value::Choice(CATEGORIES), help: "Limit to category, repeatable" },
+            Opt { name: "seller", short: None, value: Value::Any("id"), help: "Rebuild rows of seller, repeatable" },
+        ],
+        positional: None,
+    },
+    Cmd {
+        name: "maintain",
+        help: "Run ANALYZE, PRAGMA optimize and incremental vacuum on databases",
+        opts: &[],
+        positional: None,
+    },
+    Cmd {
+        name: "verify",
+        help: "Cross check databases against JSON files, report discrepancies",
+        opts: &[],
+        positional: None,
+    },
+    Cmd {
+        name: "order",
+        help: "Write order JSON as EDIFACT ORDERS message, '-' reads stdin",
+        opts: &[
+            Opt { name: "out", short: None, value: Value::File, help: "Message path (default orders/<seller>/<number>.edi)" },
+        ],
+        positional: Some(Value::File),
+    },
+    Cmd {
+        name: "ean",
+        help: "List sellers' offers of a scanned EAN code as JSON",
+        opts: &[
+            Opt { name: "buyer", short: None, value: Value::Any("id"), help: "Customer number for net prices" },
+        ],
+        positional: Some(Value::Any("code")),
+    },
+    Cmd {
+        name: "prices",
+        help: "Price a list of products as JSON, '-' reads stdin",
+        opts: &[],
+        positional: Some(Value::File),
+    },
+    Cmd {
+        name: "price",
+        help: "Explain a buyer's price of one product",
+        opts: &[
+            Opt { name: "buyer", short: None, value: Value::Any("uuid"), help: "Uuid of buyer's account at the seller" },
+            Opt { name: "seller", short: None, value: Value::Any("id"), help: "Seller id" },
+            Opt { name: "product", short: None, value: Value::Any("id"), help: "Product id of the seller" },
+            Opt { name: "qty", short: None, value: Value::Any("count"), help: "Quantity in product's unit (default 1)" },
+        ],
+        positional: None,
+    },
+    Cmd {
+        name: "show",
+        help: "Show a product of every seller by product id or EAN code",
+        opts: &[],
+        positional: Some(Value::Any("product")),
+    },
+    Cmd {
+        name: "search",
+        help: "Search products, full product ids and EAN codes first",
+        opts: &[
+            Opt { name: "lang", short: None, value: Value::Any("lang"), help: "Language (default first of lang_codes)" },
+            Opt { name: "limit", short: None, value: Value::Any("count"), help: "Max results (default 20)" },
+        ],
+        positional: Some(Value::Any("text")),
+    },
+    Cmd {
+        name: "stats",
+        help: "Count products and prices per seller and category",
+        opts: &[],
+        positional: None,
+    },
+    Cmd {
+        name: "bench",
+        help: "Time parsing, DB inserts and indexing on generated files",
+        opts: &[
+            Opt { name: "rows", short: None, value: Value::Any("count"), help: "Rows per file (default 10000)" },
+            Opt { name: "keep", short: None, value: Value::Flag, help: "Keep the scratch dir" },
+        ],
+        positional: None,
+    },
+    Cmd {
+        name: "gen-fixture",
+        help: "Write synthetic EDI files for tests and benchmarks",
+        opts: &[
+            Opt { name: "type", short: None, value: Value::Choice(FILE_TYPES), help: "File type, repeatable (default all)" },
+            Opt { name: "rows", short: None, value: Value::Any("count"), help: "Rows per file (default 100)" },
+            Opt { name: "seller", short: None, value: Value::Any("id"), help: "Seller id, repeatable" },
+            Opt { name: "buyer", short: None, value: Value::Any("id"), help: "Buyer id of discount files" },
+            Opt { name: "category", short: None, value: Value::Choice(CATEGORIES), help: "Product category (default lv)" },
+            Opt { name: "defects", short: None, value: Value::Any("count"), help: "Broken rows per file" },
+            Opt { name: "out", short: None, value: Value::Dir, help: "Output dir (default .)" },
+        ],
+        positional: None,
+    },
+    Cmd {
+        name: "completions",
+        help: "Print shell completion script",
+        opts: &[],
+        positional: Some(Value::Choice(SHELLS)),
+    },
+    Cmd {
+        name: "schema",
+        help: "Print JSON Schema of the JSON outputs",
+        opts: &[],
+        positional: None,
+    },
+    Cmd {
+        name: "health",
+        help: "Print database and import status as JSON, exit code for probes",
+        opts: &[
+            Opt { name: "ready", short: None, value: Value::Flag, help: "Fail until an import has finished" },
+        ],
+        positional: None,
+    },
+    Cmd {
+        name: "demo",
+        help: "Generate a dataset of several sellers and run the pipeline on it",
+        opts: &[
+            Opt { name: "out", short: None, value: Value::Dir, help: "Empty data dir to use (default under temp dir)" },
+            Opt { name: "rows", short: None, value: Value::Any("n"), help: "Products per seller (default 100)" },
+        ],
+        positional: None,
+    },
+    Cmd {
+        name: "help",
+        help: "Print help",
+        opts: &[],
+        positional: None,
+    },
+];
+
+pub fn help() -> String {
+    let mut out = format!("Usage: {} [dir] [command] [options]\n\nCommands:\n", BIN_NAME);
+
+    for c in COMMANDS {
+        out.push_str(&format!("  {:<18}{}\n", c.name, c.help));
+
+        for o in c.opts {
+            out.push_str(&format!("      {:<34}{}\n", opt_usage(o), o.help));
+        }
+    }
+
+    out.push_str("\nGlobal options:\n");
+
+    for o in GLOBAL_OPTS {
+        out.push_str(&format!("  {:<38}{}\n", opt_usage(o), o.help));
+    }
+
+    out
+}
+
+pub fn completions(shell: &str) -> Result<String> {
+    match shell {
+        "bash" => Ok(bash()),
+        "zsh" => Ok(zsh()),
+        "fish" => Ok(fish()),
+        _ => bail!("Unsupported shell '{}', expected one of {}", shell, SHELLS.join(", ")),
+    }
+}
+
+fn opt_usage(o: &Opt) -> String {
+    let long = match o.short {
+        Some(s) => format!("-{}, --{}", s, o.name),
+        None => format!("--{}", o.name),
+    };
+
+    match &o.value {
+        Value::Flag => long,
+        Value::Any(n) => format!("{} <{}>", long, n),
+        Value::Dir => format!("{} <path>", long),
+        Value::File => format!("{} <file>", long),
+        Value::Choice(c) => format!("{} <{}>", long, c.join("|")),
+    }
+}
+
+fn opt_words(opts: &[Opt]) -> Vec<String> {
+    let mut words = vec![];
+
+    for o in opts {
+        words.push(format!("--{}", o.name));
+
+        if let Some(s) = o.short {
+            words.push(format!("-{}", s));
+        }
+    }
+
+    words
+}
+
+fn command_names() -> Vec<&'static str> {
+    COMMANDS.iter().map(|c| c.name).collect()
+}
+
+fn bash() -> String {
+    let func = format!("_{}", BIN_NAME.replace('-', "_"));
+    let mut values = String::new();
+
+    for o in GLOBAL_OPTS.iter().chain(COMMANDS.iter().flat_map(|c| c.opts.iter())) {
+        let reply = match &o.value {
+            Value::Flag => continue,
+            Value::Any(_) => String::from("COMPREPLY=()"),
+            Value::Dir => String::from("COMPREPLY=($(compgen -d -- \"$cur\"))"),
+            Value::File => String::from("COMPREPLY=($(compgen -f -- \"$cur\"))"),
+            Value::Choice(c) => format!("COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))", c.join(" ")),
+        };
+
+        let case = format!("        --{}) {}; return;;\n", o.name, reply);
+
+        if !values.contains(&case) {
+            values.push_str(&case);
+        }
+    }
+
+    let globals = opt_words(GLOBAL_OPTS).join(" ");
+    let mut commands = String::new();
+
+    for c in COMMANDS {
+        let mut words = opt_words(c.opts);
+
+        words.push(globals.to_owned());
+
+        let positional = match &c.positional {
+            Some(Value::Choice(v)) => format!(" {}", v.join(" ")),
+            _ => String::new(),
+        };
+
+        let files = match &c.positional {
+            Some(Value::File) => " -f",
+            _ => "",
+        };
+
+        commands.push_str(&format!(
+            "        {}) COMPREPLY=($(compgen{} -W \"{}{}\" -- \"$cur\"));;\n",
+            c.name, files, words.join(" "), positional
+        ));
+    }
+
+    // Without a command the pipeline is run, offer commands and run options
+    let run = COMMANDS.iter().find(|c| c.name.eq("run")).map(|c| opt_words(c.opts)).unwrap_or_default();
+
+    format!(r#"{func}() {{
+    local cur prev cmd i
+    cur="${{COMP_WORDS[COMP_CWORD]}}"
+    prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    cmd=""
+
+    for ((i=1; i<COMP_CWORD; i++)); do
+        case "${{COMP_WORDS[i]}}" in
+            {names}) cmd="${{COMP_WORDS[i]}}"; break;;
+        esac
+    done
+
+    case "$prev" in
+{values}    esac
+
+    case "$cmd" in
+{commands}        *) COMPREPLY=($(compgen -d -W "{all} {run} {globals}" -- "$cur"));;
+    esac
+}}
+
+complete -F {func} {bin}
+"#,
+        func = func,
+        names = command_names().join("|"),
+        values = values,
+        commands = commands,
+        all = command_names().join(" "),
+        run = run.join(" "),
+        globals = globals,
+        bin = BIN_NAME,
+    )
+}
+
+fn zsh_spec(o: &Opt) -> String {
+    let help = o.help.replace('\'', "");
+    let value = match &o.value {
+        Value::Flag => String::new(),
+        Value::Any(n) => format!(":{}:", n),
+        Value::Dir => String::from(":path:_files -/"),
+        Value::File => String::from(":file:_files"),
+        Value::Choice(c) => format!(":value:({})", c.join(" ")),
+    };
+
+    match o.short {
+        Some(s) => format!("'(-{s} --{n})'{{-{s},--{n}}}'[{h}]{v}'", s = s, n = o.name, h = help, v = value),
+        None => format!("'*--{}[{}]{}'", o.name, help, value),
+    }
+}
+
+fn zsh() -> String {
+    let func = format!("_{}", BIN_NAME.replace('-', "_"));
+    let globals = GLOBAL_OPTS.iter().map(zsh_spec).collect::<Vec<String>>().join(" \\\n        ");
+    let descriptions = COMMANDS.iter()
+        .map(|c| format!("'{}:{}'", c.name, c.help.replace('\'', "")))
+        .collect::<Vec<String>>()
+        .join("\n        ");
+
+    let mut commands = String::new();
+
+    for c in COMMANDS {
+        let mut specs = c.opts.iter().map(zsh_spec).collect::<Vec<String>>();
+
+        match &c.positional {
+            Some(Value::File) => specs.push(String::from("'*:file:_files'")),
+            Some(Value::Choice(v)) => specs.push(format!("'*:value:({})'", v.join(" "))),
+            _ => (),
+        }
+
+        commands.push_str(&format!("        {}) _arguments -s $globals {} ;;\n", c.name, specs.join(" ")));
+    }
+
+    let run = COMMANDS.iter().find(|c| c.name.eq("run"))
+        .map(|c| c.opts.iter().map(zsh_spec).collect::<Vec<String>>().join(" "))
+        .unwrap_or_default();
+
+    format!(r#"#compdef {bin}
+
+{func}() {{
+    local -a globals commands
+    local cmd
+
+    globals=(
+        {globals}
+    )
+
+    commands=(
+        {descriptions}
+    )
+
+    cmd=${{words[(r)({names})]}}
+
+    case $cmd in
+{commands}        *)
+            _describe -t commands command commands
+            _arguments -s $globals {run} '*:dir:_files -/'
+            ;;
+    esac
+}}
+
+{func} "$@"
+"#,
+        bin = BIN_NAME,
+        func = func,
+        globals = globals,
+        descriptions = descriptions,
+        names = command_names().join("|"),
+        commands = commands,
+        run = run,
+    )
+}
+
+fn fish_line(condition: &str, o: &Opt) -> String {
+    let mut line = format!("complete -c {} -n '{}' -l {}", BIN_NAME, condition, o.name);
+
+    if let Some(s) = o.short {
+        line.push_str(&format!(" -s {}", s));
+    }
+
+    match &o.value {
+        Value::Flag => (),
+        Value::Any(_) => line.push_str(" -x"),
+        Value::Dir => line.push_str(" -x -a '(__fish_complete_directories)'"),
+        Value::File => line.push_str(" -r -F"),
+        Value::Choice(c) => line.push_str(&format!(" -x -a '{}'", c.join(" "))),
+    }
+
+    line.push_str(&format!(" -d '{}'\n", o.help.replace('\'', "")));
+    line
+}
+
+fn fish() -> String {
+    let mut out = format!("complete -c {} -f\n", BIN_NAME);
+    let names = command_names().join(" ");
+
+    for c in COMMANDS {
+        out.push_str(&format!(
+            "complete -c {} -n 'not __fish_seen_subcommand_from {}' -a {} -d '{}'\n",
+            BIN_NAME, names, c.name, c.help.replace('\'', "")
+        ));
+    }
+
+    for o in GLOBAL_OPTS {
+        out.push_str(&fish_line("true", o));
+    }
+
+    for c in COMMANDS {
+        // Run options are valid without the command as well
+        let condition = match c.name {
+            "run" => format!("not __fish_seen_subcommand_from {}", names.replace("run ", "")),
+            _ => format!("__fish_seen_subcommand_from {}", c.name),
+        };
+
+        for o in c.opts {
+            out.push_str(&fish_line(&condition, o));
+        }
+
+        match &c.positional {
+            Some(Value::File) => out.push_str(&format!(
+                "complete -c {} -n '__fish_seen_subcommand_from {}' -F\n", BIN_NAME, c.name
+            )),
+            Some(Value::Choice(v)) => out.push_str(&format!(
+                "complete -c {} -n '__fish_seen_subcommand_from {}' -x -a '{}'\n", BIN_NAME, c.name, v.join(" ")
+            )),
+            _ => (),
+        }
+    }
+
+    out
+}
@@ -1,16 +1,60 @@
-use rusqlite::{Connection, Result};
-use log::warn;
+use std::time::Duration;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Result};
+use log::{info, warn};
 
 use super::utils::Category;
 use super::config::Config;
+use super::search::search_table;
 
 
+// Columns of products_<k> and prices_<k>, entries tables have category as well
+const PRODUCT_COLUMNS: &str = "id, product_id, seller_id, operation, date, discount_group, unit, unit_weight, \
+    unit_volume, typical_packaging, packaging_1, packaging_1_discount, packaging_2, packaging_2_discount, \
+    packaging_3, packaging_3_discount, delivery_in_weeks, stock_item, ean_code, usage_unit, usables_in_unit, \
+    source_file, imported_at, created_at, updated_at";
+const PRICE_COLUMNS: &str = "id, product_id, price_group, price, date, discount_group, unit, units_incl, \
+    packaging_1, packaging_1_discount, packaging_2, packaging_2_discount, packaging_3, packaging_3_discount, \
+    usage_unit, usables_in_unit, stock_item, delivery_in_weeks, price_per_usage_unit, source_file, imported_at, \
+    price_eur, price_per_usage_unit_eur, vat_percent, price_incl_vat_eur, created_at, updated_at";
+// Written by the importer, a changed value doesn't make the row updated
+const PROVENANCE_COLUMNS: [&str; 5] = ["id", "source_file", "imported_at", "created_at", "updated_at"];
+
+// Wait for the other process' lock instead of failing right away
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Readers and the writer don't block each other in WAL mode. Mode is stored
+// in the database file.
+fn open(path: std::path::PathBuf) -> Result<Connection> {
+    let conn = Connection::open(path)?;
+
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    conn.query_row("pragma journal_mode = wal", [], |r| r.get::<_, String>(0))?;
+
+    Ok(conn)
+}
+
+/// Open a database of the data dir read only, it isn't created if missing.
+pub fn open_read_only_db(config: &Config, name: &str) -> Result<Connection> {
+    let conn = Connection::open_with_flags(config.dir.join(name),
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)?;
+
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+
+    Ok(conn)
+}
+
+/// Open both databases read only, for lookups running alongside imports.
+/// Databases are expected to be initialized by an earlier run.
+pub fn open_read_only(config: &Config) -> Result<(Connection, Connection)> {
+    Ok((open_read_only_db(config, "sellers.db")?, open_read_only_db(config, "buyers.db")?))
+}
+
 pub fn init(config: &Config) -> Result<(Connection, Connection)> {
     // Sellers DB
     let mut path = config.dir.to_owned();
     path.push("sellers.db");
     
-    let sellers = Connection::open(path)?;
+    let sellers = open(path)?;
 
     // Create sellers table
     sellers.execute(
@@ -49,11 +93,50 @@ pub fn init(config: &Config) -> Result<(Connection, Connection)> {
     // Create price groups table
     sellers.execute(
         "create table if not exists price_groups (
-            id text primary key
+            id text primary key,
+            label text null,
+            is_list_price integer not null default 0,
+            includes_vat integer not null default 0
         )",
         [],
     )?;
 
+    // Databases created before price group labels existed
+    add_column(&sellers, "price_groups", "label", "text null")?;
+    add_column(&sellers, "price_groups", "is_list_price", "integer not null default 0")?;
+    add_column(&sellers, "price_groups", "includes_vat", "integer not null default 0")?;
+
+    // Config may have relabeled groups we already know of
+    for g in config.price_group.iter() {
+        sellers.execute(
+            "update price_groups set label = ?2, is_list_price = ?3, includes_vat = ?4 \
+            where id = ?1",
+            params!(&g.id, &g.label, g.is_list_price, g.includes_vat)
+        )?;
+    }
+
+    // Why the search index has to be built on the next import even without
+    // new products
+    if config.import.search {
+        sellers.execute(
+            "create table if not exists search_dirty (
+                reason text primary key,
+                marked_at text not null
+            )",
+            [],
+        )?;
+    }
+
+    // Renamed sellers, search index picks the name up on next build
+    for s in config.seller.iter() {
+        let renamed = sellers.execute("update sellers set name = ?2 where id = ?1 and name != ?2",
+            params!(&s.id, &s.name))?;
+
+        if renamed > 0 && config.import.search {
+            mark_search_dirty(&sellers, &format!("seller {} renamed to {}", s.id, s.name))?;
+        }
+    }
+
     // Generic products table
     sellers.execute(
         "create table if not exists products (
@@ -65,12 +148,87 @@ pub fn init(config: &Config) -> Result<(Connection, Connection)> {
     )?;
 
 
+    // Seller products and prices of all categories, products_<k> and
+    // prices_<k> are views of a category
+    sellers.execute_batch(
+        "create table if not exists products_entries (
+            id text primary key,
+            category text not null,
+            product_id text not null,
+            seller_id text not null,
+            operation text not null,
+            date text not null,
+            discount_group text not null,
+            unit text not null,
+            unit_weight real null,
+            unit_volume real null,
+            typical_packaging integer null,
+            packaging_1 real null,
+            packaging_1_discount real null,
+            packaging_2 real null,
+            packaging_2_discount real null,
+            packaging_3 real null,
+            packaging_3_discount real null,
+            delivery_in_weeks integer null,
+            stock_item integer not null,
+            ean_code text null,
+            usage_unit text null,
+            usables_in_unit real not null,
+            source_file text null,
+            imported_at text null
+        );
+        create index if not exists products_entries_category on products_entries (category, seller_id);
+        create index if not exists products_entries_ean on products_entries (ean_code);
+        create table if not exists prices_entries (
+            id text primary key,
+            category text not null,
+            product_id text not null,
+            price_group text not null,
+            price real not null,
+            date text not null,
+            discount_group text not null,
+            unit text not null,
+            units_incl integer not null,
+            packaging_1 real null,
+            packaging_1_discount real null,
+            packaging_2 real null,
+            packaging_2_discount real null,
+            packaging_3 real null,
+            packaging_3_discount real null,
+            usage_unit text null,
+            usables_in_unit real not null,
+            stock_item integer not null,
+            delivery_in_weeks integer null,
+            price_per_usage_unit real null,
+            source_file text null,
+            imported_at text null,
+            price_eur real null,
+            price_per_usage_unit_eur real null,
+            vat_percent real null,
+            price_incl_vat_eur real null
+        );
+        create index if not exists prices_entries_category on prices_entries (category);"
+    )?;
+
+    // Price columns computed on import
+    for (column, definition) in PRICE_EUR_COLUMNS {
+        add_column(&sellers, "prices_entries", column, definition)?;
+    }
+
+    let product_columns = format!("category, {PRODUCT_COLUMNS}");
+    let price_columns = format!("category, {PRICE_COLUMNS}");
+
+    row_timestamps(&sellers, "products_entries", &product_columns)?;
+    row_timestamps(&sellers, "prices_entries", &price_columns)?;
+
     // Tables for each product category
-    for (k, v) in Category::mapper().into_iter() {
+    for (k, _) in Category::mapper().into_iter() {
         // Create translations table
         sellers.execute(
             &format!("create table if not exists product_{k}_t (
                 id text primary key,
+                seller_id text null,
+                product_id text null,
                 lang integer not null,
                 name text not null,
                 description text not null,
@@ -80,91 +238,229 @@ pub fn init(config: &Config) -> Result<(Connection, Connection)> {
             [],
         )?;
 
-        // Create full-text search index table to DB
+        // Translation id is seller id + product id + ':' + lang index. Older databases
+        // only have the id, split it assuming 12 char seller ids and the index
+        // of the lang column at the end.
+        let t = format!("product_{k}_t");
+        add_column(&sellers, &t, "seller_id", "text null")?;
+        add_column(&sellers, &t, "product_id", "text null")?;
+
+        sellers.execute(
+            &format!("update {t} set seller_id = substr(id, 1, 12), \
+                product_id = substr(id, 13, length(id) - 12 - length(lang)) where seller_id is null"),
+            [],
+        )?;
+
+        // Ids without the separator collide once indexes have more digits
+        // ('12' + lang 3 and '1' + lang 23)
+        sellers.execute(
+            &format!("update or replace {t} set id = seller_id || product_id || ':' || lang \
+                where id != seller_id || product_id || ':' || lang"),
+            [],
+        )?;
+
+        // Full-text search index of each language, so ranking and optimizing
+        // only ever see the texts of one language
         if config.import.search {
-            let result = sellers.execute(
-                &format!("create virtual table search_{k} using fts5 (
-                    lang UNINDEXED,
-                    seller_id UNINDEXED,
-                    product_id,
-                    body,
-                    tokenize='trigram'
-                )"),
-                [],
-            );
-
-            // Virtual table doesn't have 'if not exists' untill newer versions of sqlite
-            // so if this fails lets just presume it was a duplicate error (^^)
-            if let Err(e) = result {
-                match e.sqlite_error() {
-                    Some(c) => warn!("Create DB search table for {} \
-                        failed with error code ({}), most likely table already exists.",
-                        v, c.extended_code),
-                    None => warn!("Create DB search table failed without \
-                        error code. How strange is that...")
+            search_tables(&sellers, config, k)?;
+
+            // Older databases have one index of all languages
+            let combined: Option<String> = sellers.query_row(
+                "select name from sqlite_master where name = ?1", params![format!("search_{k}")], |r| r.get(0)
+            ).optional()?;
+
+            if combined.is_some() {
+                info!("Moving rows of search_{} to search indexes of each language", k);
+
+                for l in config.lang_codes.iter() {
+                    sellers.execute(
+                        &format!("insert into {} (seller_id, product_id, body) \
+                            select seller_id, product_id, body from search_{k} where lang = ?1", search_table(k, l)),
+                        params![l.to_index() as i64],
+                    )?;
                 }
+
+                sellers.execute(&format!("drop table search_{k}"), [])?;
             }
         }
 
-        // SQLite create product entries table
+        // Category tables of older databases are moved into the entries tables
+        entries_view(&sellers, "products", k, PRODUCT_COLUMNS, &[("source_file", "text null"),
+            ("imported_at", "text null"), ("created_at", "text null"), ("updated_at", "text null")])?;
+        let added = [("price_per_usage_unit", "real null"), ("source_file", "text null"),
+            ("imported_at", "text null"), ("created_at", "text null"), ("updated_at", "text null")];
+
+        entries_view(&sellers, "prices", k, PRICE_COLUMNS, &[&added[..], &PRICE_EUR_COLUMNS[..]].concat())?;
+    }
+
+    // Full product ids and EAN codes for exact matches, searched before the
+    // trigram index. Keys are upper case.
+    if config.import.search {
         sellers.execute(
-            &format!("create table if not exists products_{} (
-                id text primary key,
-                product_id text not null,
+            "create table if not exists search_keys (
+                key text not null,
                 seller_id text not null,
-                operation text not null,
-                date text not null,
-                discount_group text not null,
-                unit text not null,
-                unit_weight real null,
-                unit_volume real null,
-                typical_packaging integer null,
-                packaging_1 real null,
-                packaging_1_discount real null,
-                packaging_2 real null,
-                packaging_2_discount real null,
-                packaging_3 real null,
-                packaging_3_discount real null,
-                delivery_in_weeks integer null,
-                stock_item integer not null,
-                ean_code text null,
-                usage_unit text null,
-                usables_in_unit real not null
-            )", k),
+                product_id text not null,
+                category text not null,
+                primary key (key, seller_id, product_id)
+            ) without rowid",
             [],
         )?;
 
-        // SQLite create prices table
+        // Last build of each index and rows of each seller in it, data of
+        // products imported after the build isn't searchable yet
         sellers.execute(
-            &format!("create table if not exists prices_{} (
-                id text primary key,
-                product_id text not null,
-                price_group text not null,
-                price real not null,
-                date text not null,
-                discount_group text not null,
-                unit text not null,
-                units_incl integer not null,
-                packaging_1 real null,
-                packaging_1_discount real null,
-                packaging_2 real null,
-                packaging_2_discount real null,
-                packaging_3 real null,
-                packaging_3_discount real null,
-                usage_unit text null,
-                usables_in_unit real not null,
-                stock_item integer not null,
-                delivery_in_weeks integer null
-            )", k),
+            "create table if not exists search_status (
+                category text not null,
+                lang integer not null,
+                seller_id text not null,
+                rows integer not null,
+                built_at text not null,
+                primary key (category, lang, seller_id)
+            )",
             [],
         )?;
+
+        // Sellers removed from config still have rows in the index
+        let mut stm = sellers.prepare("select distinct seller_id from search_status where rows > 0")?;
+        let indexed = stm.query_map([], |r| r.get::<_, String>(0))?
+            .collect::<Result<Vec<String>>>()?;
+
+        for id in indexed.iter().filter(|id| !config.seller.iter().any(|s| s.id.eq(*id))) {
+            mark_search_dirty(&sellers, &format!("seller {} removed from config", id))?;
+        }
     }
 
+    // Rows moved from category tables above aren't changes of the catalog
+    outbox(&sellers, config.import.outbox_keep_days)?;
+    outbox_triggers(&sellers, "products_entries", "product", &product_columns)?;
+    outbox_triggers(&sellers, "prices_entries", "price", &price_columns)?;
+
+    // Rows imported with another VAT percent, or before the columns existed
+    price_eur_refresh(&sellers, config.vat_percent)?;
+
+    // Pipeline runs and their extracted files, unfinished runs are resumed
+    sellers.execute_batch(
+        "create table if not exists runs (
+            id integer primary key autoincrement,
+            started_at text not null,
+            finished_at text null
+        );
+        create table if not exists run_files (
+            run_id integer not null,
+            path text not null,
+            name text not null,
+            archive text not null,
+            sha256 text not null,
+            status text not null,
+            primary key (run_id, path)
+        );
+        create index if not exists run_files_sha256 on run_files (sha256);"
+    )?;
+
+    // Process of the run, tells a running import from a crashed one
+    add_column(&sellers, "runs", "pid", "integer null")?;
+
+    // Newest data of each seller per category and file kind, for catching
+    // supplier feeds that silently stopped updating
+    sellers.execute(
+        "create table if not exists freshness (
+            seller_id text not null,
+            category text not null,
+            kind text not null,
+            file_date text null,
+            newest_date text null,
+            imported_at text not null,
+            primary key (seller_id, category, kind)
+        )",
+        [],
+    )?;
+
+    // ETIM classification model and product classifications per seller
+    sellers.execute_batch(
+        "create table if not exists etim_classes (
+            id text primary key,
+            description text not null
+        );
+        create table if not exists etim_features (
+            id text primary key,
+            description text not null,
+            unit text null
+        );
+        create table if not exists etim_product_classes (
+            id text primary key,
+            seller_id text not null,
+            product_id text not null,
+            class_id text not null
+        );
+        create table if not exists etim_product_features (
+            id text primary key,
+            seller_id text not null,
+            product_id text not null,
+            feature_id text not null,
+            value text not null,
+            unit text null
+        );
+        create index if not exists etim_product_features_feature
+            on etim_product_features (feature_id, value);"
+    )?;
+
+    // LVI-numero and Sähkönumero registries, products are linked after import
+    sellers.execute_batch(
+        "create table if not exists registry_numbers (
+            registry text not null,
+            number text not null,
+            name text not null,
+            ean text null,
+            primary key (registry, number)
+        );
+        create table if not exists downloads (
+            id integer primary key autoincrement,
+            archive text not null,
+            url text not null,
+            seller_id text not null,
+            status integer not null,
+            bytes integer not null,
+            sha256 text not null,
+            downloaded_at text not null,
+            source_file text null,
+            imported_at text null
+        );
+        create index if not exists downloads_archive on downloads (archive);
+        create index if not exists downloads_source_file on downloads (source_file);
+        create table if not exists source_probes (
+            url text primary key,
+            content_length integer null,
+            last_modified text null,
+            etag text null,
+            archive text null,
+            imported_at text null
+        );
+        create table if not exists mirror_health (
+            url text primary key,
+            successes integer not null default 0,
+            failures integer not null default 0,
+            consecutive_failures integer not null default 0,
+            latency_ms real null,
+            last_error text null,
+            last_success_at text null,
+            last_failure_at text null
+        );
+        create table if not exists product_registry (
+            id text primary key,
+            seller_id text not null,
+            product_id text not null,
+            category text not null,
+            registry text not null,
+            valid integer not null
+        );"
+    )?;
+
     // Buyers DB
     let mut path = config.dir.to_owned();
     path.push("buyers.db");
     
-    let buyers = Connection::open(path)?;
+    let buyers = open(path)?;
 
     // Create buyers table
     buyers.execute(
@@ -185,9 +481,60 @@ pub fn init(config: &Config) -> Result<(Connection, Connection)> {
             buyer_id text not null,
             seller_id text not null,
             discount_group text not null,
-            price_group text not null,
+            kind text not null,
             percent_1 real not null,
-            percent_2 real not null
+            percent_2 real not null,
+            source_file text null,
+            imported_at text null
+        )",
+        [],
+    )?;
+
+    // Discount type (laji) was stored as price group before
+    rename_column(&buyers, "discounts", "price_group", "kind")?;
+    add_column(&buyers, "discounts", "source_file", "text null")?;
+    add_column(&buyers, "discounts", "imported_at", "text null")?;
+    let discount_columns = "id, buyer_id, seller_id, discount_group, kind, percent_1, percent_2, source_file, \
+        imported_at, created_at, updated_at";
+
+    row_timestamps(&buyers, "discounts", discount_columns)?;
+    outbox(&buyers, config.import.outbox_keep_days)?;
+    outbox_triggers(&buyers, "discounts", "discount", discount_columns)?;
+
+    // Discounts waiting for their discount group to be imported
+    buyers.execute(
+        "create table if not exists pending_discounts (
+            id text primary key,
+            buyer_id text not null,
+            seller_id text not null,
+            discount_group text not null,
+            kind text not null,
+            code text not null,
+            name text not null,
+            percent_1 real not null,
+            percent_2 real not null,
+            source_file text null,
+            imported_at text null
+        )",
+        [],
+    )?;
+
+    rename_column(&buyers, "pending_discounts", "price_group", "kind")?;
+
+    // Uploaded files with their checksum and import result, disputed discount
+    // changes are traced to the upload that brought them
+    buyers.execute(
+        "create table if not exists upload_audit (
+            id integer primary key autoincrement,
+            uploaded_at text not null,
+            source text not null,
+            remote_addr text null,
+            file_name text not null,
+            sha256 text not null,
+            buyer_id text null,
+            seller_id text null,
+            result text not null,
+            message text null
         )",
         [],
     )?;
@@ -195,12 +542,288 @@ pub fn init(config: &Config) -> Result<(Connection, Connection)> {
     Ok((sellers, buyers))
 }
 
-pub fn query_price_groups(conn: &Connection) -> Result<Vec<String>> {
-    let mut stm = conn.prepare("select id from price_groups")?;
-    
-    stm.query_map([], |r| {
-        Ok(r.get(0)?)
-    }).and_then(Iterator::collect)
+/// Runs all DB work of one file inside a savepoint, everything written by the
+/// writers (which use nested savepoints) is rolled back if any of it fails.
+pub fn in_savepoint<T, F>(conn: &mut Connection, f: F) -> anyhow::Result<T>
+where F: FnOnce(&mut Connection) -> anyhow::Result<T> {
+    conn.execute_batch("savepoint file_import")?;
+
+    match f(conn) {
+        Ok(v) => {
+            conn.execute_batch("release file_import")?;
+            Ok(v)
+        },
+        Err(e) => {
+            if let Err(r) = conn.execute_batch("rollback to file_import; release file_import") {
+                warn!("Failed to roll back file import: {}", r);
+            }
+
+            Err(e)
+        }
+    }
+}
+
+/// Refresh query planner statistics and return free pages to the file system.
+/// First run switches the database to incremental auto vacuum, which takes a
+/// full vacuum once.
+pub fn maintain(conn: &Connection, name: &str) -> Result<()> {
+    let auto_vacuum: i64 = conn.query_row("pragma auto_vacuum", [], |r| r.get(0))?;
+    let free_before: i64 = conn.query_row("pragma freelist_count", [], |r| r.get(0))?;
+
+    // 2 = incremental
+    match auto_vacuum == 2 {
+        true => conn.execute_batch("pragma incremental_vacuum")?,
+        false => {
+            info!("Switching {} to incremental auto vacuum, running full vacuum once...", name);
+            conn.execute_batch("pragma auto_vacuum = incremental; vacuum")?;
+        },
+    }
+
+    conn.execute_batch("analyze; pragma optimize")?;
+
+    let free_after: i64 = conn.query_row("pragma freelist_count", [], |r| r.get(0))?;
+
+    info!("Maintained {}, freed {} pages", name, (free_before - free_after).max(0));
+
+    Ok(())
+}
+
+// Euro prices rounded to cents, price per usage unit to 1/100 cents since
+// it's often a fraction of a cent. VAT is added unless the price group
+// includes it.
+const PRICE_EUR_COLUMNS: [(&str, &str); 4] = [("price_eur", "real null"), ("price_per_usage_unit_eur", "real null"),
+    ("vat_percent", "real null"), ("price_incl_vat_eur", "real null")];
+
+fn price_eur_refresh(conn: &Connection, vat_percent: f64) -> Result<()> {
+    let updated = conn.execute(
+        "update prices_entries set vat_percent = ?1, price_eur = round(price, 2), \
+            price_per_usage_unit_eur = round(price_per_usage_unit, 4), \
+            price_incl_vat_eur = round(case when coalesce((select g.includes_vat from price_groups g \
+                where g.id = price_group), 0) then price else price * (100.0 + ?1) / 100.0 end, 2) \
+            where vat_percent is null or vat_percent != ?1",
+        params![vat_percent],
+    )?;
+
+    if updated > 0 {
+        info!("Computed euro prices of {} price rows with VAT {}%", updated, vat_percent);
+    }
+
+    Ok(())
+}
+
+// Rows get created_at when inserted and updated_at when any of the columns
+// changes, for consumers syncing with a timestamp cursor. Rows of older
+// databases are dated by their import. Triggers are recreated so columns
+// added later are compared too.
+fn row_timestamps(conn: &Connection, table: &str, columns: &str) -> Result<()> {
+    const NOW: &str = "strftime('%Y-%m-%d %H:%M:%f', 'now')";
+
+    add_column(conn, table, "created_at", "text null")?;
+    add_column(conn, table, "updated_at", "text null")?;
+
+    let changed = changed_condition(columns);
+
+    conn.execute_batch(&format!(
+        "update {table} set created_at = coalesce(imported_at, {NOW}), updated_at = coalesce(imported_at, {NOW}) \
+            where created_at is null;
+        create index if not exists {table}_updated_at on {table} (updated_at);
+        drop trigger if exists {table}_created;
+        create trigger {table}_created after insert on {table} begin
+            update {table} set created_at = coalesce(new.created_at, {NOW}),
+                updated_at = coalesce(new.updated_at, {NOW}) where rowid = new.rowid;
+        end;
+        drop trigger if exists {table}_updated;
+        create trigger {table}_updated after update on {table} when {changed} begin
+            update {table} set updated_at = {NOW} where rowid = new.rowid;
+        end;"
+    ))?;
+
+    Ok(())
+}
+
+// Trigger condition of a row having other than provenance columns changed
+fn changed_condition(columns: &str) -> String {
+    columns.split(',')
+        .map(|c| c.trim())
+        .filter(|c| !PROVENANCE_COLUMNS.contains(c))
+        .map(|c| format!("old.{c} is not new.{c}"))
+        .collect::<Vec<String>>()
+        .join(" or ")
+}
+
+// Changes of the rows in order of seq, for external systems tailing the
+// catalog. Payload is the row as JSON, the deleted one on deletes.
+fn outbox(conn: &Connection, keep_days: u32) -> Result<()> {
+    conn.execute_batch(
+        "create table if not exists outbox (
+            seq integer primary key autoincrement,
+            entity text not null,
+            id text not null,
+            op text not null,
+            payload text not null,
+            created_at text not null
+        );
+        create index if not exists outbox_created_at on outbox (created_at);"
+    )?;
+
+    if keep_days > 0 {
+        let pruned = conn.execute(
+            "delete from outbox where created_at < strftime('%Y-%m-%d %H:%M:%f', 'now', ?1)",
+            params![format!("-{} days", keep_days)],
+        )?;
+
+        if pruned > 0 {
+            info!("Pruned {} outbox rows older than {} days", pruned, keep_days);
+        }
+    }
+
+    Ok(())
+}
+
+// Insert, update and delete of the table are written to the outbox. Updates
+// touching only provenance columns (re-imports of unchanged rows) are not.
+// Row timestamps are left out of the payload, they are set by triggers of
+// their own and the outbox row has the time of the change.
+fn outbox_triggers(conn: &Connection, table: &str, entity: &str, columns: &str) -> Result<()> {
+    const NOW: &str = "strftime('%Y-%m-%d %H:%M:%f', 'now')";
+
+    let payload = |row: &str| format!("json_object({})", columns.split(',')
+        .map(|c| c.trim())
+        .filter(|c| !["created_at", "updated_at"].contains(c))
+        .map(|c| format!("'{c}', {row}.{c}"))
+        .collect::<Vec<String>>()
+        .join(", "));
+    let changed = changed_condition(columns);
+
+    conn.execute_batch(&format!(
+        "drop trigger if exists {table}_outbox_insert;
+        create trigger {table}_outbox_insert after insert on {table} begin
+            insert into outbox (entity, id, op, payload, created_at)
+                values ('{entity}', new.id, 'insert', {new}, {NOW});
+        end;
+        drop trigger if exists {table}_outbox_update;
+        create trigger {table}_outbox_update after update on {table} when {changed} begin
+            insert into outbox (entity, id, op, payload, created_at)
+                values ('{entity}', new.id, 'update', {new}, {NOW});
+        end;
+        drop trigger if exists {table}_outbox_delete;
+        create trigger {table}_outbox_delete after delete on {table} begin
+            insert into outbox (entity, id, op, payload, created_at)
+                values ('{entity}', old.id, 'delete', {old}, {NOW});
+        end;",
+        new = payload("new"),
+        old = payload("old"),
+    ))?;
+
+    Ok(())
+}
+
+// Create view <table>_<k> of a category over <table>_entries. Rows of the
+// category table it replaces are moved to the entries table first, columns
+// added after the table was created are added before that. Ids are unique
+// over categories in the entries table, a product another category table has
+// as well is left for the user to resolve instead of one overwriting the other.
+fn entries_view(conn: &Connection, table: &str, k: &str, columns: &str, added: &[(&str, &str)]) -> Result<()> {
+    let name = format!("{table}_{k}");
+    let kind: Option<String> = conn.query_row(
+        "select type from sqlite_master where name = ?1", params![&name], |r| r.get(0)
+    ).optional()?;
+
+    if kind.as_deref() == Some("table") {
+        for (column, definition) in added {
+            add_column(conn, &name, column, definition)?;
+        }
+
+        let mut stmt = conn.prepare(&format!(
+            "select t.id, e.category from {name} t inner join {table}_entries e on e.id = t.id \
+                where e.category != '{k}' limit 5"
+        ))?;
+        let collisions = stmt.query_map([], |r| Ok(format!("{} ({})", r.get::<_, String>(0)?, r.get::<_, String>(1)?)))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+
+        if !collisions.is_empty() {
+            return Err(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CONSTRAINT),
+                Some(format!("Can't move rows of {} to {}_entries, products are in other categories as well: \
+                    {}. Delete them from one of the tables and run again", name, table, collisions.join(", ")))
+            ))
+        }
+
+        info!("Moving rows of {} to {}_entries", name, table);
+
+        conn.execute_batch(&format!(
+            "begin;
+            insert into {table}_entries (category, {columns}) select '{k}', {columns} from {name};
+            drop table {name};
+            commit;"
+        )).inspect_err(|_| {
+            // Batch stops at the failing statement, the transaction is left open
+            let _ = conn.execute_batch("rollback");
+        })?;
+    }
+
+    // Recreated so columns added to the entries table show up
+    conn.execute_batch(&format!(
+        "drop view if exists {name};
+        create view {name} as select {columns} from {table}_entries where category = '{k}';"
+    ))?;
+
+    Ok(())
+}
+
+/// Rename column of an existing table if it still has the old name.
+pub fn rename_column(conn: &Connection, table: &str, from: &str, to: &str) -> Result<()> {
+    let mut stm = conn.prepare(&format!("select name from pragma_table_info('{}')", table))?;
+    let columns = stm.query_map([], |r| r.get::<_, String>(0))?
+        .collect::<Result<Vec<String>>>()?;
+
+    if columns.iter().any(|c| c.eq(from)) && !columns.iter().any(|c| c.eq(to)) {
+        conn.execute(&format!("alter table {} rename column {} to {}", table, from, to), [])?;
+    }
+
+    Ok(())
+}
+
+/// Add column to an existing table unless it's already there. Sqlite has no
+/// 'add column if not exists' so check the table info first.
+pub fn add_column(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    let mut stm = conn.prepare(&format!("select name from pragma_table_info('{}')", table))?;
+    let columns = stm.query_map([], |r| r.get::<_, String>(0))?
+        .collect::<Result<Vec<String>>>()?;
+
+    if !columns.iter().any(|c| c.eq(column)) {
+        conn.execute(&format!("alter table {} add column {} {}", table, column, definition), [])?;
+    }
+
+    Ok(())
+}
+
+/// Search index is built on the next import for the reason, whether products
+/// were imported or not.
+pub fn mark_search_dirty(conn: &Connection, reason: &str) -> Result<()> {
+    conn.execute(
+        "insert or ignore into search_dirty (reason, marked_at) values (?1, strftime('%Y-%m-%d %H:%M:%f', 'now'))",
+        params![reason],
+    )?;
+
+    Ok(())
+}
+
+/// Full-text search index tables of the category, one for each language.
+pub fn search_tables(conn: &Connection, config: &Config, k: &str) -> Result<()> {
+    for l in config.lang_codes.iter() {
+        conn.execute(
+            &format!("create virtual table if not exists {} using fts5 (
+                seller_id UNINDEXED,
+                product_id,
+                body,
+                tokenize='trigram'
+            )", search_table(k, l)),
+            [],
+        )?;
+    }
+
+    Ok(())
 }
 
 pub fn query_discount_groups(conn: &Connection) -> Result<Vec<String>> {
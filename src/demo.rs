@@ -0,0 +1,112 @@
+use std::env::temp_dir;
+use std::fs::{create_dir_all, read, read_dir, write, File};
+use std::io::Write;
+use std::path::PathBuf;
+use anyhow::{anyhow, bail, Result};
+use log::info;
+use rand::distributions::{Alphanumeric, DistString};
+use rusqlite::{params, Connection};
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+use crate::completions::BIN_NAME;
+use crate::config::Config;
+use crate::edi::{EdiFileKind, UPLOAD_DIR_NAME};
+use crate::fixture::{generate, FixtureSpec, DEFAULT_BUYER, DISCOUNT_GROUPS};
+
+
+// Sellers of the demo dataset, carrying the same products at the same prices
+const DEMO_SELLERS: [(&str, &str); 3] = [("003700000001", "Demo Tukku Oy"), ("003700000002", "Esimerkki LVI Oy"),
+    ("003700000003", "Testisähkö Oy")];
+
+fn demo_config() -> String {
+    let mut config = String::from("vat_percent = 25.5\nlang_codes = [\"fin\"]\n\n\
+        [import]\njson = true\nsqlite = true\nsearch = true\n\n\
+        [[price_group]]\nid = \"01\"\nlabel = \"List price VAT 0%\"\nis_list_price = true\n");
+
+    // No sources, files of the sellers are dropped to uploads
+    for (id, name) in DEMO_SELLERS {
+        config.push_str(&format!("\n[[seller]]\nid = \"{}\"\nname = \"{}\"\n", id, name));
+    }
+
+    config
+}
+
+/// Data dir with a config of the demo sellers and their generated product,
+/// price and discount files zipped in uploads, ready for a run. Dir is created
+/// under the temp dir unless given, a given one has to be empty.
+pub fn prepare(out: Option<&str>, rows: usize) -> Result<PathBuf> {
+    let dir = match out {
+        Some(d) => PathBuf::from(d),
+        None => temp_dir().join(format!("lvisweb-demo-{}", Alphanumeric.sample_string(&mut rand::thread_rng(), 10))),
+    };
+
+    if dir.is_dir() && read_dir(&dir)?.next().is_some() {
+        bail!("Demo dir {:?} is not empty", dir)
+    }
+
+    let uploads = dir.join(UPLOAD_DIR_NAME);
+    create_dir_all(&uploads).map_err(|e| anyhow!("Failed to create demo dir {:?}: {}", dir, e))?;
+    write(dir.join("config.toml"), demo_config())?;
+
+    let sellers = DEMO_SELLERS.iter().map(|(id, _)| id.to_string()).collect::<Vec<String>>();
+    let catalog = FixtureSpec {
+        kinds: vec![EdiFileKind::Product, EdiFileKind::Price],
+        rows,
+        sellers: sellers.to_owned(),
+        out: dir.join("fixtures"),
+        ..Default::default()
+    };
+    // One discount row for each discount group of the products
+    let discounts = FixtureSpec {
+        kinds: vec![EdiFileKind::Discount],
+        rows: DISCOUNT_GROUPS,
+        sellers,
+        out: dir.join("fixtures"),
+        ..Default::default()
+    };
+
+    for path in generate(&catalog)?.into_iter().chain(generate(&discounts)?) {
+        let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let mut zip = ZipWriter::new(File::create(uploads.join(name.replace(".txt", ".zip")))?);
+
+        zip.start_file(name, SimpleFileOptions::default())?;
+        zip.write_all(&read(&path)?)?;
+        zip.finish()?;
+    }
+
+    info!("Demo dataset of {} sellers and {} products each written to {:?}", DEMO_SELLERS.len(), rows, dir);
+
+    Ok(dir)
+}
+
+/// What the demo run imported and where to look next.
+pub fn summary(config: &Config, db_sellers: &Connection, db_buyers: &Connection) -> Result<()> {
+    let dir = config.dir.display();
+
+    println!("\nDemo data dir: {}\n", dir);
+    println!("{:<16}{:<20}{:>10}{:>10}", "Seller", "Name", "Products", "Prices");
+
+    for (id, name) in DEMO_SELLERS {
+        let count = |table: &str| db_sellers.query_row(
+            &format!("select count(*) from {} where id like ?1 || '%'", table), params![id], |r| r.get::<_, i64>(0)
+        );
+
+        println!("{:<16}{:<20}{:>10}{:>10}", id, name, count("products_entries")?, count("prices_entries")?);
+    }
+
+    let discounts: i64 = db_buyers.query_row("select count(*) from discounts", [], |r| r.get(0))?;
+
+    println!("\nBuyer {} has {} discounts from the sellers.\n", DEFAULT_BUYER, discounts);
+    println!("Databases:      {dir}/sellers.db, {dir}/buyers.db");
+    println!("JSON files:     {dir}/sellers/<seller>/");
+    println!("Import log:     {dir}/import.log");
+    println!("Source files:   {dir}/fixtures/\n");
+    println!("Try:");
+    println!("  {BIN_NAME} {dir} ean 6400000000001 --buyer {DEFAULT_BUYER}");
+    println!("  {BIN_NAME} {dir} verify");
+    println!("  {BIN_NAME} {dir} health");
+    println!("  sqlite3 {dir}/sellers.db \"select product_id, price_eur, price_incl_vat_eur from prices_lv limit 5\"");
+
+    Ok(())
+}
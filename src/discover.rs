@@ -0,0 +1,114 @@
+use chrono::NaiveDate;
+use serde_json::Value;
+
+use crate::config::Discover;
+use crate::utils::glob_match;
+
+
+// Placeholder of the pattern replaced by the publishing date of the file
+const DATE_TOKEN: &str = "{date}";
+
+// File listed in a seller's index
+#[derive(Debug, Clone)]
+pub struct Link {
+    pub url: String,
+    pub name: String,
+    pub modified: Option<String>,
+}
+
+// Absolute url of a link relative to the index page
+fn absolute(index: &str, href: &str) -> String {
+    if href.contains("://") {
+        return href.to_string()
+    }
+
+    let (scheme, rest) = index.split_once("://").unwrap_or(("https", index));
+
+    match href.starts_with('/') {
+        true => format!("{}://{}{}", scheme, rest.split('/').next().unwrap_or(rest), href),
+        false => match rest.rsplit_once('/') {
+            Some((dir, _)) => format!("{}://{}/{}", scheme, dir, href),
+            None => format!("{}://{}/{}", scheme, rest, href),
+        },
+    }
+}
+
+fn link(index: &str, href: &str, modified: Option<String>) -> Option<Link> {
+    let href = href.replace("&amp;", "&");
+    let path = href.split(['?', '#']).next().unwrap_or(&href);
+    let name = path.rsplit('/').next().filter(|n| !n.is_empty())?;
+
+    Some(Link { url: absolute(index, &href), name: name.to_string(), modified })
+}
+
+/// Links of a directory listing. JSON listings are arrays, or objects with a
+/// `files` array, of urls or objects having `url`, `href` or `name` and an
+/// optional `modified` date. Anything else is read as HTML anchors.
+pub fn index_links(index: &str, body: &str) -> Vec<Link> {
+    let entries = match serde_json::from_str::<Value>(body) {
+        Ok(Value::Array(a)) => Some(a),
+        Ok(Value::Object(mut o)) => match o.remove("files") {
+            Some(Value::Array(a)) => Some(a),
+            _ => Some(vec![]),
+        },
+        _ => None,
+    };
+
+    if let Some(entries) = entries {
+        return entries.iter().filter_map(|e| match e {
+            Value::String(s) => link(index, s, None),
+            Value::Object(o) => {
+                let href = ["url", "href", "name"].iter().find_map(|k| o.get(*k).and_then(|v| v.as_str()))?;
+                let modified = ["modified", "last_modified", "date"].iter()
+                    .find_map(|k| o.get(*k).and_then(|v| v.as_str()))
+                    .map(|m| m.to_string());
+
+                link(index, href, modified)
+            },
+            _ => None,
+        })
+        .collect()
+    }
+
+    body.split("href=").skip(1).filter_map(|s| {
+        let quote = s.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let href = s[1..].split(quote).next()?;
+
+        link(index, href, None)
+    })
+    .collect()
+}
+
+/// File name matches the discovery pattern, date token matching anything.
+/// Case is ignored.
+pub fn matches(discover: &Discover, name: &str) -> bool {
+    glob_match(&discover.pattern.replace(DATE_TOKEN, "*").to_lowercase(), &name.to_lowercase())
+}
+
+// Date in place of the token. Formats without a day, like %m%y, mean the
+// first day of the month.
+fn name_date(discover: &Discover, name: &str) -> Option<NaiveDate> {
+    let (prefix, suffix) = discover.pattern.split_once(DATE_TOKEN)?;
+    let val = name.get(prefix.len()..name.len().checked_sub(suffix.len())?)?;
+
+    match discover.date_format.contains("%d") || discover.date_format.contains("%j") {
+        true => NaiveDate::parse_from_str(val, &discover.date_format).ok(),
+        false => NaiveDate::parse_from_str(&format!("01{}", val), &format!("%d{}", discover.date_format)).ok(),
+    }
+}
+
+/// Newest link matching the pattern. Date of the file name is used when the
+/// pattern has a `{date}` token, then the listed modification time and last
+/// the name itself.
+pub fn newest(discover: &Discover, links: Vec<Link>) -> Option<Link> {
+    let dated = discover.pattern.contains(DATE_TOKEN);
+
+    links.into_iter()
+        .filter(|l| matches(discover, &l.name))
+        .filter_map(|l| match dated {
+            true => name_date(discover, &l.name).map(|d| (Some(d.format("%Y-%m-%d").to_string()), l)),
+            false => Some((l.modified.to_owned(), l)),
+        })
+        .max_by(|(a, la), (b, lb)| a.cmp(b).then(la.name.cmp(&lb.name)))
+        .map(|(_, l)| l)
+}
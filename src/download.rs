@@ -1,92 +1,705 @@
 use ureq::Agent;
+use std::collections::HashMap;
+use std::sync::Mutex;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::io::Read;
 use log::{debug, error, info};
-use anyhow::{Result, bail};
-use std::path::PathBuf;
+use anyhow::{anyhow, Context, Result};
+use std::path::{Path, PathBuf};
 use rand::distributions::{Alphanumeric, DistString};
-use std::fs::{create_dir_all, write};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::fs::{create_dir_all, metadata, write};
 
-use super::config::{Config, Seller};
+use super::config::{Config, Discover, Login, Seller};
+use super::discover;
+use super::edi::{EdiHeader, SellerIds};
+use super::error::Error;
+use super::files::{free_space, sha256_hex};
+use super::cli::RunFilter;
+use super::s3::{parse_url, LazyS3, S3_SCHEME};
+use super::timings::{self, Stage};
+use super::utils::Category;
 
-pub fn bulk_download(config: &Config, target_dir: &PathBuf) -> Result<Vec<PathBuf>> {
-    let urls = &mut config.seller.iter()
-        .flat_map(|s|url_collect(s))
-        .collect::<Vec<Vec<String>>>();
+/// Downloads every source matching the filter, except the ones that still
+/// have an unprocessed archive waiting in the downloads dir. Mirrors are tried
+/// healthiest first. Returns downloaded files and the number of sources that
+/// failed.
+pub fn bulk_download(config: &Config, target_dir: &PathBuf, filter: &RunFilter, pending: &[PathBuf],
+    db_conn: &Connection)
+-> Result<(Vec<PathBuf>, usize)> {
+    let s3 = LazyS3::new(config.s3.as_ref());
+
+    let urls = &mut sources(config, filter, Some(&s3)).into_iter()
+        .filter(|s| match is_pending(s, pending) {
+            true => {
+                info!("Skipping download of {}, previous download is still pending", s.name());
+                false
+            },
+            false => true,
+        })
+        .collect::<Vec<Source>>();
+
+    // Log in once per seller, sources of failed logins count as failed
+    let mut sessions: HashMap<String, Option<String>> = HashMap::new();
+
+    for s in urls.iter() {
+        if let (Some(l), false) = (&s.seller.login, sessions.contains_key(&s.seller.id)) {
+            let cookie = match login(s.seller, l) {
+                Ok(c) => Some(c),
+                Err(e) => {
+                    error!("Login of seller {} failed: {:#}", s.seller.id, e);
+                    None
+                },
+            };
+
+            sessions.insert(s.seller.id.to_owned(), cookie);
+        }
+    }
+
+    let count = urls.len();
+
+    urls.retain_mut(|s| match sessions.get(&s.seller.id) {
+        Some(Some(c)) => {
+            s.cookie = Some(c.to_owned());
+            true
+        },
+        Some(None) => false,
+        None => true,
+    });
+
+    // Look up urls of discovered sources, failing lookups count as failed
+    urls.retain_mut(|s| match s.discover {
+        Some(d) => match discover_url(s, d) {
+            Ok(u) => {
+                s.urls = vec![u];
+                true
+            },
+            Err(e) => {
+                error!("Discovery of {} for seller {} failed: {:#}", s.name(), s.seller.id, e);
+                false
+            },
+        },
+        None => true,
+    });
+
+    let unavailable = count - urls.len();
+
+    mirror_order(db_conn, urls)?;
+
+    if config.import.probe_changes {
+        probe_unchanged(db_conn, urls)?;
+    }
     
-    create_dir_all(&target_dir)?;
+    create_dir_all(target_dir).map_err(|e| Error::Download {
+        url: String::new(), reason: format!("Failed to create downloads dir: {}", e)
+    })?;
+
+    if config.disk.enabled {
+        disk_preflight(config, urls, pending)?;
+    }
     
-    thread::scope(|s| {
+    let attempts = Mutex::new(vec![]);
+    let attempts_ref = &attempts;
+
+    let global = config.download.max_kib_per_sec.map(Limiter::new);
+    let per_seller = config.seller.iter()
+        .filter_map(|s| s.max_kib_per_sec.map(|l| (s.id.to_owned(), Limiter::new(l))))
+        .collect::<HashMap<String, Limiter>>();
+    let (global, per_seller) = (&global, &per_seller);
+
+    let downloaded: Result<_> = thread::scope(|s| {
         let handles = urls.iter()
             .map(|v|s.spawn(move || {
+                let started = Instant::now();
                 let agent: Agent = ureq::AgentBuilder::new()
                 .timeout_read(Duration::from_secs(30))
                 .timeout_write(Duration::from_secs(60))
                 .build();
             
                 // If first url fails try the next one and so on
-                let (response, url) = try_urls(agent, v)?;
-                
-                if !response.has("Content-Length") {
-                    panic!("Url {} is missing content length header", url)
-                }
+                let (response, url) = try_urls(agent, v, attempts_ref).map_err(|e| anyhow!(e))?;
+                let probe = Probe::from_response(&response);
+                let status = response.status();
+
+                // Chunked responses have no length, it's only a size hint
+                let len = probe.content_length.and_then(|l| usize::try_from(l).ok()).unwrap_or(0);
 
-                let len: usize = response.header("Content-Length")
-                    .unwrap()
-                    .parse()
-                    .expect("Failed to parse content length from Content-Length header");
-                
                 let mut buf: Vec<u8> = Vec::with_capacity(len);
-                response.into_reader().read_to_end(&mut buf).expect("Failed to read response bytes");
+                let limiters = global.iter().chain(per_seller.get(&v.seller.id)).collect::<Vec<&Limiter>>();
+
+                let read = Throttled { inner: response.into_reader(), limiters }
+                    .read_to_end(&mut buf)
+                    .with_context(|| format!("Failed to read response of {}", url));
+
+                // Mirror breaking off mid body counts as a failed attempt too
+                if let (Err(e), Ok(mut a)) = (&read, attempts_ref.lock()) {
+                    a.push(Attempt { url: url.to_owned(), latency: started.elapsed(), error: Some(format!("{:#}", e)) });
+                }
+
+                read?;
 
                 let mut target_file = target_dir.to_owned();
                 let randy = Alphanumeric.sample_string(&mut rand::thread_rng(), 10);
-                let target_name = match url.split('/').last() {
+                // Query strings of discovered links aren't part of the name
+                let target_name = match url.split(['?', '#']).next().and_then(|u| u.split('/').next_back()) {
                     Some(s) => format!("{}-{}", randy, s),
                     None => randy,
                 };
                 target_file.push(target_name);
 
-                write(&target_file, buf.as_slice()).expect(
-                    "Failed to write downloaded content to file"
-                );
+                write(&target_file, buf.as_slice())
+                    .with_context(|| format!("Failed to write {} to {}", url, target_file.display()))?;
 
                 info!("Downloaded {} to {}", url, target_file.display());
+                timings::record_for(&target_file, Stage::Download, started.elapsed());
 
-                Ok::<PathBuf, String>(target_file)
+                Ok::<Fetched, anyhow::Error>(Fetched {
+                    archive: target_file,
+                    url,
+                    seller_id: v.seller.id.to_owned(),
+                    status,
+                    bytes: buf.len(),
+                    sha256: sha256_hex(&buf),
+                    probe,
+                })
             }))
             .collect::<Vec<_>>();
 
 
         let mut results = vec![];
+        let mut failed = unavailable;
 
-        for h in handles {
+        for (v, h) in urls.iter().zip(handles) {
             match h.join() {
                 Ok(r) => match r {
-                    Ok(p) => results.push(p),
+                    Ok(f) => results.push(f),
                     Err(e) => {
-                        error!("Download error: {}", e);
+                        error!("Download of {} for seller {} failed: {:#}", v.name(), v.seller.id, e);
+                        failed += 1;
                         continue
                     },
                 },
-                Err(e) => bail!("Threads are tangled: {}",
-                    e.downcast::<String>().unwrap()),
+                Err(e) => return Err(Error::Download {
+                    url: String::new(),
+                    reason: format!("Threads are tangled: {:?}", e.downcast::<String>())
+                }.into()),
             }
         }
 
-        Ok(results)
+        Ok((results, failed))
+    });
+
+    // Health history is nice to have, don't fail downloads over it
+    if let Err(e) = mirror_record(db_conn, attempts.into_inner().unwrap_or_default()) {
+        error!("Failed to record mirror health: {:#}", e);
+    }
+
+    let (results, failed) = downloaded?;
+
+    // Without staged probes the sources are just downloaded again next time
+    if let Err(e) = downloads_record(db_conn, &results) {
+        error!("Failed to record downloads: {:#}", e);
+    }
+
+    Ok((results.into_iter().map(|f| f.archive).collect(), failed))
+}
+
+// Archive saved from a source and how it was fetched
+struct Fetched {
+    archive: PathBuf,
+    url: String,
+    seller_id: String,
+    status: u16,
+    bytes: usize,
+    sha256: String,
+    probe: Probe,
+}
+
+// Validators of a source response, compared to the ones recorded when the
+// source was last imported
+#[derive(Debug, Default)]
+struct Probe {
+    content_length: Option<i64>,
+    last_modified: Option<String>,
+    etag: Option<String>,
+}
+
+impl Probe {
+    fn from_response(response: &ureq::Response) -> Self {
+        Self {
+            content_length: response.header("Content-Length").and_then(|l| l.parse().ok()),
+            last_modified: response.header("Last-Modified").map(|l| l.to_string()),
+            etag: response.header("ETag").map(|e| e.to_string()),
+        }
+    }
+    // Size alone could stay the same over a changed file, etag or modification
+    // time has to match as well
+    fn unchanged(&self, previous: &Probe) -> bool {
+        let validated = match (&self.etag, &previous.etag, &self.last_modified, &previous.last_modified) {
+            (Some(a), Some(b), _, _) => a.eq(b),
+            (_, _, Some(a), Some(b)) => a.eq(b),
+            _ => false,
+        };
+
+        validated && self.content_length.eq(&previous.content_length)
+    }
+}
+
+// HEAD the first url of each source, the ones unchanged since their last
+// import are dropped
+fn probe_unchanged(db_conn: &Connection, sources: &mut Vec<Source>) -> Result<()> {
+    let agent: Agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(30))
+        .build();
+
+    let mut stm = db_conn.prepare("select content_length, last_modified, etag from source_probes \
+        where url = ?1 and imported_at is not null")?;
+
+    sources.retain(|s| {
+        let url = match s.urls.first() {
+            Some(u) => u,
+            None => return true,
+        };
+
+        let previous = match stm.query_row(params![url], |r| Ok(Probe {
+            content_length: r.get(0)?, last_modified: r.get(1)?, etag: r.get(2)?
+        })) {
+            Ok(p) => p,
+            Err(_) => return true,
+        };
+
+        match s.request(&agent, "HEAD", url).and_then(|r| Ok(r.call()?)) {
+            Ok(r) if Probe::from_response(&r).unchanged(&previous) => {
+                info!("Skipping download of {}, unchanged since it was last imported", url);
+                false
+            },
+            Ok(_) => true,
+            Err(e) => {
+                debug!("Probing {} failed, downloading anyway: {}", url, e);
+                true
+            },
+        }
+    });
+
+    Ok(())
+}
+
+// Provenance of every fetch. Probes wait for the import of their archive,
+// see `downloads_confirm`.
+fn downloads_record(db_conn: &Connection, fetched: &[Fetched]) -> Result<()> {
+    let now = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
+
+    for f in fetched {
+        let archive = f.archive.file_name().map(|n| n.to_string_lossy());
+        let p = &f.probe;
+
+        db_conn.execute(
+            "insert into downloads (archive, url, seller_id, status, bytes, sha256, downloaded_at) \
+                values (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![archive, &f.url, &f.seller_id, f.status, f.bytes, &f.sha256, &now]
+        )?;
+
+        db_conn.execute(
+            "insert or replace into source_probes (url, content_length, last_modified, etag, archive, imported_at) \
+                values (?1, ?2, ?3, ?4, ?5, null)",
+            params![&f.url, p.content_length, p.last_modified, p.etag, archive]
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Header seller of an EDI file when it isn't the seller whose source the
+/// archive was downloaded from. Uploads and unrecorded archives aren't checked.
+pub fn seller_mismatch(db_conn: &Connection, seller_ids: &SellerIds, archive: &Path, edi_file: &PathBuf)
+-> Result<Option<(String, String)>> {
+    let name = match archive.file_name() {
+        Some(n) => n.to_string_lossy(),
+        None => return Ok(None),
+    };
+
+    let expected: Option<String> = db_conn.query_row(
+        "select seller_id from downloads where archive = ?1 order by id desc limit 1",
+        params![name], |r| r.get(0)
+    ).optional()?;
+
+    // Unreadable headers fail on import
+    let found = EdiHeader::read(edi_file, seller_ids).ok().and_then(|h| h.seller).map(|s| s.id);
+
+    match (expected, found) {
+        (Some(e), Some(f)) if e.ne(&f) => Ok(Some((e, f))),
+        _ => Ok(None),
+    }
+}
+
+/// Link imported archives to the EDI files extracted from them, product and
+/// price rows carry the file name in `source_file`. Sources of the archives
+/// are considered unchanged until their validators change. Takes archive and
+/// EDI file paths, both are recognized by their file names.
+pub fn downloads_confirm(db_conn: &Connection, imported: &[(PathBuf, PathBuf)]) -> Result<()> {
+    let now = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
+
+    for (archive, edi_file) in imported {
+        let (archive, edi_file) = match (archive.file_name(), edi_file.file_name()) {
+            (Some(a), Some(e)) => (a.to_string_lossy(), e.to_string_lossy()),
+            _ => continue,
+        };
+
+        db_conn.execute(
+            "update downloads set source_file = ?1, imported_at = ?2 where archive = ?3",
+            params![edi_file, &now, archive]
+        )?;
+
+        db_conn.execute(
+            "update source_probes set imported_at = ?1 where archive = ?2",
+            params![&now, archive]
+        )?;
+    }
+
+    Ok(())
+}
+
+// Paces reads of all downloads sharing it to the given rate
+struct Limiter {
+    bytes_per_sec: u64,
+    // End of the time reserved for bytes read so far
+    next: Mutex<Instant>,
+}
+
+impl Limiter {
+    fn new(kib_per_sec: u64) -> Self {
+        Self { bytes_per_sec: kib_per_sec * 1024, next: Mutex::new(Instant::now()) }
+    }
+    // Reserve time for the bytes after earlier reservations and wait it out
+    fn take(&self, bytes: usize) {
+        let until = match self.next.lock() {
+            Ok(mut next) => {
+                let start = (*next).max(Instant::now());
+                *next = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+                *next
+            },
+            Err(_) => return,
+        };
+
+        thread::sleep(until.saturating_duration_since(Instant::now()));
+    }
+}
+
+// Small reads keep the pace smooth with big buffers of read_to_end
+const THROTTLE_CHUNK: usize = 16 * 1024;
+
+struct Throttled<'a, R> {
+    inner: R,
+    limiters: Vec<&'a Limiter>,
+}
+
+impl<R: Read> Read for Throttled<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.limiters.is_empty() {
+            return self.inner.read(buf)
+        }
+
+        let len = buf.len().min(THROTTLE_CHUNK);
+        let n = self.inner.read(&mut buf[..len])?;
+
+        for l in self.limiters.iter() {
+            l.take(n);
+        }
+
+        Ok(n)
+    }
+}
+
+// Outcome of one request to a mirror
+struct Attempt {
+    url: String,
+    latency: Duration,
+    error: Option<String>,
+}
+
+// Mirrors failing lately go last, then the slower ones. Never tried mirrors
+// keep their config order after the known good ones.
+fn mirror_order(db_conn: &Connection, sources: &mut [Source]) -> Result<()> {
+    let mut stm = db_conn.prepare("select url, consecutive_failures, latency_ms from mirror_health")?;
+    let health = stm.query_map([], |r| Ok((r.get::<_, String>(0)?, (r.get::<_, i64>(1)?, r.get::<_, Option<f64>>(2)?))))?
+        .collect::<rusqlite::Result<HashMap<String, (i64, Option<f64>)>>>()?;
+
+    for s in sources.iter_mut() {
+        let before = s.urls.first().cloned();
+
+        // Stable, ties keep config order
+        s.urls.sort_by(|a, b| {
+            let (fa, la) = health.get(a).cloned().unwrap_or((0, None));
+            let (fb, lb) = health.get(b).cloned().unwrap_or((0, None));
+
+            fa.cmp(&fb).then(la.unwrap_or(f64::MAX).total_cmp(&lb.unwrap_or(f64::MAX)))
+        });
+
+        if let Some(u) = s.urls.first().filter(|u| before.as_ref().ne(&Some(*u))) {
+            debug!("Trying mirror {} first, it has been healthier", u);
+        }
+    }
+
+    Ok(())
+}
+
+// Latency is a moving average so one slow night doesn't reorder mirrors
+fn mirror_record(db_conn: &Connection, attempts: Vec<Attempt>) -> Result<()> {
+    let now = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
+
+    for a in attempts {
+        let latency = a.latency.as_secs_f64() * 1000.0;
+
+        match &a.error {
+            None => db_conn.execute(
+                "insert into mirror_health (url, successes, latency_ms, last_success_at) values (?1, 1, ?2, ?3) \
+                    on conflict (url) do update set successes = successes + 1, consecutive_failures = 0, \
+                    latency_ms = coalesce(latency_ms * 0.7 + excluded.latency_ms * 0.3, excluded.latency_ms), \
+                    last_success_at = excluded.last_success_at",
+                params![&a.url, latency, &now]
+            )?,
+            Some(e) => db_conn.execute(
+                "insert into mirror_health (url, failures, consecutive_failures, last_error, last_failure_at) \
+                    values (?1, 1, 1, ?2, ?3) on conflict (url) do update set failures = failures + 1, \
+                    consecutive_failures = consecutive_failures + 1, last_error = excluded.last_error, \
+                    last_failure_at = excluded.last_failure_at",
+                params![&a.url, e, &now]
+            )?,
+        };
+    }
+
+    Ok(())
+}
+
+// Refuse to start when the expected archives and their extracted files won't
+// fit, a full disk mid import leaves half written databases behind.
+fn disk_preflight(config: &Config, urls: &[Source], pending: &[PathBuf]) -> Result<()> {
+    let agent: Agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(15))
+        .build();
+
+    let mut expected: u64 = 0;
+
+    for v in urls {
+        // Size of the first source that answers, download tries them in order too
+        let len = v.urls.iter().find_map(|u| match v.request(&agent, "HEAD", u).and_then(|r| Ok(r.call()?)) {
+            Ok(r) => r.header("Content-Length").and_then(|l| l.parse::<u64>().ok()),
+            Err(e) => {
+                debug!("HEAD {} failed: {}", u, e);
+                None
+            },
+        });
+
+        match len {
+            Some(l) => expected += l,
+            None => debug!("Size of {} is unknown, not counted in disk space check", v.urls.join(", ")),
+        }
+    }
+
+    // Pending archives are on disk already, only their extracted files count
+    let pending_size = pending.iter()
+        .filter_map(|p| metadata(p).ok())
+        .map(|m| m.len())
+        .sum::<u64>();
+
+    let mb = 1024 * 1024;
+    let required = (expected as f64 * config.disk.space_factor
+        + pending_size as f64 * (config.disk.space_factor - 1.0).max(0.0)) as u64 / mb
+        + config.disk.min_free_mb;
+    let free = free_space(&config.dir)? / mb;
+
+    debug!("Disk space check: {} MB of archives to download, {} MB required, {} MB free", expected / mb,
+        required, free);
+
+    if free < required {
+        return Err(Error::DiskSpace { path: config.dir.to_owned(), required_mb: required, free_mb: free }.into())
+    }
+
+    Ok(())
+}
+
+/// Leftover archives belonging to the filtered sources. Without active
+/// filter all of them.
+pub fn pending_archives(config: &Config, filter: &RunFilter, archives: Vec<PathBuf>) -> Vec<PathBuf> {
+    if !filter.is_active() {
+        return archives
+    }
+
+    let sources = sources(config, filter, None);
+
+    archives.into_iter()
+        .filter(|a| sources.iter().any(|s| is_pending(s, std::slice::from_ref(a))))
+        .collect()
+}
+
+// Download source of a seller, urls are mirrors of the same file
+struct Source<'a> {
+    seller: &'a Seller,
+    urls: Vec<String>,
+    // Session cookies from seller's login
+    cookie: Option<String>,
+    s3: Option<&'a LazyS3<'a>>,
+    // Url is looked up from the index before downloading
+    discover: Option<&'a Discover>,
+}
+
+impl Source<'_> {
+    fn name(&self) -> String {
+        match self.discover {
+            Some(d) => format!("{} ({})", d.index, d.pattern),
+            None => self.urls.join(", "),
+        }
+    }
+    // Request with the seller's user agent and headers, s3:// urls are signed
+    fn request(&self, agent: &Agent, method: &str, url: &str) -> Result<ureq::Request> {
+        let mut req = match (url.starts_with(S3_SCHEME), self.s3) {
+            (true, Some(s3)) => match parse_url(url) {
+                Ok((bucket, key)) => s3.get()?.request(agent, method, bucket, key, None),
+                // Fails as an unsupported scheme, checked on config load
+                Err(_) => agent.request(method, url),
+            },
+            _ => agent.request(method, url),
+        };
+
+        if let Some(ua) = &self.seller.user_agent {
+            req = req.set("User-Agent", ua);
+        }
+
+        for (k, v) in self.seller.headers.iter() {
+            req = req.set(k, v);
+        }
+
+        if let Some(c) = &self.cookie {
+            req = req.set("Cookie", c);
+        }
+
+        Ok(req)
+    }
+}
+
+// POST the login form and collect session cookies as a Cookie header value.
+// Redirects aren't followed, cookies usually come with the redirect response.
+fn login(seller: &Seller, login: &Login) -> Result<String> {
+    let agent: Agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(30))
+        .redirects(0)
+        .build();
+
+    let source = Source { seller, urls: vec![], cookie: None, s3: None, discover: None };
+    let form = login.form.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect::<Vec<(&str, &str)>>();
+
+    debug!("Logging in to {} for seller {}...", login.url, seller.id);
+
+    let response = source.request(&agent, "POST", &login.url)
+        .and_then(|r| Ok(r.send_form(&form)?))
+        .map_err(|e| Error::Download { url: login.url.to_owned(), reason: format!("Login failed: {}", e) })?;
+
+    let cookies = response.all("Set-Cookie").iter()
+        .filter_map(|c| c.split(';').next())
+        .map(|c| c.trim())
+        .filter(|c| c.contains('='))
+        .collect::<Vec<&str>>();
+
+    if cookies.is_empty() {
+        return Err(Error::Download {
+            url: login.url.to_owned(),
+            reason: format!("Login responded {} without session cookies", response.status())
+        }.into())
+    }
+
+    info!("Logged in to {} for seller {}", login.url, seller.id);
+
+    Ok(cookies.join("; "))
+}
+
+// Newest matching file of the seller's index
+fn discover_url(source: &Source, d: &Discover) -> Result<String> {
+    let agent: Agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(30))
+        .build();
+
+    debug!("Looking up {} from index {}...", d.pattern, d.index);
+
+    let body = source.request(&agent, "GET", &d.index)
+        .and_then(|r| Ok(r.call()?.into_string()?))
+        .map_err(|e| Error::Download { url: d.index.to_owned(), reason: format!("Failed to read index: {}", e) })?;
+
+    match discover::newest(d, discover::index_links(&d.index, &body)) {
+        Some(l) => {
+            info!("Discovered {} from index {}", l.url, d.index);
+            Ok(l.url)
+        },
+        None => Err(Error::Download {
+            url: d.index.to_owned(), reason: format!("No file matching '{}' in index", d.pattern)
+        }.into()),
+    }
+}
+
+// Sellers named in the filter are downloaded regardless of their schedule
+fn scheduled(seller: &Seller, filter: &RunFilter) -> bool {
+    let schedule = match &seller.schedule {
+        Some(s) if !filter.sellers.contains(&seller.id) => s,
+        _ => return true,
+    };
+
+    match schedule.is_open(chrono::Local::now().date_naive()) {
+        true => true,
+        false => {
+            info!("Skipping downloads of seller {}, outside of its publishing schedule", seller.id);
+            false
+        },
+    }
+}
+
+fn sources<'a>(config: &'a Config, filter: &RunFilter, s3: Option<&'a LazyS3<'a>>) -> Vec<Source<'a>> {
+    config.seller.iter()
+        .filter(|s| filter.has_seller(&s.id) && scheduled(s, filter))
+        .flat_map(|s| {
+            let discovered = s.discover.iter()
+                .filter(|d| filter.has_category(&d.category))
+                .map(move |d| Source { seller: s, urls: vec![], cookie: None, s3, discover: Some(d) });
+
+            url_collect(s, filter).into_iter()
+                .map(move |urls| Source { seller: s, urls, cookie: None, s3, discover: None })
+                .chain(discovered)
+        })
+        .collect()
+}
+
+// Downloads are saved as '<random>-<url file name>' so the source of an
+// archive can be recognized from its name.
+fn is_pending(source: &Source, pending: &[PathBuf]) -> bool {
+    pending.iter().any(|p| {
+        let name = match p.file_name().and_then(|n| n.to_str()) {
+            Some(n) => match n.split_once('-') {
+                Some((_, n)) => n,
+                None => return false,
+            },
+            None => return false,
+        };
+
+        match source.discover {
+            Some(d) => discover::matches(d, name),
+            None => source.urls.iter().any(|u| u.split('/').next_back().is_some_and(|l| l.eq(name))),
+        }
     })
 }
 
-fn try_urls(agent: Agent, urls: &Vec<String>) -> Result<(ureq::Response, String), String> {
-    for u in urls {
+fn try_urls(agent: Agent, source: &Source, attempts: &Mutex<Vec<Attempt>>)
+-> Result<(ureq::Response, String), String> {
+    for u in source.urls.iter() {
         debug!("Trying to download from {}...", &u);
 
-        let call = agent.get(u)
-            .call()
+        let start = Instant::now();
+        let call = source.request(&agent, "GET", u)
+            .and_then(|r| Ok(r.call()?))
             .map_err(|e|format!("Failed to get content from url {}: {}", u, e));
 
+        if let Ok(mut v) = attempts.lock() {
+            v.push(Attempt { url: u.to_owned(), latency: start.elapsed(), error: call.as_ref().err().cloned() });
+        }
+
         match call {
             Ok(r) => return Ok((r, u.to_owned())),
             Err(e) => error!("Http call error: {}", e),
@@ -96,16 +709,25 @@ fn try_urls(agent: Agent, urls: &Vec<String>) -> Result<(ureq::Response, String)
     Err(String::from("Failed to download from any of the provided urls"))
 }
 
-fn url_collect(seller: &Seller) -> Vec<Vec<String>> {
+fn url_collect(seller: &Seller, filter: &RunFilter) -> Vec<Vec<String>> {
     // Some urls have tokens. At the moment only {mmyy} for 2 digit month
     // and year token is used.
     let mut urls = vec![];
+    let sources = [
+        (Category::WaterAndHeating, &seller.lv),
+        (Category::Ventilation, &seller.iv),
+        (Category::Electricity, &seller.sa),
+        (Category::Industrial, &seller.te),
+        (Category::Refrigeration, &seller.ky),
+    ];
 
-    if let Some(ref v) = seller.lv { url_ext(&mut urls, v) }
-    if let Some(ref v) = seller.iv { url_ext(&mut urls, v) }
-    if let Some(ref v) = seller.sa { url_ext(&mut urls, v) }
-    if let Some(ref v) = seller.te { url_ext(&mut urls, v) }
-    if let Some(ref v) = seller.ky { url_ext(&mut urls, v) }
+    for (c, s) in sources {
+        if let Some(ref v) = s {
+            if filter.has_category(&c) {
+                url_ext(&mut urls, v)
+            }
+        }
+    }
 
     urls
 }
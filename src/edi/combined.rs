@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+use std::fs::{create_dir_all, write};
+use std::path::Path;
+use anyhow::{anyhow, Result};
+use log::debug;
+use serde_json::Value;
+
+use crate::config::Config;
+use crate::edi::envelope::Envelope;
+use crate::edi::shards::read_map;
+use crate::utils::Category;
+
+
+pub const COMBINED_DIR_NAME: &str = "combined";
+
+/// Write products of each category and language of the seller with their
+/// price as `price` (null without one) into `combined/<category>.<lang>.json`,
+/// one file for frontends that don't want to join two. Built from the
+/// product and price files already written.
+pub fn combined_writer(config: &Config, supplier_dir: &Path) -> Result<()> {
+    let out = supplier_dir.join(COMBINED_DIR_NAME);
+    let seller_id = supplier_dir.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let built_at = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string();
+
+    for (k, _) in Category::mapper() {
+        let prices = read_map::<Value>(&supplier_dir.join("prices"), k)?.unwrap_or_default();
+
+        for l in config.lang_codes.iter() {
+            let name = format!("{}.{}", k, l.to_name());
+
+            let products = match read_map::<Value>(&supplier_dir.join("products"), &name)? {
+                Some(p) => p,
+                None => continue,
+            };
+
+            let combined = products.into_iter()
+                .map(|(id, mut p)| {
+                    if let Value::Object(m) = &mut p {
+                        m.insert(String::from("price"), prices.get(&id).cloned().unwrap_or(Value::Null));
+                    }
+
+                    (id, p)
+                })
+                .collect::<HashMap<String, Value>>();
+
+            create_dir_all(&out).map_err(|e| anyhow!("Failed to create {:?}: {}", out, e))?;
+
+            let path = out.join(format!("{name}.json"));
+
+            let count = combined.len();
+            let combined = Envelope::new(config, &seller_id, &built_at, combined);
+
+            write(&path, serde_json::to_string(&combined)?).map_err(|e| anyhow!("Failed to write {:?}: {}", path, e))?;
+
+            debug!("Wrote {} products with prices to {:?}", count, path);
+        }
+    }
+
+    Ok(())
+}
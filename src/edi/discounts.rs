@@ -1,21 +1,29 @@
-use log::error;
-use serde::Serialize;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
 use anyhow::{bail, Result};
-use std::fs::{File, write, create_dir_all};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, read_dir, read_to_string, write};
 use std::io::{prelude::*, BufReader};
 use rand::distributions::{Alphanumeric, DistString};
 use std::path::PathBuf;
 use rusqlite::{Connection, params};
 
-use crate::config::Config;
+use crate::config::{Config, DuplicatePolicy};
+use crate::db::query_discount_groups;
+use crate::error::Error;
+use crate::files::{create_buyer_dir, restrict_buyer_file};
+use crate::schema::SCHEMA_VERSION;
 
 use super::header::EdiParty;
-use super::{edi_line_iter, import_warning_logger, str_as_f64, EdiLine};
+use super::envelope::{self, Envelope};
+use super::{delimited_line, edi_line_iter, import_provenance, import_warning_logger, split_decimals, str_as_f64, EdiLine};
 
-const SEQ_DISC_REQLEN: usize = 92;
-const EXPL_SEQ_DISC: [usize; 7] = [
+pub(crate) const SEQ_DISC_REQLEN: usize = 92;
+pub const EXPL_SEQ_DISC: [usize; 7] = [
     1, 6, 25, 40, 2, 9, 9
 ];
+// Numeric fields, padded with zeros when read from semicolon separated lines
+pub(crate) const DISC_NUMBERS: [usize; 2] = [5, 6];
 
 // Tietuetunnus 	A 	1 	1 	R
 // Aleryhmä 	    A 	6 	2
@@ -27,16 +35,59 @@ const EXPL_SEQ_DISC: [usize; 7] = [
 //   	03 = pakkausalennus - ei kumulatiivinen 	  	  	 
 // Prosentti1 	N 	9 (2 des) 	75 	 
 // Prosentti2 	N 	9 (2 des) 	84
-#[derive(Debug, Serialize)]
-struct Discount {
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Discount {
     #[serde(rename = "disc")]
-    discount_group: String, // Alennusryhmä 6 A *
-    id: String,
-    name: String,
-    #[serde(rename = "group")]
-    price_group: String,
-    pc1: f64,
-    pc2: f64,
+    pub discount_group: String, // Alennusryhmä 6 A *
+    pub id: String,
+    pub name: String,
+    // Files written before the type was modeled have it as 'group'
+    #[serde(alias = "group")]
+    pub kind: DiscountKind, // Laji 2 A
+    pub pc1: f64,
+    pub pc2: f64,
+}
+
+// Laji of the discount row, tells how the percents are applied
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DiscountKind {
+    // Prosentti1 only
+    #[default]
+    #[serde(rename = "01")]
+    Discount,
+    // Prosentti2 on top of Prosentti1 when packaging size is reached
+    #[serde(rename = "02")]
+    CumulativePackaging,
+    // The bigger one of Prosentti1 and Prosentti2 when packaging size is reached
+    #[serde(rename = "03")]
+    Packaging,
+}
+
+impl DiscountKind {
+    pub fn from_code(code: &str) -> Result<Self> {
+        match code.trim() {
+            "" | "01" => Ok(Self::Discount),
+            "02" => Ok(Self::CumulativePackaging),
+            "03" => Ok(Self::Packaging),
+            x => bail!("Discount type (laji) has to be 01, 02 or 03. Found '{}'", x),
+        }
+    }
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Discount => "01",
+            Self::CumulativePackaging => "02",
+            Self::Packaging => "03",
+        }
+    }
+    /// Discount percent to apply. Packaging percent is given when the quantity
+    /// reaches a packaging size of the product.
+    pub fn percent(&self, percent_1: f64, packaging: Option<f64>) -> f64 {
+        match (self, packaging) {
+            (Self::CumulativePackaging, Some(p)) => 100.0 - (100.0 - percent_1) * (100.0 - p) / 100.0,
+            (Self::Packaging, Some(p)) => percent_1.max(p),
+            _ => percent_1,
+        }
+    }
 }
 
 impl Discount {
@@ -45,12 +96,13 @@ impl Discount {
             discount_group: String::new(),
             id: String::new(),
             name: String::new(),
-            price_group: String::new(),
+            kind: DiscountKind::Discount,
             pc1: 0.0f64,
             pc2: 0.0f64
         }
     }
-    fn from_line(line: String) -> Result<Self> {
+    /// Discount of a 92 char line, see `parser`.
+    pub fn from_line(line: String) -> Result<Self> {
         let mut disc = Self::new();
         let chars = line.chars();
         let mut pointer = 0;
@@ -79,7 +131,7 @@ impl Discount {
                     1 => { disc.discount_group = val },
                     2 => { disc.id = val },
                     3 => { disc.name = val },
-                    4 => { disc.price_group = val },
+                    4 => { disc.kind = DiscountKind::from_code(&val)? },
                     _ => (),
                 }
                 pointer = p;
@@ -91,7 +143,7 @@ impl Discount {
             if [5, 6].contains(&j) {
                 let (val, p) = edi_line_iter(pointer, &chars, v)?;
                 let (int, des) = match val.len() > 7 {
-                    true => val.split_at(7),
+                    true => split_decimals(&val, 7)?,
                     false => bail!("Unable to split decimals from '{}' string", val),
                 };
                 
@@ -115,39 +167,164 @@ impl Discount {
     }
 }
 
-pub fn is_discount_file(path: &PathBuf) -> Result<bool> {
-    // To prevent stupid developer errors
-    let mut total = 0;
-    
-    for v in EXPL_SEQ_DISC.iter() {
-        total += v
+// Resolve discount groups listed more than once. Identical rows are merged
+// quietly, conflicting ones are warned about and resolved by policy.
+fn discount_duplicates(path: &PathBuf, discounts: Vec<(usize, Discount)>, policy: DuplicatePolicy,
+    warnings: &mut Vec<String>)
+-> Result<Vec<(usize, Discount)>> {
+    let mut resolved: Vec<(usize, Discount)> = vec![];
+
+    for (line, d) in discounts {
+        let (first, kept) = match resolved.iter_mut().find(|(_, r)| r.discount_group.eq(&d.discount_group)) {
+            Some((l, r)) => (*l, r),
+            None => {
+                resolved.push((line, d));
+                continue
+            },
+        };
+
+        if kept.pc1 == d.pc1 && kept.pc2 == d.pc2 && kept.kind == d.kind {
+            continue
+        }
+
+        if policy == DuplicatePolicy::Fail {
+            return Err(Error::parse(path, Some(line), format!("Discount group {} is already given on line {} \
+                with different percents", d.discount_group, first)).into())
+        }
+
+        let replace = match policy {
+            DuplicatePolicy::Last => true,
+            DuplicatePolicy::Highest => d.pc1 > kept.pc1 || (d.pc1 == kept.pc1 && d.pc2 > kept.pc2),
+            _ => false,
+        };
+
+        warnings.push(format!("Line: {}, [{}]: Duplicate discount group, {} / {} % {} over {} / {} % of line {}",
+            line, &d.discount_group, d.pc1, d.pc2, if replace { "used" } else { "ignored" },
+            kept.pc1, kept.pc2, first));
+
+        if replace {
+            *kept = d;
+        }
     }
 
-    if total != SEQ_DISC_REQLEN {
-        bail!("Discount file decoder has developer level issues.")
+    Ok(resolved)
+}
+
+/// Buyers' discount files are written, with JSON import and not turned off.
+pub fn buyer_json(config: &Config) -> bool {
+    config.import.json && config.json_output.buyers
+}
+
+/// Dir of a buyer's discount files, under the buyers dir of the config when
+/// set. Otherwise discounts of the seller are under the seller's tree.
+pub fn buyer_discounts_dir(config: &Config, seller_id: &str, buyer_id: &str) -> PathBuf {
+    match &config.json_output.buyers_dir {
+        Some(d) => d.join(buyer_id).join("discounts"),
+        None => config.dir.join("sellers").join(seller_id).join("buyers").join(buyer_id).join("discounts"),
     }
-    
-    let uft8_file = File::open(path)?;
-    let reader = BufReader::new(uft8_file);
+}
 
-    // Skip headers with iterator.
-    for (i, l) in reader.lines().skip(2).enumerate() {
-        let s = match l {
-            Ok(s) => s,
-            Err(_) => bail!("unable to read line number {} from {:?}", i, path),
-        };
+// Update a buyer's discount file per discount group, like products and prices
+// are, groups missing from the new discounts are kept
+fn discounts_json_merge(config: &Config, path: &PathBuf, seller_id: &str, imported_at: &str, new: Vec<Discount>)
+-> Result<()> {
+    let mut discounts = match path.is_file() {
+        true => envelope::from_str::<Vec<Discount>>(&read_to_string(path)?)?,
+        false => vec![],
+    };
+
+    for d in new {
+        match discounts.iter_mut().find(|e| e.discount_group.eq(&d.discount_group)) {
+            Some(e) => *e = d,
+            None => discounts.push(d),
+        }
+    }
 
-        return match Discount::from_line(s) {
-            Ok(_) => Ok(true),
-            Err(_) => Ok(false),
+    // Buyer's dir holding the discounts dir is restricted as well
+    if let Some(d) = path.parent() {
+        if let Some(b) = d.parent() {
+            create_buyer_dir(config, &b.to_path_buf())?;
         }
+
+        create_buyer_dir(config, &d.to_path_buf())?;
     }
 
-    bail!("What the hell 3")
+    let discounts = Envelope::new(config, seller_id, imported_at, discounts);
+    write(path, serde_json::to_string(&discounts)?.as_bytes())?;
+    restrict_buyer_file(config, path)?;
+
+    Ok(())
+}
+
+// Discounts of a buyer from one seller in the buyer's aggregate file
+#[derive(Debug, Serialize)]
+struct SellerDiscounts {
+    seller: String,
+    name: Option<String>,
+    discounts: Vec<Discount>,
+}
+
+#[derive(Debug, Serialize)]
+struct BuyerDiscounts {
+    schema_version: u32,
+    sellers: Vec<SellerDiscounts>,
+}
+
+/// Collect buyer's discount files of all sellers into `buyers/<id>/discounts.json`,
+/// or `<id>/discounts.json` of the buyers dir, with the seller's id and name.
+/// Returns path of the written file.
+pub fn buyer_discounts_aggregate(config: &Config, buyer_id: &str) -> Result<PathBuf> {
+    let mut sellers_dir = config.dir.to_owned();
+    sellers_dir.push("sellers");
+
+    let mut seller_ids = vec![];
+
+    if sellers_dir.is_dir() {
+        for e in read_dir(&sellers_dir)? {
+            let e = e?;
+
+            if e.path().is_dir() {
+                seller_ids.push(e.file_name().to_string_lossy().to_string());
+            }
+        }
+    }
+
+    seller_ids.sort();
+
+    let mut sellers = vec![];
+
+    for seller_id in seller_ids {
+        let p = buyer_discounts_dir(config, &seller_id, buyer_id).join(format!("{seller_id}.json"));
+
+        if ! p.is_file() {
+            continue
+        }
+
+        let discounts = envelope::from_str::<Vec<Discount>>(&read_to_string(&p)?)?;
+        let name = config.seller.iter()
+            .find(|s| s.id.eq(&seller_id))
+            .map(|s| s.name.to_owned());
+
+        sellers.push(SellerDiscounts { seller: seller_id, name, discounts });
+    }
+
+    let mut path = match &config.json_output.buyers_dir {
+        Some(d) => d.join(buyer_id),
+        None => config.dir.join("buyers").join(buyer_id),
+    };
+
+    create_buyer_dir(config, &path)?;
+
+    path.push("discounts.json");
+    let aggregate = BuyerDiscounts { schema_version: SCHEMA_VERSION, sellers };
+    write(&path, serde_json::to_string(&aggregate)?.as_bytes())?;
+    restrict_buyer_file(config, &path)?;
+
+    Ok(path)
 }
 
 pub fn discounts_writer(config: &Config, path: &PathBuf, db_conn: &mut Connection,
-    discount_groups: &Vec<String>, price_groups: &Vec<String>, log: &mut File)
+    discount_groups: &[String], log: &mut File)
 -> Result<PathBuf> {
     // Open utf8 encoded file and read it line by line.
     let uft8_file = File::open(path)?;
@@ -161,7 +338,9 @@ pub fn discounts_writer(config: &Config, path: &PathBuf, db_conn: &mut Connectio
     let mut warnings = vec![];
 
     for (i, l) in reader.lines().enumerate() {
-        let line = match EdiLine::line_read(l, i, SEQ_DISC_REQLEN)? {
+        let l = l.map(|s| delimited_line(s, &EXPL_SEQ_DISC, &DISC_NUMBERS));
+        let line = match EdiLine::line_read(l, i, SEQ_DISC_REQLEN)
+            .map_err(|e| Error::parse(path, Some(i + 1), e))? {
             (Some(l), w) => {
                 warnings.extend(w);
                 l
@@ -177,41 +356,59 @@ pub fn discounts_writer(config: &Config, path: &PathBuf, db_conn: &mut Connectio
                 Ok((_, i)) => {
                     buyer_id = i;
                 },
-                Err(e) => bail!("Failed to read buyer from header: {}", e),
+                Err(e) => return Err(Error::parse(path, Some(i + 1),
+                    format!("Failed to read buyer from header: {}", e)).into()),
             },
             EdiLine::Seller(s) => match EdiParty::create(config, s) {
              Ok((d, i)) => {
                     seller_dir = d;
                     seller_id = i;
                 },
-                Err(e) => bail!("Failed to create seller dir: {}", e),
+                Err(e) => return Err(Error::parse(path, Some(i + 1),
+                        format!("Failed to read seller from header: {}", e)).into()),
             },
+            EdiLine::Record => continue,
             EdiLine::Entry(s) => match Discount::from_line(s) {
-                Ok(d) => {
-                    match discount_groups.contains(&d.discount_group) {
-                        true => match price_groups.contains(&d.price_group) {
-                            true => discounts.push(d),
-                            false => warnings.push(format!("[{}]: Ignoring as price group \
-                                '{}' was not found", &d.discount_group, &d.price_group))
-                        },
-                        false => warnings.push(format!("[{}]: Ignoring as discount group \
-                            was not found", &d.discount_group))
-                    }
+                Ok(d) if !config.discount.allows(d.pc1) || !config.discount.allows(d.pc2) => {
+                    warnings.push(format!("Line: {}, [{}]: Rejecting discount {} / {} %, allowed range is \
+                        {} - {} %", i + 1, &d.discount_group, d.pc1, d.pc2, config.discount.min_percent,
+                        config.discount.max_percent))
                 },
+                Ok(d) => discounts.push((i + 1, d)),
                 Err(e) => eprintln!("price read error '{}', line: {}", e, i + 1),
             }
         }
     }
 
+    let mut pending = vec![];
+    let discounts = discount_duplicates(path, discounts, config.discount.duplicates, &mut warnings)?
+        .into_iter()
+        .filter_map(|(line, d)| {
+            // Discounts may arrive before the seller's product and price files,
+            // with sqlite they wait in pending_discounts until groups are known
+            if discount_groups.contains(&d.discount_group) {
+                return Some(d)
+            }
+
+            match config.import.sqlite {
+                true => {
+                    warnings.push(format!("Line: {}, [{}]: Pending as discount group was not found", line,
+                        &d.discount_group));
+                    pending.push(d);
+                },
+                false => warnings.push(format!("Line: {}, [{}]: Ignoring as discount group was not found", line,
+                    &d.discount_group)),
+            }
+
+            None
+        })
+        .collect::<Vec<Discount>>();
+
     // Don't use buyer id as identifier as it comes from the supplier, can collide
     // and is considered to be somewhat private.
     let id_randy = Alphanumeric.sample_string(&mut rand::thread_rng(), 20);
     let bid = format!("{}{}", &buyer_id, &seller_id);
 
-    // Print unique warnings from decoder.
-    warnings.sort();
-    warnings.dedup();
-
     if let Err(e) = import_warning_logger(log, path, warnings) {
         error!("Failed to write {:?} warnings to log: {}", path, e);
     }
@@ -221,9 +418,11 @@ pub fn discounts_writer(config: &Config, path: &PathBuf, db_conn: &mut Connectio
         bail!("Unknown supplier {}", seller_id)
     }
 
+    let (source_file, imported_at) = import_provenance(path);
+
     // Create buyer on the database
     if config.import.sqlite {
-        let ctx = db_conn.transaction()?;
+        let ctx = db_conn.savepoint()?;
 
         ctx.execute(
             "insert or ignore into buyers (id, uuid, buyer_id, vat_percent) \
@@ -231,16 +430,31 @@ pub fn discounts_writer(config: &Config, path: &PathBuf, db_conn: &mut Connectio
             params![&bid, &id_randy, &buyer_id, config.vat_percent]
         )?;
 
+        // Latest file of the buyer replaces earlier pending rows
+        ctx.execute("delete from pending_discounts where buyer_id = ?1", params![&bid])?;
+
+        for d in pending.iter() {
+            ctx.execute(
+                "insert or replace into pending_discounts (id, buyer_id, seller_id, discount_group, \
+                    kind, code, name, percent_1, percent_2, source_file, imported_at) \
+                    values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                params!(format!("{}{}", &bid, &d.discount_group), &bid, &seller_id, &d.discount_group,
+                    d.kind.code(), &d.id, &d.name, &d.pc1, &d.pc2, &source_file, &imported_at)
+            )?;
+        }
+
         for d in discounts.iter() {
             // Add buyers' product discounts per seller and discount group
             let did = format!("{}{}", &bid, &d.discount_group);
 
             ctx.execute(
-                "insert into discounts (id, buyer_id, seller_id, discount_group, price_group, percent_1, percent_2) \
-                    values (?1, ?2, ?3, ?4, ?5, ?6, ?7) \
-                    on conflict (id) do update set price_group=excluded.price_group, \
-                    percent_1=excluded.percent_1, percent_2=excluded.percent_2",
-                params!(&did, &bid, &seller_id, &d.discount_group, &d.price_group, &d.pc1, &d.pc2)
+                "insert into discounts (id, buyer_id, seller_id, discount_group, kind, percent_1, percent_2, \
+                    source_file, imported_at) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9) \
+                    on conflict (id) do update set kind=excluded.kind, \
+                    percent_1=excluded.percent_1, percent_2=excluded.percent_2, \
+                    source_file=excluded.source_file, imported_at=excluded.imported_at",
+                params!(&did, &bid, &seller_id, &d.discount_group, d.kind.code(), &d.pc1, &d.pc2,
+                    &source_file, &imported_at)
             )?;
         }
 
@@ -252,21 +466,104 @@ pub fn discounts_writer(config: &Config, path: &PathBuf, db_conn: &mut Connectio
     buyer_dir.push("buyers");
     buyer_dir.push(&buyer_id);
 
-    // Buyer files under their respective seller.
-    if config.import.json {
-        let json = serde_json::to_string::<Vec<Discount>>(&discounts)?;
-        let mut discounts_file_path = buyer_dir.to_owned();
-        discounts_file_path.push("discounts");
+    create_buyer_dir(config, &buyer_dir)?;
+
+    // Buyer files under their respective seller or the buyers dir
+    if buyer_json(config) {
+        let path = buyer_discounts_dir(config, &seller_id, &buyer_id).join(format!("{seller_id}.json"));
+
+        discounts_json_merge(config, &path, &seller_id, &imported_at, discounts)?;
+        buyer_discounts_aggregate(config, &buyer_id)?;
+    }
+
+    Ok(buyer_dir)
+}
+
+/// Move pending discounts whose discount groups have been imported since to
+/// discounts. Buyers' JSON files are updated as well. Returns number
+/// of moved rows.
+pub fn pending_discounts_resolver(config: &Config, db_sellers: &Connection, db_buyers: &mut Connection)
+-> Result<usize> {
+    let discount_groups = query_discount_groups(db_sellers)?;
+
+    let mut stm = db_buyers.prepare(
+        "select p.id, p.buyer_id, p.seller_id, b.buyer_id, p.discount_group, p.kind, p.code, p.name, \
+            p.percent_1, p.percent_2, p.source_file, p.imported_at from pending_discounts p \
+            inner join buyers b on b.id = p.buyer_id"
+    )?;
+
+    let rows = stm.query_map([], |r| Ok((
+        (r.get::<_, String>(0)?, r.get::<_, String>(1)?, r.get::<_, String>(2)?, r.get::<_, String>(3)?),
+        Discount {
+            discount_group: r.get(4)?,
+            kind: DiscountKind::from_code(&r.get::<_, String>(5)?).unwrap_or_default(),
+            id: r.get(6)?,
+            name: r.get(7)?,
+            pc1: r.get(8)?,
+            pc2: r.get(9)?,
+        },
+        (r.get::<_, Option<String>>(10)?, r.get::<_, Option<String>>(11)?)
+    )))?
+    .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    drop(stm);
+
+    let ready = rows.into_iter()
+        .filter(|(_, d, _)| discount_groups.contains(&d.discount_group))
+        .collect::<Vec<_>>();
+
+    if ready.is_empty() {
+        return Ok(0)
+    }
+
+    let tx = db_buyers.transaction()?;
+
+    for ((did, bid, seller_id, _), d, (source_file, imported_at)) in ready.iter() {
+        tx.execute(
+            "insert into discounts (id, buyer_id, seller_id, discount_group, kind, percent_1, percent_2, \
+                source_file, imported_at) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9) \
+                on conflict (id) do update set kind=excluded.kind, \
+                percent_1=excluded.percent_1, percent_2=excluded.percent_2, \
+                source_file=excluded.source_file, imported_at=excluded.imported_at",
+            params!(did, bid, seller_id, &d.discount_group, d.kind.code(), &d.pc1, &d.pc2,
+                source_file, imported_at)
+        )?;
+
+        tx.execute("delete from pending_discounts where id = ?1", params![did])?;
+    }
+
+    tx.commit()?;
+
+    let count = ready.len();
+
+    if buyer_json(config) {
+        // Merge into existing buyer files by seller and buyer, stamped with
+        // the latest import of the pending discounts
+        let mut files: BTreeMap<(PathBuf, String), (String, Vec<Discount>)> = BTreeMap::new();
+        let mut buyers = BTreeSet::new();
+
+        for ((_, _, seller_id, buyer_id), d, (_, imported_at)) in ready {
+            let p = buyer_discounts_dir(config, &seller_id, &buyer_id).join(format!("{seller_id}.json"));
+            let (at, v) = files.entry((p, seller_id)).or_default();
+
+            if let Some(t) = imported_at.filter(|t| t.gt(at)) {
+                *at = t;
+            }
 
-        if let Err(e) = create_dir_all(&discounts_file_path) {
-            bail!("Failed to create buyer discounts dir {:?}: {}", discounts_file_path, e)
+            v.push(d);
+            buyers.insert(buyer_id);
         }
 
-        discounts_file_path.push(&seller_id);
-        discounts_file_path.set_extension("json");
+        for ((p, seller_id), (imported_at, resolved)) in files {
+            discounts_json_merge(config, &p, &seller_id, &imported_at, resolved)?;
+        }
 
-        write(&discounts_file_path, json.as_bytes())?;
+        for buyer_id in buyers {
+            buyer_discounts_aggregate(config, &buyer_id)?;
+        }
     }
 
-    Ok(buyer_dir)
-}
\ No newline at end of file
+    info!("Moved {} pending discounts with known discount and price groups to discounts", count);
+
+    Ok(count)
+}
@@ -0,0 +1,61 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::config::Config;
+use crate::schema::SCHEMA_VERSION;
+
+
+/// Top level of generated JSON files, the map or list of the file as `data`
+/// with the seller and import time it came from. A file copied elsewhere still
+/// tells whose and how fresh it is.
+#[derive(Debug, Serialize)]
+pub struct Envelope<T> {
+    pub schema_version: u32,
+    pub seller: String,
+    pub name: Option<String>,
+    pub imported_at: String,
+    pub data: T,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(config: &Config, seller_id: &str, imported_at: &str, data: T) -> Self {
+        let name = config.seller.iter()
+            .find(|s| s.id.eq(seller_id))
+            .map(|s| s.name.to_owned());
+
+        Self {
+            schema_version: SCHEMA_VERSION,
+            seller: seller_id.to_owned(),
+            name,
+            imported_at: imported_at.to_owned(),
+            data,
+        }
+    }
+}
+
+// Envelope has the seller next to data, files written before it are bare
+fn is_envelope(v: &Value) -> bool {
+    v.get("seller").is_some_and(|s| s.is_string()) && v.get("data").is_some()
+}
+
+/// Data of a generated file, the file itself when written without envelope.
+pub fn into_data(v: Value) -> Value {
+    match (is_envelope(&v), v) {
+        (true, Value::Object(mut m)) => m.remove("data").unwrap_or(Value::Null),
+        (_, v) => v,
+    }
+}
+
+/// Mutable data of a generated file, for updating it in place.
+pub fn data_mut(v: &mut Value) -> &mut Value {
+    match is_envelope(v) {
+        true => &mut v["data"],
+        false => v,
+    }
+}
+
+/// Parse data of a generated JSON file, with or without envelope.
+pub fn from_str<T: DeserializeOwned>(s: &str) -> serde_json::Result<T> {
+    serde_json::from_value(into_data(serde_json::from_str::<Value>(s)?))
+}
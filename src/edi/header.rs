@@ -1,16 +1,108 @@
+use std::collections::BTreeMap;
 use std::fs::{create_dir_all, File};
 use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
 
 use anyhow::{anyhow, bail, Result};
+use chrono::NaiveDate;
+use log::debug;
 
-use crate::config::Config;
+use crate::config::{Config, Seller};
+use crate::error::Error;
 
-use super::edi_line_iter;
+use super::{delimited_line, edi_line_iter};
 
 
 const SEQ_TITLE_REQLEN: usize = 23;
 const EXPL_SEQ_TITLE: [usize; 4] = [1, 2, 17, 3];
+// Header records read after buyer and seller at most
+const MAX_HEADER_RECORDS: usize = 16;
+
+/// Comparable form of an OVT code. Zero padded, space padded, with `FI`
+/// prefix, or plain business id with or without the dash all come out as
+/// `0037` followed by the 8 digit business id. Other ids are only trimmed.
+pub fn normalized_id(id: &str) -> String {
+    let s = id.chars().filter(|c| !c.is_whitespace() && *c != '-').collect::<String>().to_uppercase();
+    let business_id = s.strip_prefix("FI").unwrap_or(&s);
+
+    if business_id.len() == 8 && business_id.chars().all(|c| c.is_ascii_digit()) {
+        return format!("0037{}", business_id)
+    }
+
+    // 0037 + business id, more or less zeros before and after
+    let t = s.trim_start_matches('0');
+
+    if t.starts_with("37") && t.len() >= 10 && t.chars().all(|c| c.is_ascii_digit())
+        && t[10..].chars().all(|c| c == '0') {
+        return format!("00{}", &t[..10])
+    }
+
+    s
+}
+
+/// Configured seller ids and aliases with the seller id they map to. Header
+/// ids are matched as they are, or by normalized form when it's on. Empty map
+/// leaves header ids as they are.
+#[derive(Debug, Clone, Default)]
+pub struct SellerIds {
+    normalize: bool,
+    ids: Vec<(String, String)>,
+}
+
+impl SellerIds {
+    /// Aliases map to their seller, and with `normalize` ids written another
+    /// way are matched by their normalized form.
+    pub fn new(sellers: &[Seller], normalize: bool) -> Self {
+        let mut ids = vec![];
+
+        for s in sellers.iter() {
+            for id in std::iter::once(&s.id).chain(s.aliases.iter()) {
+                ids.push((id.to_owned(), s.id.to_owned()));
+
+                if normalize {
+                    ids.push((normalized_id(id), s.id.to_owned()));
+                }
+            }
+        }
+
+        Self { normalize, ids }
+    }
+    // Configured id of a seller id read from a header, the id itself when
+    // none matches
+    fn canonical(&self, id: String) -> String {
+        if self.ids.iter().any(|(_, c)| c.eq(&id)) {
+            return id
+        }
+
+        let normalized = match self.normalize {
+            true => normalized_id(&id),
+            false => id.to_owned(),
+        };
+
+        match self.ids.iter().find(|(n, _)| n.eq(&id) || n.eq(&normalized)) {
+            Some((_, c)) => {
+                debug!("Seller id '{}' of EDI header mapped to {}", id, c);
+                c.to_owned()
+            },
+            None => id,
+        }
+    }
+}
+
+/// Header lines start with `O`, entries with `R`.
+pub fn is_header_line(line: &str) -> bool {
+    line.starts_with('O')
+}
+
+// Code and value of a header record, `ODT20240101` or `O;DT;20240101`
+fn header_record(line: &str) -> Option<(String, String)> {
+    let rest = line.strip_prefix('O')?;
+    let rest = rest.strip_prefix(';').unwrap_or(rest);
+    let code = rest.get(..2)?;
+    let val = rest[2..].strip_prefix(';').unwrap_or(&rest[2..]);
+
+    Some((code.to_string(), val.trim().to_string()))
+}
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum EdiOwnership {
@@ -40,7 +132,8 @@ impl EdiParty {
     fn new() -> Self {
         Self { owner: EdiOwnership::Shared, id: String::new(), code: String::new() }
     }
-    fn from_line(line: String) -> Result<Self> {
+    fn from_line(line: String, seller_ids: &SellerIds) -> Result<Self> {
+        let line = delimited_line(line, &EXPL_SEQ_TITLE, &[]);
         let mut party = Self::new();
         let chars = line.chars();
         let mut pointer = 0;
@@ -79,6 +172,10 @@ impl EdiParty {
             pointer = p;
         }
 
+        if party.is_seller() {
+            party.id = seller_ids.canonical(party.id);
+        }
+
         Ok(party)
     }
     pub fn is_seller(&self) -> bool {
@@ -101,7 +198,7 @@ impl EdiParty {
         Ok(base)
     }
     pub fn create(config: &Config, line: String) -> Result<(PathBuf, String)> {
-        let party = match Self::from_line(line) {
+        let party = match Self::from_line(line, &config.seller_ids) {
             Ok(t) => t,
             Err(e) => bail!("Failed to read string as EDI header: {}", e),
         };
@@ -124,14 +221,20 @@ impl EdiParty {
 
 pub struct EdiHeader {
     pub seller: Option<EdiParty>,
-    pub buyer: Option<EdiParty>
+    pub buyer: Option<EdiParty>,
+    // Records following buyer and seller: DT file date, VR format version and
+    // CT contact. Other codes are kept in `records` as they are.
+    pub file_date: Option<NaiveDate>,
+    pub version: Option<String>,
+    pub contact: Option<String>,
+    pub records: BTreeMap<String, String>,
 }
 
 impl EdiHeader {
     fn new() -> Self {
-        Self { seller: None, buyer: None }
+        Self { seller: None, buyer: None, file_date: None, version: None, contact: None, records: BTreeMap::new() }
     }
-    pub fn read(path: &PathBuf) -> Result<Self> {
+    pub fn read(path: &PathBuf, seller_ids: &SellerIds) -> Result<Self> {
         // To prevent stupid developer errors
         let mut total = 0;
         
@@ -147,19 +250,38 @@ impl EdiHeader {
         let reader = BufReader::new(uft8_file);
         let mut head = Self::new();
     
-        // Read only header, two lines that is.
-        for (i, l) in reader.lines().take(2).enumerate() {
+        // Read only header, buyer and seller lines and records after them.
+        for (i, l) in reader.lines().take(2 + MAX_HEADER_RECORDS).enumerate() {
             let s = match l {
                 Ok(s) => s,
                 Err(_) => bail!("unable to read header line {} from {:?}", i, path),
             };
+
+            if i >= 2 {
+                let (code, val) = match is_header_line(&s).then(|| header_record(&s)).flatten() {
+                    Some(r) => r,
+                    None => break,
+                };
+
+                match code.as_str() {
+                    "DT" => head.file_date = Some(NaiveDate::parse_from_str(&val, "%Y%m%d").map_err(|e|
+                        Error::parse(path, Some(i + 1), format!("Invalid file date '{}': {}", val, e))
+                    )?),
+                    "VR" => head.version = Some(val),
+                    "CT" => head.contact = Some(val),
+                    _ => { head.records.insert(code, val); },
+                }
+
+                continue;
+            }
     
-            match EdiParty::from_line(s) {
+            match EdiParty::from_line(s, seller_ids) {
                 Ok(t) => match t.is_buyer() {
                     true => head.buyer = Some(t),
                     false => head.seller = Some(t),
                 },
-                Err(e) => bail!("Failed to read header line: {}", e),
+                Err(e) => return Err(Error::parse(path, Some(i + 1),
+                    format!("Failed to read header line: {}", e)).into()),
             }
         }
     
@@ -2,27 +2,47 @@ mod header;
 mod products;
 mod prices;
 mod discounts;
-
-use std::fs::File;
-use std::io::Write;
-use std::{fs::remove_file, path::PathBuf, str::Chars};
-use anyhow::{anyhow, bail, Result};
+pub mod parser;
+pub mod orders;
+mod report;
+mod combined;
+pub mod envelope;
+pub mod shards;
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{read_dir, remove_dir, remove_dir_all, write, File};
+use std::io::{BufRead, BufReader, Lines, Write};
+use std::sync::Arc;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread;
+use std::time::Instant;
+use std::{fs::remove_file, path::{Path, PathBuf}, str::Chars};
+use anyhow::{anyhow, bail, Context, Result};
 use log::{debug, error, info, warn};
 use rusqlite::Connection;
 use serde::{Serialize, Deserialize};
 
-pub use header::{EdiOwnership, EdiHeader};
-pub use discounts::{is_discount_file, discounts_writer};
-
-use crate::config::Config;
-use crate::db::{query_discount_groups, query_price_groups};
-use crate::files::{move_file, edi_file_imported};
-use self::prices::{is_price_file, prices_writer};
-use self::products::{is_product_file, products_writer};
+pub use header::{is_header_line, EdiOwnership, EdiHeader, SellerIds};
+pub use discounts::{buyer_discounts_aggregate, buyer_discounts_dir, buyer_json, discounts_writer, pending_discounts_resolver, DiscountKind};
+pub use parser::detect;
+pub use report::orphans_report;
+
+use crate::config::{Config, Seller};
+use crate::db::{in_savepoint, query_discount_groups};
+use crate::files::{create_buyer_dir, edi_file_imported, move_file, restrict_buyer_file, store_edi_file, RunTemp};
+use crate::registry::registry_crossref;
+use crate::timings::{self, Stage};
+use self::combined::combined_writer;
+use self::report::compliance_report;
+use self::prices::{price_widths, prices_writer};
+use self::products::{product_widths, products_writer};
 
 pub const EDI_DIR_NAME: &str = "edi";
 pub const UPLOAD_DIR_NAME: &str = "uploads";
 pub const DOWNLOAD_DIR_NAME: &str = "downloads";
+pub const ARCHIVE_DIR_NAME: &str = "archive";
+// Discount files of sellers not imported yet wait in pending/<seller>/
+pub const PENDING_DIR_NAME: &str = "pending";
 
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -39,25 +59,34 @@ impl EdiDate {
     fn new() -> Self {
         Self { year: String::new(), month: String::new(), day: String::new() }
     }
-    fn from_string(val: String) -> Result<Self> {
+    fn from_string(val: &str) -> Result<Self> {
         if val.len() != 8 {
             bail!("Date value should be in format 'yyyymmdd'. String 8 chars \
                 long that is.")
         }
         
         let (y, f) = match val.len() > 4 {
-            true => val.split_at(4),
+            true => split_decimals(val, 4)?,
             false => bail!("Unable to split years from date '{}' string", val),
         };
-        let (m, d) = f.split_at(2);
+        let (m, d) = split_decimals(f, 2)?;
 
         Ok(Self { year: y.to_string() , month: m.to_string(), day: d.to_string() })
     }
+    // Calendar date, not just 8 chars
+    fn is_valid(&self) -> bool {
+        match (self.year.parse(), self.month.parse(), self.day.parse()) {
+            (Ok(y), Ok(m), Ok(d)) => chrono::NaiveDate::from_ymd_opt(y, m, d).is_some(),
+            _ => false,
+        }
+    }
 }
 
 pub enum EdiLine {
     Buyer(String),
     Seller(String),
+    // Header records after buyer and seller, read by EdiHeader
+    Record,
     Entry(String)
 }
 
@@ -77,6 +106,8 @@ impl EdiLine {
     
         // ..and seller
         if i == 1 { return Ok((Some(Self::Seller(s)), vec![])) }
+
+        if is_header_line(&s) { return Ok((Some(Self::Record), vec![])) }
     
         let linelen = s.chars().count();
         let mut warnings = vec![];
@@ -111,6 +142,99 @@ impl EdiLine {
     }
 }
 
+/// Lines of an EDI file like `BufRead::lines`. Files of at least
+/// `mmap_threshold_mb` are copied into a temp dir of their own and memory
+/// mapped from there, so nobody truncates the file under the map. Mapped files
+/// are checked to be utf-8 once, invalid ones are read the buffered way so the
+/// failing line gets reported.
+pub enum EdiLines {
+    Buffered(Lines<BufReader<File>>),
+    // Map is dropped before the temp dir holding its file
+    Mapped { map: memmap2::Mmap, pos: usize, _temp: RunTemp },
+}
+
+impl EdiLines {
+    pub fn open(config: &Config, path: &PathBuf) -> Result<Self> {
+        let file = File::open(path)?;
+
+        if file.metadata()?.len() < config.import.mmap_threshold_mb * 1024 * 1024 {
+            return Ok(Self::Buffered(BufReader::new(file).lines()))
+        }
+
+        let temp = RunTemp::new(config)?;
+        let copy = temp.path.join(path.file_name().unwrap_or_default());
+
+        std::fs::copy(path, &copy).map_err(|e| anyhow!("Failed to copy {:?} for mapping: {}", path, e))?;
+
+        // Safety: the copy is private to this reader and never written to
+        let map = unsafe { memmap2::Mmap::map(&File::open(&copy)?)? };
+
+        if std::str::from_utf8(&map).is_err() {
+            return Ok(Self::Buffered(BufReader::new(file).lines()))
+        }
+
+        map.advise(memmap2::Advice::Sequential)?;
+        debug!("Reading memory mapped {:?}", path);
+
+        Ok(Self::Mapped { map, pos: 0, _temp: temp })
+    }
+}
+
+impl Iterator for EdiLines {
+    type Item = std::io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Buffered(l) => l.next(),
+            Self::Mapped { map, pos, .. } => {
+                let rest = map.get(*pos..).filter(|r| !r.is_empty())?;
+
+                let (line, len) = match rest.iter().position(|b| *b == b'\n') {
+                    Some(n) => (&rest[..n], n + 1),
+                    None => (rest, rest.len()),
+                };
+
+                *pos += len;
+
+                let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+                // Whole map was checked to be utf-8, nothing gets replaced
+                Some(Ok(String::from_utf8_lossy(line).into_owned()))
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdiFileKind {
+    Product,
+    Price,
+    Discount,
+    // Detection found no kind the lines parse as
+    Unknown,
+}
+
+impl EdiFileKind {
+    pub fn from_name(val: &str) -> Result<Self> {
+        match val {
+            "product" => Ok(Self::Product),
+            "price" => Ok(Self::Price),
+            "discount" => Ok(Self::Discount),
+            x => bail!("Invalid EDI file type '{}'. Expected one of: [product, price, discount]", x),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ImportOptions {
+    // Import even if an identical file was already imported.
+    pub force: bool,
+    // Skip file type detection.
+    pub kind: Option<EdiFileKind>,
+    // Seller the file is expected to belong to.
+    pub seller: Option<String>,
+}
+
 pub enum EdiType {
     Invalid,
     Product(bool),
@@ -122,16 +246,39 @@ impl EdiType {
     /// Reads EDI file and imports its lines into the database.
     /// Generates also JSON version of EDI data.
     pub fn file_import(edifile_path: &PathBuf, edifile_name: &String, config: &Config, db_sellers: &mut Connection,
-        db_buyers: &mut Connection, log: &mut File)
+        db_buyers: &mut Connection, log: &mut File, opts: &ImportOptions)
     -> Result<Self> {
+        timings::file_start(edifile_path);
+
         let d = chrono::Utc::now();
         let dmy = format!("{} import started on: {}", edifile_name, d.format("%d.%m.%y %H:%M:%S"));
         
         writeln!(log, "{}", dmy).unwrap();
 
+        if let Some(id) = &opts.seller {
+            let header = EdiHeader::read(edifile_path, &config.seller_ids)?;
+
+            match header.seller {
+                Some(s) => if s.id.ne(id) {
+                    bail!("EDI file header has seller {} but {} was expected", s.id, id)
+                },
+                None => bail!("EDI file header has no seller, expected {}", id),
+            }
+        }
+
+        let kind = match opts.kind {
+            Some(k) => k,
+            None => {
+                let detected = detect(edifile_path, config)?;
+                debug!("{:?} detected as {:?}, {}", edifile_path, detected.kind, detected.note);
+
+                detected.kind
+            },
+        };
+
         // Products EDI file
-        if is_product_file(edifile_path).unwrap() {
-            match edi_file_imported(config, &edifile_path, EdiOwnership::Seller) {
+        if kind.eq(&EdiFileKind::Product) {
+            match already_imported(config, edifile_path, EdiOwnership::Seller, opts) {
                 Ok(b) => match b {
                     true => {
                         info!("Skipping rewriting for up to date product source file {:?}", &edifile_path);
@@ -146,26 +293,40 @@ impl EdiType {
                     source files: {}", e)
             }
     
-            // Collect separate list for each supported language
-            let mut supplier_dir = PathBuf::new();
-            for c in config.lang_codes.iter() {
-                match products_writer(config, &edifile_path, c, db_sellers, log) {
-                    Ok(d) => { supplier_dir = d; },
-                    Err(e) => {
-                        warn!("Failed to write products from {:?} in \
-                            lang {}: {}", edifile_path, c, e);
+            // Collect separate list for each supported language. All languages
+            // are committed at once, file is moved only after that.
+            let (supplier_dir, json) = in_savepoint(db_sellers, |conn| {
+                let (supplier_dir, json) = products_writer(config, edifile_path, &config.lang_codes, conn, log)?;
+
+                // Validate against national registries, if imported
+                if config.import.sqlite {
+                    if let Some(id) = supplier_dir.file_name() {
+                        let warnings = registry_crossref(conn, &id.to_string_lossy())?;
+
+                        import_warning_logger(log, edifile_path, warnings)?;
                     }
-                };
-            }
+                }
+
+                Ok((supplier_dir, json))
+            })?;
+
+            json.write().context("Failed to write products JSON")?;
     
-            move_file(&edifile_path, &supplier_dir, EDI_DIR_NAME, edifile_name);
+            if let Err(e) = compliance_report(config, edifile_path, EdiFileKind::Product, &supplier_dir, db_sellers) {
+                error!("Failed to write compliance report of {:?}: {:#}", edifile_path, e);
+            }
+
+            store_edi_file(edifile_path, &supplier_dir, edifile_name)?;
+            resolve_pending_discounts(config, db_sellers, db_buyers);
+            retry_queued_discounts(config, &supplier_dir, db_sellers, db_buyers, log);
+            combined_json(config, &supplier_dir);
     
             return Ok(Self::Product(true))
         }
     
         // Prices EDI file
-        if is_price_file(&edifile_path).unwrap() {
-            match edi_file_imported(config, &edifile_path, EdiOwnership::Seller) {
+        if kind.eq(&EdiFileKind::Price) {
+            match already_imported(config, edifile_path, EdiOwnership::Seller, opts) {
                 Ok(b) => if b {
                     info!("Skipping rewriting for up to date price source \
                         file {:?}", &edifile_path);
@@ -176,19 +337,27 @@ impl EdiType {
                     source files: {}", e)
             }
     
-            let supplier_dir = match prices_writer(config, &edifile_path, db_sellers, log) {
-                Ok(d) => d,
-                Err(e) => bail!("Failed to write prices: {}", e),
-            };
+            let (supplier_dir, json) = in_savepoint(db_sellers, |conn| {
+                prices_writer(config, edifile_path, conn, log)
+            }).context("Failed to write prices")?;
+
+            json.write().context("Failed to write prices JSON")?;
+
+            if let Err(e) = compliance_report(config, edifile_path, EdiFileKind::Price, &supplier_dir, db_sellers) {
+                error!("Failed to write compliance report of {:?}: {:#}", edifile_path, e);
+            }
         
-            move_file(&edifile_path, &supplier_dir, EDI_DIR_NAME, edifile_name);
+            store_edi_file(edifile_path, &supplier_dir, edifile_name)?;
+            resolve_pending_discounts(config, db_sellers, db_buyers);
+            retry_queued_discounts(config, &supplier_dir, db_sellers, db_buyers, log);
+            combined_json(config, &supplier_dir);
 
             return Ok(Self::Price(true))
         }
 
         // Discount EDI file
-        if is_discount_file(&edifile_path).unwrap() {
-            match edi_file_imported(config, &edifile_path, EdiOwnership::Buyer) {
+        if kind.eq(&EdiFileKind::Discount) {
+            match already_imported(config, edifile_path, EdiOwnership::Buyer, opts) {
                 Ok(b) => if b {
                     info!("Skipping rewriting for up to date discount source \
                         file {:?}", &edifile_path);
@@ -199,25 +368,40 @@ impl EdiType {
                     source files: {}", e)
             }
 
-            // Query discount and price groups from database for possible discount file processing
+            // Buyer uploaded discounts before the first import of the seller,
+            // the file waits for the seller's catalog
+            if let Some(s) = EdiHeader::read(edifile_path, &config.seller_ids)?.seller {
+                if !config.dir.join("sellers").join(&s.id).is_dir() {
+                    create_buyer_dir(config, &config.dir.join(PENDING_DIR_NAME).join(&s.id))?;
+
+                    let queued = move_file(edifile_path, &config.dir.join(PENDING_DIR_NAME), &s.id, edifile_name)?;
+                    restrict_buyer_file(config, &queued)?;
+
+                    warn!("Seller {} of discount file {} hasn't been imported yet, queued to {:?}", s.id,
+                        edifile_name, queued);
+                    writeln!(log, "Queued {} until seller {} is imported", edifile_name, s.id)?;
+
+                    return Ok(Self::Discount(false))
+                }
+            }
+
+            // Query discount groups from database for possible discount file processing
             let discount_groups = match query_discount_groups(&db_sellers) {
                 Ok(v) => v,
                 Err(e) => bail!("Failed to query discount groups: {}", e),
             };
 
-            let price_groups = match query_price_groups(&db_sellers) {
-                Ok(v) => v,
-                Err(e) => bail!("Failed to query price groups: {}", e),
-            };
-
             debug!("Opening discounts file {:?}...", &edifile_path);
 
-            let buyer_dir = discounts_writer(
-                config, &edifile_path, db_buyers, &discount_groups, &price_groups, log
-            ).map_err(|e|anyhow!("Failed to write discounts: {}", e))?;
+            let buyer_dir = in_savepoint(db_buyers, |conn| {
+                discounts_writer(config, edifile_path, conn, &discount_groups, log)
+            }).context("Failed to write discounts")?;
 
             // Discount EDI file should be named as the discounts.txt
-            move_file(&edifile_path, &buyer_dir, EDI_DIR_NAME, "discounts.txt");
+            create_buyer_dir(config, &buyer_dir.join(EDI_DIR_NAME))?;
+
+            let archived = move_file(edifile_path, &buyer_dir, EDI_DIR_NAME, "discounts.txt")?;
+            restrict_buyer_file(config, &archived)?;
             
             return Ok(Self::Discount(true))
         }
@@ -226,7 +410,7 @@ impl EdiType {
         // is a clusterfuck situation
         if edifile_path.is_file() {
             error!("Deleting obsolete file from {:?}. File was not recognized as \
-                product nor price EDI file", &edifile_path);
+                product, price nor discount EDI file", &edifile_path);
             
             if let Err(e) = remove_file(&edifile_path) {
                 bail!("Forgot how to delete a file: {}", e)
@@ -237,29 +421,206 @@ impl EdiType {
     }
 }
 
-pub fn edi_line_iter(pointer: usize, chars: &Chars<'_>, take_next: &usize) -> Result<(String, usize)> {
-    let mut value = vec![];
+// Catalog imports may bring groups that earlier discount files were missing.
+// Import itself is done, failure here is only logged.
+fn resolve_pending_discounts(config: &Config, db_sellers: &Connection, db_buyers: &mut Connection) {
+    if !config.import.sqlite {
+        return
+    }
+
+    if let Err(e) = pending_discounts_resolver(config, db_sellers, db_buyers) {
+        error!("Failed to resolve pending discounts: {:#}", e);
+    }
+}
 
-    for (i, c) in chars.to_owned().enumerate() {
-        if i < pointer {
-            continue;
+// Products with prices of the seller rewritten after either changed, a failed
+// file is written again on the next import
+fn combined_json(config: &Config, supplier_dir: &PathBuf) {
+    if !config.import.json || !config.json_output.combined {
+        return
+    }
+
+    if let Err(e) = combined_writer(config, supplier_dir) {
+        error!("Failed to write combined products and prices of {:?}: {:#}", supplier_dir, e);
+    }
+}
+
+// Import discount files queued for the seller now that it has been imported.
+// Oldest first, so the latest file of a buyer wins. Failures are only logged.
+fn retry_queued_discounts(config: &Config, supplier_dir: &Path, db_sellers: &mut Connection,
+    db_buyers: &mut Connection, log: &mut File) {
+    let seller_id = supplier_dir.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let queue = config.dir.join(PENDING_DIR_NAME).join(&seller_id);
+
+    let entries = match read_dir(&queue) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+
+    let mut files = entries.filter_map(|e| e.ok())
+        .filter(|e| e.path().is_file())
+        .map(|e| (e.metadata().and_then(|m| m.modified()).ok(), e.path()))
+        .collect::<Vec<_>>();
+
+    files.sort();
+
+    let opts = ImportOptions { kind: Some(EdiFileKind::Discount), ..Default::default() };
+
+    for (_, path) in files {
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+
+        info!("Importing discount file {} queued for seller {}", name, seller_id);
+
+        if let Err(e) = EdiType::file_import(&path, &name, config, db_sellers, db_buyers, log, &opts) {
+            error!("Failed to import queued discount file {:?}: {:#}", path, e);
         }
+    }
+
+    // Failed ones are left for a look
+    if let Err(e) = remove_dir(&queue) {
+        debug!("Queue of seller {} not removed: {}", seller_id, e);
+    }
+}
+
+fn already_imported(config: &Config, path: &PathBuf, ownership: EdiOwnership, opts: &ImportOptions)
+-> Result<bool> {
+    match opts.force {
+        true => Ok(false),
+        false => edi_file_imported(config, path, ownership),
+    }
+}
+
+/// Seller config of a file, by the seller of its header.
+pub fn file_seller<'a>(config: &'a Config, path: &PathBuf) -> Option<&'a Seller> {
+    let party = EdiHeader::read(path, &config.seller_ids).ok()?.seller?;
+
+    config.seller.iter().find(|s| s.id.eq(&party.id))
+}
+
+// Field widths with a seller's changes, by field name, applied
+fn field_widths(base: &[usize], names: &[&str], deltas: &BTreeMap<String, i64>) -> Result<Vec<usize>> {
+    let mut widths = base.to_vec();
 
-        value.push(c);
+    for (name, delta) in deltas.iter() {
+        let j = match names.iter().position(|n| n.eq(name)) {
+            Some(j) => j,
+            None => bail!("Unknown field '{}', expected one of {}", name, names.join(", ")),
+        };
+
+        widths[j] = match usize::try_from(widths[j] as i64 + delta) {
+            Ok(w) if w > 0 => w,
+            _ => bail!("Field '{}' can't be {} chars wide", name, widths[j] as i64 + delta),
+        };
+    }
+
+    Ok(widths)
+}
+
+/// Check that field width changes of a seller apply.
+pub fn check_field_widths(seller: &Seller) -> Result<()> {
+    product_widths(Some(seller)).map_err(|e| anyhow!("Product field widths: {}", e))?;
+    price_widths(Some(seller)).map_err(|e| anyhow!("Price field widths: {}", e))?;
 
-        if value.len() == *take_next {
-            let s = String::from_iter(value);
+    Ok(())
+}
+
+/// Semicolon separated line as the fixed width line of the same layout.
+/// Columns are padded to the field widths, numbers with leading zeros. Lines
+/// without a column per field, the optional last one aside, are returned as
+/// they are. Too wide columns are kept whole, the line is then rejected as
+/// too long.
+pub fn delimited_line(line: String, widths: &[usize], numbers: &[usize]) -> String {
+    let mut cols = line.split(';').collect::<Vec<&str>>();
+
+    // Trailing separator
+    if cols.len() == widths.len() + 1 && cols.last().is_some_and(|c| c.trim().is_empty()) {
+        cols.pop();
+    }
+
+    if cols.len() < 2 || cols.len() + 1 < widths.len() || cols.len() > widths.len() {
+        return line
+    }
+
+    cols.iter().zip(widths.iter()).enumerate().map(|(j, (c, w))| match numbers.contains(&j) {
+        true => format!("{:0>w$}", c.trim(), w = w),
+        false => format!("{:<w$}", c.trim(), w = w),
+    })
+    .collect()
+}
+
+pub fn edi_line_iter(pointer: usize, chars: &Chars<'_>, take_next: &usize) -> Result<(String, usize)> {
+    let line = chars.as_str();
+    let start = match pointer {
+        0 => Some(0),
+        p => char_end(line, p),
+    };
+
+    match start.and_then(|s| char_end(&line[s..], *take_next).map(|e| &line[s..s + e])) {
+        Some(v) => Ok((v.trim().to_string(), pointer + take_next)),
+        None => bail!("Failed to extract [{}-{}] from line '{}'", pointer, take_next, line),
+    }
+}
+
+// Byte index after first n chars, none if there are less
+fn char_end(s: &str, n: usize) -> Option<usize> {
+    s.char_indices().map(|(i, _)| i).chain(std::iter::once(s.len())).nth(n)
+}
+
+/// Fixed width fields of a line read in order, as trimmed slices of the line.
+pub struct EdiFields<'a> {
+    line: &'a str,
+    at: usize,
+    pointer: usize,
+}
 
-            return Ok((s.trim().to_string(), pointer + take_next))
+impl<'a> EdiFields<'a> {
+    pub fn new(line: &'a str) -> Self {
+        Self { line, at: 0, pointer: 0 }
+    }
+    /// Next field `width` chars wide. Fails if the line is too short.
+    pub fn next_field(&mut self, width: usize) -> Result<&'a str> {
+        let rest = &self.line[self.at..];
+
+        match char_end(rest, width) {
+            Some(e) => {
+                self.at += e;
+                self.pointer += width;
+
+                Ok(rest[..e].trim())
+            },
+            None => bail!("Failed to extract [{}-{}] from line '{}'", self.pointer, width, self.line),
         }
     }
+}
+
+/// Shared copies of values repeating on most lines of a file, units and groups.
+/// A file of 500k lines has a few dozen distinct ones.
+#[derive(Debug, Default)]
+pub struct Interner(HashSet<Arc<str>>);
+
+impl Interner {
+    pub fn intern(&mut self, val: &str) -> Arc<str> {
+        if let Some(s) = self.0.get(val) {
+            return s.clone()
+        }
+
+        let s: Arc<str> = Arc::from(val);
+        self.0.insert(s.clone());
 
-    let failing = String::from_iter(chars.to_owned());
+        s
+    }
+}
 
-    bail!("Failed to extract [{}-{}] from line '{}'", pointer, take_next, failing)
+// Split fixed width number at byte index. Numbers are ascii, anything else
+// would panic in split_at if the index lands inside a multibyte char.
+pub fn split_decimals(val: &str, at: usize) -> Result<(&str, &str)> {
+    match val.is_char_boundary(at) {
+        true => Ok(val.split_at(at)),
+        false => bail!("Non numeric characters in '{}'", val),
+    }
 }
 
-pub fn str_as_f64(int: &str, des: &str, val: &String) -> Result<f64> {
+pub fn str_as_f64(int: &str, des: &str, val: &str) -> Result<f64> {
     let mut d: f64 = match int.parse() {
         Ok(f) => f,
         Err(e) => bail!("Failed to read integers ({}) from \
@@ -278,16 +639,211 @@ pub fn str_as_f64(int: &str, des: &str, val: &String) -> Result<f64> {
     Ok(d)
 }
 
-pub fn import_warning_logger(log: &mut File, path: &PathBuf, warnings: Vec<String>) -> Result<()> {
-    if !warnings.is_empty() {
-        writeln!(log, "File {:?} produced {} warnings:", path, warnings.len())?;
+/// Source file name and timestamp stored on every imported row so a value can be
+/// traced back to the file that set it.
+pub fn import_provenance(path: &Path) -> (String, String) {
+    let file = match path.file_name() {
+        Some(n) => n.to_string_lossy().into_owned(),
+        None => path.to_string_lossy().into_owned(),
+    };
+    let at = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string();
+
+    (file, at)
+}
+
+/// JSON output of a file. Files are serialized while the database is written
+/// and put on disk only after the database work of the file is committed, so a
+/// rolled back import leaves the earlier files in place.
+#[derive(Debug, Default)]
+pub struct StagedJson {
+    // Files of the other layout and stale shards
+    remove: Vec<PathBuf>,
+    files: Vec<(PathBuf, String)>,
+}
 
-        warn!("File {:?} produced {} warnings. All warnings are logged.", path, warnings.len());
+impl StagedJson {
+    pub fn add<T: Serialize>(&mut self, path: PathBuf, v: &T) -> Result<()> {
+        self.files.push((path, serde_json::to_string(v)?));
+
+        Ok(())
+    }
+    /// File or dir to remove before the files are written.
+    pub fn remove(&mut self, path: PathBuf) {
+        self.remove.push(path);
+    }
+    /// Files of `other` are written after these.
+    pub fn append(&mut self, mut other: Self) {
+        self.remove.append(&mut other.remove);
+        self.files.append(&mut other.files);
     }
+    pub fn write(self) -> Result<()> {
+        let started = Instant::now();
+
+        for p in self.remove {
+            match p.is_dir() {
+                true => remove_dir_all(&p),
+                false if p.is_file() => remove_file(&p),
+                false => Ok(()),
+            }.map_err(|e| anyhow!("Failed to remove {:?}: {}", p, e))?;
+        }
+
+        for (path, s) in self.files {
+            write(&path, s).map_err(|e| anyhow!("Failed to write {:?}: {}", path, e))?;
+        }
 
-    for w in warnings {
-        writeln!(log, "Warning: {}", w)?;
+        timings::record(Stage::JsonWrite, started.elapsed());
+
+        Ok(())
+    }
+}
+
+/// Serialize JSON files sent by `body` on a thread of their own, so the next
+/// category can be written to the database meanwhile. Errors of the serializer
+/// come first, a failed send only means it stopped.
+pub fn with_json_writer<T, R, F>(body: F) -> Result<(R, StagedJson)>
+where T: Serialize + Send, F: FnOnce(&SyncSender<(PathBuf, T)>) -> Result<R> {
+    thread::scope(|s| {
+        // One category waiting is enough to keep both sides busy
+        let (tx, rx) = sync_channel::<(PathBuf, T)>(1);
+
+        let writer = s.spawn(move || -> Result<StagedJson> {
+            let mut staged = StagedJson::default();
+
+            for (path, v) in rx {
+                let started = Instant::now();
+
+                staged.add(path, &v)?;
+
+                timings::record(Stage::JsonWrite, started.elapsed());
+            }
+
+            Ok(staged)
+        });
+
+        let result = body(&tx);
+        drop(tx);
+
+        match writer.join() {
+            Ok(Ok(staged)) => Ok((result?, staged)),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(anyhow!("JSON writer thread panicked")),
+        }
+    })
+}
+
+// Warnings of the same kind, first one kept as an example
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WarningSummary {
+    pub warning: String,
+    pub count: usize,
+    // First few line numbers that produced the warning
+    pub lines: Vec<usize>,
+}
+
+const WARNING_EXAMPLE_LINES: usize = 5;
+
+// Split "Line: N, ..." into line number and message, "Skipping line N, ..."
+// keeps its message as is
+fn warning_line(warning: &str) -> (Option<usize>, &str) {
+    if let Some((n, msg)) = warning.strip_prefix("Line: ").and_then(|r| r.split_once(", ")) {
+        if let Ok(n) = n.parse::<usize>() {
+            return (Some(n), msg)
+        }
+    }
+
+    if let Some((n, _)) = warning.strip_prefix("Skipping line ").and_then(|r| r.split_once(", ")) {
+        if let Ok(n) = n.parse::<usize>() {
+            return (Some(n), warning)
+        }
+    }
+
+    (None, warning)
+}
+
+// Mask product identifiers, quoted values and numbers so warnings of the same
+// kind share a key
+fn warning_kind(message: &str) -> String {
+    let message = match message.starts_with('[') {
+        true => message.split_once("]: ").map(|(_, m)| m).unwrap_or(message),
+        false => message,
+    };
+
+    let mut key = String::new();
+    let mut quoted = false;
+
+    for c in message.chars() {
+        match c {
+            '\'' => {
+                quoted = !quoted;
+                key.push(c);
+            },
+            _ if quoted => (),
+            c if c.is_ascii_digit() => {
+                if !key.ends_with('#') {
+                    key.push('#');
+                }
+            },
+            c => key.push(c),
+        }
+    }
+
+    key
+}
+
+/// Group warnings by kind with occurrence counts and a few example line
+/// numbers. Most frequent kinds come first.
+pub fn aggregate_warnings(warnings: &[String]) -> Vec<WarningSummary> {
+    let mut kinds: Vec<(String, WarningSummary)> = vec![];
+
+    for w in warnings.iter() {
+        let (line, message) = warning_line(w);
+        let key = warning_kind(message);
+
+        let i = match kinds.iter().position(|(k, _)| k.eq(&key)) {
+            Some(i) => i,
+            None => {
+                kinds.push((key, WarningSummary { warning: message.to_string(), ..Default::default() }));
+                kinds.len() - 1
+            },
+        };
+
+        let summary = &mut kinds[i].1;
+        summary.count += 1;
+
+        if let Some(n) = line {
+            if summary.lines.len() < WARNING_EXAMPLE_LINES && !summary.lines.contains(&n) {
+                summary.lines.push(n);
+            }
+        }
+    }
+
+    let mut summaries = kinds.into_iter().map(|(_, s)| s).collect::<Vec<WarningSummary>>();
+
+    // Stable sort, equal counts stay in order of first occurrence
+    summaries.sort_by_key(|s| std::cmp::Reverse(s.count));
+    summaries
+}
+
+pub fn import_warning_logger(log: &mut File, path: &PathBuf, warnings: Vec<String>) -> Result<()> {
+    if warnings.is_empty() {
+        return Ok(())
+    }
+
+    let summaries = aggregate_warnings(&warnings);
+
+    writeln!(log, "File {:?} produced {} warnings of {} kinds:", path, warnings.len(), summaries.len())?;
+
+    warn!("File {:?} produced {} warnings of {} kinds. All warnings are logged.", path, warnings.len(),
+        summaries.len());
+
+    for s in summaries {
+        match s.lines.is_empty() {
+            true => writeln!(log, "Warning: {} times: {}", s.count, s.warning)?,
+            false => writeln!(log, "Warning: {} times, e.g. line{} {}: {}", s.count,
+                if s.lines.len() > 1 { "s" } else { "" },
+                s.lines.iter().map(|n| n.to_string()).collect::<Vec<String>>().join(", "), s.warning)?,
+        }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
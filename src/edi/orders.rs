@@ -0,0 +1,205 @@
+use std::fs::{create_dir_all, read_to_string, write};
+use std::io::{stdin, Read};
+use std::path::PathBuf;
+use anyhow::{anyhow, bail, Result};
+use chrono::Local;
+use log::{info, warn};
+use rusqlite::Connection;
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::error::Error;
+use crate::lookup::{buyer_discount, catalog_price, net_price, BuyerDiscount};
+
+
+pub const ORDERS_DIR_NAME: &str = "orders";
+
+// Order the web shop wants to send, read from JSON
+#[derive(Debug, Deserialize)]
+pub struct OrderRequest {
+    pub number: String,
+    // Customer number given by the seller, same as in discount files
+    pub buyer: String,
+    pub seller: String,
+    // yyyymmdd
+    pub delivery_date: Option<String>,
+    pub lines: Vec<OrderLine>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OrderLine {
+    pub product: String,
+    pub quantity: f64,
+}
+
+// Order line with catalog data and buyer's net price
+#[derive(Debug)]
+struct PricedLine {
+    product: String,
+    quantity: f64,
+    unit: String,
+    price_unit: String,
+    units_incl: i64,
+    list_price: f64,
+    discount_percent: f64,
+}
+
+impl PricedLine {
+    fn net_price(&self) -> f64 {
+        net_price(self.list_price, self.discount_percent)
+    }
+}
+
+/// Read order request from a JSON file (or stdin when path is '-') and write
+/// it as EDIFACT ORDERS D.96A message. Returns path of the written message.
+pub fn order_writer(config: &Config, input: &PathBuf, out: Option<&PathBuf>, db_sellers: &Connection,
+    db_buyers: &Connection)
+-> Result<PathBuf> {
+    let json = match input.as_os_str().eq("-") {
+        true => {
+            let mut s = String::new();
+            stdin().read_to_string(&mut s).map_err(|e| anyhow!("Failed to read order from stdin: {}", e))?;
+            s
+        },
+        false => read_to_string(input).map_err(|e| anyhow!("Failed to read order {:?}: {}", input, e))?,
+    };
+
+    let order = serde_json::from_str::<OrderRequest>(&json)
+        .map_err(|e| Error::parse(input, Some(e.line()), e.to_string()))?;
+
+    // Number ends up in the file name
+    if order.number.is_empty() || !order.number.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        bail!("Order number '{}' should contain only letters, digits, '-' and '_'", order.number)
+    }
+
+    if order.lines.is_empty() {
+        bail!("Order {} has no lines", order.number)
+    }
+
+    if !config.seller.iter().any(|s| s.id.eq(&order.seller)) {
+        bail!("Seller {} of order {} is not configured", order.seller, order.number)
+    }
+
+    let mut priced = vec![];
+
+    for l in order.lines.iter() {
+        priced.push(price_line(&order, l, db_sellers, db_buyers)?);
+    }
+
+    let message = orders_message(&order, &priced);
+
+    let path = match out {
+        Some(p) => p.to_owned(),
+        None => {
+            let mut p = config.dir.to_owned();
+            p.push(ORDERS_DIR_NAME);
+            p.push(&order.seller);
+            p.push(format!("{}.edi", &order.number));
+            p
+        },
+    };
+
+    if let Some(d) = path.parent() {
+        create_dir_all(d)?;
+    }
+
+    write(&path, message).map_err(|e| anyhow!("Failed to write order {:?}: {}", path, e))?;
+
+    info!("Order {} with {} lines written to {:?}", order.number, priced.len(), path);
+
+    Ok(path)
+}
+
+// Find product from any category, then buyer's discount for its discount group
+fn price_line(order: &OrderRequest, line: &OrderLine, db_sellers: &Connection, db_buyers: &Connection)
+-> Result<PricedLine> {
+    let price = match catalog_price(db_sellers, &order.seller, &line.product)? {
+        Some(p) => p,
+        None => bail!("Product {} with price not found for seller {}", line.product, order.seller),
+    };
+
+    let discount = match buyer_discount(db_buyers, &order.buyer, &order.seller, &price.discount_group)? {
+        Some(d) => d,
+        None => {
+            warn!("Buyer {} has no discount for group {} of product {}, using list price",
+                order.buyer, price.discount_group, line.product);
+            BuyerDiscount::default()
+        },
+    };
+
+    // Quantity reaching a packaging size may get packaging discount as well
+    let discount_percent = discount.percent(&price, line.quantity);
+
+    Ok(PricedLine {
+        product: line.product.to_owned(),
+        quantity: line.quantity,
+        unit: price.unit,
+        price_unit: price.price_unit,
+        units_incl: price.units_incl,
+        list_price: price.list_price,
+        discount_percent,
+    })
+}
+
+// Release character in front of EDIFACT service characters
+fn escape(val: &str) -> String {
+    let mut s = String::new();
+
+    for c in val.chars() {
+        if ['?', '+', ':', '\''].contains(&c) {
+            s.push('?');
+        }
+
+        s.push(c);
+    }
+
+    s
+}
+
+fn orders_message(order: &OrderRequest, lines: &[PricedLine]) -> String {
+    let now = Local::now();
+    let reference = escape(&order.number);
+
+    // Counted from UNH to UNT, both included
+    let mut segments = vec![
+        String::from("UNH+1+ORDERS:D:96A:UN"),
+        format!("BGM+220+{}+9", reference),
+        format!("DTM+137:{}:102", now.format("%Y%m%d")),
+    ];
+
+    if let Some(d) = &order.delivery_date {
+        segments.push(format!("DTM+2:{}:102", escape(d)));
+    }
+
+    // Buyer's customer number is assigned by the seller (91), seller id is
+    // the OVT code, mutually defined (ZZZ)
+    segments.push(format!("NAD+BY+{}::91", escape(&order.buyer)));
+    segments.push(format!("NAD+SU+{}::ZZZ", escape(&order.seller)));
+
+    for (i, l) in lines.iter().enumerate() {
+        segments.push(format!("LIN+{}++{}:SA", i + 1, escape(&l.product)));
+        segments.push(format!("QTY+21:{}:{}", l.quantity, escape(&l.unit)));
+        segments.push(format!("PRI+AAA:{:.2}:::{}:{}", l.net_price(), l.units_incl, escape(&l.price_unit)));
+        segments.push(format!("PRI+AAB:{:.2}:::{}:{}", l.list_price, l.units_incl, escape(&l.price_unit)));
+
+        if l.discount_percent > 0.0 {
+            segments.push(String::from("ALC+A"));
+            segments.push(format!("PCD+1:{:.2}", l.discount_percent));
+        }
+    }
+
+    segments.push(String::from("UNS+S"));
+    segments.push(format!("CNT+2:{}", lines.len()));
+    segments.push(format!("UNT+{}+1", segments.len() + 1));
+
+    let mut message = format!("UNA:+.? 'UNB+UNOC:3+{}+{}+{}+{}'",
+        escape(&order.buyer), escape(&order.seller), now.format("%y%m%d:%H%M"), reference);
+
+    for s in segments {
+        message.push_str(&s);
+        message.push('\'');
+    }
+
+    message.push_str(&format!("UNZ+1+{}'", reference));
+    message
+}
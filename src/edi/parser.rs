@@ -0,0 +1,264 @@
+//! Line parsers of product, price and discount files. Each takes a single
+//! fixed width line (232, 100 and 92 chars) without the line break, semicolon
+//! separated lines are padded to that first. Malformed input, untrusted
+//! uploads included, is returned as an error, never a panic.
+//!
+//! `ProductFileReader` and `PriceFileReader` read whole files from any
+//! `BufRead`, without the database and file writes of the importers.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Lines};
+use std::iter::Enumerate;
+use std::path::PathBuf;
+use anyhow::{anyhow, bail, Result};
+
+use crate::config::{Config, Seller};
+use crate::utils::Lang;
+
+use super::{delimited_line, file_seller, is_header_line, EdiFileKind, EdiLine, Interner};
+pub use super::products::Product;
+pub use super::prices::Price;
+pub use super::discounts::Discount;
+// Field widths of the spec
+pub use super::products::EXPL_SEQ_PRODUCT;
+pub use super::prices::EXPL_SEQ_PRICE;
+pub use super::discounts::EXPL_SEQ_DISC;
+pub(crate) use super::products::{product_widths, PRODUCT_NUMBERS};
+pub(crate) use super::prices::{price_widths, PRICE_NUMBERS};
+pub(crate) use super::discounts::DISC_NUMBERS;
+
+
+// Next entry line and its number, header and empty lines are left out
+fn next_entry<R: BufRead>(lines: &mut Enumerate<Lines<R>>, widths: &[usize], numbers: &[usize])
+-> Option<Result<(usize, String)>> {
+    let reqlen = widths.iter().sum();
+
+    for (i, l) in lines.by_ref() {
+        let l = l.map(|s| delimited_line(s, widths, numbers));
+
+        match EdiLine::line_read(l, i, reqlen) {
+            Ok((Some(EdiLine::Entry(s)), _)) => return Some(Ok((i + 1, s))),
+            Ok((Some(_), _)) => continue,
+            Ok((None, w)) if w.is_empty() => continue,
+            Ok((None, w)) => return Some(Err(anyhow!(w.join(", ")))),
+            Err(e) => return Some(Err(e)),
+        }
+    }
+
+    None
+}
+
+/// Products of a product file from any reader, without database or file
+/// writes. Lines that fail to parse are errors, parser warnings are dropped.
+pub struct ProductFileReader<R: BufRead> {
+    lines: Enumerate<Lines<R>>,
+    widths: Vec<usize>,
+    lang: Option<Lang>,
+    custom_langs: Vec<Lang>,
+    interner: Interner,
+}
+
+impl<R: BufRead> ProductFileReader<R> {
+    /// Reader of products in all languages, fields as wide as the spec says.
+    pub fn new(reader: R) -> Self {
+        Self {
+            lines: reader.lines().enumerate(),
+            widths: EXPL_SEQ_PRODUCT.to_vec(),
+            lang: None,
+            custom_langs: vec![],
+            interner: Interner::default(),
+        }
+    }
+    /// Field widths of the seller's `field_widths`.
+    pub fn seller(mut self, seller: Option<&Seller>) -> Result<Self> {
+        self.widths = product_widths(seller)?;
+        Ok(self)
+    }
+    /// Products of other languages are errors.
+    pub fn lang(mut self, lang: &Lang, custom_langs: &[Lang]) -> Self {
+        self.lang = Some(lang.to_owned());
+        self.custom_langs = custom_langs.to_vec();
+        self
+    }
+}
+
+impl<R: BufRead> Iterator for ProductFileReader<R> {
+    type Item = Result<Product>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (n, s) = match next_entry(&mut self.lines, &self.widths, &PRODUCT_NUMBERS)? {
+            Ok(t) => t,
+            Err(e) => return Some(Err(e)),
+        };
+
+        Some(Product::from_line(s, &self.widths, self.lang.as_ref(), &self.custom_langs, &mut self.interner)
+            .map(|(p, _)| p)
+            .map_err(|e| anyhow!("Line {}: {}", n, e)))
+    }
+}
+
+/// Prices of a price file from any reader, see `ProductFileReader`.
+pub struct PriceFileReader<R: BufRead> {
+    lines: Enumerate<Lines<R>>,
+    widths: Vec<usize>,
+    interner: Interner,
+}
+
+impl<R: BufRead> PriceFileReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { lines: reader.lines().enumerate(), widths: EXPL_SEQ_PRICE.to_vec(), interner: Interner::default() }
+    }
+    /// Field widths of the seller's `field_widths`.
+    pub fn seller(mut self, seller: Option<&Seller>) -> Result<Self> {
+        self.widths = price_widths(seller)?;
+        Ok(self)
+    }
+}
+
+impl<R: BufRead> Iterator for PriceFileReader<R> {
+    type Item = Result<Price>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (n, s) = match next_entry(&mut self.lines, &self.widths, &PRICE_NUMBERS)? {
+            Ok(t) => t,
+            Err(e) => return Some(Err(e)),
+        };
+
+        Some(Price::from_line(s, &self.widths, &mut self.interner)
+            .map(|(p, _)| p)
+            .map_err(|e| anyhow!("Line {}: {}", n, e)))
+    }
+}
+
+// Counts of parsed and rejected lines
+fn tally(results: impl Iterator<Item = bool>) -> (usize, usize) {
+    results.fold((0, 0), |(parsed, rejected), ok| match ok {
+        true => (parsed + 1, rejected),
+        false => (parsed, rejected + 1),
+    })
+}
+
+/// Parse entry lines of a file without writing anything. Returns counts of
+/// parsed and rejected lines. Product lines of other languages are rejected.
+pub fn parse_lines(path: &PathBuf, kind: EdiFileKind, config: &Config, lang_filter: &Lang)
+-> Result<(usize, usize)> {
+    let seller_conf = file_seller(config, path);
+    let reader = BufReader::new(File::open(path)?);
+
+    Ok(match kind {
+        EdiFileKind::Product => tally(ProductFileReader::new(reader)
+            .seller(seller_conf)?
+            .lang(lang_filter, &config.custom_langs)
+            .map(|r| r.is_ok())),
+        EdiFileKind::Price => tally(PriceFileReader::new(reader).seller(seller_conf)?.map(|r| r.is_ok())),
+        EdiFileKind::Discount => {
+            let mut lines = reader.lines().enumerate();
+
+            tally(std::iter::from_fn(|| next_entry(&mut lines, &EXPL_SEQ_DISC, &DISC_NUMBERS))
+                .map(|r| r.and_then(|(_, s)| Discount::from_line(s)).is_ok()))
+        },
+        EdiFileKind::Unknown => bail!("Unknown kind of lines can't be parsed"),
+    })
+}
+
+// Entry lines read to detect the kind of a file
+const DETECT_SAMPLE: usize = 5;
+
+/// Kind of an EDI file with a note on how it was told.
+#[derive(Debug)]
+pub struct Detection {
+    pub kind: EdiFileKind,
+    pub note: String,
+}
+
+/// Detect kind of an EDI file from a sample of its first entry lines. Kind most
+/// of the lines parse as wins, products before prices and discounts on a tie.
+pub fn detect(path: &PathBuf, config: &Config) -> Result<Detection> {
+    let seller_conf = file_seller(config, path);
+    let product = product_widths(seller_conf)?;
+    let price = price_widths(seller_conf)?;
+
+    let reader = BufReader::new(File::open(path)?);
+    let sample = reader.lines()
+        .skip(2)
+        .filter(|l| !l.as_ref().is_ok_and(|s| s.trim().is_empty() || is_header_line(s)))
+        .take(DETECT_SAMPLE)
+        .collect::<std::io::Result<Vec<String>>>()?;
+
+    if sample.is_empty() {
+        return Ok(Detection { kind: EdiFileKind::Unknown, note: String::from("no entry lines") })
+    }
+
+    let mut interner = Interner::default();
+    let mut counts = [(EdiFileKind::Product, 0), (EdiFileKind::Price, 0), (EdiFileKind::Discount, 0)];
+
+    for l in sample.iter() {
+        let s = delimited_line(l.to_owned(), &product, &PRODUCT_NUMBERS);
+        if Product::from_line(s, &product, None, &config.custom_langs, &mut interner).is_ok() {
+            counts[0].1 += 1;
+        }
+
+        if Price::from_line(delimited_line(l.to_owned(), &price, &PRICE_NUMBERS), &price, &mut interner).is_ok() {
+            counts[1].1 += 1;
+        }
+
+        if Discount::from_line(delimited_line(l.to_owned(), &EXPL_SEQ_DISC, &DISC_NUMBERS)).is_ok() {
+            counts[2].1 += 1;
+        }
+    }
+
+    // First of the highest counts
+    let (kind, n) = counts.iter().fold(counts[0], |best, c| match c.1 > best.1 { true => *c, false => best });
+
+    Ok(match n {
+        0 => Detection {
+            kind: EdiFileKind::Unknown,
+            note: format!("none of {} sample lines parse as product, price or discount", sample.len()),
+        },
+        n => Detection { kind, note: format!("{} of {} sample lines parse", n, sample.len()) },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    // Lines of any chars, and of chars the fields are made of so that parsing
+    // gets past the first field more often
+    fn line(len: usize) -> impl Strategy<Value = String> {
+        prop_oneof![
+            proptest::collection::vec(any::<char>(), len),
+            proptest::collection::vec(proptest::sample::select(
+                "RrOo0123456789 .,-+;ABCDEFGHIJKLMNOPQRSTUVWXYZäöÅ".chars().collect::<Vec<char>>()), len),
+        ].prop_map(String::from_iter)
+    }
+
+    proptest! {
+        #[test]
+        fn product_line_never_panics(s in line(232)) {
+            let _ = Product::from_line(s.to_owned(), &EXPL_SEQ_PRODUCT, None, &[], &mut Interner::default());
+            let _ = Product::from_line(s, &EXPL_SEQ_PRODUCT, Some(&Lang::Fin), &[], &mut Interner::default());
+        }
+
+        #[test]
+        fn price_line_never_panics(s in line(100)) {
+            let _ = Price::from_line(s, &EXPL_SEQ_PRICE, &mut Interner::default());
+        }
+
+        #[test]
+        fn discount_line_never_panics(s in line(92)) {
+            let _ = Discount::from_line(s);
+        }
+
+        #[test]
+        fn delimited_lines_never_panic(s in line(60)) {
+            let _ = Product::from_line(delimited_line(s.to_owned(), &EXPL_SEQ_PRODUCT, &PRODUCT_NUMBERS),
+                &EXPL_SEQ_PRODUCT, None, &[], &mut Interner::default());
+            let _ = Price::from_line(delimited_line(s.to_owned(), &EXPL_SEQ_PRICE, &PRICE_NUMBERS),
+                &EXPL_SEQ_PRICE, &mut Interner::default());
+            let _ = Discount::from_line(delimited_line(s, &EXPL_SEQ_DISC, &DISC_NUMBERS));
+        }
+    }
+}
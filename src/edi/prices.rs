@@ -1,35 +1,56 @@
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
 use serde::{Deserialize, Serialize};
 use anyhow::{anyhow, bail, Result};
-use std::fs::{File, write, create_dir_all, read_to_string};
-use std::io::{prelude::*, BufReader};
-use log::{debug, error};
+use std::fs::{File, create_dir_all, read_to_string};
+use log::{debug, error, info};
 use rusqlite::{Connection, params};
 
-use crate::config::Config;
+use crate::error::Error;
+use crate::timings::{self, Stage};
+use crate::config::{Config, PriceGroup, Seller};
 use crate::utils::Category;
+use super::envelope::{self, Envelope};
 use super::header::EdiParty;
-use super::{edi_line_iter, import_warning_logger, str_as_f64, EdiDate, EdiLine};
+use super::{delimited_line, field_widths, import_provenance, import_warning_logger, with_json_writer, split_decimals, str_as_f64, EdiDate, EdiFields, EdiLine, EdiLines, Interner, StagedJson};
 
-const SEQ_PRICE_REQLEN: usize = 100;
-const EXPL_SEQ_PRICE: [usize; 19] = [
+pub(crate) const SEQ_PRICE_REQLEN: usize = 100;
+pub const EXPL_SEQ_PRICE: [usize; 19] = [
     1, 1, 9, 2, 9, 8, 6, 3, 4, 9, 5, 9, 5, 9, 5, 3, 9, 1, 2
 ];
+// Names of the fields above for sellers' width changes
+const PRICE_FIELDS: [&str; 19] = [
+    "row", "category", "identifier", "price_group", "price", "date", "discount_group", "unit", "units_incl",
+    "packaging_1", "packaging_1_discount", "packaging_2", "packaging_2_discount", "packaging_3",
+    "packaging_3_discount", "usage_unit", "usables_in_unit", "stock_item", "delivery_in_weeks"
+];
+
+// Numeric fields, padded with zeros when read from semicolon separated lines
+pub(crate) const PRICE_NUMBERS: [usize; 11] = [4, 5, 8, 9, 10, 11, 12, 13, 14, 16, 18];
+
+/// Price line field widths of a seller, the spec ones without changes.
+pub(crate) fn price_widths(seller: Option<&Seller>) -> Result<Vec<usize>> {
+    match seller {
+        Some(s) => field_widths(&EXPL_SEQ_PRICE, &PRICE_FIELDS, &s.field_widths.price),
+        None => Ok(EXPL_SEQ_PRICE.to_vec()),
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct Price {
+pub struct Price {
     #[serde(skip)]
-    category: Category, 
+    pub category: Category,
     #[serde(skip)]
-    identifier: String, // Tuotenumero 9 A
+    pub identifier: String, // Tuotenumero 9 A
     #[serde(rename = "group")]
-    price_group: String, // Hintalaji 2 A 01 = ohjehinta alv 0%
+    price_group: Arc<str>, // Hintalaji 2 A 01 = ohjehinta alv 0%
     price: f64,// Hinta 9(N2) ovh sentteinä
     date: EdiDate, // Voimaantulopvm 8 vvvvkkpp
     #[serde(rename = "disc")]
-    discount_group: String, // Alennusryhmä 6 A *
-    unit: String, // Yksikkö 3 A
+    discount_group: Arc<str>, // Alennusryhmä 6 A *
+    unit: Arc<str>, // Yksikkö 3 A
     #[serde(rename = "incl")]
     units_incl: i64, // Hinnoitteluyksikkö 4 N Esim. 1, 10, 100, 100 = kuinka monta perusyksikköä hinta sisältää, meillä aina 1
     #[serde(rename = "p1", skip_serializing_if = "Option::is_none")]
@@ -45,9 +66,11 @@ struct Price {
     #[serde(rename = "p3d", skip_serializing_if = "Option::is_none")]
     packaging_3_discount: Option<f64>,
     #[serde(rename = "i", skip_serializing_if = "Option::is_none")]
-    usage_unit: Option<String>, // Käyttöyksikkö 3 A *
+    usage_unit: Option<Arc<str>>, // Käyttöyksikkö 3 A *
     #[serde(rename = "ix")]
     usables_in_unit: f64, // Käyttöyksikkökerroin 9(N4) Oletusarvo 10000 (=1)
+    #[serde(rename = "ppu", default, skip_serializing_if = "Option::is_none")]
+    price_per_usage_unit: Option<f64>, // Computed, cents per käyttöyksikkö
     #[serde(rename = "stock")]
     stock_item: Option<bool>,
     #[serde(rename = "delay", skip_serializing_if = "Option::is_none")]
@@ -59,11 +82,11 @@ impl Price {
         Self {
             category: Category::Unset,
             identifier: String::new(),
-            price_group: String::new(),
+            price_group: Arc::from(""),
             price: 0.0f64,
             date: EdiDate::new(),
-            discount_group: String::new(),
-            unit: String::new(),
+            discount_group: Arc::from(""),
+            unit: Arc::from(""),
             units_incl: 0i64,
             packaging_1: None,
             packaging_1_discount: None,
@@ -73,19 +96,22 @@ impl Price {
             packaging_3_discount: None,
             usage_unit: None,
             usables_in_unit: 0.0f64,
+            price_per_usage_unit: None,
             stock_item: None,
             delivery_in_weeks: None,
         }
     }
-    fn from_line(line: String) -> Result<(Self, Vec<String>)> {
+    pub(crate) fn has_discount_group(&self) -> bool {
+        !self.discount_group.is_empty()
+    }
+    pub(crate) fn from_line(line: String, widths: &[usize], interner: &mut Interner) -> Result<(Self, Vec<String>)> {
         let mut price = Self::new();
-        let chars = line.chars();
-        let mut pointer = 0;
+        let mut fields = EdiFields::new(&line);
         let mut warnings = vec![];
 
-        for (j, v) in EXPL_SEQ_PRICE.iter().enumerate() {
+        for (j, v) in widths.iter().enumerate() {
             if j == 0 {
-                let (val, p) = edi_line_iter(pointer, &chars, v)?;
+                let val = fields.next_field(*v)?;
 
                 if val.chars().count() != 1 || v.ne(&1) {
                     bail!("Trying to extract row id from pointer with invalid length.")
@@ -94,38 +120,36 @@ impl Price {
                 if val.ne("R") {
                     bail!("Row identifier is fixed 'R', found '{}'", val)
                 }
-
-                pointer = p;
                 continue;
             }
 
             // Special cases.
             let handled = match j {
                 1 => {
-                    let (val, p) = edi_line_iter(pointer, &chars, v)?;
-                    price.category = Category::from_edi_str(val.as_str())?;
-                    Some(p)
+                    let val = fields.next_field(*v)?;
+                    price.category = Category::from_edi_str(val)?;
+                    Some(())
                 },
                 4 => {
-                    let (val, p) = edi_line_iter(pointer, &chars, v)?;
+                    let val = fields.next_field(*v)?;
                     let (int, des) = match val.len() > 7 {
-                        true => val.split_at(7),
+                        true => split_decimals(val, 7)?,
                         false => bail!("Unable to split decimals from '{}' string", val),
                     };
                     
                     // Price in eur _cents_
-                    price.price = str_as_f64(int, des, &val)?;
+                    price.price = str_as_f64(int, des, val)?;
                     // let eurs = price.price / 100.0;
 
-                    Some(p)
+                    Some(())
                 },
                 5 => {
-                    let (val, p) = edi_line_iter(pointer, &chars, v)?;
+                    let val = fields.next_field(*v)?;
                     price.date = EdiDate::from_string(val)?;
-                    Some(p)
+                    Some(())
                 },
                 8 => {
-                    let (val, p) = edi_line_iter(pointer, &chars, v)?;
+                    let val = fields.next_field(*v)?;
                     let int: i64 = match val.parse() {
                         Ok(f) => f,
                         Err(e) => bail!("Failed to read '{}' as \
@@ -136,32 +160,32 @@ impl Price {
                         price.units_incl = int;
                     }
 
-                    Some(p)
+                    Some(())
                 },
                 16 => {
-                    let (val, p) = edi_line_iter(pointer, &chars, v)?;
+                    let val = fields.next_field(*v)?;
                     let (int, des) = match val.len() > 5 {
-                        true => val.split_at(5),
+                        true => split_decimals(val, 5)?,
                         false => bail!("Unable to split decimals from '{}' string", val),
                     };
                     
-                    let d = str_as_f64(int, des, &val)?;
+                    let d = str_as_f64(int, des, val)?;
                     
                     price.usables_in_unit = d;
-                    Some(p)
+                    Some(())
                 },
                 17 => {
-                    let (val, p) = edi_line_iter(pointer, &chars, v)?;
+                    let val = fields.next_field(*v)?;
                     if val.eq("E") {
                         price.stock_item = Some(false);
                     }
-                    Some(p)
+                    Some(())
                 },
                 18 => {
                     // Last chunk and optional, some source files seem to ignore
                     // this completely. They should not, but hey nothing is perfect.
-                    let (val, p) = match edi_line_iter(pointer, &chars, v) {
-                        Ok(t) => t,
+                    let val = match fields.next_field(*v) {
+                        Ok(v) => v,
                         Err(_) => {
                             warnings.push("Optional last value in price catalog \
                                 ignored. Should be '00' for empty.".to_string());
@@ -182,19 +206,18 @@ impl Price {
                         }
                     }
 
-                    Some(p)
+                    Some(())
                 },
                 _ => None,
             };
 
-            if let Some(p) = handled {
-                pointer = p;
+            if handled.is_some() {
                 continue;
             }
 
             // Strings, required ones.
             if [2, 3, 6, 7].contains(&j) {
-                let (val, p) = edi_line_iter(pointer, &chars, v)?;
+                let val = fields.next_field(*v)?;
                 
                 if val.is_empty() {
                     match j {
@@ -207,28 +230,26 @@ impl Price {
                 }
 
                 match j {
-                    2 => { price.identifier = val },
-                    3 => { price.price_group = val },
-                    6 => { price.discount_group = val },
-                    7 => { price.unit = val },
+                    2 => { price.identifier = val.to_string() },
+                    3 => { price.price_group = interner.intern(val) },
+                    6 => { price.discount_group = interner.intern(val) },
+                    7 => { price.unit = interner.intern(val) },
                     _ => (),
                 }
-                pointer = p;
 
                 continue;
             }
 
             // Optional strings
             if [15].contains(&j) {
-                let (val, p) = edi_line_iter(pointer, &chars, v)?;
-                pointer = p;
+                let val = fields.next_field(*v)?;
                 
                 if val.is_empty() {
                     continue;
                 }
 
                 match j {
-                    15 => { price.usage_unit = Some(val) },
+                    15 => { price.usage_unit = Some(interner.intern(val)) },
                     _ => (),
                 }
 
@@ -237,8 +258,7 @@ impl Price {
 
             // Optional floating point numbers.
             if [9, 10, 11, 12, 13, 14].contains(&j) {
-                let (val, p) = edi_line_iter(pointer, &chars, v)?;
-                pointer = p;
+                let val = fields.next_field(*v)?;
                 
                 if val.is_empty() {
                     continue;
@@ -246,33 +266,33 @@ impl Price {
 
                 let (int, des) = match j {
                     9 => match val.len() > 7 {
-                        true => val.split_at(7),
+                        true => split_decimals(val, 7)?,
                         false => continue,
                     },
                     10 => match val.len() > 3 {
-                        true => val.split_at(3),
+                        true => split_decimals(val, 3)?,
                         false => continue,
                     },
                     11 => match val.len() > 7 {
-                        true => val.split_at(7),
+                        true => split_decimals(val, 7)?,
                         false => continue,
                     },
                     12 => match val.len() > 3 {
-                        true => val.split_at(3),
+                        true => split_decimals(val, 3)?,
                         false => continue,
                     },
                     13 => match val.len() > 7 {
-                        true => val.split_at(7),
+                        true => split_decimals(val, 7)?,
                         false => continue,
                     },
                     14 => match val.len() > 3 {
-                        true => val.split_at(3),
+                        true => split_decimals(val, 3)?,
                         false => continue,
                     },
                     _ => bail!("Stupid developer issue on optional number fields"),
                 };
 
-                let d = str_as_f64(int, des, &val)?;
+                let d = str_as_f64(int, des, val)?;
 
                 // Ignore zero results as these are optional fields.
                 let zero = 0.0f64;
@@ -299,55 +319,62 @@ impl Price {
 
         Ok((price, warnings))
     }
-}
-
-pub fn is_price_file(path: &PathBuf) -> Result<bool> {
-    // To prevent stupid developer errors
-    let mut total = 0;
-    
-    for v in EXPL_SEQ_PRICE.iter() {
-        total += v
-    }
+    fn count_price_per_usage_unit(&mut self) {
+        // Price covers 'units_incl' units, each having 'usables_in_unit' usage units
+        let usables = self.units_incl as f64 * self.usables_in_unit;
 
-    if total != SEQ_PRICE_REQLEN {
-        bail!("Price file decoder has developer level issues.")
+        self.price_per_usage_unit = match usables > 0.0 {
+            true => Some(self.price / usables),
+            false => None,
+        };
     }
-    
-    let uft8_file = File::open(path)?;
-    let reader = BufReader::new(uft8_file);
-
-    // Skip headers with iterator.
-    for (i, l) in reader.lines().skip(2).enumerate() {
-        let s = match l {
-            Ok(s) => s,
-            Err(_) => bail!("unable to read line number {} from {:?}", i, path),
+    // Euro price, price per usage unit and price including VAT, rounded the
+    // same way as when refreshed in the database
+    fn eur_prices(&self, vat_percent: f64, includes_vat: bool) -> (f64, Option<f64>, f64) {
+        let incl_vat = match includes_vat {
+            true => self.price,
+            false => self.price * (100.0 + vat_percent) / 100.0,
         };
 
-        return match Price::from_line(s) {
-            Ok(_) => Ok(true),
-            Err(_) => Ok(false),
-        }
+        ((self.price * 100.0).round() / 100.0,
+            self.price_per_usage_unit.map(|p| (p * 10000.0).round() / 10000.0),
+            (incl_vat * 100.0).round() / 100.0)
     }
-
-    bail!("What the hell 2")
 }
 
 pub fn prices_writer(config: &Config, path: &PathBuf, db_conn: &mut Connection, log: &mut File)
--> Result<PathBuf> {
+-> Result<(PathBuf, StagedJson)> {
     // Open utf8 encoded file and read it line by line.
-    let uft8_file = File::open(path)?;
-    let reader = BufReader::new(uft8_file);
+    let reader = EdiLines::open(config, path)?;
     let file_suffix = "json";
 
     let mut supplier_dir = PathBuf::new();
     let mut id = String::new();
     let mut prices = HashMap::new();
 
+    let mut seller_conf: Option<&Seller> = None;
+    let mut skipped = 0;
+    // Lines are read as the spec says until the seller is known
+    let mut widths = EXPL_SEQ_PRICE.to_vec();
+    let mut reqlen = SEQ_PRICE_REQLEN;
+
     let mut warnings = vec![];
-    let ctx = db_conn.transaction()?;
+    let mut interner = Interner::default();
+    let ctx = db_conn.savepoint()?;
+    let started = Instant::now();
+
+    for (i, l) in reader.enumerate() {
+        // Known bad lines of the seller, not worth a warning
+        if let (Ok(raw), Some(sc)) = (&l, seller_conf) {
+            if sc.skips_line(raw) {
+                skipped += 1;
+                continue
+            }
+        }
 
-    for (i, l) in reader.lines().enumerate() {
-        let line = match EdiLine::line_read(l, i, SEQ_PRICE_REQLEN)? {
+        let l = l.map(|s| delimited_line(s, &widths, &PRICE_NUMBERS));
+        let line = match EdiLine::line_read(l, i, reqlen)
+            .map_err(|e| Error::parse(path, Some(i + 1), e))? {
             (Some(l), w) => {
                 warnings.push(w);
                 l
@@ -359,14 +386,15 @@ pub fn prices_writer(config: &Config, path: &PathBuf, db_conn: &mut Connection,
         };
 
         match line {
-            EdiLine::Buyer(_) => continue,
+            EdiLine::Buyer(_) | EdiLine::Record => continue,
             EdiLine::Seller(s) => {
                 match EdiParty::create(config, s) {
                     Ok((d, i)) => {
                         supplier_dir = d;
                         id = i;
                     },
-                    Err(e) => bail!("Failed to create seller dir: {}", e),
+                    Err(e) => return Err(Error::parse(path, Some(i + 1),
+                        format!("Failed to read seller from header: {}", e)).into()),
                 }
 
                 let sc = match config.seller.iter().find(|s| s.id.eq(&id)) {
@@ -378,9 +406,14 @@ pub fn prices_writer(config: &Config, path: &PathBuf, db_conn: &mut Connection,
                     }
                 };
 
+                seller_conf = Some(sc);
+                widths = price_widths(seller_conf)?;
+                reqlen = widths.iter().sum();
+
                 if config.import.sqlite {
                     ctx.execute(
-                        "insert or ignore into sellers (id, name) values (?1, ?2)",
+                        "insert into sellers (id, name) values (?1, ?2) \
+                            on conflict (id) do update set name = excluded.name",
                         [&id, &sc.name]
                     )?;
                 }
@@ -398,16 +431,18 @@ pub fn prices_writer(config: &Config, path: &PathBuf, db_conn: &mut Connection,
 
                         if extf.is_file() {
                             let s = read_to_string(extf)?;
-                            let pri = serde_json::from_str::<HashMap<String, Price>>(&s)?;
+                            let pri = envelope::from_str::<HashMap<String, Price>>(&s)?;
 
                             prices.insert(v, pri);
                         }
                     }
                 }
             },
-            EdiLine::Entry(s) => match Price::from_line(s) {
-                Ok((p, w)) => {
-                    warnings.push(w);
+            EdiLine::Entry(s) => match Price::from_line(s, &widths, &mut interner) {
+                Ok((mut p, w)) => {
+                    warnings.push(w.into_iter().map(|w| format!("Line: {}, {}", i + 1, w)).collect());
+
+                    p.count_price_per_usage_unit();
     
                     match prices.get_mut(&p.category) {
                         Some(m) => {
@@ -428,23 +463,49 @@ pub fn prices_writer(config: &Config, path: &PathBuf, db_conn: &mut Connection,
         }
     }
 
-    // Print unique warnings from decoder.
-    let mut warnings = warnings.concat();
-    warnings.sort();
-    warnings.dedup();
+    let warnings = warnings.concat();
+
+    timings::record(Stage::Parse, started.elapsed());
+
+    let started = Instant::now();
+
+    if skipped > 0 {
+        info!("Skipped {} known bad lines of {:?}", skipped, path);
+    }
 
     if let Err(e) = import_warning_logger(log, path, warnings) {
         error!("Failed to write {:?} warnings to log: {}", path, e);
     }
 
+    let (source_file, imported_at) = import_provenance(path);
+
     // Needed if json files are written.
     let mut prices_dir = supplier_dir.to_owned();
     prices_dir.push("prices");
+
+    let mut staged = StagedJson::default();
     
     if config.import.json {
         create_dir_all(&prices_dir).map_err(|e|anyhow!(
             "Failed to create supplier prices dir {:?}: {}", prices_dir, e
         ))?;
+
+        // Labels for the price group codes used in category files
+        let mut groups = prices.values()
+            .flat_map(|m| m.values().map(|p| p.price_group.to_owned()))
+            .collect::<Vec<Arc<str>>>();
+
+        groups.sort();
+        groups.dedup();
+
+        let groups = groups.iter()
+            .map(|g| (g.to_owned(), config.price_group(g)))
+            .collect::<HashMap<Arc<str>, PriceGroup>>();
+
+        let mut file = prices_dir.to_owned();
+        file.push(format!("groups.{}", &file_suffix));
+
+        staged.add(file, &Envelope::new(config, &id, &imported_at, groups))?;
     }
 
     if config.import.sqlite {
@@ -452,7 +513,7 @@ pub fn prices_writer(config: &Config, path: &PathBuf, db_conn: &mut Connection,
         let mut units = prices.iter()
             .flat_map(|(_, m)|
                 m.iter().map(|(_, p)| p.unit.to_owned())
-            ).collect::<Vec<String>>();
+            ).collect::<Vec<Arc<str>>>();
         
         units.sort();
         units.dedup();
@@ -469,7 +530,7 @@ pub fn prices_writer(config: &Config, path: &PathBuf, db_conn: &mut Connection,
             .flat_map(|(_, m)|
                 m.iter()
                 .map(|(_, p)| p.discount_group.to_owned())
-            ).collect::<Vec<String>>();
+            ).collect::<Vec<Arc<str>>>();
 
         discgr.sort();
         discgr.dedup();
@@ -486,15 +547,20 @@ pub fn prices_writer(config: &Config, path: &PathBuf, db_conn: &mut Connection,
             .flat_map(|(_, m)|
                 m.iter()
                 .map(|(_, p)| p.price_group.to_owned())
-            ).collect::<Vec<String>>();
+            ).collect::<Vec<Arc<str>>>();
 
         pricegr.sort();
         pricegr.dedup();
 
         for u in pricegr.iter() {
+            let g = config.price_group(u);
+
             ctx.execute(
-                "insert or ignore into price_groups (id) values (?1)",
-                [u]
+                "insert into price_groups (id, label, is_list_price, includes_vat) \
+                values (?1, ?2, ?3, ?4) on conflict (id) do update set \
+                label=excluded.label, is_list_price=excluded.is_list_price, \
+                includes_vat=excluded.includes_vat",
+                params!(&g.id, &g.label, g.is_list_price, g.includes_vat)
             )?;
         }
     }
@@ -507,56 +573,80 @@ pub fn prices_writer(config: &Config, path: &PathBuf, db_conn: &mut Connection,
     //     false => ctx.rollback()?,
     // };
 
-    for (k, v) in prices {
-        let tx = db_conn.transaction()?;
+    // JSON of the previous category is serialized while the next one goes to
+    // the database
+    let written = with_json_writer(|json| {
+        for (k, v) in prices {
+            let tx = db_conn.savepoint()?;
 
-        for p in v.values() {
             if config.import.sqlite {
-                let pid = p.identifier.to_owned();
-                let prid = format!("{}{}", &id, &pid);
-
-                tx.execute(
-                    &format!("insert into prices_{} (id, product_id, price_group, price, \
-                    date, discount_group, unit, units_incl, packaging_1, \
-                    packaging_1_discount, packaging_2, packaging_2_discount, packaging_3, \
-                    packaging_3_discount, usage_unit, usables_in_unit, stock_item, \
-                    delivery_in_weeks) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, \
-                    ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18) on conflict (id) do update \
-                    set price_group=excluded.price_group, price=excluded.price, \
-                    date=excluded.date, discount_group=excluded.discount_group, \
-                    unit=excluded.unit, units_incl=excluded.units_incl, \
-                    packaging_1=excluded.packaging_1, packaging_1_discount=excluded.packaging_1_discount, \
-                    packaging_2=excluded.packaging_2, packaging_2_discount=excluded.packaging_2_discount, \
-                    packaging_3=excluded.packaging_3, packaging_3_discount=excluded.packaging_3_discount, \
-                    usage_unit=excluded.usage_unit, usables_in_unit=excluded.usables_in_unit, \
-                    stock_item=excluded.stock_item, \
-                    delivery_in_weeks=excluded.delivery_in_weeks", k),
-                    params!(
-                        &prid, &pid, &p.price_group, &p.price, &format!(
-                            "{}-{}-{} 00:00:00.000", &p.date.year,
-                            &p.date.month,
-                            &p.date.day),
-                        &p.discount_group, &p.unit, &p.units_incl,
-                        &p.packaging_1, &p.packaging_1_discount,
-                        &p.packaging_2, &p.packaging_2_discount, &p.packaging_3,
-                        &p.packaging_3_discount, &p.usage_unit, &p.usables_in_unit,
-                        p.stock_item.unwrap_or(true), &p.delivery_in_weeks
-                    )
-                ).map_err(|e|anyhow!("Price add failure: {}", e))?;
+                let mut stm = tx.prepare_cached(
+                    "insert into prices_entries (id, product_id, price_group, price, \
+                        date, discount_group, unit, units_incl, packaging_1, \
+                        packaging_1_discount, packaging_2, packaging_2_discount, packaging_3, \
+                        packaging_3_discount, usage_unit, usables_in_unit, stock_item, \
+                        delivery_in_weeks, price_per_usage_unit, source_file, imported_at, category, \
+                        price_eur, price_per_usage_unit_eur, vat_percent, price_incl_vat_eur) \
+                        values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, \
+                        ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26) \
+                        on conflict (id) do update \
+                        set category=excluded.category, price_group=excluded.price_group, price=excluded.price, \
+                        date=excluded.date, discount_group=excluded.discount_group, \
+                        unit=excluded.unit, units_incl=excluded.units_incl, \
+                        packaging_1=excluded.packaging_1, packaging_1_discount=excluded.packaging_1_discount, \
+                        packaging_2=excluded.packaging_2, packaging_2_discount=excluded.packaging_2_discount, \
+                        packaging_3=excluded.packaging_3, packaging_3_discount=excluded.packaging_3_discount, \
+                        usage_unit=excluded.usage_unit, usables_in_unit=excluded.usables_in_unit, \
+                        stock_item=excluded.stock_item, \
+                        delivery_in_weeks=excluded.delivery_in_weeks, \
+                        price_per_usage_unit=excluded.price_per_usage_unit, \
+                        source_file=excluded.source_file, imported_at=excluded.imported_at, \
+                        price_eur=excluded.price_eur, price_per_usage_unit_eur=excluded.price_per_usage_unit_eur, \
+                        vat_percent=excluded.vat_percent, price_incl_vat_eur=excluded.price_incl_vat_eur"
+                )?;
+
+                for p in v.values() {
+                    let pid = p.identifier.to_owned();
+                    let prid = format!("{}{}", &id, &pid);
+                    let (eur, ppu_eur, incl_vat) = p.eur_prices(config.vat_percent,
+                        config.price_group(&p.price_group).includes_vat);
+
+                    stm.execute(
+                        params!(
+                            &prid, &pid, &p.price_group, &p.price, &format!(
+                                "{}-{}-{} 00:00:00.000", &p.date.year,
+                                &p.date.month,
+                                &p.date.day),
+                            &p.discount_group, &p.unit, &p.units_incl,
+                            &p.packaging_1, &p.packaging_1_discount,
+                            &p.packaging_2, &p.packaging_2_discount, &p.packaging_3,
+                            &p.packaging_3_discount, &p.usage_unit, &p.usables_in_unit,
+                            p.stock_item.unwrap_or(true), &p.delivery_in_weeks,
+                            &p.price_per_usage_unit, &source_file, &imported_at, k.to_name(),
+                            eur, ppu_eur, config.vat_percent, incl_vat
+                        )
+                    ).map_err(|e|Error::Db(format!("Price add failure: {}", e)))?;
+                }
             }
-        }
 
-        tx.commit()?;
-        
-        if config.import.json {
-            let json = serde_json::to_string(&v)?;
-            let name = format!("{}.{}", k, &file_suffix);
-            let mut file = prices_dir.to_owned();
-            file.push(name);
-            
-            write(&file, json.as_bytes())?;
+            tx.commit()?;
+
+            if config.import.json {
+                let mut file = prices_dir.to_owned();
+                file.push(format!("{}.{}", k, &file_suffix));
+
+                json.send((file, Envelope::new(config, &id, &imported_at, v)))
+                    .map_err(|_| anyhow!("JSON writer stopped"))?;
+            }
         }
-    }
 
-    Ok(supplier_dir)
+        Ok(supplier_dir)
+    });
+
+    timings::record(Stage::DbWrite, started.elapsed());
+
+    let (supplier_dir, mut json_files) = written?;
+    json_files.append(staged);
+
+    Ok((supplier_dir, json_files))
 }
@@ -1,33 +1,55 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{collections::HashMap, path::PathBuf, sync::Arc, thread, time::Instant};
 use serde::{Serialize, Deserialize};
-use anyhow::{anyhow, bail, Result};
-use log::{debug, error};
-use std::fs::{File, write, create_dir_all, read_to_string};
-use std::io::{prelude::*, BufReader};
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, error, info};
+use std::fs::{File, create_dir_all};
 use rusqlite::{Connection, params};
 
-use crate::config::Config;
+use crate::error::Error;
+use crate::timings::{self, Stage};
+use crate::config::{Config, Seller};
 use crate::edi::header::EdiParty;
-use crate::edi::{import_warning_logger, str_as_f64};
+use crate::edi::envelope::Envelope;
+use crate::edi::shards::{read_map, shard_files, stage_index};
+use crate::edi::{import_provenance, import_warning_logger, with_json_writer, split_decimals, str_as_f64, StagedJson};
 use crate::utils::{Category, Lang, Operation};
-use super::{EdiDate, EdiLine, edi_line_iter};
+use super::{EdiDate, EdiFields, EdiLine, EdiLines, Interner, delimited_line, field_widths};
 
-const SEQ_PROD_REQLEN: usize = 232;
-const EXPL_SEQ_PRODUCT: [usize; 27] = [
+pub(crate) const SEQ_PROD_REQLEN: usize = 232;
+pub const EXPL_SEQ_PRODUCT: [usize; 27] = [
     1, 1, 9, 1, 3, 8, 35, 35, 20, 7, 6, 3, 7, 7, 9, 9, 5, 9, 5,
     9, 5, 3, 2, 1, 20, 3, 9
 ];
+// Names of the fields above for sellers' width changes
+const PRODUCT_FIELDS: [&str; 27] = [
+    "row", "category", "identifier", "operation", "lang", "date", "name", "description", "search_tags",
+    "search_code", "discount_group", "unit", "unit_weight", "unit_volume", "typical_packaging", "packaging_1",
+    "packaging_1_discount", "packaging_2", "packaging_2_discount", "packaging_3", "packaging_3_discount",
+    "tax_class", "delivery_in_weeks", "stock_item", "ean_code", "usage_unit", "usables_in_unit"
+];
+
+// Numeric fields, padded with zeros when read from semicolon separated lines
+pub(crate) const PRODUCT_NUMBERS: [usize; 12] = [5, 12, 13, 14, 15, 16, 17, 18, 19, 20, 22, 26];
+
+/// Product line field widths of a seller, the spec ones without changes.
+pub(crate) fn product_widths(seller: Option<&Seller>) -> Result<Vec<usize>> {
+    match seller {
+        Some(s) => field_widths(&EXPL_SEQ_PRODUCT, &PRODUCT_FIELDS, &s.field_widths.product),
+        None => Ok(EXPL_SEQ_PRODUCT.to_vec()),
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct Product {
+pub struct Product {
+    // Files written before the category was included read as unset
+    #[serde(rename = "cat", default)]
+    pub category: Category,
     #[serde(skip)]
-    category: Category, 
-    #[serde(skip)]
-    identifier: String, // Tuotenumero 9 A
+    pub identifier: String, // Tuotenumero 9 A
     #[serde(rename = "op")]
     operation: Operation,
     #[serde(skip)]
-    lang: Lang,
+    pub lang: Lang,
     date: EdiDate, // Voimaantulopvm 8 vvvvkkpp
     name: String, // Tuotteen nimi 35 A Tuotekuvaus 1
     #[serde(rename = "name2")]
@@ -37,8 +59,8 @@ struct Product {
     #[serde(rename = "ref", skip_serializing_if = "Option::is_none")]
     search_code: Option<String>, // Pikakoodi 7 A * ei käytössä
     #[serde(rename = "disc", skip_serializing_if = "Option::is_none")]
-    discount_group: Option<String>, // Alennusryhmä 6 A *
-    unit: String, // Yksikkö 3 A
+    discount_group: Option<Arc<str>>, // Alennusryhmä 6 A *
+    unit: Arc<str>, // Yksikkö 3 A
     #[serde(rename = "weight", skip_serializing_if = "Option::is_none")]
     unit_weight: Option<f64>, // Yksikön paino, kg 7(3) *
     #[serde(rename = "vol", skip_serializing_if = "Option::is_none")]
@@ -66,7 +88,7 @@ struct Product {
     #[serde(rename = "ean", skip_serializing_if = "Option::is_none")]
     ean_code: Option<String>, // EAN-koodi 20 A * ei käytössä
     #[serde(rename = "i", skip_serializing_if = "Option::is_none")]
-    usage_unit: Option<String>, // Käyttöyksikkö 3 A *
+    usage_unit: Option<Arc<str>>, // Käyttöyksikkö 3 A *
     #[serde(rename = "ix")]
     usables_in_unit: f64 // Käyttöyksikkökerroin 9(N4) Oletusarvo 10000 (=1)
 }
@@ -84,7 +106,7 @@ impl Product {
             search_tags: None,
             search_code: None,
             discount_group: None,
-            unit: String::new(),
+            unit: Arc::from(""),
             unit_weight: None,
             unit_volume: None,
             typical_packaging: None,
@@ -102,15 +124,22 @@ impl Product {
             usables_in_unit: 0.0f64
         }
     }
-    fn from_line(line: String, lang_filter: Option<&Lang>) -> Result<(Self, Vec<String>)> {
+    pub(crate) fn has_ean(&self) -> bool {
+        self.ean_code.as_ref().is_some_and(|e| !e.is_empty())
+    }
+    pub(crate) fn has_discount_group(&self) -> bool {
+        self.discount_group.as_ref().is_some_and(|d| !d.is_empty())
+    }
+    pub(crate) fn from_line(line: String, widths: &[usize], lang_filter: Option<&Lang>, custom_langs: &[Lang],
+        interner: &mut Interner)
+    -> Result<(Self, Vec<String>)> {
         let mut product = Self::new();
         let mut warnings = vec![];
-        let chars = line.chars();
-        let mut pointer = 0;
+        let mut fields = EdiFields::new(&line);
 
-        for (j, v) in EXPL_SEQ_PRODUCT.iter().enumerate() {
+        for (j, v) in widths.iter().enumerate() {
             if j == 0 {
-                let (val, p) = edi_line_iter(pointer, &chars, v)?;
+                let val = fields.next_field(*v)?;
 
                 if val.chars().count() != 1 || v.ne(&1) {
                     bail!("Trying to extract row id from pointer with invalid length.")
@@ -119,31 +148,29 @@ impl Product {
                 if val.ne("R") {
                     bail!("Row identifier is fixed 'R', found '{}'", val)
                 }
-
-                pointer = p;
                 continue;
             }
 
             // Special cases.
             let handled = match j {
                 1 => {
-                    let (val, p) = edi_line_iter(pointer, &chars, v)?;
-                    product.category = Category::from_edi_str(val.as_str())?;
-                    Some(p)
+                    let val = fields.next_field(*v)?;
+                    product.category = Category::from_edi_str(val)?;
+                    Some(())
                 },
                 3 => {
-                    let (val, p) = edi_line_iter(pointer, &chars, v)?;
-                    let op = match Operation::from_str(val.as_str()) {
+                    let val = fields.next_field(*v)?;
+                    let op = match Operation::from_str(val) {
                         Ok(o) => o,
                         Err(e) => bail!("Product with ID: {} fails for bad \
                             operation: {}", product.identifier, e)
                     };
                     product.operation = op;
-                    Some(p)
+                    Some(())
                 },
                 4 => {
-                    let (val, p) = edi_line_iter(pointer, &chars, v)?;
-                    let l = match Lang::from_name(&val) {
+                    let val = fields.next_field(*v)?;
+                    let l = match Lang::from_name(val, custom_langs) {
                         Ok(l) => l,
                         Err(e) => bail!("Product has invalid language {val}: {}", e),
                     };
@@ -156,15 +183,15 @@ impl Product {
                     }
 
                     product.lang = l;
-                    Some(p)
+                    Some(())
                 },
                 5 => {
-                    let (val, p) = edi_line_iter(pointer, &chars, v)?;
+                    let val = fields.next_field(*v)?;
                     product.date = EdiDate::from_string(val)?;
-                    Some(p)
+                    Some(())
                 },
                 14 => {
-                    let (val, p) = edi_line_iter(pointer, &chars, v)?;
+                    let val = fields.next_field(*v)?;
                     if ! val.is_empty() {
                         let int: i64 = match val.parse() {
                             Ok(f) => f,
@@ -174,10 +201,10 @@ impl Product {
                         product.typical_packaging = Some(int);
                     }
 
-                    Some(p)
+                    Some(())
                 },
                 22 => {
-                    let (val, p) = edi_line_iter(pointer, &chars, v)?;
+                    let val = fields.next_field(*v)?;
                     
                     if ! val.is_empty() {
                         let int: i32 = match val.parse() {
@@ -191,39 +218,38 @@ impl Product {
                         }
                     }
 
-                    Some(p)
+                    Some(())
                 },
                 23 => {
-                    let (val, p) = edi_line_iter(pointer, &chars, v)?;
+                    let val = fields.next_field(*v)?;
                     
                     if val.eq("E") {
                         product.stock_item = Some(false);
                     }
-                    Some(p)
+                    Some(())
                 },
                 26 => {
-                    let (val, p) = edi_line_iter(pointer, &chars, v)?;
+                    let val = fields.next_field(*v)?;
                     let (int, des) = match val.len() > 5 {
-                        true => val.split_at(5),
+                        true => split_decimals(val, 5)?,
                         false => bail!("Unable to split decimals from '{}' string", val),
                     };
                     
-                    let d = str_as_f64(int, des, &val)?;
+                    let d = str_as_f64(int, des, val)?;
                     
                     product.usables_in_unit = d;
-                    Some(p)
+                    Some(())
                 },
                 _ => None,
             };
 
-            if let Some(p) = handled {
-                pointer = p;
+            if handled.is_some() {
                 continue;
             }
 
             // String types, required fields.
             if [2, 6, 7, 11].contains(&j) {
-                let (val, p) = edi_line_iter(pointer, &chars, v)?;
+                let val = fields.next_field(*v)?;
                 
                 if val.is_empty() {
                     match j {
@@ -238,33 +264,31 @@ impl Product {
                 }
 
                 match j {
-                    2 => { product.identifier = val },
-                    6 => { product.name = val },
-                    7 => { product.description = val },
-                    11 => { product.unit = val },
+                    2 => { product.identifier = val.to_string() },
+                    6 => { product.name = val.to_string() },
+                    7 => { product.description = val.to_string() },
+                    11 => { product.unit = interner.intern(val) },
                     _ => (),
                 }
-                pointer = p;
 
                 continue;
             }
 
             // String types, optional.
             if [8, 9, 10, 21, 24, 25].contains(&j) {
-                let (val, p) = edi_line_iter(pointer, &chars, v)?;
-                pointer = p;
+                let val = fields.next_field(*v)?;
                 
                 if val.is_empty() {
                     continue;
                 }
 
                 match j {
-                    8 => { product.search_tags = Some(val) },
-                    9 => { product.search_code = Some(val) },
-                    10 => { product.discount_group = Some(val) },
-                    21 => { product.tax_class = Some(val) },
-                    24 => { product.ean_code = Some(val) },
-                    25 => { product.usage_unit = Some(val) },
+                    8 => { product.search_tags = Some(val.to_string()) },
+                    9 => { product.search_code = Some(val.to_string()) },
+                    10 => { product.discount_group = Some(interner.intern(val)) },
+                    21 => { product.tax_class = Some(val.to_string()) },
+                    24 => { product.ean_code = Some(val.to_string()) },
+                    25 => { product.usage_unit = Some(interner.intern(val)) },
                     _ => (),
                 }
 
@@ -273,8 +297,7 @@ impl Product {
 
             // Optional floating point numbers.
             if [12, 13, 15, 16, 17, 18, 19, 20].contains(&j) {
-                let (val, p) = edi_line_iter(pointer, &chars, v)?;
-                pointer = p;
+                let val = fields.next_field(*v)?;
                 
                 if val.is_empty() {
                     continue;
@@ -282,41 +305,41 @@ impl Product {
 
                 let (int, des) = match j {
                     12 => match val.len() > 4 {
-                        true => val.split_at(4),
+                        true => split_decimals(val, 4)?,
                         false => continue,
                     },
                     13 => match val.len() > 4 {
-                        true => val.split_at(4),
+                        true => split_decimals(val, 4)?,
                         false => continue,
                     },
                     15 => match val.len() > 7 {
-                        true => val.split_at(7),
+                        true => split_decimals(val, 7)?,
                         false => continue,
                     },
                     16 => match val.len() > 3 {
-                        true => val.split_at(3),
+                        true => split_decimals(val, 3)?,
                         false => continue,
                     },
                     17 => match val.len() > 7 {
-                        true => val.split_at(7),
+                        true => split_decimals(val, 7)?,
                         false => continue,
                     },
                     18 => match val.len() > 3 {
-                        true => val.split_at(3),
+                        true => split_decimals(val, 3)?,
                         false => continue,
                     },
                     19 => match val.len() > 7 {
-                        true => val.split_at(7),
+                        true => split_decimals(val, 7)?,
                         false => continue,
                     },
                     20 => match val.len() > 3 {
-                        true => val.split_at(3),
+                        true => split_decimals(val, 3)?,
                         false => continue,
                     },
                     _ => bail!("Stupid developer issue on optional number fields"),
                 };
 
-                let d = str_as_f64(int, des, &val)?;
+                let d = str_as_f64(int, des, val)?;
 
                 // Ignore zero results as these are optional fields.
                 let zero = 0.0f64;
@@ -347,60 +370,101 @@ impl Product {
     }
 }
 
-pub fn is_product_file(path: &PathBuf) -> Result<bool> {
-    // To prevent stupid developer errors
-    let mut total = 0;
-    
-    for v in EXPL_SEQ_PRODUCT.iter() {
-        total += v
-    }
+// Products of a file in one language, parsed and waiting to be written
+struct LangProducts {
+    lang: Lang,
+    supplier_dir: PathBuf,
+    seller_id: String,
+    seller_name: Option<String>,
+    categorized: HashMap<Category, HashMap<String, Product>>,
+    warnings: Vec<String>,
+    skipped: usize,
+}
 
-    if total != SEQ_PROD_REQLEN {
-        bail!("Product file decoder has developer level issues.")
-    }
-    
-    let uft8_file = File::open(path)?;
-    let reader = BufReader::new(uft8_file);
-
-    // Skip headers with iterator.
-    for (i, l) in reader.lines().skip(2).enumerate() {
-        let s = match l {
-            Ok(s) => s,
-            Err(_) => bail!("unable to read line number {} from {:?}", i, path),
-        };
+/// Write products of a file in each of the languages. Languages are parsed on
+/// threads of their own when there are more than one, database and JSON writes
+/// are done one language at a time in the given order.
+pub fn products_writer(config: &Config, path: &PathBuf, langs: &[Lang], db_conn: &mut Connection, log: &mut File)
+-> Result<(PathBuf, StagedJson)> {
+    let started = Instant::now();
+    let parsed = match langs.len() > 1 {
+        true => thread::scope(|s| {
+            let handles = langs.iter()
+                .map(|l| s.spawn(move || products_parser(config, path, l)))
+                .collect::<Vec<_>>();
+
+            handles.into_iter()
+                .zip(langs.iter())
+                .map(|(h, l)| match h.join() {
+                    Ok(r) => r.with_context(|| format!("Failed to parse products from {:?} in lang {}", path, l)),
+                    Err(_) => Err(anyhow!("Parser thread of lang {} panicked", l)),
+                })
+                .collect::<Result<Vec<LangProducts>>>()
+        })?,
+        false => langs.iter()
+            .map(|l| products_parser(config, path, l)
+                .with_context(|| format!("Failed to parse products from {:?} in lang {}", path, l)))
+            .collect::<Result<Vec<LangProducts>>>()?,
+    };
 
-        return match Product::from_line(s, None) {
-            Ok(_) => Ok(true),
-            Err(_) => Ok(false),
-        }
+    timings::record(Stage::Parse, started.elapsed());
+
+    let started = Instant::now();
+    let mut supplier_dir = PathBuf::new();
+    let mut json = StagedJson::default();
+
+    for p in parsed {
+        let lang = p.lang.to_owned();
+        let (d, j) = products_db_writer(config, path, p, db_conn, log)
+            .with_context(|| format!("Failed to write products from {:?} in lang {}", path, lang))?;
+
+        supplier_dir = d;
+        json.append(j);
     }
 
-    bail!("What the hell")
+    timings::record(Stage::DbWrite, started.elapsed());
+
+    Ok((supplier_dir, json))
 }
 
-pub fn products_writer(config: &Config, path: &PathBuf, lang_filter: &Lang, db_conn: &mut Connection, log: &mut File)
--> Result<PathBuf> {
+// Read products of one language from the file. Existing JSON files of the
+// seller are read in as well, products of the file are updated over them.
+fn products_parser(config: &Config, path: &PathBuf, lang_filter: &Lang) -> Result<LangProducts> {
     // Open utf8 encoded file and read it line by line.
-    let uft8_file = match File::open(path) {
-        Ok(f) => f,
+    let reader = match EdiLines::open(config, path) {
+        Ok(r) => r,
         Err(e) => bail!("Failed to open products source file (utf-8) \
             for reading: {}", e)
     };
 
     debug!("Adding products with language code: {}", lang_filter);
 
-    let reader = BufReader::new(uft8_file);
-    let file_suffix = format!("{}.json", lang_filter.to_name());
-
     let mut supplier_dir = PathBuf::new();
     let mut seller_id = String::new();
+    let mut seller_name = None;
     let mut categorized_products = HashMap::new();
 
+    let mut seller_conf: Option<&Seller> = None;
+    let mut skipped = 0;
+    // Lines are read as the spec says until the seller is known
+    let mut widths = EXPL_SEQ_PRODUCT.to_vec();
+    let mut reqlen = SEQ_PROD_REQLEN;
+
     let mut warnings = vec![];
-    let ctx = db_conn.transaction()?;
+    let mut interner = Interner::default();
+
+    for (i, l) in reader.enumerate() {
+        // Known bad lines of the seller, not worth a warning
+        if let (Ok(raw), Some(sc)) = (&l, seller_conf) {
+            if sc.skips_line(raw) {
+                skipped += 1;
+                continue
+            }
+        }
 
-    for (i, l) in reader.lines().enumerate() {
-        let line = match EdiLine::line_read(l, i, SEQ_PROD_REQLEN)? {
+        let l = l.map(|s| delimited_line(s, &widths, &PRODUCT_NUMBERS));
+        let line = match EdiLine::line_read(l, i, reqlen)
+            .map_err(|e| Error::parse(path, Some(i + 1), e))? {
             (Some(l), w) => {
                 warnings.extend(w);
                 l
@@ -412,14 +476,15 @@ pub fn products_writer(config: &Config, path: &PathBuf, lang_filter: &Lang, db_c
         };
 
         match line {
-            EdiLine::Buyer(_) => continue,
+            EdiLine::Buyer(_) | EdiLine::Record => continue,
             EdiLine::Seller(s) => {
                 match EdiParty::create(config, s) {
                     Ok((d, i)) => {
                         supplier_dir = d;
                         seller_id = i;
                     },
-                    Err(e) => bail!("Failed to create seller dir: {}", e),
+                    Err(e) => return Err(Error::parse(path, Some(i + 1),
+                        format!("Failed to read seller from header: {}", e)).into()),
                 }
 
                 let sc = match config.seller.iter().find(|s| s.id.eq(&seller_id)) {
@@ -431,12 +496,10 @@ pub fn products_writer(config: &Config, path: &PathBuf, lang_filter: &Lang, db_c
                     }
                 };
 
-                if config.import.sqlite {
-                    ctx.execute(
-                        "insert or ignore into sellers (id, name) values (?1, ?2)",
-                        [&seller_id, &sc.name]
-                    )?;
-                }
+                seller_conf = Some(sc);
+                seller_name = Some(sc.name.to_owned());
+                widths = product_widths(seller_conf)?;
+                reqlen = widths.iter().sum();
 
                 // Take existing values to categories and update to those instead
                 // of overwriting the whole crap. This is for the json file. DB
@@ -446,22 +509,19 @@ pub fn products_writer(config: &Config, path: &PathBuf, lang_filter: &Lang, db_c
                     products_dir.push("products");
 
                     for (k, v) in Category::mapper() {
-                        let name = format!("{}.{}", k, &file_suffix);
-                        let mut extf = products_dir.to_owned();
-                        extf.push(name);
-
-                        if extf.is_file() {
-                            let s = read_to_string(extf)?;
-                            let prod = serde_json::from_str::<HashMap<String, Product>>(&s)?;
+                        // One file or shards of it
+                        let name = format!("{}.{}", k, lang_filter.to_name());
 
+                        if let Some(mut prod) = read_map::<Product>(&products_dir, &name)? {
+                            prod.values_mut().for_each(|p| p.category = v.to_owned());
                             categorized_products.insert(v, prod);
                         }
                     }
                 }
             },
-            EdiLine::Entry(s) => match Product::from_line(s, Some(lang_filter)) {
+            EdiLine::Entry(s) => match Product::from_line(s, &widths, Some(lang_filter), &config.custom_langs, &mut interner) {
                 Ok((p, w)) => {
-                    warnings.extend(w);
+                    warnings.extend(w.into_iter().map(|w| format!("Line: {}, {}", i + 1, w)));
 
                     match categorized_products.get_mut(&p.category) {
                         Some(m) => {
@@ -477,18 +537,47 @@ pub fn products_writer(config: &Config, path: &PathBuf, lang_filter: &Lang, db_c
                         }
                     }
                 },
-                Err(e) => warnings.push(format!("Product read: {}", e)),
+                Err(e) => warnings.push(format!("Line: {}, Product read: {}", i + 1, e)),
             }
         }
     }
 
-    warnings.sort();
-    warnings.dedup();
+    Ok(LangProducts {
+        lang: lang_filter.to_owned(),
+        supplier_dir,
+        seller_id,
+        seller_name,
+        categorized: categorized_products,
+        warnings,
+        skipped,
+    })
+}
+
+// Write parsed products of one language to the database, JSON files are staged
+fn products_db_writer(config: &Config, path: &PathBuf, parsed: LangProducts, db_conn: &mut Connection, log: &mut File)
+-> Result<(PathBuf, StagedJson)> {
+    let LangProducts { lang, supplier_dir, seller_id, seller_name, categorized: categorized_products,
+        warnings, skipped } = parsed;
+    let lang_filter = &lang;
+
+    if skipped > 0 {
+        info!("Skipped {} known bad lines of {:?}", skipped, path);
+    }
 
     if let Err(e) = import_warning_logger(log, path, warnings) {
         error!("Failed to write {:?} warnings to log: {}", path, e);
     }
 
+    let ctx = db_conn.savepoint()?;
+
+    if let (true, Some(name)) = (config.import.sqlite, &seller_name) {
+        ctx.execute(
+            "insert into sellers (id, name) values (?1, ?2) \
+                on conflict (id) do update set name = excluded.name",
+            [&seller_id, name]
+        )?;
+    }
+
     // Needed if json files are written.
     let mut products_dir = supplier_dir.to_owned();
     products_dir.push("products");
@@ -504,7 +593,7 @@ pub fn products_writer(config: &Config, path: &PathBuf, lang_filter: &Lang, db_c
         let mut units = categorized_products.iter()
             .flat_map(|(_, m)|
                 m.iter().map(|(_, p)| p.unit.to_owned())
-            ).collect::<Vec<String>>();
+            ).collect::<Vec<Arc<str>>>();
 
         units.sort();
         units.dedup();
@@ -517,17 +606,18 @@ pub fn products_writer(config: &Config, path: &PathBuf, lang_filter: &Lang, db_c
         }
 
         // SQLite add missing languages
-        for (k, l) in Lang::mapper() {
-            let lang_found = categorized_products.iter().any(|(_, m)|
-                m.iter().any(|(_, p)| p.lang.eq(&l)));
+        let mut langs = categorized_products.values()
+            .flat_map(|m|
+                m.values().map(|p| p.lang.to_owned())
+            ).collect::<Vec<Lang>>();
 
-            if ! lang_found {
-                continue;
-            }
-        
+        langs.sort();
+        langs.dedup();
+
+        for l in langs.iter() {
             let resp = ctx.execute(
                 "insert or ignore into languages (id, name) values (?1, ?2)",
-                params!(l.to_index(), k)
+                params!(l.to_index(), l.to_name())
             );
 
             if let Err(e) = resp {
@@ -541,7 +631,7 @@ pub fn products_writer(config: &Config, path: &PathBuf, lang_filter: &Lang, db_c
                 m.iter()
                 .filter(|(_, p)| p.discount_group.is_some())
                 .map(|(_, p)| p.discount_group.as_ref().unwrap().to_owned())
-            ).collect::<Vec<String>>();
+            ).collect::<Vec<Arc<str>>>();
 
         discgr.sort();
         discgr.dedup();
@@ -566,83 +656,172 @@ pub fn products_writer(config: &Config, path: &PathBuf, lang_filter: &Lang, db_c
                 values (?1, ?2, ?3) on conflict (id) do update set \
                 tax_class=excluded.tax_class",
                 params!(&p.identifier, category, &p.tax_class)
-            ).map_err(|e|anyhow!("Generic product write to DB error: {}", e))?;
+            ).map_err(|e|Error::Db(format!("Generic product write to DB error: {}", e)))?;
         }
     }
     
     ctx.commit()?;
     
-    for (k, v) in categorized_products {
-        let tx = db_conn.transaction()?;
+    let (source_file, imported_at) = import_provenance(path);
+
+    // Statements are prepared once per category, JSON of the previous category
+    // is serialized while the next one goes to the database. Removals and
+    // shard indexes are staged after the files.
+    let mut staged = StagedJson::default();
+
+    let (supplier_dir, mut json_files) = with_json_writer(|json| {
+        for (k, v) in categorized_products {
+            let tx = db_conn.savepoint()?;
 
-        for p in v.values() {
             if config.import.sqlite {
-                let eid = format!("{}{}", &seller_id, &p.identifier);
+                let mut translations = tx.prepare_cached(&format!(
+                    "insert into product_{}_t (id, seller_id, product_id, lang, \
+                        name, description, tags, code) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8) \
+                        on conflict (id) do update set seller_id=excluded.seller_id, \
+                        product_id=excluded.product_id, name=excluded.name, \
+                        description=excluded.description, tags=excluded.tags, \
+                        code=excluded.code", k
+                ))?;
+
+                let mut products = tx.prepare_cached(
+                    "insert into products_entries (id, product_id, seller_id, \
+                        operation, date, discount_group, unit, unit_weight, \
+                        unit_volume, typical_packaging, packaging_1, packaging_1_discount, \
+                        packaging_2, packaging_2_discount, packaging_3, packaging_3_discount, \
+                        delivery_in_weeks, stock_item, ean_code, usage_unit, usables_in_unit, \
+                        source_file, imported_at, category) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, \
+                        ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24) \
+                        on conflict (id) do update \
+                        set category=excluded.category, operation=excluded.operation, date=excluded.date, \
+                        discount_group=excluded.discount_group, \
+                        unit=excluded.unit, unit_weight=excluded.unit_weight, \
+                        unit_volume=excluded.unit_volume, typical_packaging=excluded.typical_packaging, \
+                        packaging_1=excluded.packaging_1, packaging_1_discount=excluded.packaging_1_discount, \
+                        packaging_2=excluded.packaging_2, packaging_2_discount=excluded.packaging_2_discount, \
+                        packaging_3=excluded.packaging_3, packaging_3_discount=excluded.packaging_3_discount, \
+                        delivery_in_weeks=excluded.delivery_in_weeks, \
+                        stock_item=excluded.stock_item, ean_code=excluded.ean_code, \
+                        usage_unit=excluded.usage_unit, usables_in_unit=excluded.usables_in_unit, \
+                        source_file=excluded.source_file, imported_at=excluded.imported_at"
+                )?;
+
                 let lix = lang_filter.to_index();
-                let tid = format!("{}{}", &eid, lix);
-
-                // Create translation for seller product
-                tx.execute(
-                    &format!("insert into product_{}_t (id, lang, name, \
-                    description, tags, code) values (?1, ?2, ?3, ?4, ?5, ?6) \
-                    on conflict (id) do update set name=excluded.name, \
-                    description=excluded.description, tags=excluded.tags, \
-                    code=excluded.code", k),
-                    params!(&tid, &p.lang.to_index(), &p.name, &p.description,
-                        &p.search_tags, &p.search_code)
-                ).map_err(|e|anyhow!("Product translation DB write error: {}", e))?;
-
-                // Create seller product with references to translation and generic product
-                tx.execute(
-                    &format!("insert into products_{} (id, product_id, seller_id, \
-                    operation, date, discount_group, unit, unit_weight, \
-                    unit_volume, typical_packaging, packaging_1, packaging_1_discount, \
-                    packaging_2, packaging_2_discount, packaging_3, packaging_3_discount, \
-                    delivery_in_weeks, stock_item, ean_code, usage_unit, usables_in_unit) \
-                    values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, \
-                    ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21) on conflict (id) do update \
-                    set operation=excluded.operation, date=excluded.date, \
-                    discount_group=excluded.discount_group, \
-                    unit=excluded.unit, unit_weight=excluded.unit_weight, \
-                    unit_volume=excluded.unit_volume, typical_packaging=excluded.typical_packaging, \
-                    packaging_1=excluded.packaging_1, packaging_1_discount=excluded.packaging_1_discount, \
-                    packaging_2=excluded.packaging_2, packaging_2_discount=excluded.packaging_2_discount, \
-                    packaging_3=excluded.packaging_3, packaging_3_discount=excluded.packaging_3_discount, \
-                    delivery_in_weeks=excluded.delivery_in_weeks, \
-                    stock_item=excluded.stock_item, ean_code=excluded.ean_code, \
-                    usage_unit=excluded.usage_unit, usables_in_unit=excluded.usables_in_unit", k),
-                    params!(
-                        &eid, &p.identifier, &seller_id, p.operation.to_name(),
-                        &format!(
-                            "{}-{}-{} 00:00:00.000", &p.date.year,
-                            &p.date.month,
-                            &p.date.day
-                        ), &p.discount_group, &p.unit, &p.unit_weight, &p.unit_volume,
-                        &p.typical_packaging, &p.packaging_1, &p.packaging_1_discount,
-                        &p.packaging_2, &p.packaging_2_discount, &p.packaging_3,
-                        &p.packaging_3_discount, &p.delivery_in_weeks, p.stock_item.unwrap_or(true),
-                        &p.ean_code, &p.usage_unit, &p.usables_in_unit
-                    )
-                ).map_err(|e|anyhow!("Product DB entry failure: {}", e))?;
+
+                for p in v.values() {
+                    let eid = format!("{}{}", &seller_id, &p.identifier);
+                    let tid = format!("{}:{}", &eid, lix);
+
+                    // Create translation for seller product
+                    translations.execute(
+                        params!(&tid, &seller_id, &p.identifier, &p.lang.to_index(), &p.name,
+                            &p.description, &p.search_tags, &p.search_code)
+                    ).map_err(|e|Error::Db(format!("Product translation DB write error: {}", e)))?;
+
+                    // Create seller product with references to translation and generic product
+                    products.execute(
+                        params!(
+                            &eid, &p.identifier, &seller_id, p.operation.to_name(),
+                            &format!(
+                                "{}-{}-{} 00:00:00.000", &p.date.year,
+                                &p.date.month,
+                                &p.date.day
+                            ), &p.discount_group, &p.unit, &p.unit_weight, &p.unit_volume,
+                            &p.typical_packaging, &p.packaging_1, &p.packaging_1_discount,
+                            &p.packaging_2, &p.packaging_2_discount, &p.packaging_3,
+                            &p.packaging_3_discount, &p.delivery_in_weeks, p.stock_item.unwrap_or(true),
+                            &p.ean_code, &p.usage_unit, &p.usables_in_unit,
+                            &source_file, &imported_at, k.to_name()
+                        )
+                    ).map_err(|e|Error::Db(format!("Product DB entry failure: {}", e)))?;
+                }
             }
-        }
 
-        tx.commit()?;
+            tx.commit()?;
 
-        // Json file, simplified format
-        if config.import.json {
-            let json = serde_json::to_string(&v)?;
-            let name = format!("{}.{}", k, &file_suffix);
-            let mut file = products_dir.to_owned();
+            // Json file, simplified format. Shards are written like files and
+            // their index after them.
+            if config.import.json {
+                let name = format!("{}.{}", k, lang_filter.to_name());
+                let (files, index) = shard_files(&config.json_output, &products_dir, &name, v, &mut staged)?;
 
-            file.push(name);
-            
-            write(
-                &file,
-                json.as_bytes()
-            )?;
+                for (f, m) in files {
+                    json.send((f, Envelope::new(config, &seller_id, &imported_at, m)))
+                        .map_err(|_| anyhow!("JSON writer stopped"))?;
+                }
+
+                if let Some(i) = index {
+                    stage_index(&mut staged, &products_dir, &name, &i)?;
+                }
+            }
         }
+
+        Ok(supplier_dir)
+    })?;
+
+    json_files.append(staged);
+
+    Ok((supplier_dir, json_files))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use crate::config::ConfigSource;
+    use crate::edi::EdiFileKind;
+    use crate::fixture::{generate, FixtureSpec, DEFAULT_SELLER};
+
+    use super::*;
+
+    fn config(dir: &std::path::Path, mmap_threshold_mb: u64) -> Config {
+        write(dir.join("config.toml"), format!(
+            "vat_percent = 25.5\nlang_codes = [\"fin\"]\n\n[import]\njson = false\nsqlite = false\n\
+            search = false\nmmap_threshold_mb = {}\n\n[[seller]]\nid = \"{}\"\nname = \"Test\"\n",
+            mmap_threshold_mb, DEFAULT_SELLER
+        )).unwrap();
+
+        let source = ConfigSource { dir: Some(dir.to_string_lossy().into_owned()), file: None, container: true };
+
+        Config::new(&source).unwrap()
     }
 
-    Ok(supplier_dir)
+    #[test]
+    fn mapped_and_buffered_parse_the_same() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = generate(&FixtureSpec {
+            kinds: vec![EdiFileKind::Product],
+            rows: 500,
+            sellers: vec![DEFAULT_SELLER.to_string()],
+            defects: 5,
+            out: dir.path().join("fixtures"),
+            ..Default::default()
+        }).unwrap();
+
+        let parse = |threshold| {
+            let config = config(dir.path(), threshold);
+            let parsed = products_parser(&config, &files[0], &Lang::Fin).unwrap();
+
+            (serde_json::to_value(&parsed.categorized).unwrap(), parsed.warnings, parsed.skipped)
+        };
+
+        let mapped = parse(0);
+
+        assert!(!mapped.0.as_object().unwrap().is_empty());
+        assert_eq!(mapped, parse(u64::MAX / (1024 * 1024)));
+    }
+
+    #[test]
+    fn mapped_lines_match_buffered() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lines.txt");
+
+        write(&path, "first\r\nsecond\n\nlast without newline").unwrap();
+
+        let lines = |threshold| EdiLines::open(&config(dir.path(), threshold), &path).unwrap()
+            .collect::<std::io::Result<Vec<String>>>().unwrap();
+
+        assert!(matches!(EdiLines::open(&config(dir.path(), 0), &path).unwrap(), EdiLines::Mapped { .. }));
+        assert_eq!(lines(0), lines(16));
+        assert_eq!(lines(0), vec!["first", "second", "", "last without newline"]);
+    }
 }
@@ -0,0 +1,286 @@
+use std::collections::BTreeMap;
+use std::fs::{read_to_string, write, File};
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use anyhow::Result;
+use chrono::{Local, NaiveDate};
+use log::{info, warn};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::freshness::record_freshness;
+use crate::stats::orphans;
+use crate::timings::{file_timings, StageTimings};
+
+use super::parser::{price_widths, product_widths, Price, Product, PRICE_NUMBERS, PRODUCT_NUMBERS};
+use super::{aggregate_warnings, delimited_line, file_seller, EdiDate, EdiFileKind, EdiHeader, EdiLine, Interner, WarningSummary};
+
+
+pub const REPORT_FILE_NAME: &str = "report.json";
+// Voimaantulopvm is the sixth field of both product and price lines
+const DATE_FIELD: usize = 5;
+// Orphan product ids listed in the report, the rest are only counted
+const ORPHAN_EXAMPLES: usize = 20;
+
+// Rows left without their counterpart after imports: products without a price
+// in the product report, prices without a product in the price report
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Orphans {
+    pub count: usize,
+    pub product_ids: Vec<String>,
+}
+
+impl Orphans {
+    fn new(ids: &[String]) -> Self {
+        Self { count: ids.len(), product_ids: ids.iter().take(ORPHAN_EXAMPLES).cloned().collect() }
+    }
+}
+
+// Quality of the latest imported file of a kind
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FileReport {
+    pub file: String,
+    pub generated_at: String,
+    pub lines: usize,
+    pub lines_with_warnings: usize,
+    pub warning_percent: f64,
+    pub rejected: usize,
+    pub short_lines: usize,
+    pub invalid_dates: usize,
+    pub missing_ean: usize,
+    pub missing_discount_group: usize,
+    // Known bad lines of the seller, not counted in the rest
+    #[serde(default)]
+    pub skipped: usize,
+    // Newest voimaantulopvm per category, yyyy-mm-dd
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub newest_dates: BTreeMap<String, String>,
+    #[serde(default)]
+    pub warnings: Vec<WarningSummary>,
+    // Set by the reconciliation after imports
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub orphans: Option<Orphans>,
+    // Time spent in each stage of the import, with --timings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timings: Option<StageTimings>,
+}
+
+impl FileReport {
+    fn finish(&mut self) {
+        self.warning_percent = match self.lines > 0 {
+            true => (self.lines_with_warnings as f64 / self.lines as f64 * 10000.0).round() / 100.0,
+            false => 0.0,
+        };
+    }
+}
+
+fn valid_date(line: &str, at: usize) -> bool {
+    let val = line.chars().skip(at).take(8).collect::<String>();
+
+    match EdiDate::from_string(&val) {
+        Ok(d) => d.is_valid(),
+        Err(_) => false,
+    }
+}
+
+fn line_date(line: &str, at: usize) -> Option<NaiveDate> {
+    let val = line.chars().skip(at).take(8).collect::<String>();
+
+    NaiveDate::parse_from_str(&val, "%Y%m%d").ok()
+}
+
+/// Count line level issues of a seller's product or price file. Products are
+/// read in all languages.
+pub fn file_report(config: &Config, path: &PathBuf, kind: EdiFileKind) -> Result<FileReport> {
+    let seller_conf = file_seller(config, path);
+    let (widths, numbers) = match kind {
+        EdiFileKind::Product => (product_widths(seller_conf)?, &PRODUCT_NUMBERS[..]),
+        _ => (price_widths(seller_conf)?, &PRICE_NUMBERS[..]),
+    };
+
+    let reqlen = widths.iter().sum();
+    let date_at = widths[..DATE_FIELD].iter().sum();
+
+    let mut report = FileReport {
+        file: path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default(),
+        generated_at: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        ..Default::default()
+    };
+
+    let reader = BufReader::new(File::open(path)?);
+    let mut warnings = vec![];
+    let mut interner = Interner::default();
+    let mut newest = BTreeMap::new();
+
+    for (i, l) in reader.lines().enumerate() {
+        if let (Ok(raw), Some(sc)) = (&l, seller_conf) {
+            if sc.skips_line(raw) {
+                report.skipped += 1;
+                continue
+            }
+        }
+
+        let l = l.map(|s| delimited_line(s, &widths, numbers));
+        let (line, mut flagged) = match EdiLine::line_read(l, i, reqlen)? {
+            (Some(EdiLine::Entry(s)), w) => {
+                let flagged = !w.is_empty();
+                warnings.extend(w);
+                (s, flagged)
+            },
+            (Some(_), _) => continue,
+            (None, w) => {
+                // Overlong lines are skipped, empty ones are fine
+                if !w.is_empty() {
+                    report.lines += 1;
+                    report.lines_with_warnings += 1;
+                    report.rejected += 1;
+                    warnings.extend(w);
+                }
+
+                continue
+            },
+        };
+
+        report.lines += 1;
+
+        if line.chars().count() < reqlen {
+            report.short_lines += 1;
+        }
+
+        if !valid_date(&line, date_at) {
+            report.invalid_dates += 1;
+            warnings.push(format!("Line: {}, Invalid date", i + 1));
+            flagged = true;
+        }
+
+        let date = line_date(&line, date_at);
+        let parsed = match kind {
+            EdiFileKind::Product => Product::from_line(line, &widths, None, &config.custom_langs, &mut interner)
+                .map(|(p, w)| (p.category.to_name(), p.has_ean(), p.has_discount_group(), w)),
+            _ => Price::from_line(line, &widths, &mut interner)
+                .map(|(p, w)| (p.category.to_name(), true, p.has_discount_group(), w)),
+        };
+
+        match parsed {
+            Ok((category, has_ean, has_discount_group, w)) => {
+                if let Some(d) = date {
+                    newest.entry(category).and_modify(|n: &mut NaiveDate| *n = d.max(*n)).or_insert(d);
+                }
+
+                if !w.is_empty() {
+                    flagged = true;
+                }
+
+                warnings.extend(w.into_iter().map(|w| format!("Line: {}, {}", i + 1, w)));
+
+                if !has_ean {
+                    report.missing_ean += 1;
+                }
+
+                if !has_discount_group {
+                    report.missing_discount_group += 1;
+                }
+            },
+            Err(e) => {
+                warnings.push(format!("Line: {}, Rejected: {}", i + 1, e));
+                report.rejected += 1;
+                flagged = true;
+            },
+        }
+
+        if flagged {
+            report.lines_with_warnings += 1;
+        }
+    }
+
+    report.warnings = aggregate_warnings(&warnings);
+    report.newest_dates = newest.into_iter()
+        .map(|(c, d)| (c.to_string(), d.format("%Y-%m-%d").to_string()))
+        .collect();
+    report.finish();
+
+    Ok(report)
+}
+
+/// Write file quality into `sellers/<id>/report.json`, keyed by file kind so
+/// the latest product and price files are both reported. With sqlite newest
+/// dates of the file are stored for freshness checks as well.
+pub fn compliance_report(config: &Config, path: &PathBuf, kind: EdiFileKind, supplier_dir: &PathBuf,
+    db_conn: &Connection)
+-> Result<()> {
+    let mut report = file_report(config, path, kind)?;
+    report.timings = file_timings(path);
+
+    let name = match kind {
+        EdiFileKind::Product => "product",
+        EdiFileKind::Price => "price",
+        EdiFileKind::Discount => "discount",
+        EdiFileKind::Unknown => "unknown",
+    };
+
+    info!("{} file {} of seller {:?}: {}% lines with warnings, {} rejected, {} short, {} invalid dates, \
+        {} missing EAN, {} missing discount group, {} skipped", name, report.file,
+        supplier_dir.file_name().unwrap_or_default(), report.warning_percent, report.rejected,
+        report.short_lines, report.invalid_dates, report.missing_ean, report.missing_discount_group,
+        report.skipped);
+
+    let mut report_path = supplier_dir.to_owned();
+    report_path.push(REPORT_FILE_NAME);
+
+    let mut reports: BTreeMap<String, FileReport> = match report_path.is_file() {
+        true => serde_json::from_str(&read_to_string(&report_path)?).unwrap_or_default(),
+        false => BTreeMap::new(),
+    };
+
+    if config.import.sqlite {
+        let seller_id = supplier_dir.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let file_date = EdiHeader::read(path, &config.seller_ids).ok().and_then(|h| h.file_date);
+
+        record_freshness(db_conn, &seller_id, name, &report.newest_dates, file_date)?;
+    }
+
+    reports.insert(name.to_string(), report);
+
+    write(&report_path, serde_json::to_string_pretty(&reports)?)?;
+
+    Ok(())
+}
+
+/// Reconcile products and prices of each seller after imports. Orphans go to
+/// the product and price reports in `sellers/<id>/report.json`.
+pub fn orphans_report(config: &Config, db_conn: &Connection) -> Result<()> {
+    for seller in config.seller.iter() {
+        let mut report_path = config.dir.to_owned();
+        report_path.push("sellers");
+        report_path.push(&seller.id);
+        report_path.push(REPORT_FILE_NAME);
+
+        // Nothing imported for the seller yet
+        if !report_path.is_file() {
+            continue
+        }
+
+        let mut reports: BTreeMap<String, FileReport> = serde_json::from_str(&read_to_string(&report_path)?)?;
+        let (products, prices) = orphans(db_conn, &seller.id)?;
+
+        if !products.is_empty() {
+            warn!("Seller {} has {} products without a price", seller.id, products.len());
+        }
+
+        if !prices.is_empty() {
+            warn!("Seller {} has {} prices without a product, {} for example", seller.id, prices.len(),
+                prices.iter().take(5).cloned().collect::<Vec<String>>().join(", "));
+        }
+
+        for (kind, ids) in [("product", &products), ("price", &prices)] {
+            if let Some(r) = reports.get_mut(kind) {
+                r.orphans = Some(Orphans::new(ids));
+            }
+        }
+
+        write(&report_path, serde_json::to_string_pretty(&reports)?)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,151 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{create_dir_all, read_dir, read_to_string};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{JsonOutput, ShardScheme};
+use crate::edi::{envelope, StagedJson};
+use crate::schema::SCHEMA_VERSION;
+
+
+// <name>.index.json next to the <name>/ dir of shards
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShardIndex {
+    pub schema_version: u32,
+    pub scheme: ShardScheme,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefix_len: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shards: Option<u32>,
+    // Shard key to the file relative to the index
+    pub files: BTreeMap<String, ShardFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShardFile {
+    pub file: String,
+    pub count: usize,
+}
+
+// 32 bit FNV-1a of the id bytes, easy to compute the same in a browser
+fn fnv1a(id: &str) -> u32 {
+    id.bytes().fold(0x811c9dc5u32, |h, b| (h ^ b as u32).wrapping_mul(0x01000193))
+}
+
+/// Shard of a product id: upper cased prefix with other than letters and
+/// digits as '_', or the hash modulo shard count.
+pub fn shard_key(conf: &JsonOutput, id: &str) -> String {
+    let key = match conf.shard {
+        ShardScheme::Prefix => id.chars()
+            .take(conf.prefix_len)
+            .map(|c| match c.is_ascii_alphanumeric() {
+                true => c.to_ascii_uppercase(),
+                false => '_',
+            })
+            .collect::<String>(),
+        ShardScheme::Count | ShardScheme::None => (fnv1a(id) % conf.shards).to_string(),
+    };
+
+    match key.is_empty() {
+        true => String::from("_"),
+        false => key,
+    }
+}
+
+/// Map written as <name>.json, or as shards under <name>/ with an index.
+/// Either layout is read, so changing the scheme keeps existing data.
+pub fn read_map<T: DeserializeOwned>(dir: &Path, name: &str) -> Result<Option<HashMap<String, T>>> {
+    let parse = |path: &PathBuf| -> Result<HashMap<String, T>> {
+        let s = read_to_string(path).map_err(|e| anyhow!("Failed to read {:?}: {}", path, e))?;
+
+        envelope::from_str(&s).map_err(|e| anyhow!("Failed to parse {:?}: {}", path, e))
+    };
+
+    let file = dir.join(format!("{name}.json"));
+
+    if file.is_file() {
+        return Ok(Some(parse(&file)?))
+    }
+
+    let index = dir.join(format!("{name}.index.json"));
+
+    if !index.is_file() {
+        return Ok(None)
+    }
+
+    let index: ShardIndex = serde_json::from_str(&read_to_string(&index)?)
+        .map_err(|e| anyhow!("Failed to parse {:?}: {}", index, e))?;
+    let mut map = HashMap::new();
+
+    for s in index.files.values() {
+        let path = dir.join(&s.file);
+
+        if path.is_file() {
+            map.extend(parse(&path)?);
+        }
+    }
+
+    Ok(Some(map))
+}
+
+// Files and the part of the map each one gets
+pub type ShardFiles<T> = Vec<(PathBuf, HashMap<String, T>)>;
+
+/// Files to write the map into by the configured scheme, and the index to
+/// write after them. Files of the other layout and shards no longer having
+/// products are staged for removal.
+pub fn shard_files<T>(conf: &JsonOutput, dir: &Path, name: &str, map: HashMap<String, T>, staged: &mut StagedJson)
+-> Result<(ShardFiles<T>, Option<ShardIndex>)> {
+    let file = dir.join(format!("{name}.json"));
+    let index = dir.join(format!("{name}.index.json"));
+    let shard_dir = dir.join(name);
+
+    if conf.shard.eq(&ShardScheme::None) {
+        staged.remove(index);
+        staged.remove(shard_dir);
+
+        return Ok((vec![(file, map)], None))
+    }
+
+    staged.remove(file);
+
+    create_dir_all(&shard_dir).map_err(|e| anyhow!("Failed to create shard dir {:?}: {}", shard_dir, e))?;
+
+    let mut shards: BTreeMap<String, HashMap<String, T>> = BTreeMap::new();
+
+    for (id, v) in map {
+        shards.entry(shard_key(conf, &id)).or_default().insert(id, v);
+    }
+
+    for e in read_dir(&shard_dir)? {
+        let path = e?.path();
+        let key = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+
+        if !shards.contains_key(&key) {
+            staged.remove(path);
+        }
+    }
+
+    let index = ShardIndex {
+        schema_version: SCHEMA_VERSION,
+        scheme: conf.shard,
+        prefix_len: Some(conf.prefix_len).filter(|_| conf.shard.eq(&ShardScheme::Prefix)),
+        shards: Some(conf.shards).filter(|_| conf.shard.eq(&ShardScheme::Count)),
+        files: shards.iter()
+            .map(|(k, m)| (k.to_owned(), ShardFile { file: format!("{name}/{k}.json"), count: m.len() }))
+            .collect(),
+    };
+
+    let files = shards.into_iter()
+        .map(|(k, m)| (shard_dir.join(format!("{k}.json")), m))
+        .collect();
+
+    Ok((files, Some(index)))
+}
+
+/// Stage the shard index of <name>.
+pub fn stage_index(staged: &mut StagedJson, dir: &Path, name: &str, index: &ShardIndex) -> Result<()> {
+    staged.add(dir.join(format!("{name}.index.json")), index)
+}
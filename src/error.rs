@@ -0,0 +1,112 @@
+use std::fmt;
+use std::path::PathBuf;
+
+
+/// Failure kinds callers may want to react to differently, retry a download
+/// but skip a broken file for example. Travels inside `anyhow::Error`, use
+/// `Error::kind_of` to find out what went wrong.
+#[derive(Debug)]
+pub enum Error {
+    Config(String),
+    Download { url: String, reason: String },
+    Parse { file: PathBuf, line: Option<usize>, reason: String },
+    Db(String),
+    DiskSpace { path: PathBuf, required_mb: u64, free_mb: u64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Config,
+    Download,
+    Parse,
+    Db,
+    DiskSpace,
+}
+
+// Process exit codes, wrapper scripts and systemd units can tell failure
+// classes apart with these.
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_CONFIG: i32 = 2;
+pub const EXIT_DOWNLOAD: i32 = 3;
+pub const EXIT_PARSE: i32 = 4;
+pub const EXIT_DB: i32 = 5;
+// Run finished but some sources failed to download or unzip.
+pub const EXIT_PARTIAL: i32 = 6;
+pub const EXIT_DISK: i32 = 7;
+
+impl ErrorKind {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Config => EXIT_CONFIG,
+            Self::Download => EXIT_DOWNLOAD,
+            Self::Parse => EXIT_PARSE,
+            Self::Db => EXIT_DB,
+            Self::DiskSpace => EXIT_DISK,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Config(r) => write!(f, "Config error: {}", r),
+            Self::Download { url, reason } => match url.is_empty() {
+                true => write!(f, "Download error: {}", reason),
+                false => write!(f, "Download error ({}): {}", url, reason),
+            },
+            Self::Parse { file, line, reason } => {
+                write!(f, "Parse error in {:?}", file)?;
+
+                if let Some(l) = line {
+                    write!(f, ", line {}", l)?;
+                }
+
+                write!(f, ": {}", reason)
+            },
+            Self::Db(r) => write!(f, "Database error: {}", r),
+            Self::DiskSpace { path, required_mb, free_mb } => write!(f, "Not enough disk space in {:?}: \
+                {} MB required, {} MB free", path, required_mb, free_mb),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::Db(e.to_string())
+    }
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Config(_) => ErrorKind::Config,
+            Self::Download { .. } => ErrorKind::Download,
+            Self::Parse { .. } => ErrorKind::Parse,
+            Self::Db(_) => ErrorKind::Db,
+            Self::DiskSpace { .. } => ErrorKind::DiskSpace,
+        }
+    }
+    /// First structured error (or plain sqlite error) in the error chain.
+    pub fn kind_of(e: &anyhow::Error) -> Option<ErrorKind> {
+        e.chain().find_map(|c| {
+            if let Some(e) = c.downcast_ref::<Self>() {
+                return Some(e.kind())
+            }
+
+            c.downcast_ref::<rusqlite::Error>().map(|_| ErrorKind::Db)
+        })
+    }
+    /// Exit code for the error, `fallback` kind is used when the error chain
+    /// doesn't tell.
+    pub fn exit_code(e: &anyhow::Error, fallback: Option<ErrorKind>) -> i32 {
+        match Self::kind_of(e).or(fallback) {
+            Some(k) => k.exit_code(),
+            None => EXIT_FAILURE,
+        }
+    }
+    pub fn parse<T>(file: &PathBuf, line: Option<usize>, reason: T) -> Self where T: fmt::Display {
+        Self::Parse { file: file.to_owned(), line, reason: reason.to_string() }
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, write, File};
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use anyhow::{anyhow, bail, Result};
+use log::{error, info};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::edi::envelope::Envelope;
+use crate::edi::{import_provenance, import_warning_logger};
+use crate::error::Error;
+
+
+// ETIM files are semicolon separated with a header row naming the columns:
+//   classes:  class;description
+//   features: feature;description;unit
+//   products: product;class;feature;value;unit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EtimFileKind {
+    Classes,
+    Features,
+    Products,
+}
+
+impl EtimFileKind {
+    fn from_header(header: &str) -> Result<Self> {
+        let columns = header.trim().trim_start_matches('\u{feff}').to_lowercase();
+
+        match columns.split(';').map(|c| c.trim()).collect::<Vec<&str>>().as_slice() {
+            ["class", "description"] => Ok(Self::Classes),
+            ["feature", "description", "unit"] => Ok(Self::Features),
+            ["product", "class", "feature", "value", "unit"] => Ok(Self::Products),
+            _ => bail!("Unknown ETIM file header '{}'", header.trim()),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+struct EtimFeatureValue {
+    #[serde(rename = "v")]
+    value: String,
+    #[serde(rename = "u", skip_serializing_if = "Option::is_none")]
+    unit: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct EtimProduct {
+    class: String,
+    features: BTreeMap<String, EtimFeatureValue>,
+}
+
+fn optional(val: Option<&&str>) -> Option<String> {
+    match val.map(|v| v.trim()) {
+        Some(v) if !v.is_empty() => Some(v.to_string()),
+        _ => None,
+    }
+}
+
+/// Import ETIM classes, features or product classifications of a seller.
+/// Product file replaces all earlier classifications of the seller. Returns
+/// number of imported rows.
+pub fn etim_import(config: &Config, path: &PathBuf, seller: Option<&String>, db_conn: &mut Connection,
+    log: &mut File)
+-> Result<usize> {
+    let reader = BufReader::new(File::open(path).map_err(|e| anyhow!("Failed to open {:?}: {}", path, e))?);
+    let mut lines = reader.lines();
+
+    let kind = match lines.next() {
+        Some(h) => EtimFileKind::from_header(&h?).map_err(|e| Error::parse(path, Some(1), e))?,
+        None => bail!("ETIM file {:?} is empty", path),
+    };
+
+    let seller = match (kind, seller) {
+        (EtimFileKind::Products, Some(s)) => match config.seller.iter().any(|c| c.id.eq(s)) {
+            true => s.to_owned(),
+            false => bail!("Seller {} is not configured", s),
+        },
+        (EtimFileKind::Products, None) => bail!("ETIM product classifications require --seller <id>"),
+        (_, _) => String::new(),
+    };
+
+    let mut warnings = vec![];
+    let mut products: BTreeMap<String, EtimProduct> = BTreeMap::new();
+    let mut count = 0;
+
+    let tx = db_conn.transaction()?;
+
+    if kind == EtimFileKind::Products {
+        tx.execute("delete from etim_product_classes where seller_id = ?1", params![&seller])?;
+        tx.execute("delete from etim_product_features where seller_id = ?1", params![&seller])?;
+    }
+
+    for (i, l) in lines.enumerate() {
+        // Header was line 1
+        let n = i + 2;
+        let line = l.map_err(|e| Error::parse(path, Some(n), e))?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let cols = line.split(';').collect::<Vec<&str>>();
+        let col = |c: usize| cols.get(c).map(|c| c.trim()).unwrap_or_default();
+
+        match kind {
+            EtimFileKind::Classes => {
+                if col(0).is_empty() {
+                    warnings.push(format!("Line: {}, class id missing", n));
+                    continue;
+                }
+
+                tx.execute(
+                    "insert into etim_classes (id, description) values (?1, ?2) \
+                        on conflict (id) do update set description = excluded.description",
+                    params![col(0), col(1)]
+                ).map_err(|e| Error::Db(format!("ETIM class insert failed: {}", e)))?;
+            },
+            EtimFileKind::Features => {
+                if col(0).is_empty() {
+                    warnings.push(format!("Line: {}, feature id missing", n));
+                    continue;
+                }
+
+                tx.execute(
+                    "insert into etim_features (id, description, unit) values (?1, ?2, ?3) \
+                        on conflict (id) do update set description = excluded.description, \
+                        unit = excluded.unit",
+                    params![col(0), col(1), optional(cols.get(2))]
+                ).map_err(|e| Error::Db(format!("ETIM feature insert failed: {}", e)))?;
+            },
+            EtimFileKind::Products => {
+                let (product, class, feature) = (col(0), col(1), col(2));
+
+                if product.is_empty() || class.is_empty() {
+                    warnings.push(format!("Line: {}, product or class missing", n));
+                    continue;
+                }
+
+                let id = format!("{}{}", &seller, product);
+                let p = products.entry(product.to_string()).or_default();
+
+                if p.class.ne(class) {
+                    p.class = class.to_string();
+
+                    tx.execute(
+                        "insert or replace into etim_product_classes (id, seller_id, product_id, class_id) \
+                            values (?1, ?2, ?3, ?4)",
+                        params![&id, &seller, product, class]
+                    ).map_err(|e| Error::Db(format!("ETIM product class insert failed: {}", e)))?;
+                }
+
+                // Class only rows are fine
+                if feature.is_empty() {
+                    count += 1;
+                    continue;
+                }
+
+                let value = EtimFeatureValue { value: col(3).to_string(), unit: optional(cols.get(4)) };
+
+                tx.execute(
+                    "insert or replace into etim_product_features (id, seller_id, product_id, feature_id, \
+                        value, unit) values (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![format!("{}{}", &id, feature), &seller, product, feature, &value.value, &value.unit]
+                ).map_err(|e| Error::Db(format!("ETIM product feature insert failed: {}", e)))?;
+
+                p.features.insert(feature.to_string(), value);
+            },
+        }
+
+        count += 1;
+    }
+
+    tx.commit()?;
+
+    if let Err(e) = import_warning_logger(log, path, warnings) {
+        error!("Failed to write {:?} warnings to log: {}", path, e);
+    }
+
+    if config.import.json && kind == EtimFileKind::Products {
+        let mut dir = config.dir.to_owned();
+        dir.push("sellers");
+        dir.push(&seller);
+
+        create_dir_all(&dir)?;
+        dir.push("etim.json");
+
+        let (_, imported_at) = import_provenance(path);
+        let products = Envelope::new(config, &seller, &imported_at, products);
+
+        write(&dir, serde_json::to_string(&products)?)?;
+    }
+
+    info!("Imported {} ETIM {:?} rows from {:?}", count, kind, path);
+
+    Ok(count)
+}
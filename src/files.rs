@@ -1,8 +1,13 @@
 // 3rd party libs
-use std::fs::{File, write, create_dir_all, rename, remove_file, read_dir};
+use std::fs::{File, OpenOptions, DirBuilder, Permissions, copy, write, create_dir_all, rename, remove_file,
+    remove_dir, remove_dir_all, read_dir, metadata, set_permissions};
 use std::io::{prelude::*, BufReader};
-use std::path::PathBuf;
-use log::debug;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use log::{debug, info, warn};
+use rand::distributions::{Alphanumeric, DistString};
+use chrono::{DateTime, Local};
 use encoding::all::UTF_8;
 use anyhow::{anyhow, bail, Result};
 use encoding::{Encoding, DecoderTrap};
@@ -10,25 +15,278 @@ use encoding::all::ISO_8859_1;
 
 use crate::config::Config;
 use crate::edi::EDI_DIR_NAME;
+use crate::runs::RUNS_DIR_NAME;
 
-use super::edi::{EdiOwnership, EdiHeader};
+use super::edi::{EdiOwnership, EdiHeader, SellerIds};
 
 
-pub fn move_file(from: &PathBuf, target_dir: &PathBuf, subdir: &str, name: &str) {
+pub const IMPORT_LOG_NAME: &str = "import.log";
+pub const TMP_DIR_NAME: &str = "tmp";
+
+// Leftovers younger than this may belong to a run still going on
+const TMP_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Scratch dir of one run under `<dir>/tmp`. Archives are extracted and
+/// converted here and moved to the run's `runs/<id>/` only when complete.
+/// Removed on drop.
+pub struct RunTemp {
+    pub path: PathBuf,
+    file_mode: u32,
+}
+
+impl RunTemp {
+    pub fn new(config: &Config) -> Result<Self> {
+        let mut path = config.dir.to_owned();
+        path.push(TMP_DIR_NAME);
+        path.push(format!("run-{}", Alphanumeric.sample_string(&mut rand::thread_rng(), 10)));
+
+        create_dirs(&path, config.import.dir_mode)
+            .map_err(|e| anyhow!("Failed to create temp dir {:?}: {}", path, e))?;
+
+        Ok(Self { path, file_mode: config.import.file_mode })
+    }
+    /// Move finished file from the temp dir into `dir` with configured mode.
+    pub fn persist(&self, file: &PathBuf, dir: &PathBuf) -> Result<PathBuf> {
+        let name = match file.file_name() {
+            Some(n) => n.to_string_lossy().into_owned(),
+            None => bail!("Unable to read file name of {:?}", file),
+        };
+
+        set_permissions(file, Permissions::from_mode(self.file_mode))?;
+
+        move_file(file, dir, "", &name)
+    }
+}
+
+impl Drop for RunTemp {
+    fn drop(&mut self) {
+        if let Err(e) = remove_dir_all(&self.path) {
+            warn!("Failed to remove temp dir {:?}: {}", self.path, e);
+        }
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Hex encoded SHA-256 of the data.
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex(ring::digest::digest(&ring::digest::SHA256, data).as_ref())
+}
+
+/// Create directory and its parents with `mode`, umask applies like with
+/// `mkdir`.
+pub fn create_dirs(path: &PathBuf, mode: u32) -> std::io::Result<()> {
+    DirBuilder::new().recursive(true).mode(mode).create(path)
+}
+
+/// Remove temp dirs and half converted `.clean` files crashed runs left behind.
+/// Run dirs still holding files are kept for resuming, empty ones are removed.
+pub fn temp_cleanup(config: &Config) -> Result<()> {
+    let mut tmp_dir = config.dir.to_owned();
+    tmp_dir.push(TMP_DIR_NAME);
+
+    // Shared extraction dir of older versions
+    let mut edi_dir = config.dir.to_owned();
+    edi_dir.push(EDI_DIR_NAME);
+
+    let mut runs_dir = config.dir.to_owned();
+    runs_dir.push(RUNS_DIR_NAME);
+
+    if runs_dir.is_dir() {
+        for e in read_dir(&runs_dir)? {
+            let path = e?.path();
+            let stale = metadata(&path)?.modified()?.elapsed().is_ok_and(|a| a > TMP_MAX_AGE);
+
+            if stale && path.is_dir() && read_dir(&path)?.next().is_none() {
+                debug!("Removing empty dir {:?} of an earlier run", path);
+                remove_dir(&path)?;
+            }
+        }
+    }
+
+    for dir in [tmp_dir, edi_dir] {
+        if !dir.is_dir() {
+            continue
+        }
+
+        for e in read_dir(&dir)? {
+            let path = e?.path();
+            let stale = metadata(&path)?.modified()?.elapsed().is_ok_and(|a| a > TMP_MAX_AGE);
+            let leftover = path.is_dir() || path.extension().is_some_and(|e| e.eq("clean"));
+
+            if !stale || !leftover {
+                continue
+            }
+
+            debug!("Removing leftover {:?} of an earlier run", path);
+
+            match path.is_dir() {
+                true => remove_dir_all(&path)?,
+                false => remove_file(&path)?,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Open import log for appending, rotating the previous one if it's too big or
+// from another day. Oldest rotated logs beyond the retention are removed.
+pub fn open_import_log(config: &Config) -> Result<File> {
+    let mut path = config.dir.to_owned();
+    path.push(IMPORT_LOG_NAME);
+
+    let rotated = |n: usize| config.dir.join(format!("{}.{}", IMPORT_LOG_NAME, n));
+
+    if let Ok(meta) = metadata(&path) {
+        let too_big = meta.len() > config.log.max_size_mb * 1024 * 1024;
+        let old = match meta.modified() {
+            Ok(t) => config.log.daily
+                && DateTime::<Local>::from(t).date_naive() != Local::now().date_naive(),
+            Err(_) => false,
+        };
+
+        if too_big || old {
+            if config.log.keep == 0 {
+                remove_file(&path)?;
+            } else {
+                let oldest = rotated(config.log.keep);
+
+                if oldest.is_file() {
+                    remove_file(&oldest)?;
+                }
+
+                for n in (1..config.log.keep).rev() {
+                    if rotated(n).is_file() {
+                        rename(rotated(n), rotated(n + 1))?;
+                    }
+                }
+
+                rename(&path, rotated(1))?;
+            }
+
+            debug!("Rotated {:?}", path);
+        }
+    }
+
+    let mut log = OpenOptions::new().create(true).append(true).open(&path)?;
+
+    writeln!(log, "=== Run started {} ===", Local::now().format("%Y-%m-%d %H:%M:%S"))?;
+
+    Ok(log)
+}
+
+/// Group id of a group name, or the name itself as a number.
+pub fn group_id(name: &str) -> Result<u32> {
+    if let Ok(gid) = name.parse::<u32>() {
+        return Ok(gid)
+    }
+
+    let c_name = std::ffi::CString::new(name).map_err(|e| anyhow!("Invalid group name '{}': {}", name, e))?;
+
+    // Safe, name is nul terminated and the entry is read before any other call
+    let group = unsafe { libc::getgrnam(c_name.as_ptr()) };
+
+    if group.is_null() {
+        bail!("Unknown group '{}'", name)
+    }
+
+    Ok(unsafe { (*group).gr_gid })
+}
+
+// Buyer mode and group to a dir or file, the owner stays
+fn restrict_buyer_path(config: &Config, path: &PathBuf, mode: u32) -> Result<()> {
+    set_permissions(path, Permissions::from_mode(mode))
+        .map_err(|e| anyhow!("Failed to set mode of {:?}: {}", path, e))?;
+
+    if let Some(gid) = config.import.buyer_gid {
+        std::os::unix::fs::chown(path, None, Some(gid))
+            .map_err(|e| anyhow!("Failed to set group of {:?}: {}", path, e))?;
+    }
+
+    Ok(())
+}
+
+/// Create a dir of buyer data with missing parents and restrict it to buyer
+/// dir mode and group. Existing dirs are restricted as well.
+pub fn create_buyer_dir(config: &Config, path: &PathBuf) -> Result<()> {
+    create_dirs(path, config.import.buyer_dir_mode)
+        .map_err(|e| anyhow!("Failed to create buyer dir {:?}: {}", path, e))?;
+
+    restrict_buyer_path(config, path, config.import.buyer_dir_mode)
+}
+
+/// Restrict a written file of buyer data to buyer file mode and group.
+pub fn restrict_buyer_file(config: &Config, path: &PathBuf) -> Result<()> {
+    restrict_buyer_path(config, path, config.import.buyer_file_mode)
+}
+
+/// Free space available to this process in the file system of `path`, in bytes.
+pub fn free_space(path: &PathBuf) -> Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| anyhow!("Invalid path {:?}: {}", path, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    // Safe, path is nul terminated and stat is a valid struct to write to
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        bail!("Failed to read file system stats of {:?}: {}", path, std::io::Error::last_os_error())
+    }
+
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+pub fn move_file(from: &PathBuf, target_dir: &PathBuf, subdir: &str, name: &str) -> Result<PathBuf> {
     let mut path = target_dir.to_owned();
     path.push(subdir);
 
-    if let Err(e) = create_dir_all(&path) {
-        panic!("Failed to create supplier '{}' dir to {:?}: {}",
-            subdir, path, e)
-    }
+    create_dir_all(&path).map_err(|e|anyhow!(
+        "Failed to create supplier '{}' dir to {:?}: {}", subdir, path, e
+    ))?;
 
     let mut file = path.to_owned();
     file.push(name);
 
-    if let Err(e) = rename(from, &file) {
-        panic!("Failed to move {:?} to supplier dir {:?}: {}", from, file, e)
+    match rename(from, &file) {
+        Ok(_) => (),
+        // Rename fails across filesystems (mounted data dirs), copy and delete instead
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+            copy(from, &file).map_err(|e|anyhow!(
+                "Failed to move {:?} to supplier dir {:?}: {}", from, file, e
+            ))?;
+
+            remove_file(from).map_err(|e|anyhow!(
+                "Failed to remove {:?} after copying it to {:?}: {}", from, file, e
+            ))?;
+        },
+        Err(e) => bail!("Failed to move {:?} to supplier dir {:?}: {}", from, file, e),
     }
+
+    Ok(file)
+}
+
+/// Move an imported EDI file to `edi/` of its supplier dir. Forced imports get
+/// here with files already stored, those are dropped instead of kept twice.
+pub fn store_edi_file(from: &PathBuf, supplier_dir: &Path, name: &str) -> Result<PathBuf> {
+    let dir = supplier_dir.join(EDI_DIR_NAME);
+
+    if dir.is_dir() {
+        for e in read_dir(&dir)? {
+            let path = e?.path();
+
+            if path.is_file() && same_content(from, &path)? {
+                debug!("{:?} is stored already as {:?}", from, path);
+                remove_file(from)?;
+
+                return Ok(path)
+            }
+        }
+    }
+
+    move_file(from, &supplier_dir.to_path_buf(), EDI_DIR_NAME, name)
 }
 
 // Convert file to utf-8 and make sure it follows the same known pattern
@@ -70,8 +328,7 @@ pub fn file_to_edi_utf8(from: &PathBuf, to_dir: &PathBuf, new_name: Option<Strin
 
 pub fn edi_file_imported(config: &Config, path: &PathBuf, ownership: EdiOwnership) -> Result<bool> {
     // Read title from the new file.
-    let new_edi_file = File::open(path)?;
-    let header = EdiHeader::read(path)?;
+    let header = EdiHeader::read(path, &config.seller_ids)?;
     
     // Expecting CONF_DIR/sellers/ID or CONF_DIR/sellers/ID/buyers/ID
     let (mut homedir, party) = match ownership {
@@ -121,20 +378,15 @@ pub fn edi_file_imported(config: &Config, path: &PathBuf, ownership: EdiOwnershi
             continue;
         }
 
-        let header = EdiHeader::read(&e.path())?;
+        let existing = EdiHeader::read(&e.path(), &config.seller_ids)?;
+        let same_party = match ownership {
+            EdiOwnership::Seller => existing.seller.as_ref().is_some_and(|t| t.eq(&party)),
+            EdiOwnership::Buyer => existing.buyer.as_ref().is_some_and(|t| t.eq(&party)),
+            _ => false,
+        };
 
-        match ownership {
-            EdiOwnership::Seller => if let Some(t) = header.seller {
-                if t.eq(&party) {
-                    edi_files.push(e.path());
-                }
-            },
-            EdiOwnership::Buyer => if let Some(t) = header.buyer {
-                if t.eq(&party) {
-                    edi_files.push(e.path());
-                }
-            },
-            _ => (),
+        if same_party {
+            edi_files.push((e.path(), existing.file_date));
         }
     }
 
@@ -144,24 +396,25 @@ pub fn edi_file_imported(config: &Config, path: &PathBuf, ownership: EdiOwnershi
     }
     
     // Loop through the files and compare them
-    for f in edi_files {
-        let f2 = File::open(f)?;
-    
-        // Compare filesize
-        if new_edi_file.metadata()?.len() != f2.metadata()?.len() {
-            continue;
-        }
-    
-        // Read both files to bytes
-        let f1 = BufReader::new(new_edi_file);
-        let f2 = BufReader::new(f2);
-    
-        // Do a byte to byte comparison of the two files
-        for (b1, b2) in f1.bytes().zip(f2.bytes()) {
-            if b1? != b2? {
-                continue;
+    for (f, file_date) in edi_files {
+        // Dated files of the same name are compared by date, older one than
+        // already imported is a stale copy
+        if f.file_name().eq(&path.file_name()) {
+            match (header.file_date, file_date) {
+                (Some(new), Some(old)) if new < old => {
+                    info!("{:?} is dated {} but {:?} dated {} is imported already, skipping", path, new, f, old);
+                    remove_file(path)?;
+
+                    return Ok(true)
+                },
+                (Some(new), Some(old)) if new > old => continue,
+                _ => (),
             }
         }
+
+        if !same_content(path, &f)? {
+            continue;
+        }
     
         // Same file, get rid of the newcomer and notify skip
         remove_file(path)?;
@@ -172,6 +425,37 @@ pub fn edi_file_imported(config: &Config, path: &PathBuf, ownership: EdiOwnershi
     Ok(false)
 }
 
+// Byte to byte comparison of two files, chunk at a time.
+fn same_content(a: &PathBuf, b: &PathBuf) -> Result<bool> {
+    let f1 = File::open(a)?;
+    let f2 = File::open(b)?;
+
+    // Compare filesize
+    if f1.metadata()?.len() != f2.metadata()?.len() {
+        return Ok(false)
+    }
+
+    let mut r1 = BufReader::new(f1);
+    let mut r2 = BufReader::new(f2);
+    let mut b1 = [0u8; 8192];
+    let mut b2 = [0u8; 8192];
+
+    loop {
+        let n = r1.read(&mut b1)?;
+
+        if n == 0 {
+            return Ok(true)
+        }
+
+        // Reads from the other file may come in shorter pieces
+        r2.read_exact(&mut b2[..n])?;
+
+        if b1[..n] != b2[..n] {
+            return Ok(false)
+        }
+    }
+}
+
 fn edifile_cleanup(path: PathBuf) -> Result<PathBuf> {
     // Open the given file for reading
     let f = File::open(&path)?;
@@ -195,11 +479,109 @@ fn edifile_cleanup(path: PathBuf) -> Result<PathBuf> {
         }
     }
 
-    // Validate EDI header
-    EdiHeader::read(&new_path).map_err(|e|anyhow!("Cleaned up EDI file has invalid header: {}", e))?;
+    // Validate EDI header, seller ids aren't mapped for it
+    EdiHeader::read(&new_path, &SellerIds::default()).map_err(|e|anyhow!("Cleaned up EDI file has invalid header: {}", e))?;
     
     std::fs::rename(&new_path, &path)
         .map_err(|e|anyhow!("Unable to copy example config file: {}", e))?;
 
     Ok(path)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use super::*;
+
+    fn pair(dir: &tempfile::TempDir, a: &[u8], b: &[u8]) -> (PathBuf, PathBuf) {
+        let (pa, pb) = (dir.path().join("a"), dir.path().join("b"));
+        write(&pa, a).unwrap();
+        write(&pb, b).unwrap();
+
+        (pa, pb)
+    }
+
+    #[test]
+    fn move_file_into_subdir() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("a");
+        write(&from, b"OHJ").unwrap();
+
+        let to = move_file(&from, &dir.path().to_path_buf(), "003700000001", "b").unwrap();
+
+        assert_eq!(to, dir.path().join("003700000001").join("b"));
+        assert!(!from.exists());
+        assert_eq!(std::fs::read(&to).unwrap(), b"OHJ");
+    }
+
+    #[test]
+    fn move_file_missing_source_fails() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert!(move_file(&dir.path().join("a"), &dir.path().to_path_buf(), "", "b").is_err());
+        assert!(!dir.path().join("b").exists());
+    }
+
+    #[test]
+    fn store_edi_file_keeps_one_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let supplier = dir.path().join("003700000001");
+
+        for (name, data) in [("a", b"OHJ"), ("b", b"OHJ"), ("c", b"OSE")] {
+            write(dir.path().join(name), data).unwrap();
+            store_edi_file(&dir.path().join(name), &supplier, name).unwrap();
+            assert!(!dir.path().join(name).exists());
+        }
+
+        let mut stored = read_dir(supplier.join(EDI_DIR_NAME)).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<String>>();
+        stored.sort();
+
+        assert_eq!(stored, ["a", "c"]);
+    }
+
+    #[test]
+    fn same_content_identical() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+        let (a, b) = pair(&dir, &data, &data);
+
+        assert!(same_content(&a, &b).unwrap());
+    }
+
+    #[test]
+    fn same_content_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = pair(&dir, b"", b"");
+
+        assert!(same_content(&a, &b).unwrap());
+    }
+
+    #[test]
+    fn same_content_same_size_differs() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = pair(&dir, b"OHJ 0037000000011", b"OHJ 0037000000012");
+
+        assert!(!same_content(&a, &b).unwrap());
+    }
+
+    #[test]
+    fn same_content_differs_after_first_buffer() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = vec![b'x'; 10_000];
+        let mut other = data.clone();
+        other[9_000] = b'y';
+        let (a, b) = pair(&dir, &data, &other);
+
+        assert!(!same_content(&a, &b).unwrap());
+    }
+
+    #[test]
+    fn same_content_different_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = pair(&dir, b"OHJ 0037000000011", b"OHJ 0037000000011\n");
+
+        assert!(!same_content(&a, &b).unwrap());
+    }
+}
@@ -0,0 +1,223 @@
+use std::fs::{create_dir_all, write};
+use std::path::PathBuf;
+use anyhow::{anyhow, Result};
+use log::info;
+
+use crate::edi::EdiFileKind;
+use crate::utils::Category;
+
+
+pub const DEFAULT_SELLER: &str = "003700000001";
+pub const DEFAULT_BUYER: &str = "1234567";
+
+// Discount groups products are spread over
+pub const DISCOUNT_GROUPS: usize = 50;
+
+// Synthetic EDI file settings for tests and benchmarks. Every n:th row is
+// broken on purpose when defects are requested.
+#[derive(Debug, Clone)]
+pub struct FixtureSpec {
+    pub kinds: Vec<EdiFileKind>,
+    pub rows: usize,
+    pub sellers: Vec<String>,
+    pub buyer: String,
+    pub category: Category,
+    pub defects: usize,
+    pub out: PathBuf,
+}
+
+impl Default for FixtureSpec {
+    fn default() -> Self {
+        Self {
+            kinds: vec![],
+            rows: 100,
+            sellers: vec![],
+            buyer: DEFAULT_BUYER.to_string(),
+            category: Category::WaterAndHeating,
+            defects: 0,
+            out: PathBuf::from("."),
+        }
+    }
+}
+
+impl FixtureSpec {
+    fn kinds(&self) -> Vec<EdiFileKind> {
+        match self.kinds.is_empty() {
+            true => vec![EdiFileKind::Product, EdiFileKind::Price, EdiFileKind::Discount],
+            false => self.kinds.to_owned(),
+        }
+    }
+    fn sellers(&self) -> Vec<String> {
+        match self.sellers.is_empty() {
+            true => vec![DEFAULT_SELLER.to_string()],
+            false => self.sellers.to_owned(),
+        }
+    }
+    // Row indexes that get a defect, spread evenly over the file
+    fn is_defect(&self, i: usize) -> Option<usize> {
+        if self.defects == 0 {
+            return None
+        }
+
+        let every = (self.rows / self.defects).max(1);
+
+        match i % every == every - 1 && i / every < self.defects {
+            true => Some(i / every),
+            false => None,
+        }
+    }
+}
+
+/// Write product, price and / or discount files for each seller into the
+/// output dir. Returns paths of the written files.
+pub fn generate(spec: &FixtureSpec) -> Result<Vec<PathBuf>> {
+    create_dir_all(&spec.out).map_err(|e| anyhow!("Failed to create fixture dir {:?}: {}", spec.out, e))?;
+
+    let mut files = vec![];
+
+    for seller in spec.sellers() {
+        for kind in spec.kinds() {
+            let (name, content) = match kind {
+                EdiFileKind::Product => ("product", products(spec, &seller)),
+                EdiFileKind::Price => ("price", prices(spec, &seller)),
+                EdiFileKind::Discount => ("discount", discounts(spec, &seller)),
+                EdiFileKind::Unknown => continue,
+            };
+
+            let mut path = spec.out.to_owned();
+            path.push(format!("{}-{}-{}.txt", seller, spec.category.to_name(), name));
+
+            write(&path, content).map_err(|e| anyhow!("Failed to write fixture {:?}: {}", path, e))?;
+
+            info!("Generated {} {} rows to {:?}", spec.rows, name, path);
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}
+
+// Left aligned and space padded, cut to width
+fn field(val: &str, width: usize) -> String {
+    format!("{:<w$}", val.chars().take(width).collect::<String>(), w = width)
+}
+
+// Zero padded number
+fn num(val: usize, width: usize) -> String {
+    format!("{:0>w$}", val % 10usize.pow(width as u32), w = width)
+}
+
+fn line(fields: Vec<(String, usize)>) -> String {
+    fields.into_iter().map(|(v, w)| field(&v, w)).collect::<String>()
+}
+
+fn header(buyer: &str, seller: &str) -> String {
+    format!("OBY{}A01\nOSE{}A01\n", field(buyer, 17), field(seller, 17))
+}
+
+fn category_char(category: &Category) -> &'static str {
+    match category {
+        Category::Ventilation => "I",
+        Category::Electricity => "S",
+        Category::Industrial => "P",
+        Category::Refrigeration => "K",
+        _ => "L",
+    }
+}
+
+fn product_id(i: usize) -> String {
+    num(1000000 + i, 7)
+}
+
+fn discount_group(i: usize) -> String {
+    format!("G{}", num(i % DISCOUNT_GROUPS, 5))
+}
+
+// Same defect kinds for every file type: short line, unknown category / row
+// id and a non numeric value.
+fn apply_defect(line: String, defect: usize, bad_field: (usize, usize)) -> String {
+    match defect % 3 {
+        0 => line.chars().take(line.chars().count() / 2).collect(),
+        1 => format!("X{}", &line[1..]),
+        _ => {
+            let (start, width) = bad_field;
+            format!("{}{}{}", &line[..start], field("NOTNUMBER", width), &line[start + width..])
+        },
+    }
+}
+
+fn products(spec: &FixtureSpec, seller: &str) -> String {
+    let mut out = header("", seller);
+
+    for i in 0..spec.rows {
+        let mut l = line(vec![
+            ("R".into(), 1), (category_char(&spec.category).into(), 1), (product_id(i), 9), ("1".into(), 1),
+            ("FIN".into(), 3), ("20240101".into(), 8), (format!("TUOTE {}", i), 35),
+            (format!("FIXTURE {} {}", seller, i), 35), (String::new(), 20), (String::new(), 7),
+            (discount_group(i), 6), ("KPL".into(), 3), (num(1, 7), 7), (num(0, 7), 7), (num(1, 9), 9),
+            (num(0, 9), 9), (num(0, 5), 5), (num(0, 9), 9), (num(0, 5), 5), (num(0, 9), 9), (num(0, 5), 5),
+            (String::new(), 3), ("00".into(), 2), (String::new(), 1), (num(6400000000000 + i, 13), 20),
+            ("KPL".into(), 3), (num(10000, 9), 9),
+        ]);
+
+        if let Some(d) = spec.is_defect(i) {
+            // Typical packaging size
+            l = apply_defect(l, d, (143, 9));
+        }
+
+        out.push_str(&l);
+        out.push('\n');
+    }
+
+    out
+}
+
+fn prices(spec: &FixtureSpec, seller: &str) -> String {
+    let mut out = header("", seller);
+
+    for i in 0..spec.rows {
+        // Two decimals, 1.00 .. 1000.99
+        let price = 100 + (i * 7919) % 100000;
+
+        let mut l = line(vec![
+            ("R".into(), 1), (category_char(&spec.category).into(), 1), (product_id(i), 9), ("01".into(), 2),
+            (num(price, 9), 9), ("20240101".into(), 8), (discount_group(i), 6), ("KPL".into(), 3),
+            (num(1, 4), 4), (num(0, 9), 9), (num(0, 5), 5), (num(0, 9), 9), (num(0, 5), 5), (num(0, 9), 9),
+            (num(0, 5), 5), ("KPL".into(), 3), (num(10000, 9), 9), (String::new(), 1), ("00".into(), 2),
+        ]);
+
+        if let Some(d) = spec.is_defect(i) {
+            // Price
+            l = apply_defect(l, d, (13, 9));
+        }
+
+        out.push_str(&l);
+        out.push('\n');
+    }
+
+    out
+}
+
+fn discounts(spec: &FixtureSpec, seller: &str) -> String {
+    let mut out = header(&spec.buyer, seller);
+
+    for i in 0..spec.rows {
+        // Percent with two decimals, 5.00 .. 54.00
+        let percent = (5 + i % 50) * 100;
+
+        let mut l = line(vec![
+            ("R".into(), 1), (format!("G{}", num(i, 5)), 6), (String::new(), 25), (format!("ALERYHMA {}", i), 40),
+            ("01".into(), 2), (num(percent, 9), 9), (num(0, 9), 9),
+        ]);
+
+        if let Some(d) = spec.is_defect(i) {
+            // Discount percent
+            l = apply_defect(l, d, (74, 9));
+        }
+
+        out.push_str(&l);
+        out.push('\n');
+    }
+
+    out
+}
@@ -0,0 +1,108 @@
+use std::collections::BTreeMap;
+use anyhow::Result;
+use chrono::{Local, NaiveDate};
+use log::{info, warn};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::notify::send_warning;
+
+
+// Newest data of a seller in a category, dates as yyyy-mm-dd
+#[derive(Debug, Serialize)]
+pub struct Freshness {
+    pub category: String,
+    pub kind: String,
+    pub file_date: Option<String>,
+    pub newest_date: Option<String>,
+    pub imported_at: String,
+}
+
+impl Freshness {
+    /// Date of the data, header file date or newest line date whichever is later.
+    pub fn date(&self) -> Option<NaiveDate> {
+        [&self.file_date, &self.newest_date].into_iter()
+            .flatten()
+            .filter_map(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok())
+            .max()
+    }
+}
+
+/// Store newest dates of an imported product or price file per category.
+pub fn record_freshness(db_conn: &Connection, seller_id: &str, kind: &str, newest_dates: &BTreeMap<String, String>,
+    file_date: Option<NaiveDate>)
+-> Result<()> {
+    let imported_at = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    let file_date = file_date.map(|d| d.format("%Y-%m-%d").to_string());
+
+    for (category, newest) in newest_dates.iter() {
+        db_conn.execute(
+            "insert into freshness (seller_id, category, kind, file_date, newest_date, imported_at) \
+                values (?1, ?2, ?3, ?4, ?5, ?6) \
+                on conflict (seller_id, category, kind) do update set file_date=excluded.file_date, \
+                newest_date=excluded.newest_date, imported_at=excluded.imported_at",
+            params![seller_id, category, kind, &file_date, newest, &imported_at]
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Freshness rows of a seller.
+pub fn seller_freshness(db_conn: &Connection, seller_id: &str) -> Result<Vec<Freshness>> {
+    let mut stm = db_conn.prepare(
+        "select category, kind, file_date, newest_date, imported_at from freshness \
+            where seller_id = ?1 order by category, kind"
+    )?;
+
+    let rows = stm.query_map(params![seller_id], |r| Ok(Freshness {
+        category: r.get(0)?,
+        kind: r.get(1)?,
+        file_date: r.get(2)?,
+        newest_date: r.get(3)?,
+        imported_at: r.get(4)?,
+    }))?
+    .collect::<rusqlite::Result<Vec<Freshness>>>()?;
+
+    Ok(rows)
+}
+
+/// Warn about configured sellers whose data is older than `max_age_days`, or
+/// who have none. Warnings are sent to notification channels as well. Returns
+/// number of stale sellers.
+pub fn freshness_check(config: &Config, db_conn: &Connection) -> Result<usize> {
+    let max_age = match config.freshness.max_age_days {
+        Some(d) => d,
+        None => return Ok(0),
+    };
+
+    let today = Local::now().date_naive();
+    let mut stale = vec![];
+
+    for s in config.seller.iter() {
+        let newest = seller_freshness(db_conn, &s.id)?.iter()
+            .filter_map(|f| f.date())
+            .max();
+
+        match newest {
+            Some(d) if (today - d).num_days() > max_age => stale.push(format!("Seller {} ({}): newest data \
+                is from {}, {} days old", s.id, s.name, d, (today - d).num_days())),
+            Some(_) => (),
+            None => stale.push(format!("Seller {} ({}): no imported data", s.id, s.name)),
+        }
+    }
+
+    if stale.is_empty() {
+        info!("Data of all sellers is newer than {} days", max_age);
+        return Ok(0)
+    }
+
+    for s in stale.iter() {
+        warn!("{}", s);
+    }
+
+    send_warning(config, &format!("Data older than {} days:\n{}", max_age, stale.join("\n")));
+
+    Ok(stale.len())
+}
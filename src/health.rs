@@ -0,0 +1,91 @@
+use std::collections::BTreeMap;
+use anyhow::Result;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::db::open_read_only_db;
+use crate::error::{EXIT_DB, EXIT_FAILURE};
+use crate::runs::pid_alive;
+
+
+// State of the databases and imports, for container probes and uptime
+// monitors
+#[derive(Debug, Serialize)]
+pub struct Health {
+    pub healthy: bool,
+    pub ready: bool,
+    // "ok" or the error opening or reading the database
+    pub databases: BTreeMap<&'static str, String>,
+    pub last_import: Option<String>,
+    pub import_running: bool,
+    pub import_started_at: Option<String>,
+    // Runs that crashed, the next run resumes them
+    pub unfinished_runs: usize,
+}
+
+impl Health {
+    /// Zero when healthy, and ready as well if `ready` is asked.
+    pub fn exit_code(&self, ready: bool) -> i32 {
+        match (self.healthy, self.ready) {
+            (false, _) => EXIT_DB,
+            (true, false) if ready => EXIT_FAILURE,
+            _ => 0,
+        }
+    }
+}
+
+fn runs(conn: &Connection, health: &mut Health) -> Result<()> {
+    health.last_import = conn.query_row("select max(finished_at) from runs", [], |r| r.get(0))?;
+
+    let mut stm = conn.prepare("select started_at, pid from runs where finished_at is null order by id")?;
+    let unfinished = stm.query_map(params![], |r| Ok((r.get::<_, String>(0)?, r.get::<_, Option<i64>>(1)?)))?
+        .collect::<rusqlite::Result<Vec<(String, Option<i64>)>>>()?;
+
+    for (started_at, pid) in unfinished {
+        match pid.is_some_and(pid_alive) {
+            true => {
+                health.import_running = true;
+                health.import_started_at = Some(started_at);
+            },
+            false => health.unfinished_runs += 1,
+        }
+    }
+
+    Ok(())
+}
+
+/// Databases can be opened and read, and state of the import runs. Ready once
+/// an import has finished, data is there to be looked up.
+pub fn health(config: &Config) -> Health {
+    let mut health = Health {
+        healthy: false,
+        ready: false,
+        databases: BTreeMap::new(),
+        last_import: None,
+        import_running: false,
+        import_started_at: None,
+        unfinished_runs: 0,
+    };
+
+    for name in ["sellers.db", "buyers.db"] {
+        let checked = open_read_only_db(config, name).map_err(anyhow::Error::from).and_then(|c| {
+            c.query_row("select count(*) from sqlite_master", [], |r| r.get::<_, i64>(0))?;
+
+            match name {
+                "sellers.db" => runs(&c, &mut health),
+                _ => Ok(()),
+            }
+        });
+
+        health.databases.insert(name, match checked {
+            Ok(_) => String::from("ok"),
+            Err(e) => format!("{:#}", e),
+        });
+    }
+
+    health.healthy = health.databases.values().all(|s| s.eq("ok"));
+    health.ready = health.healthy && health.last_import.is_some();
+
+    health
+}
@@ -0,0 +1,114 @@
+use std::fs::{copy, read, write, File};
+use std::io::{stdin, Read};
+use std::path::PathBuf;
+use anyhow::{anyhow, bail, Result};
+use log::info;
+use rand::distributions::{Alphanumeric, DistString};
+use rusqlite::Connection;
+
+use crate::config::Config;
+use crate::edi::{EdiType, ImportOptions, EDI_DIR_NAME};
+use crate::files::{file_to_edi_utf8, RunTemp};
+use crate::unzip::unzip_handler;
+use crate::upload::{audit_result, UploadAudit};
+
+
+/// Push one file from anywhere on the disk (or stdin when path is '-') through
+/// the import. Original file is left untouched and recorded in the upload audit
+/// log with the sender's address when given. Returns true if products were
+/// imported.
+pub fn import_file(config: &Config, path: &PathBuf, opts: &ImportOptions, remote_addr: Option<String>,
+    db_sellers: &mut Connection, db_buyers: &mut Connection, log: &mut File)
+-> Result<bool> {
+    let from_stdin = path.as_os_str().eq("-");
+
+    if !from_stdin && !path.is_file() {
+        bail!("File {:?} does not exist", path)
+    }
+
+    let randy = Alphanumeric.sample_string(&mut rand::thread_rng(), 10);
+    let temp = RunTemp::new(config)?;
+
+    let (name, work_copy) = match from_stdin {
+        true => {
+            let mut buf = vec![];
+            stdin().read_to_end(&mut buf).map_err(|e|anyhow!("Failed to read stdin: {}", e))?;
+
+            if buf.is_empty() {
+                bail!("Nothing to import from stdin")
+            }
+
+            // Zip archives start with local file header signature
+            let name = match buf.starts_with(b"PK\x03\x04") {
+                true => String::from("stdin.zip"),
+                false => String::from("stdin.txt"),
+            };
+
+            let mut work_copy = temp.path.to_owned();
+            work_copy.push(format!("{}-{}.in", randy, &name));
+
+            write(&work_copy, buf).map_err(|e|anyhow!("Failed to write stdin to file: {}", e))?;
+
+            (name, work_copy)
+        },
+        false => {
+            let name = match path.file_name() {
+                Some(n) => n.to_string_lossy().into_owned(),
+                None => bail!("Unable to read file name from {:?}", path),
+            };
+
+            let mut work_copy = temp.path.to_owned();
+            work_copy.push(format!("{}-{}.in", randy, &name));
+
+            copy(path, &work_copy).map_err(|e|anyhow!("Failed to copy {:?} for import: {}", path, e))?;
+
+            (name, work_copy)
+        },
+    };
+
+    let mut audit = UploadAudit::new("import-file", remote_addr, &name, &read(&work_copy)?);
+
+    let prepared = (|| -> Result<(PathBuf, String)> {
+        // Zipped files are unpacked same way as downloads
+        let (source, name) = match name.ends_with(".zip") {
+            true => {
+                let t = unzip_handler(&work_copy, &temp.path, &config.import);
+                std::fs::remove_file(&work_copy)?;
+                t?
+            },
+            false => (work_copy, name),
+        };
+
+        // Imported from the temp dir, pipeline runs have dirs of their own
+        let rename = format!("{}-{}", randy, &name);
+        let edi_file = file_to_edi_utf8(&source, &temp.path, Some(rename.to_owned()))
+            .and_then(|p| temp.persist(&p, &temp.path.join(EDI_DIR_NAME)))?;
+
+        Ok((edi_file, rename))
+    })();
+
+    let (edi_file, rename) = match prepared {
+        Ok(v) => v,
+        Err(e) => {
+            audit.record(db_buyers, "rejected", Some(format!("{:#}", e)));
+            return Err(e)
+        },
+    };
+
+    audit.parties(&edi_file, &config.seller_ids);
+
+    info!("Importing {:?} as {}...", path, &rename);
+
+    let imported = EdiType::file_import(&edi_file, &rename, config, db_sellers, db_buyers, log, opts);
+
+    match &imported {
+        Ok(t) => audit.record(db_buyers, audit_result(t), None),
+        Err(e) => audit.record(db_buyers, "failed", Some(format!("{:#}", e))),
+    }
+
+    match imported? {
+        EdiType::Product(b) => Ok(b),
+        EdiType::Invalid => bail!("File {:?} was not recognized as EDI file", path),
+        _ => Ok(false),
+    }
+}
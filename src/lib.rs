@@ -0,0 +1,41 @@
+//! Parsers and importers of LVI-Info EDI product, price and discount files.
+//!
+//! Library users get the line parsers and file readers of `edi::parser`, kind
+//! detection with `edi::detect` and the typed failures of `error`. Other
+//! modules are the internals of the `lvisweb-ediparser` binary and may change
+//! without notice.
+
+pub mod config;
+pub mod edi;
+pub mod error;
+pub mod utils;
+
+#[doc(hidden)] pub mod aliases;
+#[doc(hidden)] pub mod bench;
+#[doc(hidden)] pub mod cli;
+#[doc(hidden)] pub mod completions;
+#[doc(hidden)] pub mod db;
+#[doc(hidden)] pub mod demo;
+#[doc(hidden)] pub mod discover;
+#[doc(hidden)] pub mod download;
+#[doc(hidden)] pub mod etim;
+#[doc(hidden)] pub mod files;
+#[doc(hidden)] pub mod fixture;
+#[doc(hidden)] pub mod freshness;
+#[doc(hidden)] pub mod health;
+#[doc(hidden)] pub mod import_file;
+#[doc(hidden)] pub mod lookup;
+#[doc(hidden)] pub mod notify;
+#[doc(hidden)] pub mod postgres;
+#[doc(hidden)] pub mod registry;
+#[doc(hidden)] pub mod reimport;
+#[doc(hidden)] pub mod runs;
+#[doc(hidden)] pub mod s3;
+#[doc(hidden)] pub mod schema;
+#[doc(hidden)] pub mod search;
+#[doc(hidden)] pub mod show;
+#[doc(hidden)] pub mod stats;
+#[doc(hidden)] pub mod timings;
+#[doc(hidden)] pub mod unzip;
+#[doc(hidden)] pub mod upload;
+#[doc(hidden)] pub mod verify;
@@ -0,0 +1,474 @@
+use std::fmt;
+use std::fs::read_to_string;
+use std::io::{stdin, Read};
+use std::path::PathBuf;
+use anyhow::{anyhow, bail, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::edi::DiscountKind;
+use crate::error::Error;
+
+
+// Seller's offer of a product, with buyer's net price when buyer is known
+#[derive(Debug, Serialize)]
+pub struct Offer {
+    pub seller_id: String,
+    pub seller: String,
+    pub product_id: String,
+    pub category: String,
+    pub unit: String,
+    pub discount_group: String,
+    pub price_group: Option<String>,
+    pub list_price: Option<f64>,
+    pub units_incl: Option<i64>,
+    pub discount_percent: Option<f64>,
+    pub net_price: Option<f64>,
+}
+
+// Product with its price from seller's catalog
+#[derive(Debug)]
+pub struct CatalogPrice {
+    pub unit: String,
+    pub discount_group: String,
+    pub price_group: String,
+    pub list_price: f64,
+    pub price_unit: String,
+    pub units_incl: i64,
+    // Packaging sizes 1-3 with their discount percents, zero when not given
+    pub packaging: [(f64, f64); 3],
+}
+
+impl CatalogPrice {
+    /// Discount percent of the largest packaging size the quantity reaches,
+    /// none if it doesn't reach any.
+    pub fn packaging_percent(&self, quantity: f64) -> Option<f64> {
+        self.packaging.iter()
+            .filter(|(size, _)| *size > 0.0 && quantity >= *size)
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, percent)| *percent)
+    }
+}
+
+// Buyer's discount of a discount group
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BuyerDiscount {
+    pub percent_1: f64,
+    pub percent_2: f64,
+    pub kind: DiscountKind,
+}
+
+impl BuyerDiscount {
+    /// Discount percent of the quantity. Packaging discount is the bigger one
+    /// of buyer's percent 2 and the price row's discount of the reached size.
+    pub fn percent(&self, price: &CatalogPrice, quantity: f64) -> f64 {
+        let packaging = price.packaging_percent(quantity).map(|p| p.max(self.percent_2));
+
+        self.kind.percent(self.percent_1, packaging)
+    }
+}
+
+// Line of a bulk price request, quantity in product's unit
+#[derive(Debug, Deserialize)]
+pub struct PriceRequest {
+    pub seller: String,
+    pub product: String,
+    #[serde(default = "PriceRequest::default_quantity")]
+    pub quantity: f64,
+}
+
+impl PriceRequest {
+    fn default_quantity() -> f64 { 1.0 }
+}
+
+// Bulk price request of the web shop, buyer is the customer number given by
+// the sellers
+#[derive(Debug, Deserialize)]
+pub struct PricesRequest {
+    pub buyer: Option<String>,
+    pub lines: Vec<PriceRequest>,
+}
+
+// Priced line, products not found have only the error set
+#[derive(Debug, Default, Serialize)]
+pub struct PricedItem {
+    pub seller: String,
+    pub product: String,
+    pub quantity: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub list_price: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub units_incl: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discount_percent: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discount_kind: Option<DiscountKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub net_price: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// One product priced for a buyer step by step, for answering customer pricing
+// questions
+#[derive(Debug)]
+pub struct PriceCheck {
+    pub seller_id: String,
+    pub seller: String,
+    pub customer_number: String,
+    pub product_id: String,
+    pub quantity: f64,
+    pub unit: String,
+    pub price: CatalogPrice,
+    // Buyer's discount of the group, none when buyer has no discount for it
+    pub discount: Option<BuyerDiscount>,
+    pub discount_percent: f64,
+    pub net_price: f64,
+    pub vat_percent: f64,
+    // List price of the price group includes VAT already
+    pub includes_vat: bool,
+    pub net_price_incl_vat: f64,
+    pub total: f64,
+    pub total_incl_vat: f64,
+}
+
+impl fmt::Display for PriceCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let p = &self.price;
+
+        writeln!(f, "{:<16}{} {}", "Seller", self.seller_id, self.seller)?;
+        writeln!(f, "{:<16}{}", "Customer", self.customer_number)?;
+        writeln!(f, "{:<16}{}, {} {}", "Product", self.product_id, self.quantity, self.unit)?;
+        writeln!(f, "{:<16}{:.2} / {} {}, price group {}{}", "List price", p.list_price, p.units_incl,
+            p.price_unit, p.price_group, if self.includes_vat { " (incl. VAT)" } else { "" })?;
+
+        match &self.discount {
+            Some(d) => writeln!(f, "{:<16}group {}, {} % (kind {})", "Discount", p.discount_group,
+                self.discount_percent, d.kind.code())?,
+            None => writeln!(f, "{:<16}group {}, none for the buyer", "Discount", p.discount_group)?,
+        }
+
+        writeln!(f, "{:<16}{:.2}", "Net price", self.net_price)?;
+        writeln!(f, "{:<16}{:.2} (VAT {} %)", "Incl. VAT", self.net_price_incl_vat, self.vat_percent)?;
+        write!(f, "{:<16}{:.2}, incl. VAT {:.2}", "Total", self.total, self.total_incl_vat)
+    }
+}
+
+// Euro amounts are rounded to cents
+fn cents(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
+
+/// List price reduced by discount percent, rounded to cents.
+pub fn net_price(list_price: f64, discount_percent: f64) -> f64 {
+    ((100.0 - discount_percent) / 100.0 * list_price * 100.0).round() / 100.0
+}
+
+/// Buyer's discount of seller's discount group. Buyer is the customer number
+/// given by the seller.
+pub fn buyer_discount(db_buyers: &Connection, buyer: &str, seller_id: &str, discount_group: &str)
+-> Result<Option<BuyerDiscount>> {
+    let discount = db_buyers.query_row(
+        "select d.percent_1, d.percent_2, d.kind from discounts d inner join buyers b on b.id = d.buyer_id \
+            where b.buyer_id = ?1 and d.seller_id = ?2 and d.discount_group = ?3",
+        params![buyer, seller_id, discount_group],
+        |r| Ok(BuyerDiscount {
+            percent_1: r.get(0)?,
+            percent_2: r.get(1)?,
+            kind: DiscountKind::from_code(&r.get::<_, String>(2)?).unwrap_or_default(),
+        })
+    ).optional()?;
+
+    Ok(discount)
+}
+
+/// Find product of the seller from any category together with its price.
+pub fn catalog_price(db_sellers: &Connection, seller_id: &str, product: &str) -> Result<Option<CatalogPrice>> {
+    let id = format!("{}{}", seller_id, product);
+
+    let row = db_sellers.query_row(
+        "select p.unit, p.discount_group, r.price_group, r.price, r.unit, r.units_incl, r.packaging_1, \
+            r.packaging_1_discount, r.packaging_2, r.packaging_2_discount, r.packaging_3, r.packaging_3_discount \
+            from products_entries p inner join prices_entries r on r.id = p.id where p.id = ?1",
+        params![&id],
+        |r| Ok(CatalogPrice {
+            unit: r.get(0)?,
+            discount_group: r.get(1)?,
+            price_group: r.get(2)?,
+            list_price: r.get(3)?,
+            price_unit: r.get(4)?,
+            units_incl: r.get::<_, i64>(5)?.max(1),
+            packaging: [
+                (r.get::<_, Option<f64>>(6)?.unwrap_or(0.0), r.get::<_, Option<f64>>(7)?.unwrap_or(0.0)),
+                (r.get::<_, Option<f64>>(8)?.unwrap_or(0.0), r.get::<_, Option<f64>>(9)?.unwrap_or(0.0)),
+                (r.get::<_, Option<f64>>(10)?.unwrap_or(0.0), r.get::<_, Option<f64>>(11)?.unwrap_or(0.0)),
+            ],
+        })
+    ).optional()?;
+
+    Ok(row)
+}
+
+/// Price of one product of the seller for a buyer, identified by the uuid of
+/// the buyer's customer account at the seller. Net price includes VAT of the
+/// buyer unless the list price has it already.
+pub fn price_check(db_sellers: &Connection, db_buyers: &Connection, buyer: &str, seller_id: &str, product: &str,
+    quantity: f64)
+-> Result<PriceCheck> {
+    if !quantity.is_finite() || quantity <= 0.0 {
+        bail!("Invalid quantity {}", quantity)
+    }
+
+    let account = db_buyers.query_row(
+        "select id, buyer_id, vat_percent from buyers where uuid = ?1",
+        params![buyer],
+        |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?, r.get::<_, f64>(2)?))
+    ).optional()?;
+
+    let (customer_number, vat_percent) = match account {
+        Some((id, number, vat)) if id.eq(&format!("{}{}", number, seller_id)) => (number, vat),
+        Some(_) => bail!("Buyer {} is not a customer of seller {}", buyer, seller_id),
+        None => bail!("Buyer {} not found", buyer),
+    };
+
+    let seller = db_sellers.query_row("select name from sellers where id = ?1", params![seller_id], |r| r.get(0))
+        .optional()?
+        .ok_or_else(|| anyhow!("Seller {} not found", seller_id))?;
+
+    let price = catalog_price(db_sellers, seller_id, product)?
+        .ok_or_else(|| anyhow!("Product {} of seller {} with price not found", product, seller_id))?;
+
+    let includes_vat = db_sellers.query_row(
+        "select includes_vat from price_groups where id = ?1", params![&price.price_group], |r| r.get(0)
+    ).optional()?.unwrap_or(false);
+
+    let discount = buyer_discount(db_buyers, &customer_number, seller_id, &price.discount_group)?;
+    let discount_percent = discount.map_or(0.0, |d| d.percent(&price, quantity));
+    let net = net_price(price.list_price, discount_percent);
+
+    let with_vat = |v: f64| match includes_vat {
+        true => v,
+        false => cents(v * (100.0 + vat_percent) / 100.0),
+    };
+    // Price is for units_incl units of the product
+    let total = cents(net * quantity / price.units_incl as f64);
+
+    Ok(PriceCheck {
+        seller_id: seller_id.to_owned(),
+        seller,
+        customer_number,
+        product_id: product.to_owned(),
+        quantity,
+        unit: price.unit.to_owned(),
+        discount,
+        discount_percent,
+        net_price: net,
+        vat_percent,
+        includes_vat,
+        net_price_incl_vat: with_vat(net),
+        total,
+        total_incl_vat: with_vat(total),
+        price,
+    })
+}
+
+/// Read bulk price request from a JSON file, or stdin when path is '-'.
+pub fn prices_request(input: &PathBuf) -> Result<PricesRequest> {
+    let json = match input.as_os_str().eq("-") {
+        true => {
+            let mut s = String::new();
+            stdin().read_to_string(&mut s).map_err(|e| anyhow!("Failed to read request from stdin: {}", e))?;
+            s
+        },
+        false => read_to_string(input).map_err(|e| anyhow!("Failed to read request {:?}: {}", input, e))?,
+    };
+
+    let request = serde_json::from_str::<PricesRequest>(&json)
+        .map_err(|e| Error::parse(input, Some(e.line()), e.to_string()))?;
+
+    Ok(request)
+}
+
+/// Net prices of many products at once, for pricing a shopping cart. Lines not
+/// found are returned with an error instead of failing the whole request.
+pub fn bulk_prices(db_sellers: &Connection, db_buyers: &Connection, request: &PricesRequest)
+-> Result<Vec<PricedItem>> {
+    let mut items = vec![];
+
+    for l in request.lines.iter() {
+        let mut item = PricedItem {
+            seller: l.seller.to_owned(),
+            product: l.product.to_owned(),
+            quantity: l.quantity,
+            ..Default::default()
+        };
+
+        if !l.quantity.is_finite() || l.quantity <= 0.0 {
+            item.error = Some(format!("Invalid quantity {}", l.quantity));
+            items.push(item);
+            continue
+        }
+
+        let price = match catalog_price(db_sellers, &l.seller, &l.product)? {
+            Some(p) => p,
+            None => {
+                item.error = Some(String::from("Product with price not found"));
+                items.push(item);
+                continue
+            },
+        };
+
+        let discount = match &request.buyer {
+            Some(b) => buyer_discount(db_buyers, b, &l.seller, &price.discount_group)?,
+            None => None,
+        }
+        .unwrap_or_default();
+
+        let percent = discount.percent(&price, l.quantity);
+        let net = net_price(price.list_price, percent);
+
+        item.unit = Some(price.unit);
+        item.list_price = Some(price.list_price);
+        item.units_incl = Some(price.units_incl);
+        item.discount_percent = Some(percent);
+        item.discount_kind = request.buyer.as_ref().map(|_| discount.kind);
+        item.net_price = Some(net);
+        // Price is for units_incl units of the product
+        item.total = Some((net * l.quantity / price.units_incl as f64 * 100.0).round() / 100.0);
+
+        items.push(item);
+    }
+
+    Ok(items)
+}
+
+/// Offers of all sellers for a scanned EAN code, cheapest net (or list) price
+/// first. Net prices are filled in when buyer is given and has a discount.
+pub fn ean_offers(db_sellers: &Connection, db_buyers: &Connection, ean: &str, buyer: Option<&str>)
+-> Result<Vec<Offer>> {
+    let ean = ean.trim();
+
+    if ean.is_empty() || !ean.chars().all(|c| c.is_ascii_digit()) {
+        bail!("EAN code '{}' should contain only digits", ean)
+    }
+
+    let mut stm = db_sellers.prepare(
+        "select p.seller_id, s.name, p.product_id, p.category, p.unit, p.discount_group, r.price_group, r.price, \
+            r.units_incl from products_entries p inner join sellers s on s.id = p.seller_id \
+            left join prices_entries r on r.id = p.id where p.ean_code = ?1"
+    )?;
+
+    let mut offers = stm.query_map(params![ean], |r| Ok(Offer {
+        seller_id: r.get(0)?,
+        seller: r.get(1)?,
+        product_id: r.get(2)?,
+        category: r.get(3)?,
+        unit: r.get(4)?,
+        discount_group: r.get(5)?,
+        price_group: r.get(6)?,
+        list_price: r.get(7)?,
+        units_incl: r.get(8)?,
+        discount_percent: None,
+        net_price: None,
+    }))?
+    .collect::<rusqlite::Result<Vec<Offer>>>()?;
+
+    if let Some(b) = buyer {
+        for o in offers.iter_mut() {
+            if let Some(p) = o.list_price {
+                o.discount_percent = buyer_discount(db_buyers, b, &o.seller_id, &o.discount_group)?
+                    .map(|d| d.percent_1);
+                o.net_price = o.discount_percent.map(|d| net_price(p, d));
+            }
+        }
+    }
+
+    offers.sort_by(|a, b| {
+        let price = |o: &Offer| o.net_price.or(o.list_price).unwrap_or(f64::MAX);
+        price(a).total_cmp(&price(b))
+    });
+
+    Ok(offers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn price() -> CatalogPrice {
+        CatalogPrice {
+            unit: "KPL".to_string(),
+            discount_group: "A1".to_string(),
+            price_group: String::new(),
+            list_price: 100.0,
+            price_unit: "KPL".to_string(),
+            units_incl: 1,
+            packaging: [(10.0, 5.0), (50.0, 8.0), (100.0, 12.0)],
+        }
+    }
+
+    fn assert_percent(got: f64, want: f64, quantity: f64) {
+        assert!((got - want).abs() < 1e-9, "quantity {}: got {}, want {}", quantity, got, want);
+    }
+
+    #[test]
+    fn packaging_percent_at_sizes() {
+        let p = price();
+
+        for (quantity, want) in [(9.99, None), (10.0, Some(5.0)), (10.01, Some(5.0)),
+            (49.99, Some(5.0)), (50.0, Some(8.0)), (50.01, Some(8.0)),
+            (99.99, Some(8.0)), (100.0, Some(12.0)), (100.01, Some(12.0))] {
+            assert_eq!(p.packaging_percent(quantity), want, "quantity {}", quantity);
+        }
+    }
+
+    #[test]
+    fn packaging_percent_skips_missing_sizes() {
+        let mut p = price();
+        p.packaging = [(0.0, 0.0), (50.0, 8.0), (0.0, 0.0)];
+
+        assert_eq!(p.packaging_percent(0.0), None);
+        assert_eq!(p.packaging_percent(49.99), None);
+        assert_eq!(p.packaging_percent(50.0), Some(8.0));
+    }
+
+    #[test]
+    fn cumulative_packaging_discount() {
+        let p = price();
+        let d = BuyerDiscount { percent_1: 20.0, percent_2: 6.0, kind: DiscountKind::CumulativePackaging };
+
+        // Buyer's percent 2 wins over the smallest size, rows' percents over the rest
+        for (quantity, want) in [(9.99, 20.0), (10.0, 24.8), (10.01, 24.8),
+            (49.99, 24.8), (50.0, 26.4), (50.01, 26.4),
+            (99.99, 26.4), (100.0, 29.6), (100.01, 29.6)] {
+            assert_percent(d.percent(&p, quantity), want, quantity);
+        }
+    }
+
+    #[test]
+    fn packaging_discount_larger_wins() {
+        let p = price();
+        let d = BuyerDiscount { percent_1: 9.0, percent_2: 6.0, kind: DiscountKind::Packaging };
+
+        // Percent 1 wins until the largest size gives more
+        for (quantity, want) in [(9.99, 9.0), (10.0, 9.0), (10.01, 9.0),
+            (49.99, 9.0), (50.0, 9.0), (50.01, 9.0),
+            (99.99, 9.0), (100.0, 12.0), (100.01, 12.0)] {
+            assert_percent(d.percent(&p, quantity), want, quantity);
+        }
+    }
+
+    #[test]
+    fn plain_discount_ignores_packaging() {
+        let p = price();
+        let d = BuyerDiscount { percent_1: 9.0, percent_2: 6.0, kind: DiscountKind::Discount };
+
+        for quantity in [9.99, 10.0, 50.0, 100.0, 100.01] {
+            assert_percent(d.percent(&p, quantity), 9.0, quantity);
+        }
+    }
+}
@@ -1,44 +1,457 @@
-mod download;
-mod utils;
-mod config;
-mod db;
-mod unzip;
-mod files;
-mod edi;
-mod upload;
-mod search;
-
-use std::fs::{create_dir_all, read_dir, File};
+use std::fs::{create_dir_all, read_dir, remove_file, File};
+use std::io::stdout;
 use std::path::PathBuf;
 use std::process::exit;
-use log::{debug, error, info};
+use std::time::Instant;
+use log::{debug, error, info, warn};
 
-use download::bulk_download;
-use config::Config;
-use unzip::unzip_from;
-use edi::{EdiType, DOWNLOAD_DIR_NAME};
-use upload::read_uploads;
+use lvisweb_ediparser::{aliases, bench, completions, db, demo, edi, fixture, freshness, health, lookup,
+    postgres, runs, s3, schema, search, show, stats, timings, verify};
+use lvisweb_ediparser::download::{bulk_download, downloads_confirm, pending_archives, seller_mismatch};
+use lvisweb_ediparser::config::{Config, MismatchPolicy};
+use lvisweb_ediparser::unzip::unzip_from;
+use lvisweb_ediparser::edi::{EdiHeader, EdiType, ImportOptions, DOWNLOAD_DIR_NAME};
+use lvisweb_ediparser::upload::{audit_result, read_uploads};
+use rusqlite::Connection;
 
-use crate::search::search_index_builder;
+use lvisweb_ediparser::cli::{Args, Command, RunFilter};
+use lvisweb_ediparser::error::{Error, ErrorKind, EXIT_CONFIG, EXIT_DB, EXIT_FAILURE, EXIT_PARTIAL};
+use lvisweb_ediparser::edi::orders::order_writer;
+use lvisweb_ediparser::etim::etim_import;
+use lvisweb_ediparser::registry::{registry_import, Registry};
+use lvisweb_ediparser::import_file::import_file;
+use lvisweb_ediparser::reimport::reimport;
+use lvisweb_ediparser::search::search_index_builder;
+use lvisweb_ediparser::utils::Lang;
+use lvisweb_ediparser::notify::{send_failure, FailureDetails};
+use lvisweb_ediparser::files::{open_import_log, temp_cleanup};
 
 
 fn main() {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    let mut args = match Args::parse() {
+        Ok(a) => a,
+        Err(e) => {
+            env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+            error!("Invalid arguments: {:#}", e);
+            exit(EXIT_CONFIG);
+        }
+    };
+
+    args.init_logger();
+
+    if args.timings {
+        timings::enable();
+    }
+
+    // Commands that don't need the data directory
+    match &args.command {
+        Command::Help => {
+            print!("{}", completions::help());
+            return
+        },
+        Command::GenFixture(spec) => match fixture::generate(spec) {
+            Ok(_) => return,
+            Err(e) => {
+                error!("Failed to generate fixtures: {:#}", e);
+                exit(EXIT_FAILURE)
+            },
+        },
+        Command::Schema => match serde_json::to_string_pretty(&schema::schema()) {
+            Ok(s) => {
+                println!("{}", s);
+                return
+            },
+            Err(e) => {
+                error!("Failed to serialize schema: {:#}", e);
+                exit(EXIT_FAILURE)
+            },
+        },
+        Command::Completions { shell } => match completions::completions(shell) {
+            Ok(s) => {
+                print!("{}", s);
+                return
+            },
+            Err(e) => {
+                error!("{:#}", e);
+                exit(EXIT_CONFIG)
+            },
+        },
+        _ => (),
+    }
+
+    // Demo runs the regular pipeline on the data dir it prepared
+    let demo = matches!(args.command, Command::Demo { .. });
+
+    if let Command::Demo { out, rows } = &args.command {
+        match demo::prepare(out.as_deref(), *rows) {
+            Ok(d) => {
+                args.source.file = Some(d.join("config.toml").to_string_lossy().into_owned());
+                args.source.dir = Some(d.to_string_lossy().into_owned());
+                args.command = Command::Run(RunFilter::default());
+            },
+            Err(e) => {
+                error!("Failed to prepare demo: {:#}", e);
+                exit(EXIT_FAILURE)
+            },
+        }
+    }
 
-    let config = match Config::new() {
+    let config = match Config::new(&args.source) {
         Ok(c) => c,
         Err(e) => {
-            error!("Failed to read config file: {}", e);
-            std::process::exit(1);
+            error!("Failed to read config file: {:#}", e);
+            exit(EXIT_CONFIG);
         }
     };
 
-    let (mut db_sellers, mut db_buyers) = match db::init(&config) {
+    // Probes only read, databases aren't created nor migrated
+    if let Command::Health { ready } = args.command {
+        let status = health::health(&config);
+
+        match serde_json::to_string_pretty(&status) {
+            Ok(s) => println!("{}", s),
+            Err(e) => error!("Failed to serialize health status: {:#}", e),
+        }
+
+        exit(status.exit_code(ready))
+    }
+
+    // Lookups don't wait for a running import, unless there's nothing to look
+    // up from yet
+    let read_only = args.command.is_read_only() && config.dir.join("sellers.db").is_file()
+        && config.dir.join("buyers.db").is_file();
+    let opened = match read_only {
+        true => db::open_read_only(&config),
+        false => db::init(&config),
+    };
+
+    let (mut db_sellers, mut db_buyers) = match opened {
         Ok(d) => d,
         Err(e) => {
-            error!("Failed to initialize database: {}", e);
-            exit(1);
+            fail(&config, format!("Failed to initialize database: {:#}", e), EXIT_DB)
+        }
+    };
+
+    // Lookups leave files of a running import alone
+    if args.command.is_import() {
+        if let Err(e) = temp_cleanup(&config) {
+            warn!("Failed to clean up leftovers of earlier runs: {:#}", e);
+        }
+    }
+
+    // Opened by the import commands only
+    let mut log = None;
+
+    // Data of seller aliases is merged before anything new is imported
+    let aliases_merged = match args.command.is_import() {
+        true => match aliases::merge_aliases(&config, &mut db_sellers, &mut db_buyers) {
+            Ok(b) => b,
+            Err(e) => fail(&config, format!("Failed to merge seller aliases: {:#}", e), Error::exit_code(&e, None)),
+        },
+        false => false,
+    };
+
+    let maintain = config.import.maintain && args.command.is_import();
+    let manifests = config.import.json && args.command.is_import();
+    let freshness = config.import.sqlite && args.command.is_import();
+    let reconcile = config.import.sqlite && args.command.is_import();
+    let sink = config.s3.as_ref().is_some_and(|s| s.sink.is_some()) && args.command.is_import();
+    let replicate = config.postgres.is_some() && args.command.is_import();
+    let search_check = config.import.search && args.command.is_import();
+
+    let (build_search_index, mut partial) = match args.command {
+        Command::Run(filter) => run(&config, &filter, &mut db_sellers, &mut db_buyers, import_log(&config, &mut log)),
+        Command::Reimport { seller, file } => {
+            match reimport(&config, &seller, file.as_ref(), &mut db_sellers, &mut db_buyers,
+                import_log(&config, &mut log)) {
+                Ok(b) => (b, false),
+                Err(e) => {
+                    fail_on(&config, format!("Reimport failed: {:#}", e), &e, None, Error::exit_code(&e, None))
+                }
+            }
+        },
+        Command::ImportFile { path, kind, seller, remote_addr } => {
+            let opts = ImportOptions { force: true, kind, seller };
+
+            match import_file(&config, &PathBuf::from(&path), &opts, remote_addr, &mut db_sellers, &mut db_buyers,
+                import_log(&config, &mut log)) {
+                Ok(b) => (b, false),
+                Err(e) => {
+                    fail_on(&config, format!("Failed to import file {}: {:#}", path, e), &e, Some(&PathBuf::from(path)),
+                        Error::exit_code(&e, None))
+                }
+            }
+        },
+        Command::ImportRegistry { path, registry } => {
+            // Parser makes sure registry is set
+            let registry = registry.unwrap_or(Registry::Lvi);
+
+            match registry_import(&PathBuf::from(&path), registry, &mut db_sellers) {
+                Ok(_) => (false, false),
+                Err(e) => fail(&config, format!("Failed to import registry file {}: {:#}", path, e),
+                    Error::exit_code(&e, None)),
+            }
+        },
+        Command::ImportEtim { path, seller } => {
+            match etim_import(&config, &PathBuf::from(&path), seller.as_ref(), &mut db_sellers,
+                import_log(&config, &mut log)) {
+                Ok(_) => (false, false),
+                Err(e) => fail(&config, format!("Failed to import ETIM file {}: {:#}", path, e),
+                    Error::exit_code(&e, None)),
+            }
+        },
+        Command::Order { input, out } => {
+            let out = out.map(PathBuf::from);
+
+            match order_writer(&config, &PathBuf::from(&input), out.as_ref(), &db_sellers, &db_buyers) {
+                Ok(_) => (false, false),
+                Err(e) => fail(&config, format!("Failed to write order: {:#}", e), Error::exit_code(&e, None)),
+            }
+        },
+        Command::Ean { code, buyer } => {
+            match lookup::ean_offers(&db_sellers, &db_buyers, &code, buyer.as_deref()) {
+                Ok(o) if o.is_empty() => fail(&config, format!("No products found with EAN {}", code), EXIT_FAILURE),
+                Ok(o) => match serde_json::to_string_pretty(&o) {
+                    Ok(s) => println!("{}", s),
+                    Err(e) => fail(&config, format!("Failed to serialize offers: {:#}", e), EXIT_FAILURE),
+                },
+                Err(e) => fail(&config, format!("EAN lookup failed: {:#}", e), Error::exit_code(&e, None)),
+            }
+
+            (false, false)
+        },
+        Command::Prices { input } => {
+            let priced = lookup::prices_request(&PathBuf::from(&input))
+                .and_then(|r| lookup::bulk_prices(&db_sellers, &db_buyers, &r));
+
+            match priced.and_then(|p| Ok(serde_json::to_string_pretty(&p)?)) {
+                Ok(s) => println!("{}", s),
+                Err(e) => fail(&config, format!("Price lookup failed: {:#}", e), Error::exit_code(&e, None)),
+            }
+
+            (false, false)
+        },
+        Command::Price { buyer, seller, product, quantity } => {
+            match lookup::price_check(&db_sellers, &db_buyers, &buyer, &seller, &product, quantity) {
+                Ok(p) => println!("{}", p),
+                Err(e) => fail(&config, format!("Price check failed: {:#}", e), Error::exit_code(&e, None)),
+            }
+
+            (false, false)
+        },
+        Command::Show { key } => {
+            match show::show(&db_sellers, &key) {
+                Ok(p) if p.is_empty() => fail(&config, format!("No products found with id or EAN {}", key),
+                    EXIT_FAILURE),
+                Ok(p) => println!("{}", p.iter().map(|p| p.to_string()).collect::<Vec<String>>().join("\n\n")),
+                Err(e) => fail(&config, format!("Product lookup failed: {:#}", e), Error::exit_code(&e, None)),
+            }
+
+            (false, false)
+        },
+        Command::Search { text, lang, limit } => {
+            let lang = match lang {
+                Some(l) => Lang::from_name(&l, &config.custom_langs),
+                None => config.lang_codes.first().cloned().ok_or_else(|| anyhow::anyhow!("No lang_codes configured")),
+            };
+
+            match lang.and_then(|l| search::search(&config, &db_sellers, &text, &l, limit)) {
+                Ok(h) if h.is_empty() => fail(&config, format!("No products found with {}", text), EXIT_FAILURE),
+                Ok(h) => println!("{}", h.iter().map(|h| h.to_string()).collect::<Vec<String>>().join("\n")),
+                Err(e) => fail(&config, format!("Search failed: {:#}", e), Error::exit_code(&e, None)),
+            }
+
+            (false, false)
+        },
+        Command::Stats => {
+            match stats::stats(&db_sellers) {
+                Ok(s) => stats::print(&s),
+                Err(e) => fail(&config, format!("Failed to read statistics: {:#}", e), Error::exit_code(&e, None)),
+            }
+
+            (false, false)
+        },
+        Command::Reindex(filter) => match search::reindex(&config, &mut db_sellers, &filter) {
+            Ok(n) => {
+                info!("Search indexes rebuilt with {} rows", n);
+                (false, false)
+            },
+            Err(e) => fail(&config, format!("Failed to rebuild search index: {:#}", e), Error::exit_code(&e, None)),
+        },
+        Command::Maintain => {
+            for (conn, name) in [(&db_sellers, "sellers.db"), (&db_buyers, "buyers.db")] {
+                if let Err(e) = db::maintain(conn, name) {
+                    fail(&config, format!("Failed to maintain {}: {:#}", name, e), EXIT_DB)
+                }
+            }
+
+            (false, false)
+        },
+        Command::Verify => match verify::verify(&config, &db_sellers) {
+            Ok(0) => (false, false),
+            Ok(n) => fail(&config, format!("Verification found {} discrepancies", n), EXIT_FAILURE),
+            Err(e) => fail(&config, format!("Verification failed: {:#}", e), Error::exit_code(&e, None)),
+        },
+        Command::Bench { rows, keep } => match bench::bench(&config, rows, keep) {
+            Ok(_) => (false, false),
+            Err(e) => fail(&config, format!("Benchmark failed: {:#}", e), Error::exit_code(&e, None)),
+        },
+        Command::Help | Command::GenFixture(_) | Command::Schema | Command::Completions { .. }
+            | Command::Health { .. } | Command::Demo { .. } => unreachable!(),
+    };
+
+    // Seller renames and removals, and builds skipped or failed earlier need
+    // a build without new products too
+    let search_dirty = match search_check {
+        true => match search::dirty_reasons(&config, &db_sellers) {
+            Ok(r) => r,
+            Err(e) => fail(&config, format!("Failed to check search index: {:#}", e),
+                Error::exit_code(&e, Some(ErrorKind::Db))),
+        },
+        false => vec![],
+    };
+
+    if !build_search_index && !aliases_merged && !search_dirty.is_empty() {
+        info!("Search index is out of date: {}", search_dirty.join(", "));
+    }
+
+    // Build search indexes for each product group
+    if config.import.search && (build_search_index || aliases_merged || !search_dirty.is_empty()) {
+        debug!("Building search indexes...");
+        let started = Instant::now();
+
+        if let Err(e) = search_index_builder(&config, &mut db_sellers) {
+            fail(&config, format!("Failed to update search index: {:#}", e),
+                Error::exit_code(&e, Some(ErrorKind::Db)))
+        }
+
+        timings::record_run(timings::Stage::Index, started.elapsed());
+    }
+
+    // Products that still aren't searchable mean the build was skipped
+    if search_check {
+        match search::stale_indexes(&config, &db_sellers) {
+            Ok(s) if s.is_empty() => (),
+            Ok(s) => fail(&config, format!("Search index was not built: {}", s.join(", ")), EXIT_FAILURE),
+            Err(e) => fail(&config, format!("Failed to check search index: {:#}", e),
+                Error::exit_code(&e, Some(ErrorKind::Db))),
+        }
+    }
+
+    // Prices of discontinued products and products left without a price
+    if reconcile {
+        if let Err(e) = edi::orphans_report(&config, &db_sellers) {
+            error!("Failed to reconcile products and prices: {:#}", e);
+        }
+    }
+
+    // Catch supplier feeds that stopped updating without failing anything
+    if freshness {
+        if let Err(e) = freshness::freshness_check(&config, &db_sellers) {
+            error!("Failed to check freshness of imported data: {:#}", e);
+        }
+    }
+
+    // Consumers check the schema version from manifests before loading files
+    if manifests {
+        if let Err(e) = schema::manifest_writer(&config, &db_sellers) {
+            error!("Failed to write JSON manifests: {:#}", e);
+        }
+    }
+
+    // Maintenance is an optimization, failing it doesn't fail the run
+    if maintain {
+        for (conn, name) in [(&db_sellers, "sellers.db"), (&db_buyers, "buyers.db")] {
+            if let Err(e) = db::maintain(conn, name) {
+                error!("Failed to maintain {}: {:#}", name, e);
+            }
+        }
+    }
+
+    // Outputs are already in place locally, a failed upload is retried next run
+    if sink {
+        if let Err(e) = s3::sink_upload(&config, &db_sellers, &db_buyers) {
+            error!("Failed to upload to object storage: {:#}", e);
+            partial = true;
+        }
+    }
+
+    // Unsent changes stay in the outbox, the next run sends them
+    if replicate {
+        if let Err(e) = postgres::replicate(&config, &db_sellers, &db_buyers) {
+            error!("Failed to replicate to Postgres: {:#}", e);
+            partial = true;
         }
+    }
+
+    // Same table goes to the import log for later comparison
+    if timings::is_enabled() {
+        let printed = timings::summary(&mut stdout()).and_then(|_| match log.as_mut() {
+            Some(l) => timings::summary(l),
+            None => Ok(()),
+        });
+
+        if let Err(e) = printed {
+            error!("Failed to print timings: {}", e);
+        }
+    }
+
+    if demo {
+        if let Err(e) = demo::summary(&config, &db_sellers, &db_buyers) {
+            error!("Failed to summarize demo: {:#}", e);
+        }
+    }
+
+    if partial {
+        let msg = "Run finished, but some sources could not be processed, see import.log";
+
+        warn!("{}", msg);
+        send_failure(&config, msg, None);
+        exit(EXIT_PARTIAL)
+    }
+}
+
+// Keep file log of imports for debugging, previous runs are rotated
+fn import_log<'a>(config: &Config, log: &'a mut Option<File>) -> &'a mut File {
+    log.get_or_insert_with(|| match open_import_log(config) {
+        Ok(f) => f,
+        Err(e) => fail(config, format!("Failed to open import log: {:#}", e), EXIT_FAILURE),
+    })
+}
+
+// Log the error, notify if configured and exit
+fn fail(config: &Config, msg: String, code: i32) -> ! {
+    error!("{}", msg);
+    send_failure(config, &msg, None);
+    exit(code)
+}
+
+// Like fail, notifications get the seller, file and line of the error
+fn fail_on(config: &Config, msg: String, e: &anyhow::Error, file: Option<&PathBuf>, code: i32) -> ! {
+    error!("{}", msg);
+    send_failure(config, &msg, Some(&FailureDetails::of(config, e, file)));
+    exit(code)
+}
+
+// Download, unzip and import sources and uploads. Returns true if products
+// were updated and true if some of the sources failed.
+fn run(config: &Config, filter: &RunFilter, db_sellers: &mut Connection, db_buyers: &mut Connection,
+    log: &mut File)
+-> (bool, bool) {
+    if let Some(s) = filter.sellers.iter().find(|s| !config.seller.iter().any(|c| c.id.eq(*s))) {
+        fail(config, format!("Seller {} in run filter is not configured", s), EXIT_CONFIG)
+    }
+
+    // Files of the run are recorded so a crashed run can be resumed
+    let run_id = match runs::run_start(db_sellers) {
+        Ok(i) => i,
+        Err(e) => fail(config, format!("Failed to record run: {:#}", e), EXIT_DB),
+    };
+
+    // Extracted files wait for import in a dir of their own
+    let run_dir = match runs::run_dir(config, run_id) {
+        Ok(p) => p,
+        Err(e) => fail(config, format!("{:#}", e), EXIT_FAILURE),
     };
 
     // Start pulling EDI source files defined for each seller
@@ -46,105 +459,190 @@ fn main() {
     downloads_dir.push(DOWNLOAD_DIR_NAME);
     
     if let Err(e) = create_dir_all(&downloads_dir) {
-        error!("Failed to create downloads dir: {}", e);
-        exit(1);
+        fail(config, format!("Failed to create downloads dir: {:#}", e), EXIT_FAILURE)
     }
 
     // If we have content in downloads dir lets process that before downloading more
     let downloaded_files = match read_dir(&downloads_dir) {
         Ok(c) => c,
         Err(e) => {
-            error!("Failed to read downloads dir: {}", e);
-            exit(1);
+            fail(config, format!("Failed to read downloads dir: {:#}", e), EXIT_FAILURE)
         }
     };
 
-    let mut archives = downloaded_files
+    let archives = downloaded_files
         .into_iter().map(|e|e.unwrap().path())
         .collect::<Vec<PathBuf>>();
 
-    // Empty dir means we have nothing left to process from previous runs, pull EDI content
-    if archives.is_empty() {
-        match bulk_download(&config, &downloads_dir) {
-            Ok(v) => archives.extend(v),
-            Err(e) => {
-                error!("Failed to download zip archives: {}", e);
-                exit(1);
-            },
-        }
-    }
+    // Process leftovers from previous runs, but still download sources that
+    // don't have anything pending. Filtered run leaves other sellers' files be.
+    let mut archives = pending_archives(config, filter, archives);
 
-    let edi_files = match unzip_from(archives, &config) {
-        Ok(v) => v,
+    let mut partial = match bulk_download(config, &downloads_dir, filter, &archives, db_sellers) {
+        Ok((v, failed)) => {
+            archives.extend(v);
+            failed > 0
+        },
+        Err(e) => {
+            fail_on(config, format!("Failed to download zip archives: {:#}", e), &e, None,
+                Error::exit_code(&e, Some(ErrorKind::Download)))
+        },
+    };
+
+    let archive_count = archives.len();
+
+    // Extracted files a crashed run didn't get to import go first
+    let mut edi_files = match runs::resumable_files(db_sellers) {
+        Ok(v) => v.into_iter()
+            .filter(|(p, _, _)| match EdiHeader::read(p, &config.seller_ids).map(|h| h.seller) {
+                Ok(Some(s)) => filter.has_seller(&s.id),
+                _ => !filter.is_active(),
+            })
+            .collect::<Vec<_>>(),
+        Err(e) => {
+            error!("Failed to read files of unfinished runs: {:#}", e);
+            vec![]
+        },
+    };
+
+    let resumed = edi_files.len();
+
+    match unzip_from(archives, config, &run_dir) {
+        Ok(v) => edi_files.extend(v),
         Err(e) => {
-            error!("Failed to unzip downloaded files: {}", e);
-            exit(1);
+            fail(config, format!("Failed to unzip downloaded files: {:#}", e), EXIT_FAILURE)
         }
     };
 
-    // Keep file log for debugging
-    let mut log_path = config.dir.to_owned();
-    log_path.push("import.log");
-    
-    // Open log file for writing
-    let mut log = File::create(&log_path).unwrap();
-    
+    // Broken archives are skipped by unzip
+    if edi_files.len() - resumed < archive_count {
+        partial = true;
+    }
+
+    if let Err(e) = runs::run_files_add(db_sellers, run_id, &edi_files) {
+        error!("Failed to record files of the run: {:#}", e);
+    }
 
     // Process downloaded EDI files
+    let opts = ImportOptions::default();
     let mut build_search_index = false;
 
-    for (path, filename) in edi_files {
-        // Search index updating is pointless without new products.
-        match EdiType::file_import(&path, &filename, &config, &mut db_sellers, &mut db_buyers, &mut log) {
-            Ok(t) => match t {
-                EdiType::Product(b) => {
-                    if !build_search_index && b {
-                        build_search_index = true;
+    let mut imported = vec![];
+
+    for (path, filename, archive) in edi_files {
+        // Crashed run got this far already with the same content
+        if let Ok(true) = runs::committed_earlier(db_sellers, run_id, &path) {
+            info!("EDI file '{}' was imported by an unfinished run already, skipping", filename);
+
+            if let Err(e) = remove_file(&path) {
+                error!("Failed to delete imported EDI file {:?}: {}", path, e);
+            }
+
+            run_file_done(db_sellers, run_id, &path, "skipped");
+            continue;
+        }
+
+        // Filing data under another seller than the source's is likely an
+        // exporter misconfiguration
+        match seller_mismatch(db_sellers, &config.seller_ids, &archive, &path) {
+            Ok(Some((expected, found))) => {
+                let msg = format!("EDI file '{}' downloaded for seller {} has seller {} in its header",
+                    filename, expected, found);
+
+                if config.import.seller_mismatch.eq(&MismatchPolicy::Fail) {
+                    error!("{}, rejecting it", msg);
+
+                    if let Err(e) = remove_file(&path) {
+                        error!("Failed to delete rejected EDI file {:?}: {}", path, e);
                     }
-                },
-                _ => (),
+
+                    run_file_done(db_sellers, run_id, &path, "rejected");
+                    partial = true;
+                    continue;
+                }
+
+                warn!("{}", msg);
+            },
+            Ok(None) => (),
+            Err(e) => error!("Failed to check seller of EDI file '{}': {:#}", filename, e),
+        }
+
+        imported.push((archive, path.to_owned()));
+
+        // Search index updating is pointless without new products.
+        match EdiType::file_import(&path, &filename, config, db_sellers, db_buyers, log, &opts) {
+            Ok(t) => {
+                run_file_done(db_sellers, run_id, &path, "committed");
+
+                if let EdiType::Product(true) = t {
+                    build_search_index = true;
+                }
             },
             Err(e) => {
-                error!("Failed to process EDI file '{}' {:?}: {}", filename, path, e);
-                exit(1)
+                fail_on(config, format!("Failed to process EDI file '{}' {:?}: {:#}", filename, path, e), &e,
+                    Some(&path), Error::exit_code(&e, None))
             }
         }
     }
 
+    // Failed imports have exited already
+    if let Err(e) = downloads_confirm(db_sellers, &imported) {
+        error!("Failed to link downloads to imports: {:#}", e);
+    }
+
+    if filter.is_active() {
+        run_finish(config, db_sellers, run_id);
+        return (build_search_index, partial)
+    }
+
     // Read and prepare upload dir files
-    let edi_files = match read_uploads(&config) {
+    let edi_files = match read_uploads(config, &run_dir, db_buyers) {
         Ok(v) => v,
         Err(e) => {
-            error!("Failed to process uploads: {}", e);
-            exit(1);
+            fail(config, format!("Failed to process uploads: {:#}", e), EXIT_FAILURE)
         }
     };
 
     // Process uploaded EDI files
-    for (path, name) in edi_files {
-        match EdiType::file_import(&path, &name, &config, &mut db_sellers, &mut db_buyers, &mut log) {
+    for (path, name, audit) in edi_files {
+        let imported = EdiType::file_import(&path, &name, config, db_sellers, db_buyers, log, &opts);
+
+        match &imported {
+            Ok(t) => audit.record(db_buyers, audit_result(t), None),
+            Err(e) => audit.record(db_buyers, "failed", Some(format!("{:#}", e))),
+        }
+
+        match imported {
             Ok(t) => match t {
                 EdiType::Discount(b) => {
                     if b {
                         info!("Updated discounts of {} from uploads", name);
                     }
                 },
+                EdiType::Product(true) => build_search_index = true,
                 _ => (),
             },
             Err(e) => {
-                error!("Failed to process EDI file '{}' {:?}: {}", name, path, e);
-                exit(1)
+                fail_on(config, format!("Failed to process EDI file '{}' {:?}: {:#}", name, path, e), &e,
+                    Some(&path), Error::exit_code(&e, None))
             }
         }
     }
 
-    // Build search indexes for each product group
-    if config.import.search && build_search_index {
-        debug!("Building search indexes...");
+    run_finish(config, db_sellers, run_id);
 
-        if let Err(e) = search_index_builder(&config, &mut db_sellers) {
-            error!("Failed to update search index: {}", e);
-            exit(1)
-        }
+    (build_search_index, partial)
+}
+
+// Run bookkeeping failing doesn't fail the run, resuming just isn't possible
+fn run_file_done(db_conn: &Connection, run_id: i64, path: &PathBuf, status: &str) {
+    if let Err(e) = runs::run_file_done(db_conn, run_id, path, status) {
+        error!("Failed to record {} file {:?} of the run: {:#}", status, path, e);
+    }
+}
+
+fn run_finish(config: &Config, db_conn: &Connection, run_id: i64) {
+    if let Err(e) = runs::run_finish(config, db_conn, run_id) {
+        error!("Failed to record run finished: {:#}", e);
     }
 }
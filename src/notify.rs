@@ -0,0 +1,224 @@
+use std::path::PathBuf;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Result};
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::{Message, SmtpTransport, Transport};
+use log::{debug, error};
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::edi::EdiHeader;
+use crate::error::Error;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    // Plaintext, for local relays only
+    None,
+    // STARTTLS when the server offers it, plaintext otherwise
+    Opportunistic,
+    // STARTTLS required
+    Starttls,
+    // TLS from the start (SMTPS, port 465)
+    Tls,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Smtp {
+    // Server as 'host' or 'host:port', port defaults to the one of `tls`
+    pub host: String,
+    #[serde(default = "Smtp::default_tls")]
+    pub tls: SmtpTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // Connect and each command has this long to answer
+    #[serde(default = "Smtp::default_timeout_secs")]
+    pub timeout_secs: u64,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+impl Smtp {
+    fn default_tls() -> SmtpTls { SmtpTls::Opportunistic }
+    fn default_timeout_secs() -> u64 { 30 }
+
+    /// Credentials only go over encrypted connections.
+    pub fn check(&self) -> Result<()> {
+        if self.username.is_some() != self.password.is_some() {
+            bail!("SMTP username and password have to be given together")
+        }
+
+        if self.username.is_some() && matches!(self.tls, SmtpTls::None | SmtpTls::Opportunistic) {
+            bail!("SMTP credentials require tls = \"starttls\" or \"tls\"")
+        }
+
+        self.server()?;
+
+        for a in self.to.iter().chain(std::iter::once(&self.from)) {
+            a.parse::<Mailbox>().map_err(|e| anyhow!("Invalid email address '{}': {}", a, e))?;
+        }
+
+        Ok(())
+    }
+    fn server(&self) -> Result<(&str, u16)> {
+        match self.host.rsplit_once(':') {
+            Some((h, p)) => Ok((h, p.parse().map_err(|_| anyhow!("Invalid SMTP port in '{}'", self.host))?)),
+            None => Ok((self.host.as_str(), match self.tls {
+                SmtpTls::Tls => 465,
+                SmtpTls::Starttls => 587,
+                SmtpTls::None | SmtpTls::Opportunistic => 25,
+            })),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Notify {
+    pub slack_webhook: Option<String>,
+    pub smtp: Option<Smtp>,
+}
+
+/// Where a run failed, read from the structured error of the chain.
+#[derive(Debug, Default)]
+pub struct FailureDetails {
+    pub seller: Option<String>,
+    pub file: Option<PathBuf>,
+    pub line: Option<usize>,
+    pub url: Option<String>,
+}
+
+impl FailureDetails {
+    /// Details of the error, `file` being processed is used when the error
+    /// doesn't name one.
+    pub fn of(config: &Config, e: &anyhow::Error, file: Option<&PathBuf>) -> Self {
+        let mut details = Self { file: file.cloned(), ..Default::default() };
+
+        match e.chain().find_map(|c| c.downcast_ref::<Error>()) {
+            Some(Error::Parse { file, line, .. }) => {
+                details.file = Some(file.to_owned());
+                details.line = *line;
+            },
+            Some(Error::Download { url, .. }) if !url.is_empty() => details.url = Some(url.to_owned()),
+            _ => (),
+        }
+
+        // Header tells the seller as long as the file is still in place
+        details.seller = details.file.as_ref()
+            .and_then(|f| EdiHeader::read(f, &config.seller_ids).ok()?.seller)
+            .map(|s| match config.seller.iter().find(|c| c.id.eq(&s.id)) {
+                Some(c) => format!("{} ({})", c.name, s.id),
+                None => s.id,
+            });
+
+        details
+    }
+    // Labeled values that are known
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![];
+
+        if let Some(s) = &self.seller {
+            fields.push(("Seller", s.to_owned()));
+        }
+
+        if let Some(f) = &self.file {
+            fields.push(("File", f.display().to_string()));
+        }
+
+        if let Some(l) = self.line {
+            fields.push(("Line", l.to_string()));
+        }
+
+        if let Some(u) = &self.url {
+            fields.push(("URL", u.to_owned()));
+        }
+
+        fields
+    }
+}
+
+/// Send run failure summary to every configured channel. Failing to notify
+/// is only logged, we're already on a failure path.
+pub fn send_failure(config: &Config, summary: &str, details: Option<&FailureDetails>) {
+    let fields = details.map(|d| d.fields()).unwrap_or_default();
+
+    send(config, &format!("EDI import failed in {}", config.dir.display()), summary, &fields)
+}
+
+/// Send warnings of a run that didn't fail to every configured channel.
+pub fn send_warning(config: &Config, summary: &str) {
+    send(config, &format!("EDI import warnings in {}", config.dir.display()), summary, &[])
+}
+
+fn send(config: &Config, subject: &str, summary: &str, fields: &[(&str, String)]) {
+    let notify = match &config.notify {
+        Some(n) => n,
+        None => return,
+    };
+
+    if let Some(url) = &notify.slack_webhook {
+        let fields = fields.iter()
+            .map(|(k, v)| serde_json::json!({ "title": k, "value": v, "short": true }))
+            .collect::<Vec<serde_json::Value>>();
+
+        let payload = serde_json::json!({
+            "text": format!("*{}*", subject),
+            "attachments": [{ "color": "danger", "fields": fields, "text": format!("```{}```", summary) }],
+        }).to_string();
+
+        if let Err(e) = ureq::post(url).set("Content-Type", "application/json").send_string(&payload) {
+            error!("Failed to send Slack notification: {}", e);
+        }
+    }
+
+    if let Some(smtp) = &notify.smtp {
+        let mut body = fields.iter().map(|(k, v)| format!("{}: {}\n", k, v)).collect::<String>();
+
+        if !body.is_empty() {
+            body.push('\n');
+        }
+
+        body.push_str(summary);
+
+        if let Err(e) = send_mail(smtp, subject, &body) {
+            error!("Failed to send email notification: {:#}", e);
+        }
+    }
+}
+
+fn send_mail(smtp: &Smtp, subject: &str, body: &str) -> Result<()> {
+    let mut message = Message::builder()
+        .from(smtp.from.parse()?)
+        .subject(subject);
+
+    for t in smtp.to.iter() {
+        message = message.to(t.parse()?);
+    }
+
+    let (host, port) = smtp.server()?;
+    let params = || TlsParameters::new(host.to_owned());
+
+    let tls = match smtp.tls {
+        SmtpTls::None => Tls::None,
+        SmtpTls::Opportunistic => Tls::Opportunistic(params()?),
+        SmtpTls::Starttls => Tls::Required(params()?),
+        SmtpTls::Tls => Tls::Wrapper(params()?),
+    };
+
+    let mut transport = SmtpTransport::builder_dangerous(host)
+        .port(port)
+        .tls(tls)
+        .timeout(Some(Duration::from_secs(smtp.timeout_secs)));
+
+    if let (Some(u), Some(p)) = (&smtp.username, &smtp.password) {
+        transport = transport.credentials(Credentials::new(u.to_owned(), p.to_owned()));
+    }
+
+    transport.build().send(&message.body(body.to_owned())?)?;
+
+    debug!("Notification mailed to {}", smtp.to.join(", "));
+
+    Ok(())
+}
@@ -0,0 +1,245 @@
+use std::str::FromStr;
+use anyhow::{anyhow, bail, Result};
+use log::{debug, info, warn};
+use postgres::config::{Host, SslMode};
+use postgres::{Client, Transaction};
+use rusqlite::{params, Connection, OptionalExtension};
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::config::{Config, Replica};
+
+
+// Tables replicated from a database with their outbox entity and remote name
+const SELLERS_TABLES: [(&str, &str, &str); 2] = [("product", "products_entries", "products"),
+    ("price", "prices_entries", "prices")];
+const BUYERS_TABLES: [(&str, &str, &str); 1] = [("discount", "discounts", "discounts")];
+
+// Loopback and unix sockets, tunnels end up here as well
+fn is_local(host: &Host) -> bool {
+    match host {
+        Host::Tcp(h) => ["localhost", "127.0.0.1", "::1"].contains(&h.as_str()),
+        #[cfg(unix)]
+        Host::Unix(_) => true,
+    }
+}
+
+/// Connect the url, `sslmode` of it decides on TLS and server certificates are
+/// verified against the Mozilla roots. Password defaults to PGPASSWORD, it's
+/// only sent to remote hosts with `sslmode=require` as the server may ask for
+/// it in cleartext.
+fn connect(url: &str) -> Result<Client> {
+    let mut pg = postgres::Config::from_str(url).map_err(|e| anyhow!("Invalid Postgres url: {}", e))?;
+
+    if pg.get_password().is_none() {
+        if let Ok(p) = std::env::var("PGPASSWORD") {
+            pg.password(p);
+        }
+    }
+
+    if pg.get_application_name().is_none() {
+        pg.application_name("lvisweb-ediparser");
+    }
+
+    // Tables and columns already there are noticed on every sync
+    pg.notice_callback(|n| debug!("Postgres: {}", n.message()));
+
+    if pg.get_password().is_some() && pg.get_ssl_mode().ne(&SslMode::Require)
+        && !pg.get_hosts().iter().all(is_local) {
+        bail!("Postgres password would be sent unencrypted, add sslmode=require to the url")
+    }
+
+    pg.connect(MakeRustlsConnect::with_webpki_roots())
+        .map_err(|e| anyhow!("Failed to connect Postgres: {}", e))
+}
+
+// Columns replicated from a local table with their Postgres types. Row
+// timestamps stay local, outbox payloads don't have them.
+fn columns(conn: &Connection, table: &str) -> Result<Vec<(String, &'static str)>> {
+    let mut stm = conn.prepare(&format!("select name, type from pragma_table_info('{}')", table))?;
+    let columns = stm.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))?
+        .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+
+    Ok(columns.into_iter()
+        .filter(|(c, _)| !["created_at", "updated_at"].contains(&c.as_str()))
+        .map(|(c, t)| {
+            let t = match t.to_lowercase().as_str() {
+                "integer" => "bigint",
+                "real" => "double precision",
+                _ => "text",
+            };
+            (c, t)
+        })
+        .collect())
+}
+
+// Remote table with every local column, columns added locally later are added
+// remotely as well
+fn remote_table(pg: &mut Client, remote: &str, columns: &[(String, &str)]) -> Result<()> {
+    let mut sql = format!("create table if not exists {} (id text primary key);", remote);
+
+    for (c, t) in columns.iter().filter(|(c, _)| c.ne("id")) {
+        sql.push_str(&format!("alter table {} add column if not exists {} {};", remote, c, t));
+    }
+
+    pg.batch_execute(&sql)?;
+
+    Ok(())
+}
+
+// Records are given as JSON array of objects in the only parameter
+fn upsert(remote: &str, columns: &[(String, &str)]) -> String {
+    let set = columns.iter()
+        .filter(|(c, _)| c.ne("id"))
+        .map(|(c, _)| format!("{c} = excluded.{c}"))
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    format!("insert into {remote} select * from jsonb_populate_recordset(null::{remote}, $1::text::jsonb) \
+        on conflict (id) do update set {set}")
+}
+
+// Outbox entity, local table, remote table and its columns
+type Replicated<'a> = (&'a str, &'a str, String, Vec<(String, &'static str)>);
+
+// Every row of the tables replaces the remote ones, for the first sync and
+// after outbox rows not sent yet were pruned
+fn full_copy(tx: &mut Transaction, conn: &Connection, tables: &[Replicated], batch: usize) -> Result<usize> {
+    let mut count = 0;
+
+    for (_, local, remote, columns) in tables.iter() {
+        let json = columns.iter().map(|(c, _)| format!("'{c}', {c}")).collect::<Vec<String>>().join(", ");
+        let mut stm = conn.prepare(&format!("select json_object({}) from {}", json, local))?;
+        let rows = stm.query_map([], |r| r.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+
+        tx.batch_execute(&format!("delete from {};", remote))?;
+
+        let upsert = tx.prepare(&upsert(remote, columns))?;
+
+        for chunk in rows.chunks(batch) {
+            tx.execute(&upsert, &[&format!("[{}]", chunk.join(","))])?;
+        }
+
+        debug!("Copied {} rows of {} to Postgres {}", rows.len(), local, remote);
+        count += rows.len();
+    }
+
+    Ok(count)
+}
+
+// Send outbox of a database from the remote cursor on. Each batch is committed
+// with the cursor, so an interrupted sync continues where it stopped.
+fn sync_database(pg: &mut Client, replica: &Replica, conn: &Connection, source: &str,
+    tables: &[(&str, &str, &str)])
+-> Result<usize> {
+    let p = &replica.table_prefix;
+    let mut replicated: Vec<Replicated> = vec![];
+
+    for (entity, local, remote) in tables {
+        let columns = columns(conn, local)?;
+        let remote = format!("{}{}", p, remote);
+
+        remote_table(pg, &remote, &columns)?;
+        replicated.push((*entity, *local, remote, columns));
+    }
+
+    let cursor = pg.query_opt(&format!("select seq from {p}sync where source = $1"), &[&source])?
+        .map(|r| r.get::<_, i64>(0));
+
+    let last: i64 = conn.query_row("select seq from sqlite_sequence where name = 'outbox'", [], |r| r.get(0))
+        .optional()?
+        .unwrap_or(0);
+    let first: Option<i64> = conn.query_row("select min(seq) from outbox where seq > ?1",
+        params![cursor.unwrap_or(0)], |r| r.get(0))?;
+
+    let set_cursor = format!("insert into {p}sync (source, seq) values ($1, $2) \
+        on conflict (source) do update set seq = excluded.seq");
+
+    // Changes between the cursor and the oldest outbox row are gone
+    let gap = match cursor {
+        None => true,
+        Some(c) => c < last && first.is_none_or(|f| f > c + 1),
+    };
+
+    if gap {
+        if cursor.is_some() {
+            warn!("Outbox of {}.db was pruned past the Postgres cursor, copying all rows", source);
+        }
+
+        let mut tx = pg.transaction()?;
+        let count = full_copy(&mut tx, conn, &replicated, replica.batch_size)?;
+        tx.execute(&set_cursor, &[&source, &last])?;
+        tx.commit()?;
+
+        info!("Copied {} rows of {}.db to Postgres", count, source);
+
+        return Ok(count)
+    }
+
+    let mut cursor = cursor.unwrap_or(0);
+    let mut count = 0;
+    let mut stm = conn.prepare("select seq, entity, id, op, payload from outbox where seq > ?1 order by seq limit ?2")?;
+
+    loop {
+        let rows = stm.query_map(params![cursor, replica.batch_size], |r| Ok((
+            r.get::<_, i64>(0)?, r.get::<_, String>(1)?, r.get::<_, String>(2)?, r.get::<_, String>(3)?,
+            r.get::<_, String>(4)?
+        )))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let (seq, ..) = match rows.last() {
+            Some(r) => r,
+            None => break,
+        };
+
+        let mut tx = pg.transaction()?;
+
+        for (_, entity, id, op, payload) in rows.iter() {
+            // Entities of the other database or no longer replicated
+            let (_, _, remote, columns) = match replicated.iter().find(|(e, ..)| e.eq(entity)) {
+                Some(r) => r,
+                None => continue,
+            };
+
+            match op.as_str() {
+                "delete" => tx.execute(&format!("delete from {} where id = $1", remote), &[id])?,
+                _ => tx.execute(&upsert(remote, columns), &[&format!("[{}]", payload)])?,
+            };
+        }
+
+        tx.execute(&set_cursor, &[&source, seq])?;
+        tx.commit()?;
+
+        cursor = *seq;
+        count += rows.len();
+    }
+
+    if count > 0 {
+        info!("Replicated {} changes of {}.db to Postgres", count, source);
+    }
+
+    Ok(count)
+}
+
+/// Push changes of products, prices and discounts to the configured Postgres.
+/// Tables are created remotely on first sync and filled with every row, after
+/// that changes are sent from the outbox tables. Returns number of rows and
+/// changes sent.
+pub fn replicate(config: &Config, db_sellers: &Connection, db_buyers: &Connection) -> Result<usize> {
+    let replica = match &config.postgres {
+        Some(r) => r,
+        None => return Ok(0),
+    };
+
+    let mut pg = connect(&replica.url)?;
+
+    pg.batch_execute(&format!("create table if not exists {}sync (source text primary key, seq bigint not null)",
+        replica.table_prefix))?;
+
+    let count = sync_database(&mut pg, replica, db_sellers, "sellers", &SELLERS_TABLES)?
+        + sync_database(&mut pg, replica, db_buyers, "buyers", &BUYERS_TABLES)?;
+
+    pg.close()?;
+
+    Ok(count)
+}
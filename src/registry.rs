@@ -0,0 +1,120 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use anyhow::{anyhow, bail, Result};
+use log::{debug, info};
+use rusqlite::{params, Connection};
+
+use crate::error::Error;
+use crate::utils::Category;
+
+
+// National product number registries. Electrical products use Sähkönumero,
+// everything else LVI-numero.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Registry {
+    Lvi,
+    Sahko,
+}
+
+impl Registry {
+    pub fn from_name(val: &str) -> Result<Self> {
+        match val.to_lowercase().as_str() {
+            "lvi" => Ok(Self::Lvi),
+            "sahko" | "sähkö" => Ok(Self::Sahko),
+            x => bail!("Invalid registry '{}'. Expected one of: [lvi, sahko]", x),
+        }
+    }
+    pub fn to_name(self) -> &'static str {
+        match self {
+            Self::Lvi => "lvi",
+            Self::Sahko => "sahko",
+        }
+    }
+    fn for_category(category: &Category) -> Self {
+        match category {
+            Category::Electricity => Self::Sahko,
+            _ => Self::Lvi,
+        }
+    }
+}
+
+/// Replace registry numbers from a published registry file. File is semicolon
+/// separated `number;name;ean` with a header row, ean may be empty. Returns
+/// number of imported rows.
+pub fn registry_import(path: &PathBuf, registry: Registry, db_conn: &mut Connection) -> Result<usize> {
+    let reader = BufReader::new(File::open(path).map_err(|e| anyhow!("Failed to open {:?}: {}", path, e))?);
+    let tx = db_conn.transaction()?;
+    let mut count = 0;
+
+    tx.execute("delete from registry_numbers where registry = ?1", params![registry.to_name()])?;
+
+    // Skip header
+    for (i, l) in reader.lines().enumerate().skip(1) {
+        let line = l.map_err(|e| Error::parse(path, Some(i + 1), e))?;
+        let cols = line.split(';').map(|c| c.trim()).collect::<Vec<&str>>();
+
+        let number = match cols.first() {
+            Some(n) if !n.is_empty() => n,
+            _ => continue,
+        };
+
+        let ean = cols.get(2).filter(|e| !e.is_empty());
+
+        tx.execute(
+            "insert or replace into registry_numbers (registry, number, name, ean) values (?1, ?2, ?3, ?4)",
+            params![registry.to_name(), number, cols.get(1).unwrap_or(&""), ean]
+        ).map_err(|e| Error::Db(format!("Registry number insert failed: {}", e)))?;
+
+        count += 1;
+    }
+
+    tx.commit()?;
+
+    info!("Imported {} {} registry numbers from {:?}", count, registry.to_name(), path);
+
+    Ok(count)
+}
+
+/// Link seller products to registry numbers. Registries that have not been
+/// imported are skipped. Returns warnings of products missing from registry.
+pub fn registry_crossref(db_conn: &Connection, seller_id: &str) -> Result<Vec<String>> {
+    let mut warnings = vec![];
+
+    for (k, c) in Category::mapper() {
+        let registry = Registry::for_category(&c).to_name();
+
+        let known: i64 = db_conn.query_row(
+            "select count(*) from registry_numbers where registry = ?1",
+            params![registry],
+            |r| r.get(0)
+        )?;
+
+        if known == 0 {
+            continue;
+        }
+
+        db_conn.execute(
+            &format!("insert or replace into product_registry (id, seller_id, product_id, category, registry, \
+                valid) select p.id, p.seller_id, p.product_id, '{k}', ?2, r.number is not null from products_entries p \
+                left join registry_numbers r on r.registry = ?2 and r.number = p.product_id \
+                where p.category = '{k}' and p.seller_id = ?1"),
+            params![seller_id, registry]
+        ).map_err(|e| Error::Db(format!("Registry cross reference failed: {}", e)))?;
+
+        let mut stm = db_conn.prepare(
+            "select product_id from product_registry where seller_id = ?1 and category = ?2 and valid = 0"
+        )?;
+
+        let missing = stm.query_map(params![seller_id, k], |r| r.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+
+        debug!("{} {} products of seller {} missing from {} registry", missing.len(), k, seller_id, registry);
+
+        for m in missing {
+            warnings.push(format!("[{}]: Product not found in {} registry", m, registry));
+        }
+    }
+
+    Ok(warnings)
+}
@@ -0,0 +1,82 @@
+use std::fs::{copy, read_dir, File};
+use std::path::PathBuf;
+use anyhow::{anyhow, bail, Result};
+use log::info;
+use rusqlite::Connection;
+
+use crate::config::Config;
+use crate::edi::{EdiType, ImportOptions, EDI_DIR_NAME};
+use crate::files::RunTemp;
+
+
+/// Runs already imported seller files from `sellers/<id>/edi/` through the
+/// import again. Returns true if products were imported and search index
+/// needs updating.
+pub fn reimport(config: &Config, seller: &str, file: Option<&String>, db_sellers: &mut Connection,
+    db_buyers: &mut Connection, log: &mut File)
+-> Result<bool> {
+    let mut seller_edi_dir = config.dir.to_owned();
+    seller_edi_dir.push("sellers");
+    seller_edi_dir.push(seller);
+    seller_edi_dir.push(EDI_DIR_NAME);
+
+    if !seller_edi_dir.is_dir() {
+        bail!("Seller {} has no imported EDI files in {:?}", seller, seller_edi_dir)
+    }
+
+    let mut names = vec![];
+
+    for r in read_dir(&seller_edi_dir)? {
+        let e = r?;
+        let name = e.file_name().to_string_lossy().into_owned();
+
+        if !e.path().is_file() {
+            continue;
+        }
+
+        if let Some(f) = file {
+            if name.ne(f) {
+                continue;
+            }
+        }
+
+        names.push(name);
+    }
+
+    if names.is_empty() {
+        match file {
+            Some(f) => bail!("File '{}' not found from {:?}", f, seller_edi_dir),
+            None => bail!("No files to reimport in {:?}", seller_edi_dir),
+        }
+    }
+
+    // Work on copies in a temp dir, import moves the file back over the
+    // original when done.
+    let temp = RunTemp::new(config)?;
+    let work_dir = &temp.path;
+
+    let opts = ImportOptions { force: true, ..Default::default() };
+    let mut products = false;
+
+    names.sort();
+
+    for n in names {
+        let mut from = seller_edi_dir.to_owned();
+        from.push(&n);
+
+        let mut path: PathBuf = work_dir.to_owned();
+        path.push(&n);
+
+        copy(&from, &path).map_err(|e|anyhow!("Failed to copy {:?} for reimport: {}", from, e))?;
+
+        info!("Reimporting {} of seller {}...", n, seller);
+
+        match EdiType::file_import(&path, &n, config, db_sellers, db_buyers, log, &opts) {
+            Ok(EdiType::Product(b)) => products = products || b,
+            Ok(_) => (),
+            Err(e) => bail!("Failed to reimport EDI file '{}': {}", n, e),
+        }
+    }
+
+    Ok(products)
+}
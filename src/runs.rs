@@ -0,0 +1,163 @@
+use std::fs::{read, remove_dir};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use rusqlite::{params, Connection};
+
+use crate::config::Config;
+use crate::files::{create_dirs, sha256_hex};
+
+
+pub const RUNS_DIR_NAME: &str = "runs";
+
+
+// Finished runs kept for tracing, older ones are pruned
+const RUNS_KEPT: i64 = 100;
+
+fn now() -> String {
+    chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+/// Process of a run still exists. Pid might be reused after a crash, a false
+/// positive goes away with the next run.
+pub fn pid_alive(pid: i64) -> bool {
+    let r = unsafe { libc::kill(pid as libc::pid_t, 0) };
+
+    r == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+// Another process is still working on the run. Containers restart with the
+// same pid, a run of our own pid is a crashed one.
+fn run_going_on(pid: Option<i64>) -> bool {
+    pid.is_some_and(|p| p != std::process::id() as i64 && pid_alive(p))
+}
+
+/// Working dir of a run, `runs/<id>/`. Extracted files wait here for their
+/// import, so runs going on at the same time never touch each other's files.
+pub fn run_dir(config: &Config, run_id: i64) -> Result<PathBuf> {
+    let mut path = config.dir.to_owned();
+    path.push(RUNS_DIR_NAME);
+    path.push(run_id.to_string());
+
+    create_dirs(&path, config.import.dir_mode)
+        .map_err(|e| anyhow!("Failed to create run dir {:?}: {}", path, e))?;
+
+    Ok(path)
+}
+
+/// Record start of a pipeline run. Returns id of the run.
+pub fn run_start(db_conn: &Connection) -> Result<i64> {
+    db_conn.execute("insert into runs (started_at, pid) values (?1, ?2)", params![now(), std::process::id()])?;
+
+    Ok(db_conn.last_insert_rowid())
+}
+
+/// Mark the run finished and remove its emptied dir. Crashed earlier runs were
+/// resumed by this one, so they are finished as well. Runs of other processes
+/// still going on are left be.
+pub fn run_finish(config: &Config, db_conn: &Connection, run_id: i64) -> Result<()> {
+    let mut stm = db_conn.prepare("select id, pid from runs where id <= ?1 and finished_at is null")?;
+    let unfinished = stm.query_map(params![run_id], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, Option<i64>>(1)?)))?
+        .collect::<rusqlite::Result<Vec<(i64, Option<i64>)>>>()?;
+
+    for (id, pid) in unfinished {
+        if id != run_id && run_going_on(pid) {
+            continue
+        }
+
+        db_conn.execute("update runs set finished_at = ?1 where id = ?2", params![now(), id])?;
+
+        // Imported files were moved out, anything left is kept for a look
+        let dir = config.dir.join(RUNS_DIR_NAME).join(id.to_string());
+
+        if dir.is_dir() {
+            if let Err(e) = remove_dir(&dir) {
+                warn!("Failed to remove dir {:?} of finished run: {}", dir, e);
+            } else {
+                debug!("Removed dir {:?} of finished run", dir);
+            }
+        }
+    }
+
+    db_conn.execute("delete from run_files where run_id <= ?1", params![run_id - RUNS_KEPT])?;
+    db_conn.execute("delete from runs where id <= ?1", params![run_id - RUNS_KEPT])?;
+
+    Ok(())
+}
+
+/// Add extracted EDI files of the run as pending, with their checksums.
+pub fn run_files_add(db_conn: &Connection, run_id: i64, files: &[(PathBuf, String, PathBuf)]) -> Result<()> {
+    for (path, name, archive) in files {
+        db_conn.execute(
+            "insert or replace into run_files (run_id, path, name, archive, sha256, status) \
+                values (?1, ?2, ?3, ?4, ?5, 'pending')",
+            params![run_id, path.to_string_lossy(), name, archive.to_string_lossy(), sha256_hex(&read(path)?)]
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Set status of a file of the run: committed once imported, rejected when it
+/// was left out on purpose.
+pub fn run_file_done(db_conn: &Connection, run_id: i64, path: &Path, status: &str) -> Result<()> {
+    db_conn.execute("update run_files set status = ?1 where run_id = ?2 and path = ?3",
+        params![status, run_id, path.to_string_lossy()])?;
+
+    Ok(())
+}
+
+/// File with the same checksum was already committed by a run that didn't
+/// finish, re-downloaded archives of a crashed run for example.
+pub fn committed_earlier(db_conn: &Connection, run_id: i64, path: &PathBuf) -> Result<bool> {
+    let sha256 = sha256_hex(&read(path)?);
+    let count: i64 = db_conn.query_row(
+        "select count(*) from run_files f inner join runs r on r.id = f.run_id \
+            where r.finished_at is null and r.id < ?1 and f.status = 'committed' and f.sha256 = ?2",
+        params![run_id, sha256],
+        |r| r.get(0)
+    )?;
+
+    Ok(count > 0)
+}
+
+/// Pending files of runs that crashed, still in their run dir unchanged.
+/// These were extracted already, their archives are gone.
+pub fn resumable_files(db_conn: &Connection) -> Result<Vec<(PathBuf, String, PathBuf)>> {
+    let mut stm = db_conn.prepare(
+        "select f.path, f.name, f.archive, f.sha256, r.pid from run_files f inner join runs r on r.id = f.run_id \
+            where r.finished_at is null and f.status = 'pending' order by r.id"
+    )?;
+
+    let rows = stm.query_map([], |r| Ok((
+        r.get::<_, String>(0)?, r.get::<_, String>(1)?, r.get::<_, String>(2)?, r.get::<_, String>(3)?,
+        r.get::<_, Option<i64>>(4)?
+    )))?
+    .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut files: Vec<(PathBuf, String, PathBuf)> = vec![];
+
+    for (path, name, archive, sha256, pid) in rows {
+        let path = PathBuf::from(path);
+
+        // Files of a run going on in another process are its own to import
+        if run_going_on(pid) {
+            continue
+        }
+
+        // Runs crashing one after another list the same files
+        if !path.is_file() || files.iter().any(|(p, _, _)| p.eq(&path)) {
+            continue
+        }
+
+        if sha256_hex(&read(&path)?).ne(&sha256) {
+            warn!("EDI file {:?} of an unfinished run has changed since, not resuming it", path);
+            continue
+        }
+
+        info!("Resuming import of '{}' left by an unfinished run", name);
+        files.push((path, name, PathBuf::from(archive)));
+    }
+
+    Ok(files)
+}
@@ -0,0 +1,357 @@
+use std::fs::{create_dir_all, read_dir, remove_file, File};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Result};
+use chrono::Utc;
+use log::{debug, info, warn};
+use ring::{digest, hmac};
+use rusqlite::{params, Connection};
+use ureq::Agent;
+
+use crate::config::{Config, ObjectStorage};
+use crate::edi::buyer_json;
+use crate::error::Error;
+use crate::files::{hex, sha256_hex, TMP_DIR_NAME};
+
+
+pub const S3_SCHEME: &str = "s3://";
+
+// Payload isn't hashed for downloads, archives can be large
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+// Files larger than a part are uploaded in parts, a single PUT is capped at
+// 5 GB and parts only have to be kept in memory one at a time
+const PART_SIZE: u64 = 64 * 1024 * 1024;
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data.as_bytes()).as_ref().to_vec()
+}
+
+// Percent encode everything but unreserved characters, slashes of the key kept
+fn uri_encode(val: &str) -> String {
+    val.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => (b as char).to_string(),
+        b => format!("%{:02X}", b),
+    }).collect()
+}
+
+// Hex encoded SHA-256 of a file, read in chunks
+fn file_sha256(path: &Path) -> Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    let mut buf = vec![0u8; 64 * 1024];
+
+    loop {
+        match reader.read(&mut buf)? {
+            0 => break,
+            n => ctx.update(&buf[..n]),
+        }
+    }
+
+    Ok(hex(ctx.finish().as_ref()))
+}
+
+// Value between two tags of an XML response
+fn xml_value<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let len = xml[start..].find(&format!("</{}>", tag))?;
+
+    Some(&xml[start..start + len])
+}
+
+/// Split `s3://bucket/key` into bucket and key.
+pub fn parse_url(url: &str) -> Result<(&str, &str)> {
+    match url.strip_prefix(S3_SCHEME).and_then(|r| r.split_once('/')) {
+        Some((b, k)) if !b.is_empty() && !k.is_empty() => Ok((b, k)),
+        _ => bail!("Invalid S3 url '{}', expected s3://bucket/key", url),
+    }
+}
+
+/// Signature version 4 signed requests to an S3 compatible storage. Buckets are
+/// addressed path style, `<endpoint>/<bucket>/<key>`.
+pub struct S3<'a> {
+    storage: &'a ObjectStorage,
+    access_key: String,
+    secret_key: String,
+}
+
+impl<'a> S3<'a> {
+    pub fn new(storage: &'a ObjectStorage) -> Result<Self> {
+        let key = |val: &Option<String>, env: &str| match val {
+            Some(v) => Ok(v.to_owned()),
+            None => std::env::var(env).map_err(|_| anyhow!("S3 credentials missing, set them in config or {}", env)),
+        };
+
+        Ok(Self {
+            storage,
+            access_key: key(&storage.access_key, "AWS_ACCESS_KEY_ID")?,
+            secret_key: key(&storage.secret_key, "AWS_SECRET_ACCESS_KEY")?,
+        })
+    }
+    fn host(&self) -> &str {
+        let e = self.storage.endpoint.split_once("://").map(|(_, r)| r).unwrap_or(&self.storage.endpoint);
+        e.split('/').next().unwrap_or(e)
+    }
+    /// Signed request for an object. Other headers set later aren't signed.
+    pub fn request(&self, agent: &Agent, method: &str, bucket: &str, key: &str, payload_hash: Option<&str>)
+    -> ureq::Request {
+        self.query_request(agent, method, bucket, key, &[], payload_hash)
+    }
+    // Signed request with query parameters, as the multipart upload ones
+    fn query_request(&self, agent: &Agent, method: &str, bucket: &str, key: &str, query: &[(&str, &str)],
+        payload_hash: Option<&str>)
+    -> ureq::Request {
+        let path = uri_encode(&format!("/{}/{}", bucket, key));
+        let payload_hash = payload_hash.unwrap_or(UNSIGNED_PAYLOAD);
+        let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+
+        // Canonical query has the parameters sorted and slashes encoded too
+        let mut query = query.iter()
+            .map(|(k, v)| format!("{}={}", uri_encode(k).replace('/', "%2F"), uri_encode(v).replace('/', "%2F")))
+            .collect::<Vec<String>>();
+        query.sort();
+        let query = query.join("&");
+
+        let auth = self.authorization(method, &path, &query, payload_hash, &amz_date);
+
+        let url = match query.is_empty() {
+            true => format!("{}{}", self.storage.endpoint.trim_end_matches('/'), path),
+            false => format!("{}{}?{}", self.storage.endpoint.trim_end_matches('/'), path, query),
+        };
+
+        agent.request(method, &url)
+            .set("x-amz-content-sha256", payload_hash)
+            .set("x-amz-date", &amz_date)
+            .set("Authorization", &auth)
+    }
+    fn authorization(&self, method: &str, path: &str, query: &str, payload_hash: &str, amz_date: &str) -> String {
+        let date = &amz_date[..8];
+        let scope = format!("{}/{}/s3/aws4_request", date, self.storage.region);
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+
+        let canonical = format!("{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, query, self.host(), payload_hash, amz_date, signed_headers, payload_hash);
+
+        let to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, sha256_hex(canonical.as_bytes()));
+
+        let mut key = hmac_sha256(format!("AWS4{}", self.secret_key).as_bytes(), date);
+
+        for part in [self.storage.region.as_str(), "s3", "aws4_request"] {
+            key = hmac_sha256(&key, part);
+        }
+
+        format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}", self.access_key, scope,
+            signed_headers, hex(&hmac_sha256(&key, &to_sign)))
+    }
+    /// Upload a file without reading it into memory. Files of up to a part
+    /// are streamed in one PUT, larger ones are uploaded in parts.
+    fn put_file(&self, agent: &Agent, bucket: &str, key: &str, path: &Path, content_type: &str) -> Result<()> {
+        let len = path.metadata()?.len();
+
+        let uploaded = match len > PART_SIZE {
+            true => self.put_parts(agent, bucket, key, path, content_type),
+            false => self.request(agent, "PUT", bucket, key, Some(&file_sha256(path)?))
+                .set("Content-Type", content_type)
+                .set("Content-Length", &len.to_string())
+                .send(File::open(path)?)
+                .map(|_| ())
+                .map_err(|e| e.into()),
+        };
+
+        uploaded.map_err(|e| anyhow!("Failed to upload {}{}/{}: {}", S3_SCHEME, bucket, key, e))
+    }
+    // Multipart upload, aborted on failure so the parts aren't left stored
+    fn put_parts(&self, agent: &Agent, bucket: &str, key: &str, path: &Path, content_type: &str) -> Result<()> {
+        let empty = sha256_hex(&[]);
+
+        let created = self.query_request(agent, "POST", bucket, key, &[("uploads", "")], Some(&empty))
+            .set("Content-Type", content_type)
+            .call()?
+            .into_string()?;
+
+        let upload_id = xml_value(&created, "UploadId")
+            .ok_or_else(|| anyhow!("Multipart upload wasn't started: {}", created))?
+            .to_owned();
+
+        let parts = || -> Result<String> {
+            let mut file = File::open(path)?;
+            let mut etags = String::new();
+
+            for n in 1.. {
+                let mut part = Vec::with_capacity(PART_SIZE as usize);
+                (&mut file).take(PART_SIZE).read_to_end(&mut part)?;
+
+                if part.is_empty() {
+                    break
+                }
+
+                let number = n.to_string();
+                let response = self.query_request(agent, "PUT", bucket, key,
+                    &[("partNumber", &number), ("uploadId", &upload_id)], Some(&sha256_hex(&part)))
+                    .send_bytes(&part)?;
+
+                let etag = response.header("ETag").ok_or_else(|| anyhow!("Part {} has no ETag", n))?;
+                etags.push_str(&format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>", n, etag));
+            }
+
+            Ok(format!("<CompleteMultipartUpload>{}</CompleteMultipartUpload>", etags))
+        };
+
+        let completed = parts().and_then(|body| {
+            // Errors of completing come with 200 OK in the body
+            let response = self.query_request(agent, "POST", bucket, key, &[("uploadId", &upload_id)],
+                Some(&sha256_hex(body.as_bytes())))
+                .send_string(&body)?
+                .into_string()?;
+
+            match xml_value(&response, "Code") {
+                Some(c) => bail!("Completing multipart upload failed: {}", c),
+                None => Ok(()),
+            }
+        });
+
+        if completed.is_err() {
+            let aborted = self.query_request(agent, "DELETE", bucket, key, &[("uploadId", &upload_id)], Some(&empty))
+                .call();
+
+            if let Err(e) = aborted {
+                warn!("Failed to abort multipart upload of {}{}/{}: {}", S3_SCHEME, bucket, key, e);
+            }
+        }
+
+        completed
+    }
+}
+
+/// Client created when the first `s3://` url is met, runs without them don't
+/// need credentials.
+pub struct LazyS3<'a> {
+    storage: Option<&'a ObjectStorage>,
+    client: OnceLock<S3<'a>>,
+}
+
+impl<'a> LazyS3<'a> {
+    pub fn new(storage: Option<&'a ObjectStorage>) -> Self {
+        Self { storage, client: OnceLock::new() }
+    }
+    pub fn get(&self) -> Result<&S3<'a>> {
+        if let Some(c) = self.client.get() {
+            return Ok(c)
+        }
+
+        let client = match self.storage {
+            Some(s) => S3::new(s)?,
+            None => bail!("Objects of S3 urls can't be downloaded without the [s3] section in config"),
+        };
+
+        Ok(self.client.get_or_init(|| client))
+    }
+}
+
+// Files under dir with their path relative to base
+fn collect_files(dir: &Path, base: &Path, found: &mut Vec<(PathBuf, String)>) -> Result<()> {
+    for e in read_dir(dir)? {
+        let path = e?.path();
+
+        if path.is_dir() {
+            collect_files(&path, base, found)?;
+        } else if path.extension().is_some_and(|x| x.eq("json")) {
+            let rel = path.strip_prefix(base)?.to_string_lossy().into_owned();
+            found.push((path, rel));
+        }
+    }
+
+    Ok(())
+}
+
+/// Upload generated JSON files and, if enabled, database snapshots to the
+/// configured sink bucket. Keys mirror paths under the data dir, files of a
+/// separate buyers dir go under `buyers/`. Returns the number of uploaded
+/// objects.
+pub fn sink_upload(config: &Config, db_sellers: &Connection, db_buyers: &Connection) -> Result<usize> {
+    let storage = match &config.s3 {
+        Some(s) => s,
+        None => return Ok(0),
+    };
+
+    let sink = match &storage.sink {
+        Some(s) => s,
+        None => return Ok(0),
+    };
+
+    let s3 = S3::new(storage)?;
+    let agent: Agent = ureq::AgentBuilder::new().timeout(Duration::from_secs(300)).build();
+    let prefix = match sink.prefix.trim_matches('/') {
+        "" => String::new(),
+        p => format!("{}/", p),
+    };
+
+    let mut count = 0;
+
+    if sink.json {
+        let mut files = vec![];
+
+        for name in ["schema.json", "categories.json"] {
+            let path = config.dir.join(name);
+
+            if path.is_file() {
+                files.push((path, name.to_string()));
+            }
+        }
+
+        // Manifests, etim.json and buyer files without a dir of their own
+        // are in the sellers tree
+        let sellers_dir = config.dir.join("sellers");
+
+        if sellers_dir.is_dir() {
+            collect_files(&sellers_dir, &config.dir, &mut files)?;
+        }
+
+        let buyers_dir = match &config.json_output.buyers_dir {
+            Some(d) => d.to_owned(),
+            None => config.dir.join("buyers"),
+        };
+
+        if buyer_json(config) && buyers_dir.is_dir() {
+            let mut buyers = vec![];
+            collect_files(&buyers_dir, &buyers_dir, &mut buyers)?;
+
+            files.extend(buyers.into_iter().map(|(p, rel)| (p, format!("buyers/{}", rel))));
+        }
+
+        for (path, rel) in files {
+            debug!("Uploading {:?} to {}{}/{}{}", path, S3_SCHEME, sink.bucket, prefix, rel);
+
+            s3.put_file(&agent, &sink.bucket, &format!("{}{}", prefix, rel), &path, "application/json")?;
+            count += 1;
+        }
+    }
+
+    if sink.databases {
+        create_dir_all(config.dir.join(TMP_DIR_NAME))?;
+
+        for (conn, name) in [(db_sellers, "sellers.db"), (db_buyers, "buyers.db")] {
+            let snapshot = config.dir.join(TMP_DIR_NAME).join(format!("snapshot-{}", name));
+
+            if snapshot.is_file() {
+                remove_file(&snapshot)?;
+            }
+
+            // Consistent copy even if another process is writing
+            conn.execute("vacuum into ?1", params![snapshot.to_string_lossy()])
+                .map_err(|e| Error::Db(format!("Snapshot of {} failed: {}", name, e)))?;
+
+            let uploaded = s3.put_file(&agent, &sink.bucket, &format!("{}{}", prefix, name), &snapshot,
+                "application/vnd.sqlite3");
+
+            remove_file(&snapshot)?;
+            uploaded?;
+            count += 1;
+        }
+    }
+
+    info!("Uploaded {} objects to {}{}/{}", count, S3_SCHEME, sink.bucket, prefix);
+
+    Ok(count)
+}
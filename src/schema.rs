@@ -0,0 +1,419 @@
+use std::fs::{read, read_dir, write};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use log::debug;
+use rusqlite::Connection;
+use serde_json::{json, Value};
+
+use crate::config::Config;
+use crate::edi::envelope;
+use crate::files::sha256_hex;
+use crate::freshness::seller_freshness;
+use crate::search::seller_search_status;
+use crate::utils::{Category, Lang};
+
+
+/// Version of the JSON output layouts. Bumped on changes breaking consumers:
+/// renamed or removed keys, changed types or file structure. New optional keys
+/// don't bump it.
+pub const SCHEMA_VERSION: u32 = 3;
+
+fn date() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "y": { "type": "string" },
+            "m": { "type": "string" },
+            "d": { "type": "string" }
+        },
+        "required": ["y", "m", "d"]
+    })
+}
+
+fn packaging(props: &mut serde_json::Map<String, Value>) {
+    for k in ["p1", "p1d", "p2", "p2d", "p3", "p3d"] {
+        props.insert(k.to_string(), json!({ "type": "number" }));
+    }
+}
+
+fn product() -> Value {
+    let mut props = json!({
+        "op": { "enum": ["a", "m", "d"] },
+        "cat": { "enum": ["lv", "iv", "sa", "te", "ky"] },
+        "date": { "$ref": "#/$defs/date" },
+        "name": { "type": "string" },
+        "name2": { "type": "string" },
+        "tag": { "type": "string" },
+        "ref": { "type": "string" },
+        "disc": { "type": "string" },
+        "unit": { "type": "string" },
+        "weight": { "type": "number" },
+        "vol": { "type": "number" },
+        "pkg": { "type": "integer" },
+        "tax": { "type": "string" },
+        "delay": { "type": "integer" },
+        "stock": { "type": ["boolean", "null"] },
+        "ean": { "type": "string" },
+        "i": { "type": "string" },
+        "ix": { "type": "number" }
+    });
+
+    if let Some(m) = props.as_object_mut() {
+        packaging(m);
+    }
+
+    json!({
+        "type": "object",
+        "properties": props,
+        "required": ["op", "date", "name", "name2", "unit", "stock", "ix"]
+    })
+}
+
+fn price() -> Value {
+    let mut props = json!({
+        "group": { "type": "string" },
+        "price": { "type": "number", "description": "Cents" },
+        "date": { "$ref": "#/$defs/date" },
+        "disc": { "type": "string" },
+        "unit": { "type": "string" },
+        "incl": { "type": "integer" },
+        "i": { "type": "string" },
+        "ix": { "type": "number" },
+        "ppu": { "type": "number", "description": "Cents per usage unit" },
+        "stock": { "type": ["boolean", "null"] },
+        "delay": { "type": "integer" }
+    });
+
+    if let Some(m) = props.as_object_mut() {
+        packaging(m);
+    }
+
+    json!({
+        "type": "object",
+        "properties": props,
+        "required": ["group", "price", "date", "disc", "unit", "incl", "ix", "stock"]
+    })
+}
+
+fn discount() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "disc": { "type": "string" },
+            "id": { "type": "string" },
+            "name": { "type": "string" },
+            "kind": { "enum": ["01", "02", "03"], "description": "Laji" },
+            "pc1": { "type": "number" },
+            "pc2": { "type": "number" }
+        },
+        "required": ["disc", "id", "name", "kind", "pc1", "pc2"]
+    })
+}
+
+// Top level of data files, the seller and import time with the data
+fn envelope(description: &str, data: Value) -> Value {
+    json!({
+        "description": description,
+        "type": "object",
+        "properties": {
+            "schema_version": { "const": SCHEMA_VERSION },
+            "seller": { "type": "string" },
+            "name": { "type": ["string", "null"] },
+            "imported_at": { "type": "string" },
+            "data": data
+        },
+        "required": ["schema_version", "seller", "name", "imported_at", "data"]
+    })
+}
+
+/// JSON Schema of the product, price and discount files.
+pub fn schema() -> Value {
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "lvisweb-ediparser JSON outputs",
+        "schema_version": SCHEMA_VERSION,
+        "$defs": {
+            "date": date(),
+            "product": product(),
+            "price": price(),
+            "discount": discount(),
+            "products": envelope("sellers/<id>/products/<category>.<lang>.json, data keyed by product number",
+                json!({
+                    "type": "object",
+                    "additionalProperties": { "$ref": "#/$defs/product" }
+                })
+            ),
+            "products_shards": {
+                "description": "sellers/<id>/products/<category>.<lang>.index.json, with [json] shard set \
+                    products are in the listed files of the same layout as products",
+                "type": "object",
+                "properties": {
+                    "schema_version": { "const": SCHEMA_VERSION },
+                    "scheme": { "enum": ["prefix", "count"] },
+                    "prefix_len": { "type": "integer" },
+                    "shards": { "type": "integer" },
+                    "files": {
+                        "type": "object",
+                        "additionalProperties": {
+                            "type": "object",
+                            "properties": {
+                                "file": { "type": "string" },
+                                "count": { "type": "integer" }
+                            }
+                        }
+                    }
+                }
+            },
+            "prices": envelope("sellers/<id>/prices/<category>.json, data keyed by product number",
+                json!({
+                    "type": "object",
+                    "additionalProperties": { "$ref": "#/$defs/price" }
+                })
+            ),
+            "combined": envelope("sellers/<id>/combined/<category>.<lang>.json with [json] combined = true, data \
+                keyed by product number, products with their price or null, imported_at is the build time",
+                json!({
+                    "type": "object",
+                    "additionalProperties": {
+                        "allOf": [{ "$ref": "#/$defs/product" }],
+                        "properties": {
+                            "price": { "oneOf": [{ "$ref": "#/$defs/price" }, { "type": "null" }] }
+                        }
+                    }
+                })
+            ),
+            "price_groups": envelope("sellers/<id>/prices/groups.json, data keyed by price group",
+                json!({
+                    "type": "object",
+                    "additionalProperties": {
+                        "type": "object",
+                        "properties": {
+                            "id": { "type": "string" },
+                            "label": { "type": "string" },
+                            "is_list_price": { "type": "boolean" },
+                            "includes_vat": { "type": "boolean" }
+                        }
+                    }
+                })
+            ),
+            "discount_list": {
+                "type": "array",
+                "items": { "$ref": "#/$defs/discount" }
+            },
+            "discounts": envelope("sellers/<id>/buyers/<id>/discounts/<seller>.json",
+                json!({ "$ref": "#/$defs/discount_list" })
+            ),
+            "buyer_discounts": {
+                "description": "buyers/<id>/discounts.json",
+                "type": "object",
+                "properties": {
+                    "schema_version": { "const": SCHEMA_VERSION },
+                    "sellers": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "seller": { "type": "string" },
+                                "name": { "type": ["string", "null"] },
+                                "discounts": { "$ref": "#/$defs/discount_list" }
+                            }
+                        }
+                    }
+                }
+            },
+            "categories": {
+                "description": "categories.json, display names of category codes keyed by code and language",
+                "type": "object",
+                "properties": {
+                    "schema_version": { "const": SCHEMA_VERSION },
+                    "categories": {
+                        "type": "object",
+                        "additionalProperties": {
+                            "type": "object",
+                            "additionalProperties": { "type": "string" }
+                        }
+                    }
+                }
+            },
+            "manifest": {
+                "description": "sellers/<id>/manifest.json",
+                "type": "object",
+                "properties": {
+                    "schema_version": { "const": SCHEMA_VERSION },
+                    "seller": { "type": "string" },
+                    "updated_at": { "type": "string" },
+                    "files": { "type": "array", "items": { "type": "string" } },
+                    "entries": {
+                        "description": "Number of entries in data of each file",
+                        "type": "object",
+                        "additionalProperties": { "type": "integer" }
+                    },
+                    "sha256": {
+                        "description": "Hex encoded SHA-256 of each file",
+                        "type": "object",
+                        "additionalProperties": { "type": "string" }
+                    },
+                    "freshness": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "category": { "type": "string" },
+                                "kind": { "enum": ["product", "price"] },
+                                "file_date": { "type": ["string", "null"] },
+                                "newest_date": { "type": ["string", "null"] },
+                                "imported_at": { "type": "string" }
+                            }
+                        }
+                    },
+                    "search": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "category": { "type": "string" },
+                                "lang": { "type": "string" },
+                                "rows": { "type": "integer" },
+                                "built_at": { "type": "string" },
+                                "stale": { "type": "boolean" }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Number of entries of a generated JSON file, keys of a map or items of a
+/// list in its data.
+pub fn entry_count(bytes: &[u8]) -> Option<usize> {
+    match envelope::into_data(serde_json::from_slice(bytes).ok()?) {
+        Value::Object(m) => Some(m.len()),
+        Value::Array(a) => Some(a.len()),
+        _ => None,
+    }
+}
+
+// JSON files under dir, paths relative to base. Buyers' files are left out,
+// they are private to each buyer.
+pub(crate) fn json_files(base: &Path, dir: &Path, files: &mut Vec<String>) -> Result<()> {
+    for e in read_dir(dir)? {
+        let p = e?.path();
+
+        if p.is_dir() && p.ends_with("buyers") {
+            continue
+        } else if p.is_dir() {
+            json_files(base, &p, files)?;
+        } else if p.extension().is_some_and(|x| x.eq("json")) && !p.ends_with("manifest.json") {
+            if let Ok(r) = p.strip_prefix(base) {
+                files.push(r.to_string_lossy().to_string());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Display names of categories in the built in languages that have them, by
+/// category code and language name.
+pub fn categories() -> Value {
+    let langs = [Lang::Fin, Lang::Swe, Lang::Eng, Lang::Nor];
+    let categories = Category::mapper().iter()
+        .map(|(k, c)| {
+            let names = langs.iter()
+                .filter_map(|l| c.localized(l).map(|n| (l.to_name().to_string(), json!(n))))
+                .collect::<serde_json::Map<String, Value>>();
+
+            (k.to_string(), Value::Object(names))
+        })
+        .collect::<serde_json::Map<String, Value>>();
+
+    json!({
+        "schema_version": SCHEMA_VERSION,
+        "categories": categories
+    })
+}
+
+/// Write `schema.json` and `categories.json` into the data dir and `manifest.json` listing JSON files
+/// of each seller with their entry counts and checksums, both carrying the
+/// schema version. Freshness of seller's data
+/// is included when sqlite is used.
+pub fn manifest_writer(config: &Config, db_conn: &Connection) -> Result<()> {
+    let mut path = config.dir.to_owned();
+    path.push("schema.json");
+
+    write(&path, serde_json::to_string_pretty(&schema())?)
+        .map_err(|e| anyhow!("Failed to write {:?}: {}", path, e))?;
+
+    path.set_file_name("categories.json");
+
+    write(&path, serde_json::to_string_pretty(&categories())?)
+        .map_err(|e| anyhow!("Failed to write {:?}: {}", path, e))?;
+
+    let mut sellers_dir = config.dir.to_owned();
+    sellers_dir.push("sellers");
+
+    if !sellers_dir.is_dir() {
+        return Ok(())
+    }
+
+    let updated_at = Utc::now().to_rfc3339();
+
+    for e in read_dir(&sellers_dir)? {
+        let dir = e?.path();
+
+        if !dir.is_dir() {
+            continue
+        }
+
+        let mut files = vec![];
+        json_files(&dir, &dir, &mut files)?;
+        files.sort();
+
+        let mut entries = serde_json::Map::new();
+        let mut sha256 = serde_json::Map::new();
+
+        for f in files.iter() {
+            let bytes = read(dir.join(f)).map_err(|e| anyhow!("Failed to read {:?}: {}", dir.join(f), e))?;
+
+            if let Some(n) = entry_count(&bytes) {
+                entries.insert(f.to_owned(), json!(n));
+            }
+
+            sha256.insert(f.to_owned(), json!(sha256_hex(&bytes)));
+        }
+
+        let seller = dir.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let freshness = match config.import.sqlite {
+            true => seller_freshness(db_conn, &seller)?,
+            false => vec![],
+        };
+        let search = match config.import.search {
+            true => seller_search_status(config, db_conn, &seller)?,
+            false => vec![],
+        };
+
+        let manifest = json!({
+            "schema_version": SCHEMA_VERSION,
+            "seller": seller,
+            "updated_at": updated_at,
+            "files": files,
+            "entries": entries,
+            "sha256": sha256,
+            "freshness": freshness,
+            "search": search,
+        });
+
+        let mut path = PathBuf::from(&dir);
+        path.push("manifest.json");
+
+        write(&path, serde_json::to_string_pretty(&manifest)?)
+            .map_err(|e| anyhow!("Failed to write {:?}: {}", path, e))?;
+
+        debug!("Wrote manifest of {} JSON files to {:?}", files.len(), path);
+    }
+
+    Ok(())
+}
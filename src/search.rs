@@ -1,11 +1,16 @@
-use std::collections::HashMap;
-use anyhow::{anyhow, Result};
-use log::debug;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use anyhow::{bail, Result};
+use chrono::Utc;
+use log::{debug, info};
 use rusqlite::{Connection, params};
 use serde::Serialize;
 
-use crate::utils::Category;
-use super::config::Config;
+use crate::cli::RunFilter;
+use crate::db;
+use crate::error::Error;
+use crate::utils::{Category, Lang};
+use super::config::{Config, SearchField, SearchIndex};
 
 #[derive(Debug, PartialEq, Eq, Serialize)]
 pub struct DbProductSearch {
@@ -21,127 +26,631 @@ pub fn search_index_builder(conf: &Config, db_conn: &mut Connection) -> Result<(
         .map(|c| (c.id.to_owned(), c.name.to_owned()))
         .collect::<HashMap<String, String>>();
 
-    let active_ids = active_sellers.to_owned()
-        .into_keys()
-        .collect::<Vec<String>>()
-        .join(", ");
+    let active_ids = quoted_ids(active_sellers.keys());
+
+    search_keys_builder(db_conn, &active_ids)?;
 
     for (k, v) in Category::mapper() {
-        // TODO: does not delete products that were removed from catalog though.
-        // Products of obsolete suppliers should be deleted
-        db_conn.execute(
-            &format!("delete from search_{} where seller_id not in ({})", k, active_ids),
-            []
-        ).map_err(|e|anyhow!("Failed to delete obsolete {} search rows: {}", k, e))?;
-
-        // Load current search index for category
-        let index_rows = query_search_index(&db_conn, k)?;
-        
-        // Connection to DB of current category
-        let translation_rows = query_search_index_translations(&db_conn, k, &active_sellers)?;
-        
-        // Loop products from catalog and run insert or update on the
-        // search index
+        // Translations of sellers removed from config are left in the catalog
+        let mut translation_rows = query_search_index_translations(db_conn, k, &active_sellers, &conf.search)?
+            .into_iter()
+            .filter(|r| active_sellers.contains_key(&r.seller_id))
+            .collect::<Vec<DbProductSearch>>();
+
+        if !conf.lang_fallback.is_empty() {
+            translation_rows = fallback_translations(conf, translation_rows);
+        }
+
+        // Each language has its own index table
+        for l in conf.lang_codes.iter() {
+            let table = search_table(k, l);
+            let lang = l.to_index() as i8;
+
+            // TODO: does not delete products that were removed from catalog though.
+            // Products of obsolete suppliers should be deleted
+            db_conn.execute(
+                &format!("delete from {} where seller_id not in ({})", table, active_ids),
+                []
+            ).map_err(|e|Error::Db(format!("Failed to delete obsolete {} search rows: {}", table, e)))?;
+
+            // Load current search index for category and language
+            let index_rows = query_search_index(db_conn, &table, lang)?;
+
+            // Loop products from catalog and run insert or update on the
+            // search index
+            let tx = db_conn.transaction()?;
+
+            for i in translation_rows.iter().filter(|t| t.lang == lang) {
+                // Update if we have changes (e.g. renamed seller), insert if missing
+                match index_rows.iter().find(|s|
+                    s.seller_id.eq(&i.seller_id) && s.product_id.eq(&i.product_id)
+                ) {
+                    Some(s) => if s.ne(i) {
+                        tx.execute(
+                            &format!("update {} set body = ?3 where seller_id = ?1 and product_id = ?2", table),
+                            params!(&s.seller_id, &s.product_id, &i.body)
+                        ).map_err(|e|Error::Db(format!("Search index DB row update error: {}", e)))?;
+                    },
+                    None => {
+                        tx.execute(
+                            &format!("insert into {} (seller_id, product_id, body) values (?1, ?2, ?3)", table),
+                            params!(&i.seller_id, &i.product_id, &i.body)
+                        ).map_err(|e|Error::Db(format!("Search index DB write error: {}", e)))?;
+                    }
+                }
+            }
+
+            tx.commit()?;
+
+            debug!("Optimizing {} search index...", table);
+
+            db_conn.execute(
+                &format!("insert into {0}({0}) VALUES('optimize')", table), []
+            ).map_err(|e|Error::Db(format!("Failed to optimize {} search index for {}: {}", l, v, e)))?;
+
+            record_status(conf, db_conn, k, l)?;
+        }
+    }
+
+    db_conn.execute("delete from search_dirty", [])
+        .map_err(|e|Error::Db(format!("Failed to clear search index dirty marks: {}", e)))?;
+
+    Ok(())
+}
+
+/// Reasons the search index has to be built even if no products were
+/// imported: marks of seller renames and removals, and products imported
+/// after the last build of their index, or never indexed at all.
+pub fn dirty_reasons(conf: &Config, db_conn: &Connection) -> Result<Vec<String>> {
+    let mut stm = db_conn.prepare("select reason from search_dirty order by marked_at")?;
+    let mut reasons = stm.query_map([], |r| r.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    reasons.extend(stale_indexes(conf, db_conn)?);
+
+    Ok(reasons)
+}
+
+/// Categories of sellers with products imported after their index was last
+/// built, a build was skipped or failed.
+pub fn stale_indexes(conf: &Config, db_conn: &Connection) -> Result<Vec<String>> {
+    let mut stm = db_conn.prepare(&format!(
+        "select p.seller_id, p.category, max(s.built_at) from products_entries p \
+            left join search_status s on s.seller_id = p.seller_id and s.category = p.category \
+            where p.seller_id in ({}) group by p.seller_id, p.category \
+            having max(s.built_at) is null or max(p.imported_at) > max(s.built_at) \
+            order by p.seller_id, p.category",
+        quoted_ids(conf.seller.iter().map(|s| &s.id))
+    ))?;
+
+    let stale = stm.query_map([], |r| {
+        let (seller, category, built_at) = (r.get::<_, String>(0)?, r.get::<_, String>(1)?,
+            r.get::<_, Option<String>>(2)?);
+
+        Ok(match built_at {
+            Some(b) => format!("products of seller {} in {} imported after the index build at {}", seller, category, b),
+            None => format!("products of seller {} in {} were never indexed", seller, category),
+        })
+    })?
+    .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    Ok(stale)
+}
+
+/// Drop the search indexes of the categories and build them again from the
+/// translations, or only the rows of the given sellers. Search keys are
+/// rebuilt as a whole. Returns the number of rows indexed.
+pub fn reindex(conf: &Config, db_conn: &mut Connection, filter: &RunFilter) -> Result<usize> {
+    if !conf.import.search {
+        bail!("Search index is disabled, enable search under [import]")
+    }
+
+    if let Some(s) = filter.sellers.iter().find(|s| !conf.seller.iter().any(|c| c.id.eq(*s))) {
+        bail!("Seller {} is not in the config", s)
+    }
+
+    let active_sellers = conf.seller.iter()
+        .map(|c| (c.id.to_owned(), c.name.to_owned()))
+        .collect::<HashMap<String, String>>();
+
+    search_keys_builder(db_conn, &quoted_ids(active_sellers.keys()))?;
+
+    let mut indexed = 0;
+
+    for (k, v) in Category::mapper().into_iter().filter(|(_, c)| filter.has_category(c)) {
+        let mut translation_rows = query_search_index_translations(db_conn, k, &active_sellers, &conf.search)?
+            .into_iter()
+            .filter(|r| active_sellers.contains_key(&r.seller_id) && filter.has_seller(&r.seller_id))
+            .collect::<Vec<DbProductSearch>>();
+
+        if !conf.lang_fallback.is_empty() {
+            translation_rows = fallback_translations(conf, translation_rows);
+        }
+
         let tx = db_conn.transaction()?;
 
-        for i in translation_rows {
-            // Update if we have changes, insert if missing
-            match index_rows.iter().find(|s|
-                s.seller_id.eq(&i.seller_id) && s.product_id.eq(&i.product_id)
-            ) {
-                Some(s) => if s.ne(&i) {
-                    tx.execute(
-                        &format!("update search_{} set body = ?3 \
-                            where search.seller_id = ?1 and search.product_id = ?2", k),
-                        params!(&s.seller_id, &s.product_id, &i.body)
-                    ).map_err(|e|anyhow!("Search index DB row update error: {}", e))?;
-                },
-                None => {
-                    tx.execute(
-                        &format!("insert into search_{} (seller_id, product_id, lang, \
-                            body) values (?1, ?2, ?3, ?4)", k),
-                        params!(&i.seller_id, &i.product_id, &i.lang, &i.body)
-                    ).map_err(|e|anyhow!("Search index DB write error: {}", e))?;
+        // Whole tables are recreated, sellers' rows only deleted
+        match filter.sellers.is_empty() {
+            true => {
+                for l in conf.lang_codes.iter() {
+                    tx.execute(&format!("drop table if exists {}", search_table(k, l)), [])
+                        .map_err(|e|Error::Db(format!("Failed to drop {} search index: {}", v, e)))?;
                 }
+
+                db::search_tables(&tx, conf, k)?;
+            },
+            false => {
+                for l in conf.lang_codes.iter() {
+                    tx.execute(&format!("delete from {} where seller_id in ({})", search_table(k, l),
+                        quoted_ids(filter.sellers.iter())), [])
+                        .map_err(|e|Error::Db(format!("Failed to delete {} search rows: {}", v, e)))?;
+                }
+            },
+        }
+
+        for l in conf.lang_codes.iter() {
+            let lang = l.to_index() as i8;
+
+            for i in translation_rows.iter().filter(|t| t.lang == lang) {
+                tx.execute(
+                    &format!("insert into {} (seller_id, product_id, body) values (?1, ?2, ?3)", search_table(k, l)),
+                    params!(&i.seller_id, &i.product_id, &i.body)
+                ).map_err(|e|Error::Db(format!("Search index DB write error: {}", e)))?;
+
+                indexed += 1;
             }
         }
 
         tx.commit()?;
-        
-        debug!("Optimizing {} search indexes...", k);
 
+        for l in conf.lang_codes.iter() {
+            db_conn.execute(
+                &format!("insert into {0}({0}) VALUES('optimize')", search_table(k, l)), []
+            ).map_err(|e|Error::Db(format!("Failed to optimize {} search index for {}: {}", l, v, e)))?;
+
+            record_status(conf, db_conn, k, l)?;
+        }
+
+        info!("Rebuilt {} search indexes from {} translations", v, translation_rows.len());
+    }
+
+    // Filtered rebuild may leave the rest of the index behind
+    if !filter.is_active() {
+        db_conn.execute("delete from search_dirty", [])
+            .map_err(|e|Error::Db(format!("Failed to clear search index dirty marks: {}", e)))?;
+    }
+
+    Ok(indexed)
+}
+
+// Rows of each configured seller in the index of the category and language,
+// with the time of the build. Sellers without rows get 0.
+fn record_status(conf: &Config, db_conn: &Connection, k: &str, lang: &Lang) -> Result<()> {
+    let built_at = Utc::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string();
+
+    let mut stm = db_conn.prepare(&format!("select seller_id, count(*) from {} group by seller_id",
+        search_table(k, lang)))?;
+    let counts = stm.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect::<rusqlite::Result<HashMap<String, i64>>>()?;
+
+    db_conn.execute("delete from search_status where category = ?1 and lang = ?2",
+        params![k, lang.to_index() as i64])?;
+
+    for s in conf.seller.iter() {
         db_conn.execute(
-            &format!("insert into search_{}(search_{}) VALUES('optimize')", k, k), []
-        ).map_err(|e|anyhow!("Failed to optimize search index for {}: {}", v, e))?;
+            "insert into search_status (category, lang, seller_id, rows, built_at) values (?1, ?2, ?3, ?4, ?5)",
+            params![k, lang.to_index() as i64, &s.id, counts.get(&s.id).unwrap_or(&0), &built_at]
+        )?;
     }
 
     Ok(())
 }
 
-fn query_search_index<T>(db_conn: &Connection, table: T) -> Result<Vec<DbProductSearch>>
-where T: AsRef<str> {
+// Search index of a seller in one category and language
+#[derive(Debug, Serialize)]
+pub struct SearchStatus {
+    pub category: String,
+    pub lang: String,
+    pub rows: i64,
+    pub built_at: String,
+    // Products of the category were imported after the build
+    pub stale: bool,
+}
+
+/// Rows and build times of the seller's search indexes. Indexes of languages
+/// no longer configured are left out.
+pub fn seller_search_status(conf: &Config, db_conn: &Connection, seller_id: &str) -> Result<Vec<SearchStatus>> {
+    let mut stm = db_conn.prepare(
+        "select s.category, s.lang, s.rows, s.built_at, exists (select 1 from products_entries p \
+            where p.seller_id = s.seller_id and p.category = s.category and p.imported_at > s.built_at) \
+            from search_status s where s.seller_id = ?1 order by s.category, s.lang"
+    )?;
+
+    let rows = stm.query_map(params![seller_id], |r| Ok((r.get::<_, i64>(1)?, SearchStatus {
+        category: r.get(0)?,
+        lang: String::new(),
+        rows: r.get(2)?,
+        built_at: r.get(3)?,
+        stale: r.get(4)?,
+    })))?
+    .collect::<rusqlite::Result<Vec<(i64, SearchStatus)>>>()?;
+
+    Ok(rows.into_iter()
+        .filter_map(|(i, mut s)| {
+            s.lang = conf.lang_codes.iter().find(|l| l.to_index() as i64 == i)?.to_name().to_string();
+            Some(s)
+        })
+        .collect())
+}
+
+// Quoted, unquoted ids lose their leading zeros and match nothing
+fn quoted_ids<'a, I>(ids: I) -> String where I: Iterator<Item = &'a String> {
+    ids.map(|id| format!("'{}'", id.replace('\'', "''")))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Full-text index table of the category in the language.
+pub fn search_table(category: &str, lang: &Lang) -> String {
+    format!("search_{}_{}", category, lang.to_name())
+}
+
+// Product ids and EAN codes of active sellers are rewritten on every build,
+// plain inserts are cheap compared to the text index
+fn search_keys_builder(db_conn: &mut Connection, active_ids: &str) -> Result<()> {
+    let tx = db_conn.transaction()?;
+
+    tx.execute("delete from search_keys", [])
+        .map_err(|e|Error::Db(format!("Failed to clear search keys: {}", e)))?;
+    tx.execute(
+        &format!("insert or ignore into search_keys (key, seller_id, product_id, category) \
+            select upper(trim(product_id)), seller_id, product_id, category from products_entries \
+                where seller_id in ({0}) \
+            union all \
+            select upper(trim(ean_code)), seller_id, product_id, category from products_entries \
+                where seller_id in ({0}) and trim(coalesce(ean_code, '')) != ''", active_ids),
+        []
+    ).map_err(|e|Error::Db(format!("Search keys DB write error: {}", e)))?;
+
+    tx.commit()?;
+
+    Ok(())
+}
+
+// Product found by the search command, exact matches of a full product id or
+// EAN code come first
+#[derive(Debug)]
+pub struct SearchHit {
+    pub exact: bool,
+    pub seller_id: String,
+    pub product_id: String,
+    pub category: String,
+    pub text: String,
+}
+
+impl fmt::Display for SearchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.exact {
+            true => "exact",
+            false => "text",
+        };
+
+        write!(f, "{:<7}{:<14}{:<20}{:<4}{}", kind, self.seller_id, self.product_id, self.category, self.text)
+    }
+}
+
+/// Products matching the text in the language, full product ids and EAN codes
+/// first and then trigram matches of the search index by rank. Text matches
+/// need at least 3 characters, stopwords of the language aren't searched.
+pub fn search(conf: &Config, db_conn: &Connection, text: &str, lang: &Lang, limit: usize) -> Result<Vec<SearchHit>> {
+    let text = text.trim();
+
+    if text.is_empty() {
+        bail!("Search text is empty")
+    }
+
+    if !conf.lang_codes.contains(lang) {
+        bail!("Language {} is not in lang_codes and has no search index", lang)
+    }
+
+    // Tables are created by imports
+    let tables: i64 = db_conn.query_row(
+        "select count(*) from sqlite_master where name in ('search_keys', ?1)",
+        params![search_table(Category::WaterAndHeating.to_name(), lang)], |r| r.get(0)
+    )?;
+
+    if tables < 2 {
+        bail!("Search index is not built, enable search under [import] and run an import")
+    }
+
+    let words = conf.search.strip_stopwords(text, lang.to_index());
+
     let mut stm = db_conn.prepare(
-        &format!("select * from search_{}", table.as_ref())
+        "select seller_id, product_id, category from search_keys where key = upper(?1) \
+            order by seller_id, product_id"
+    )?;
+    let mut hits = stm.query_map(params![text], |r| Ok(SearchHit {
+        exact: true,
+        seller_id: r.get(0)?,
+        product_id: r.get(1)?,
+        category: r.get(2)?,
+        text: String::new(),
+    }))?
+    .collect::<rusqlite::Result<Vec<SearchHit>>>()?;
+
+    // Text of an exact hit is its search body in the language
+    for h in hits.iter_mut() {
+        if Category::from_name(&h.category).is_err() {
+            continue
+        }
+
+        let mut stm = db_conn.prepare(&format!(
+            "select body from {} where seller_id = ?1 and product_id = ?2", search_table(&h.category, lang)
+        ))?;
+
+        h.text = stm.query_row(params![&h.seller_id, &h.product_id], |r| r.get(0)).unwrap_or_default();
+    }
+
+    if words.chars().count() < 3 {
+        hits.truncate(limit);
+        return Ok(hits)
+    }
+
+    // Whole text as one phrase, quotes doubled
+    let phrase = format!("\"{}\"", words.replace('"', "\"\""));
+    let mut ranked = vec![];
+
+    for (k, _) in Category::mapper() {
+        let table = search_table(k, lang);
+        let mut stm = db_conn.prepare(&format!(
+            "select seller_id, product_id, body, rank from {table} where {table} match ?1 order by rank limit ?2"
+        ))?;
+
+        let rows = stm.query_map(params![&phrase, limit as i64], |r| Ok((r.get::<_, f64>(3)?, SearchHit {
+            exact: false,
+            seller_id: r.get(0)?,
+            product_id: r.get(1)?,
+            category: k.to_string(),
+            text: r.get(2)?,
+        })))?
+        .collect::<rusqlite::Result<Vec<(f64, SearchHit)>>>()?;
+
+        ranked.extend(rows);
+    }
+
+    // Rank is negative, best match first
+    ranked.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    for (_, h) in ranked {
+        if !hits.iter().any(|e| e.seller_id.eq(&h.seller_id) && e.product_id.eq(&h.product_id)) {
+            hits.push(h);
+        }
+    }
+
+    hits.truncate(limit);
+
+    Ok(hits)
+}
+
+// Products missing a configured language get the text of the first fallback
+// language they have, so searches in every language find them
+fn fallback_translations(conf: &Config, rows: Vec<DbProductSearch>) -> Vec<DbProductSearch> {
+    let mut products: BTreeMap<(String, String), Vec<DbProductSearch>> = BTreeMap::new();
+
+    for r in rows {
+        products.entry((r.seller_id.to_owned(), r.product_id.to_owned())).or_default().push(r);
+    }
+
+    let mut rows = vec![];
+
+    for ((seller_id, product_id), mut translations) in products {
+        for lang in conf.lang_codes.iter() {
+            let index = lang.to_index() as i8;
+
+            if translations.iter().any(|t| t.lang == index) {
+                continue
+            }
+
+            let body = conf.lang_chain(lang).iter()
+                .find_map(|l| translations.iter().find(|t| t.lang == l.to_index() as i8))
+                .map(|t| t.body.to_owned());
+
+            if let Some(body) = body {
+                translations.push(DbProductSearch {
+                    lang: index,
+                    seller_id: seller_id.to_owned(),
+                    product_id: product_id.to_owned(),
+                    body,
+                });
+            }
+        }
+
+        rows.extend(translations);
+    }
+
+    rows
+}
+
+fn query_search_index(db_conn: &Connection, table: &str, lang: i8) -> Result<Vec<DbProductSearch>> {
+    let mut stm = db_conn.prepare(
+        &format!("select seller_id, product_id, body from {}", table)
     )?;
 
     stm.query_map([], |r| {
         Ok(DbProductSearch {
-            lang: r.get(0)?,
-            seller_id: r.get(1)?,
-            product_id: r.get(2)?,
-            body: r.get(3)?,
+            lang,
+            seller_id: r.get(0)?,
+            product_id: r.get(1)?,
+            body: r.get(2)?,
         })
     }).and_then(Iterator::collect)
-    .map_err(|e|anyhow!("Failed to query search index: {}", e))
+    .map_err(|e|Error::Db(format!("Failed to query search index: {}", e)).into())
 }
 
-fn query_search_index_translations<T>(db_conn: &Connection, table: T, active_sellers: &HashMap<String, String>)
--> Result<Vec<DbProductSearch>>
+fn query_search_index_translations<T>(db_conn: &Connection, table: T, active_sellers: &HashMap<String, String>,
+search: &SearchIndex) -> Result<Vec<DbProductSearch>>
 where T: AsRef<str> {
+    let category = Category::from_name(table.as_ref())?;
+
+    // EAN code is on the product, translations only have the texts
     let mut stm = db_conn.prepare(
-        &format!("select substr(id, 0, 13), substr(id, 13, 7), \
-        lang, name, description, tags, code \
-        from product_{}_t", table.as_ref()
+        &format!("select t.seller_id, t.product_id, \
+        t.lang, t.name, t.description, t.tags, t.code, p.ean_code \
+        from product_{}_t t left join products_entries p on p.id = t.seller_id || t.product_id", table.as_ref()
     ))?;
 
     stm.query_map([], |r| {
         let seller_id: String = r.get(0)?;
-        let mut name = r.get_ref(3)?.as_str()?.to_string();
+        let lang: i8 = r.get(2)?;
 
-        // Prefix seller name to product name
-        if let Some(n) = active_sellers.get(&seller_id) {
-            name = name + ", " + n;
-        }
-        
-        // Should be present, could be an empty string though.
-        let mut body = match r.get_ref(4)?.as_str() {
-            Ok(s) => match s.is_empty() {
-                true => name,
-                false => name + ", " + s
-            },
-            Err(_) => name,
+        // Optional fields are null or empty strings, both are left out
+        let text = |i: usize| -> rusqlite::Result<Option<String>> {
+            Ok(r.get_ref(i)?.as_str_or_null().ok().flatten().map(|s| s.to_string()))
         };
 
-        // Tags are optional, append to description
-        if let Ok(o) = r.get_ref(5)?.as_str_or_null() {
-            if let Some(s) = o {
-                body = body + ", " + s;
-            }
-        }
+        let mut parts = vec![];
+
+        for f in search.body.iter() {
+            let part = match f {
+                SearchField::Name => text(3)?,
+                SearchField::Seller => active_sellers.get(&seller_id).cloned(),
+                SearchField::Description => text(4)?,
+                SearchField::Tags => text(5)?,
+                SearchField::Code => text(6)?,
+                SearchField::Ean => text(7)?,
+                SearchField::Category => Some(category.label().to_string()),
+            };
 
-        // Search code is optional, append to description
-        if let Ok(o) = r.get_ref(6)?.as_str_or_null() {
-            if let Some(s) = o {
-                body = body + ", " + s;
+            if let Some(p) = part.map(|p| search.strip_stopwords(&p, lang as usize)).filter(|p| !p.is_empty()) {
+                parts.push(p);
             }
         }
 
         Ok(DbProductSearch {
-            lang: r.get(2)?,
+            lang,
             seller_id,
             product_id: r.get(1)?,
-            body
+            body: parts.join(", "),
         })
     }).and_then(Iterator::collect)
-    .map_err(|e|anyhow!("Failed to query search translations: {}", e))
+    .map_err(|e|Error::Db(format!("Failed to query search translations: {}", e)).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use crate::config::ConfigSource;
+
+    use super::*;
+
+    const SELLERS: [&str; 2] = ["003700000001", "003700000002"];
+
+    fn config(dir: &std::path::Path) -> Config {
+        write(dir.join("config.toml"), format!(
+            "vat_percent = 25.5\nlang_codes = [\"fin\"]\n\n[import]\njson = false\nsqlite = true\nsearch = true\n\n\
+            [[seller]]\nid = \"{}\"\nname = \"Yksi\"\n\n[[seller]]\nid = \"{}\"\nname = \"Kaksi\"\n\n\
+            [search]\nbody = [\"name\"]\n", SELLERS[0], SELLERS[1]
+        )).unwrap();
+
+        let source = ConfigSource { dir: Some(dir.to_string_lossy().into_owned()), file: None, container: true };
+
+        Config::new(&source).unwrap()
+    }
+
+    // Tables reindex reads and writes, with a product of each seller named
+    // after the category
+    fn database(conf: &Config) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+
+        conn.execute_batch(
+            "create table products_entries (id text primary key, category text not null, product_id text not null,
+                seller_id text not null, ean_code text null);
+            create table search_keys (key text not null, seller_id text not null, product_id text not null,
+                category text not null, primary key (key, seller_id, product_id)) without rowid;
+            create table search_status (category text not null, lang integer not null, seller_id text not null,
+                rows integer not null, built_at text not null, primary key (category, lang, seller_id));
+            create table search_dirty (reason text primary key, marked_at text not null);"
+        ).unwrap();
+
+        for (k, _) in Category::mapper() {
+            conn.execute(&format!("create table product_{k}_t (id text primary key, seller_id text null,
+                product_id text null, lang integer not null, name text not null, description text not null,
+                tags text null, code text null)"), []).unwrap();
+
+            db::search_tables(&conn, conf, k).unwrap();
+
+            for s in SELLERS {
+                rename(&conn, k, s, &format!("{} {}", k, s));
+            }
+        }
+
+        conn
+    }
+
+    fn rename(conn: &Connection, k: &str, seller_id: &str, name: &str) {
+        conn.execute(&format!("insert or replace into product_{k}_t (id, seller_id, product_id, lang, name, \
+            description) values (?1 || 'P1:1', ?1, 'P1', 1, ?2, '')"), params![seller_id, name]).unwrap();
+    }
+
+    fn indexed(conn: &Connection, k: &str) -> Vec<(String, String)> {
+        let mut stm = conn.prepare(&format!("select seller_id, body from {} order by seller_id",
+            search_table(k, &Lang::Fin))).unwrap();
+
+        stm.query_map([], |r| Ok((r.get(0)?, r.get(1)?))).unwrap()
+            .collect::<rusqlite::Result<Vec<(String, String)>>>().unwrap()
+    }
+
+    fn filter(sellers: &[&str], categories: &[Category]) -> RunFilter {
+        RunFilter { sellers: sellers.iter().map(|s| s.to_string()).collect(), categories: categories.to_vec() }
+    }
+
+    #[test]
+    fn reindex_seller_keeps_other_sellers() {
+        let dir = tempfile::tempdir().unwrap();
+        let conf = config(dir.path());
+        let mut conn = database(&conf);
+
+        assert_eq!(reindex(&conf, &mut conn, &filter(&[], &[])).unwrap(), 10);
+
+        rename(&conn, "lv", SELLERS[0], "uusi");
+        rename(&conn, "lv", SELLERS[1], "ei indeksoitu");
+
+        assert_eq!(reindex(&conf, &mut conn, &filter(&SELLERS[..1], &[])).unwrap(), 5);
+        assert_eq!(indexed(&conn, "lv"), [
+            (SELLERS[0].to_string(), String::from("uusi")),
+            (SELLERS[1].to_string(), format!("lv {}", SELLERS[1])),
+        ]);
+    }
+
+    #[test]
+    fn reindex_category_leaves_others_be() {
+        let dir = tempfile::tempdir().unwrap();
+        let conf = config(dir.path());
+        let mut conn = database(&conf);
+
+        reindex(&conf, &mut conn, &filter(&[], &[])).unwrap();
+
+        rename(&conn, "lv", SELLERS[1], "uusi");
+        rename(&conn, "sa", SELLERS[1], "ei indeksoitu");
+
+        assert_eq!(reindex(&conf, &mut conn, &filter(&[], &[Category::WaterAndHeating])).unwrap(), 2);
+        assert_eq!(indexed(&conn, "lv"), [
+            (SELLERS[0].to_string(), format!("lv {}", SELLERS[0])),
+            (SELLERS[1].to_string(), String::from("uusi")),
+        ]);
+        assert_eq!(indexed(&conn, "sa"), [
+            (SELLERS[0].to_string(), format!("sa {}", SELLERS[0])),
+            (SELLERS[1].to_string(), format!("sa {}", SELLERS[1])),
+        ]);
+    }
+
+    #[test]
+    fn reindex_unknown_seller_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let conf = config(dir.path());
+        let mut conn = database(&conf);
+
+        assert!(reindex(&conf, &mut conn, &filter(&["003799999999"], &[])).is_err());
+    }
 }
@@ -0,0 +1,130 @@
+use std::fmt;
+use anyhow::{bail, Result};
+use rusqlite::{params, Connection};
+
+use crate::utils::Category;
+
+
+// Product as one seller has it, with names in the imported languages and the
+// price if there's one
+#[derive(Debug)]
+pub struct SellerProduct {
+    pub seller_id: String,
+    pub seller: String,
+    pub product_id: String,
+    pub category: String,
+    pub ean_code: Option<String>,
+    pub unit: String,
+    pub discount_group: String,
+    pub stock_item: Option<bool>,
+    pub delivery_in_weeks: Option<i64>,
+    pub updated_at: Option<String>,
+    // Language code and name
+    pub names: Vec<(String, String)>,
+    pub price: Option<ProductPrice>,
+}
+
+#[derive(Debug)]
+pub struct ProductPrice {
+    pub price_group: String,
+    pub price: f64,
+    pub units_incl: i64,
+    pub unit: String,
+    pub price_incl_vat_eur: Option<f64>,
+}
+
+impl fmt::Display for SellerProduct {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} {}", self.seller_id, self.seller)?;
+        writeln!(f, "  {:<16}{} ({})", "Product", self.product_id, self.category)?;
+        writeln!(f, "  {:<16}{}", "EAN", self.ean_code.as_deref().unwrap_or("-"))?;
+        writeln!(f, "  {:<16}{}, discount group {}", "Unit", self.unit, self.discount_group)?;
+
+        for (lang, name) in self.names.iter() {
+            writeln!(f, "  {:<16}{}", format!("Name {}", lang), name)?;
+        }
+
+        match &self.price {
+            Some(p) => {
+                write!(f, "  {:<16}{:.2} / {} {}", format!("Price {}", p.price_group), p.price, p.units_incl, p.unit)?;
+
+                match p.price_incl_vat_eur {
+                    Some(v) => writeln!(f, " (incl. VAT {:.2})", v)?,
+                    None => writeln!(f)?,
+                }
+            },
+            None => writeln!(f, "  {:<16}-", "Price")?,
+        }
+
+        let stock = match self.stock_item {
+            Some(true) => "yes",
+            Some(false) => "no",
+            None => "-",
+        };
+
+        writeln!(f, "  {:<16}{}", "Stock item", stock)?;
+        writeln!(f, "  {:<16}{}", "Delivery", self.delivery_in_weeks.map_or(String::from("-"), |w| format!("{} weeks", w)))?;
+        write!(f, "  {:<16}{}", "Updated", self.updated_at.as_deref().unwrap_or("-"))
+    }
+}
+
+/// Product of every seller having it by product id or EAN code, for quick
+/// inspection of what was imported.
+pub fn show(db_sellers: &Connection, key: &str) -> Result<Vec<SellerProduct>> {
+    let key = key.trim();
+
+    if key.is_empty() {
+        bail!("Product id or EAN code is empty")
+    }
+
+    let mut stm = db_sellers.prepare(
+        "select p.seller_id, coalesce(s.name, ''), p.product_id, p.category, p.ean_code, p.unit, p.discount_group, \
+            p.stock_item, p.delivery_in_weeks, p.updated_at, r.price_group, r.price, r.units_incl, r.unit, \
+            r.price_incl_vat_eur from products_entries p left join sellers s on s.id = p.seller_id \
+            left join prices_entries r on r.id = p.id where p.product_id = ?1 or p.ean_code = ?1 \
+            order by p.seller_id, p.product_id"
+    )?;
+
+    let mut products = stm.query_map(params![key], |r| Ok(SellerProduct {
+        seller_id: r.get(0)?,
+        seller: r.get(1)?,
+        product_id: r.get(2)?,
+        category: r.get(3)?,
+        ean_code: r.get(4)?,
+        unit: r.get(5)?,
+        discount_group: r.get(6)?,
+        stock_item: r.get(7)?,
+        delivery_in_weeks: r.get(8)?,
+        updated_at: r.get(9)?,
+        names: vec![],
+        price: match r.get::<_, Option<f64>>(11)? {
+            Some(price) => Some(ProductPrice {
+                price_group: r.get(10)?,
+                price,
+                units_incl: r.get::<_, i64>(12)?.max(1),
+                unit: r.get(13)?,
+                price_incl_vat_eur: r.get(14)?,
+            }),
+            None => None,
+        },
+    }))?
+    .collect::<rusqlite::Result<Vec<SellerProduct>>>()?;
+
+    for p in products.iter_mut() {
+        // Names are in the translation table of the category
+        if Category::from_name(&p.category).is_err() {
+            continue
+        }
+
+        let mut stm = db_sellers.prepare(&format!(
+            "select coalesce(l.name, t.lang), t.name from product_{}_t t left join languages l on l.id = t.lang \
+                where t.seller_id = ?1 and t.product_id = ?2 order by t.lang",
+            p.category
+        ))?;
+
+        p.names = stm.query_map(params![&p.seller_id, &p.product_id], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+    }
+
+    Ok(products)
+}
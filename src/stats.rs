@@ -0,0 +1,95 @@
+use anyhow::Result;
+use rusqlite::{params, Connection};
+
+
+// Counts of one seller and category
+#[derive(Debug, Default)]
+pub struct CategoryStats {
+    pub seller_id: String,
+    pub seller: String,
+    pub category: String,
+    pub products: i64,
+    pub prices: i64,
+    pub products_without_price: i64,
+    pub prices_without_product: i64,
+    pub last_import: Option<String>,
+}
+
+/// Product and price counts per seller and category, with the products that
+/// have no price and prices that have no product. Price rows carry no seller,
+/// it's the id without the product id.
+pub fn stats(db_sellers: &Connection) -> Result<Vec<CategoryStats>> {
+    let mut stm = db_sellers.prepare(
+        "select t.seller_id, coalesce(s.name, ''), t.category, sum(t.product), sum(t.price), \
+            sum(t.product and not t.price), sum(t.price and not t.product), max(t.imported_at) from ( \
+                select p.seller_id, p.category, 1 as product, r.id is not null as price, \
+                    max(p.imported_at, coalesce(r.imported_at, '')) as imported_at \
+                    from products_entries p left join prices_entries r on r.id = p.id \
+                union all \
+                select substr(r.id, 1, length(r.id) - length(r.product_id)), r.category, 0, 1, r.imported_at \
+                    from prices_entries r where not exists (select 1 from products_entries p where p.id = r.id) \
+            ) t left join sellers s on s.id = t.seller_id group by t.seller_id, t.category order by t.seller_id, t.category"
+    )?;
+
+    let rows = stm.query_map([], |r| Ok(CategoryStats {
+        seller_id: r.get(0)?,
+        seller: r.get(1)?,
+        category: r.get(2)?,
+        products: r.get(3)?,
+        prices: r.get(4)?,
+        products_without_price: r.get(5)?,
+        prices_without_product: r.get(6)?,
+        last_import: r.get(7)?,
+    }))?
+    .collect::<rusqlite::Result<Vec<CategoryStats>>>()?;
+
+    Ok(rows)
+}
+
+/// Print the counts as a table, with totals of all sellers.
+pub fn print(stats: &[CategoryStats]) {
+    println!("{:<14}{:<20}{:<10}{:>10}{:>10}{:>12}{:>12}  Last import", "Seller", "Name", "Category", "Products",
+        "Prices", "No price", "No product");
+
+    let mut total = CategoryStats::default();
+
+    for s in stats {
+        // Long names are cut to keep the columns
+        let name = s.seller.chars().take(19).collect::<String>();
+        let last_import = s.last_import.as_deref().map_or("-", |d| d.get(..19).unwrap_or(d));
+
+        println!("{:<14}{:<20}{:<10}{:>10}{:>10}{:>12}{:>12}  {}", s.seller_id, name, s.category, s.products,
+            s.prices, s.products_without_price, s.prices_without_product, last_import);
+
+        total.products += s.products;
+        total.prices += s.prices;
+        total.products_without_price += s.products_without_price;
+        total.prices_without_product += s.prices_without_product;
+    }
+
+    println!("{:<44}{:>10}{:>10}{:>12}{:>12}", "Total", total.products, total.prices, total.products_without_price,
+        total.prices_without_product);
+}
+
+/// Product ids of the seller without a price, and product ids of prices
+/// without a product. Suppliers keep pricing discontinued items.
+pub fn orphans(db_sellers: &Connection, seller_id: &str) -> Result<(Vec<String>, Vec<String>)> {
+    let ids = |sql: &str| -> Result<Vec<String>> {
+        let mut stm = db_sellers.prepare(sql)?;
+        let ids = stm.query_map(params![seller_id], |r| r.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+
+        Ok(ids)
+    };
+
+    let products = ids(
+        "select p.product_id from products_entries p where p.seller_id = ?1 \
+            and not exists (select 1 from prices_entries r where r.id = p.id) order by p.product_id"
+    )?;
+    let prices = ids(
+        "select r.product_id from prices_entries r where r.id = ?1 || r.product_id \
+            and not exists (select 1 from products_entries p where p.id = r.id) order by r.product_id"
+    )?;
+
+    Ok((products, prices))
+}
@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+
+#[derive(Debug, Clone, Copy)]
+pub enum Stage {
+    Download,
+    Unzip,
+    Utf8,
+    Parse,
+    DbWrite,
+    JsonWrite,
+    Index,
+}
+
+/// Milliseconds spent in each stage.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StageTimings {
+    pub download_ms: f64,
+    pub unzip_ms: f64,
+    pub utf8_ms: f64,
+    pub parse_ms: f64,
+    pub db_write_ms: f64,
+    pub json_write_ms: f64,
+    pub index_ms: f64,
+}
+
+impl StageTimings {
+    fn add(&mut self, stage: Stage, took: Duration) {
+        let ms = took.as_secs_f64() * 1000.0;
+
+        match stage {
+            Stage::Download => self.download_ms += ms,
+            Stage::Unzip => self.unzip_ms += ms,
+            Stage::Utf8 => self.utf8_ms += ms,
+            Stage::Parse => self.parse_ms += ms,
+            Stage::DbWrite => self.db_write_ms += ms,
+            Stage::JsonWrite => self.json_write_ms += ms,
+            Stage::Index => self.index_ms += ms,
+        }
+    }
+    fn merge(&mut self, other: &Self) {
+        for (a, b) in self.values_mut().into_iter().zip(other.values()) {
+            *a += b;
+        }
+    }
+    fn values(&self) -> [f64; 7] {
+        [self.download_ms, self.unzip_ms, self.utf8_ms, self.parse_ms, self.db_write_ms, self.json_write_ms,
+            self.index_ms]
+    }
+    fn values_mut(&mut self) -> [&mut f64; 7] {
+        [&mut self.download_ms, &mut self.unzip_ms, &mut self.utf8_ms, &mut self.parse_ms, &mut self.db_write_ms,
+            &mut self.json_write_ms, &mut self.index_ms]
+    }
+    pub fn total_ms(&self) -> f64 {
+        self.values().iter().sum()
+    }
+}
+
+// Timings of the run. Archives are timed by their path until unzipped, EDI
+// files by the path they are imported from.
+struct Collector {
+    current: Option<PathBuf>,
+    files: BTreeMap<PathBuf, StageTimings>,
+    // EDI file and the archive it was extracted from
+    sources: BTreeMap<PathBuf, PathBuf>,
+    run: StageTimings,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static COLLECTOR: Mutex<Collector> = Mutex::new(Collector {
+    current: None,
+    files: BTreeMap::new(),
+    sources: BTreeMap::new(),
+    run: StageTimings {
+        download_ms: 0.0, unzip_ms: 0.0, utf8_ms: 0.0, parse_ms: 0.0, db_write_ms: 0.0, json_write_ms: 0.0,
+        index_ms: 0.0
+    },
+});
+
+/// Start collecting timings, nothing is recorded otherwise.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn with_collector<F: FnOnce(&mut Collector)>(f: F) {
+    if !is_enabled() {
+        return
+    }
+
+    if let Ok(mut c) = COLLECTOR.lock() {
+        f(&mut c);
+    }
+}
+
+/// Stages recorded without a path go to this file until the next one.
+pub fn file_start(path: &Path) {
+    with_collector(|c| c.current = Some(path.to_path_buf()));
+}
+
+/// Time spent on the current file, or the run if no file is being imported.
+pub fn record(stage: Stage, took: Duration) {
+    with_collector(|c| match c.current.to_owned() {
+        Some(p) => c.files.entry(p).or_default().add(stage, took),
+        None => c.run.add(stage, took),
+    });
+}
+
+/// Time spent on a file or archive.
+pub fn record_for(path: &Path, stage: Stage, took: Duration) {
+    with_collector(|c| c.files.entry(path.to_path_buf()).or_default().add(stage, took));
+}
+
+/// Time spent on the run as a whole, search index building for example.
+pub fn record_run(stage: Stage, took: Duration) {
+    with_collector(|c| c.run.add(stage, took));
+}
+
+/// EDI file was extracted from the archive, download and unzip of the archive
+/// are counted to the file.
+pub fn source(edi_file: &Path, archive: &Path) {
+    with_collector(|c| { c.sources.insert(edi_file.to_path_buf(), archive.to_path_buf()); });
+}
+
+/// Timings of an EDI file with the download and unzip of its archive.
+pub fn file_timings(path: &Path) -> Option<StageTimings> {
+    if !is_enabled() {
+        return None
+    }
+
+    let c = COLLECTOR.lock().ok()?;
+    let mut t = c.files.get(path).cloned().unwrap_or_default();
+
+    if let Some(a) = c.sources.get(path).and_then(|a| c.files.get(a)) {
+        t.merge(a);
+    }
+
+    Some(t)
+}
+
+/// Table of timings per EDI file and in total.
+pub fn summary<W: Write>(out: &mut W) -> std::io::Result<()> {
+    let c = match COLLECTOR.lock() {
+        Ok(c) => c,
+        Err(_) => return Ok(()),
+    };
+
+    let name = |p: &Path| p.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let archives = c.sources.values().collect::<Vec<&PathBuf>>();
+    let mut total = c.run.to_owned();
+
+    writeln!(out, "{:<28}{:>10}{:>10}{:>10}{:>10}{:>10}{:>10}{:>10}{:>12}", "File (ms)", "Download", "Unzip",
+        "UTF-8", "Parse", "DB write", "JSON", "Index", "Total")?;
+
+    let row = |out: &mut W, label: &str, t: &StageTimings| {
+        let v = t.values();
+
+        writeln!(out, "{:<28}{:>10.1}{:>10.1}{:>10.1}{:>10.1}{:>10.1}{:>10.1}{:>10.1}{:>12.1}", label, v[0], v[1],
+            v[2], v[3], v[4], v[5], v[6], t.total_ms())
+    };
+
+    // Archives that failed to unzip are shown on their own
+    for (p, t) in c.files.iter() {
+        // Archives are shown with their EDI files
+        if archives.contains(&p) {
+            continue
+        }
+
+        let mut t = t.to_owned();
+
+        if let Some(a) = c.sources.get(p).and_then(|a| c.files.get(a)) {
+            t.merge(a);
+        }
+
+        total.merge(&t);
+        row(out, &name(p), &t)?;
+    }
+
+    if c.run.total_ms() > 0.0 {
+        row(out, "(run)", &c.run)?;
+    }
+
+    row(out, "Total", &total)
+}
@@ -4,31 +4,33 @@ use anyhow::{anyhow, bail, Result};
 use std::io::copy;
 use std::os::unix::fs::PermissionsExt;
 use std::path::PathBuf;
-use std::fs::{create_dir_all, remove_file, set_permissions, File, Permissions};
+use std::time::Instant;
+use std::fs::{remove_file, set_permissions, File, Permissions};
 
+use crate::config::ImportTargets;
+use crate::timings::{self, Stage};
 
-use crate::edi::EDI_DIR_NAME;
-use super::config::Config;
-use super::files::file_to_edi_utf8;
 
-pub fn unzip_from(archives: Vec<PathBuf>, config: &Config) -> Result<Vec<(PathBuf, String)>> {
-    // Unzip and save files with randomized names into the sources dir.
-    let mut edi_dir = config.dir.to_owned();
-    edi_dir.push(EDI_DIR_NAME);
+use crate::edi::{EdiHeader, ARCHIVE_DIR_NAME};
+use super::config::Config;
+use super::files::{create_dirs, file_to_edi_utf8, move_file, RunTemp};
 
-    create_dir_all(&edi_dir).map_err(|e|anyhow!("Failed to create edi dir: {}", e))?;
+/// Extract archives into the dir of the run. Returns extracted files with
+/// their original names and the archive they came from.
+pub fn unzip_from(archives: Vec<PathBuf>, config: &Config, run_dir: &PathBuf)
+-> Result<Vec<(PathBuf, String, PathBuf)>> {
     let mut edi_files = vec![];
 
+    // Extract and convert in a temp dir, run dir only gets complete files
+    let temp = RunTemp::new(config)?;
+
     for a in archives {
-        let (f, n) = match unzip_handler(&a, &edi_dir) {
-            Ok(t) => {
-                if let Err(e) = remove_file(&a) {
-                    bail!("Failed to delete obsolete zip archive {:?}: \
-                        {}", a, e)
-                }
+        let started = Instant::now();
+        let unzipped = unzip_handler(&a, &temp.path, &config.import);
+        timings::record_for(&a, Stage::Unzip, started.elapsed());
 
-                t
-            },
+        let (f, n) = match unzipped {
+            Ok(t) => t,
             Err(e) => {
                 error!("Failed to unzip file {:?} ({}), skipping...", a, e);
 
@@ -40,12 +42,22 @@ pub fn unzip_from(archives: Vec<PathBuf>, config: &Config) -> Result<Vec<(PathBu
             }
         };
 
-        match file_to_edi_utf8(&f, &edi_dir, None) {
-            Ok(p) => edi_files.push((p, n)),
+        let started = Instant::now();
+
+        match file_to_edi_utf8(&f, &temp.path, None).and_then(|p| temp.persist(&p, run_dir)) {
+            Ok(p) => {
+                timings::record_for(&p, Stage::Utf8, started.elapsed());
+                timings::source(&p, &a);
+
+                archive_dispose(config, &a, Some(&p))?;
+                edi_files.push((p, n, a));
+            },
             Err(e) => {
                 error!("Failed to convert source file '{}' ({:?}) \
                     to utf-8 format: {}", n, f, e);
 
+                archive_dispose(config, &a, None)?;
+
                 if let Err(e) = remove_file(&f) {
                     bail!("Failed to delete non utf-8 convertable file {:?}: {}", f, e)
                 }
@@ -58,8 +70,43 @@ pub fn unzip_from(archives: Vec<PathBuf>, config: &Config) -> Result<Vec<(PathBu
     Ok(edi_files)
 }
 
+// Either delete the handled zip archive or move it under archive/<seller>/<date>/
+// so supplier deliveries can be traced later on.
+fn archive_dispose(config: &Config, archive: &PathBuf, edi_file: Option<&PathBuf>) -> Result<()> {
+    if !config.import.keep_archives {
+        return remove_file(archive).map_err(|e|anyhow!(
+            "Failed to delete obsolete zip archive {:?}: {}", archive, e
+        ))
+    }
+
+    // Seller from the extracted file header, unreadable ones are kept aside
+    let seller = match edi_file.map(|f| EdiHeader::read(f, &config.seller_ids)) {
+        Some(Ok(h)) => match h.seller {
+            Some(s) => s.id,
+            None => String::from("unknown"),
+        },
+        _ => String::from("unknown"),
+    };
+
+    let mut target = config.dir.to_owned();
+    target.push(ARCHIVE_DIR_NAME);
+    target.push(seller);
+    target.push(chrono::Utc::now().format("%Y-%m-%d").to_string());
+
+    let name = match archive.file_name() {
+        Some(n) => n.to_string_lossy().into_owned(),
+        None => bail!("Unable to read file name of zip archive {:?}", archive),
+    };
 
-pub fn unzip_handler(archive_file: &PathBuf, unzip_dir: &PathBuf) -> Result<(PathBuf, String)> {
+    move_file(archive, &target, "", &name)?;
+
+    debug!("Archived zip {:?} to {:?}", archive, target);
+
+    Ok(())
+}
+
+pub fn unzip_handler(archive_file: &PathBuf, unzip_dir: &PathBuf, modes: &ImportTargets)
+-> Result<(PathBuf, String)> {
     let file = File::open(archive_file)?;
 
     let mut extracted_file_path = unzip_dir.to_owned();
@@ -87,7 +134,9 @@ pub fn unzip_handler(archive_file: &PathBuf, unzip_dir: &PathBuf) -> Result<(Pat
     // Directories. Should never be the case here but left as a reminder.
     if (*archived_file.name()).ends_with('/') {
         info!("File {} extracted to {:?}", 0, extracted_file_path);
-        create_dir_all(&extracted_file_path)?;
+        create_dirs(&extracted_file_path, modes.dir_mode)?;
+
+        return Ok((extracted_file_path, extracted_file_name))
     } 
     else {
         debug!(
@@ -100,7 +149,7 @@ pub fn unzip_handler(archive_file: &PathBuf, unzip_dir: &PathBuf) -> Result<(Pat
         // Make sure target dir for unzip action exists
         if let Some(p) = extracted_file_path.parent() {
             if ! p.exists() {
-                create_dir_all(p)?;
+                create_dirs(&p.to_path_buf(), modes.dir_mode)?;
             }
         }
 
@@ -108,8 +157,8 @@ pub fn unzip_handler(archive_file: &PathBuf, unzip_dir: &PathBuf) -> Result<(Pat
         copy(&mut archived_file, &mut outfile)?;
     }
 
-    // Get and Set permissions
-    set_permissions(&extracted_file_path, Permissions::from_mode(0o755))?;
+    // Data files, nothing to execute
+    set_permissions(&extracted_file_path, Permissions::from_mode(modes.file_mode))?;
 
     Ok((extracted_file_path, extracted_file_name))
 }
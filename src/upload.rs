@@ -1,27 +1,85 @@
 use std::path::PathBuf;
-use std::fs::{create_dir_all, remove_file, read_dir};
+use std::fs::{create_dir_all, read, remove_file, read_dir};
 
 use anyhow::{anyhow, bail, Result};
 use log::{error, warn};
 use rand::distributions::{Alphanumeric, DistString};
+use rusqlite::{params, Connection};
 
-use crate::edi::{EDI_DIR_NAME, UPLOAD_DIR_NAME};
-use crate::files::file_to_edi_utf8;
+use crate::edi::{EdiHeader, EdiType, SellerIds, UPLOAD_DIR_NAME};
+use crate::files::{file_to_edi_utf8, sha256_hex, RunTemp};
 use crate::unzip::unzip_handler;
 use crate::config::Config;
 
 
-pub fn read_uploads(config: &Config) -> Result<Vec<(PathBuf, String)>> {
+/// Uploaded file as it arrived, recorded with the result of its import in
+/// `upload_audit` of buyers.db.
+pub struct UploadAudit {
+    source: &'static str,
+    remote_addr: Option<String>,
+    file_name: String,
+    sha256: String,
+    buyer_id: Option<String>,
+    seller_id: Option<String>,
+}
+
+impl UploadAudit {
+    pub fn new(source: &'static str, remote_addr: Option<String>, file_name: &str, data: &[u8]) -> Self {
+        Self {
+            source,
+            remote_addr,
+            file_name: file_name.to_owned(),
+            sha256: sha256_hex(data),
+            buyer_id: None,
+            seller_id: None,
+        }
+    }
+    /// Buyer and seller from the header of the converted EDI file, read before
+    /// the import moves it.
+    pub fn parties(&mut self, edi_file: &PathBuf, seller_ids: &SellerIds) {
+        if let Ok(h) = EdiHeader::read(edi_file, seller_ids) {
+            self.buyer_id = h.buyer.map(|b| b.id);
+            self.seller_id = h.seller.map(|s| s.id);
+        }
+    }
+    /// Record the upload with its result. Failing to record is only logged,
+    /// the import itself is done.
+    pub fn record(&self, db_buyers: &Connection, result: &str, message: Option<String>) {
+        let at = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string();
+
+        if let Err(e) = db_buyers.execute(
+            "insert into upload_audit (uploaded_at, source, remote_addr, file_name, sha256, buyer_id, \
+                seller_id, result, message) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![at, self.source, &self.remote_addr, &self.file_name, &self.sha256, &self.buyer_id,
+                &self.seller_id, result, message]
+        ) {
+            error!("Failed to record upload of {} to the audit log: {}", self.file_name, e);
+        }
+    }
+}
+
+/// Result of an upload's import in the audit log.
+pub fn audit_result(t: &EdiType) -> &'static str {
+    match t {
+        EdiType::Product(true) | EdiType::Price(true) | EdiType::Discount(true) => "imported",
+        EdiType::Product(false) | EdiType::Price(false) | EdiType::Discount(false) => "skipped",
+        EdiType::Invalid => "invalid",
+    }
+}
+
+/// Unzip and convert uploaded files into the dir of the run. Returns the files
+/// with their randomized names and audit entries, files that couldn't be
+/// converted are recorded as rejected.
+pub fn read_uploads(config: &Config, run_dir: &PathBuf, db_buyers: &Connection)
+-> Result<Vec<(PathBuf, String, UploadAudit)>> {
     // Create uploads dir in case it doesn't exist
     let mut uploads_dir = config.dir.to_owned();
     uploads_dir.push(UPLOAD_DIR_NAME);
 
     create_dir_all(&uploads_dir).map_err(|e|anyhow!("Failed to create uploads dir: {}", e))?;
 
-    let mut edi_dir = config.dir.to_owned();
-    edi_dir.push(EDI_DIR_NAME);
-
     let mut edi_files = vec![];
+    let temp = RunTemp::new(config)?;
 
     for p in read_dir(uploads_dir)? {
         let n = p?;
@@ -33,25 +91,29 @@ pub fn read_uploads(config: &Config) -> Result<Vec<(PathBuf, String)>> {
             continue;
         }
 
+        let mut audit = UploadAudit::new("uploads", None, &name, &read(&path)
+            .map_err(|e| anyhow!("Failed to read uploaded file {:?}: {}", path, e))?);
+
         // Handle uploaded zip files
         if name.ends_with(".zip") {
-            match unzip_handler(&path, &edi_dir) {
+            match unzip_handler(&path, &temp.path, &config.import) {
                 Ok(t) => {
                     if let Err(e) = remove_file(&path) {
                         bail!("Failed to delete obsolete zip archive {:?}: \
                             {}", path, e)
                     }
-    
+
                     path = t.0;
                     name = t.1;
                 },
                 Err(e) => {
                     error!("Failed to unzip uploaded file {:?} ({}), skipping...", path, e);
-    
+                    audit.record(db_buyers, "rejected", Some(format!("Failed to unzip: {}", e)));
+
                     if let Err(e) = remove_file(&path) {
                         bail!("Failed to delete non unzippable uploaded file {:?}: {}", path, e)
                     }
-    
+
                     continue;
                 }
             };
@@ -59,12 +121,17 @@ pub fn read_uploads(config: &Config) -> Result<Vec<(PathBuf, String)>> {
 
         let randy = Alphanumeric.sample_string(&mut rand::thread_rng(), 10);
         let rename = format!("{}-{}", randy, &name);
-        
-        match file_to_edi_utf8(&path, &edi_dir, Some(rename.to_owned())) {
-            Ok(p) => edi_files.push((p, rename)),
+
+        match file_to_edi_utf8(&path, &temp.path, Some(rename.to_owned()))
+            .and_then(|p| temp.persist(&p, run_dir)) {
+            Ok(p) => {
+                audit.parties(&p, &config.seller_ids);
+                edi_files.push((p, rename, audit));
+            },
             Err(e) => {
                 warn!("Failed to convert source file '{}' ({:?}) to utf-8 \
                     format: {}", &name, path, e);
+                audit.record(db_buyers, "rejected", Some(format!("Failed to convert to utf-8: {}", e)));
 
                 if let Err(e) = remove_file(&path) {
                     bail!("Failed to delete non utf-8 convertable file {:?}: {}", path, e)
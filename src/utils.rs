@@ -2,7 +2,7 @@ use serde::{Serialize, Deserialize};
 use std::fmt;
 use anyhow::{Result, bail};
 
-#[derive(Debug, Serialize, PartialEq, Clone, Eq, PartialOrd, Ord, Hash, Default)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Eq, PartialOrd, Ord, Hash, Default)]
 pub enum Category {
     #[default]
     Unset,
@@ -37,6 +37,12 @@ impl Category {
 
         Ok(c)
     }
+    pub fn from_name(val: &str) -> Result<Self> {
+        match Self::mapper().into_iter().find(|(k, _)| k.eq(&val.to_lowercase())) {
+            Some((_, v)) => Ok(v),
+            None => bail!("Invalid category '{}' provided. Expected one of: [lv, iv, sa, te, ky]", val),
+        }
+    }
     pub fn to_name(&self) -> &'static str {
         match self {
             Self::Unset => "unset",
@@ -47,6 +53,37 @@ impl Category {
             Self::Refrigeration => "ky",
         }
     }
+    /// Finnish name of the category, as the catalogs call it.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Unset => "",
+            Self::WaterAndHeating => "LVI",
+            Self::Ventilation => "Ilmanvaihto",
+            Self::Electricity => "Sähkö",
+            Self::Industrial => "Teollisuus",
+            Self::Refrigeration => "Kylmä",
+        }
+    }
+    /// Display name of the category in a built in language other than Danish
+    /// or Estonian.
+    pub fn localized(&self, lang: &Lang) -> Option<&'static str> {
+        let names = match self {
+            Self::Unset => return None,
+            Self::WaterAndHeating => ["LVI", "VVS", "Plumbing and heating", "VVS"],
+            Self::Ventilation => ["Ilmanvaihto", "Ventilation", "Ventilation", "Ventilasjon"],
+            Self::Electricity => ["Sähkö", "El", "Electrical", "Elektro"],
+            Self::Industrial => ["Teollisuus", "Industri", "Industrial", "Industri"],
+            Self::Refrigeration => ["Kylmä", "Kyla", "Refrigeration", "Kulde"],
+        };
+
+        match lang {
+            Lang::Fin => Some(names[0]),
+            Lang::Swe => Some(names[1]),
+            Lang::Eng => Some(names[2]),
+            Lang::Nor => Some(names[3]),
+            _ => None,
+        }
+    }
     pub fn mapper() -> [(&'static str, Self); 5] {
         [
             (Self::WaterAndHeating.to_name(), Self::WaterAndHeating),
@@ -58,14 +95,17 @@ impl Category {
     }
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
-#[serde(rename_all = "lowercase")]
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Lang {
     #[default]
     Fin,
     Swe,
     Eng,
     Nor,
+    Dan,
+    Est,
+    // Languages defined in config, name and DB index.
+    Custom(String, usize),
 }
 
 impl fmt::Display for Lang {
@@ -75,44 +115,61 @@ impl fmt::Display for Lang {
 }
 
 impl Lang {
+    // Indexes end up in the DB and translation ids. Never renumber these,
+    // new built in languages get the next free number.
     pub fn to_index(&self) -> usize {
         match self {
             Self::Fin => 1,
             Self::Swe => 2,
             Self::Eng => 3,
-            Self::Nor => 4
-
+            Self::Nor => 4,
+            Self::Dan => 5,
+            Self::Est => 6,
+            Self::Custom(_, i) => *i,
         }
     }
-    pub fn to_name(&self) -> &'static str {
+    pub fn to_name(&self) -> &str {
         match self {
             Self::Fin => "fin",
             Self::Swe => "swe",
             Self::Eng => "eng",
-            Self::Nor => "nor"
-
+            Self::Nor => "nor",
+            Self::Dan => "dan",
+            Self::Est => "est",
+            Self::Custom(n, _) => n.as_str(),
         }
     }
-    pub fn from_name<T>(val: T) -> Result<Self> where T: AsRef<str> {
+    /// Built in languages are always accepted, `custom` ones come from config.
+    pub fn from_name<T>(val: T, custom: &[Self]) -> Result<Self> where T: AsRef<str> {
+        let name = val.as_ref().to_lowercase();
+
         for (k, v) in Self::mapper() {
-            if k.eq(&val.as_ref().to_lowercase()) {
+            if k.eq(&name) {
                 return Ok(v)
             }
         }
 
-        let names = Self::mapper().into_iter()
+        if let Some(l) = custom.iter().find(|l| l.to_name().eq(&name)) {
+            return Ok(l.to_owned())
+        }
+
+        let mut names = Self::mapper().into_iter()
             .map(|(k, _)| k)
-            .collect::<Vec<&'static str>>();
+            .collect::<Vec<&str>>();
+
+        names.extend(custom.iter().map(|l| l.to_name()));
 
         bail!("Invalid language name {} provided. Expected one of: [{}]",
             val.as_ref(), names.join(", "))
     }
-    pub fn mapper() -> [(&'static str, Self); 4] {
+    pub fn mapper() -> [(&'static str, Self); 6] {
         [
-            (Self::Fin.to_name(), Self::Fin),
-            (Self::Swe.to_name(), Self::Swe),
-            (Self::Eng.to_name(), Self::Eng),
-            (Self::Nor.to_name(), Self::Nor)
+            ("fin", Self::Fin),
+            ("swe", Self::Swe),
+            ("eng", Self::Eng),
+            ("nor", Self::Nor),
+            ("dan", Self::Dan),
+            ("est", Self::Est),
         ]
     }
 }
@@ -163,3 +220,31 @@ impl Operation {
         }
     }
 }
+
+/// Shell style glob, `*` matches any run of characters and `?` one of them.
+pub fn glob_match(pattern: &str, val: &str) -> bool {
+    let p = pattern.chars().collect::<Vec<char>>();
+    let v = val.chars().collect::<Vec<char>>();
+    let (mut i, mut j) = (0, 0);
+    // Pattern position after the latest star and value position it matches from
+    let mut star: Option<(usize, usize)> = None;
+
+    while j < v.len() {
+        if i < p.len() && (p[i] == '?' || p[i] == v[j]) {
+            i += 1;
+            j += 1;
+        } else if i < p.len() && p[i] == '*' {
+            star = Some((i + 1, j));
+            i += 1;
+        } else if let Some((si, sj)) = star {
+            // Let the star take one more character
+            star = Some((si, sj + 1));
+            i = si;
+            j = sj + 1;
+        } else {
+            return false
+        }
+    }
+
+    p[i..].iter().all(|c| *c == '*')
+}
@@ -0,0 +1,270 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{read, read_to_string};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+use rusqlite::{params, Connection};
+use serde_json::Value;
+
+use crate::config::Config;
+use crate::edi::envelope;
+use crate::edi::shards::read_map;
+use crate::files::sha256_hex;
+use crate::schema::{entry_count, json_files};
+use crate::search::search_table;
+use crate::utils::Category;
+
+
+// Rows compared field by field per seller, category and file
+const SPOT_CHECKS: usize = 20;
+// Ids listed per discrepancy, the rest are only counted
+const EXAMPLES: usize = 5;
+
+fn examples(ids: &[&String]) -> String {
+    let mut s = ids.iter().take(EXAMPLES).map(|i| i.as_str()).collect::<Vec<&str>>().join(", ");
+
+    if ids.len() > EXAMPLES {
+        s.push_str(&format!(" and {} more", ids.len() - EXAMPLES));
+    }
+
+    s
+}
+
+fn read_json(path: &PathBuf) -> Result<Option<serde_json::Map<String, Value>>> {
+    if !path.is_file() {
+        return Ok(None)
+    }
+
+    let s = read_to_string(path).map_err(|e| anyhow!("Failed to read {:?}: {}", path, e))?;
+
+    match envelope::from_str::<Value>(&s).map_err(|e| anyhow!("Failed to parse {:?}: {}", path, e))? {
+        Value::Object(m) => Ok(Some(m)),
+        _ => Err(anyhow!("Expected an object in {:?}", path)),
+    }
+}
+
+// Ids missing from either side
+fn compare_ids(found: &mut Vec<String>, what: &str, db: &BTreeSet<String>, json: &BTreeSet<String>) {
+    let only_db = db.difference(json).collect::<Vec<&String>>();
+    let only_json = json.difference(db).collect::<Vec<&String>>();
+
+    if db.len() != json.len() {
+        found.push(format!("{}: {} rows in DB, {} in JSON", what, db.len(), json.len()));
+    }
+
+    if !only_db.is_empty() {
+        found.push(format!("{}: missing from JSON: {}", what, examples(&only_db)));
+    }
+
+    if !only_json.is_empty() {
+        found.push(format!("{}: missing from DB: {}", what, examples(&only_json)));
+    }
+}
+
+fn query_map(db_conn: &Connection, sql: &str, seller_id: &str) -> Result<BTreeMap<String, Value>> {
+    let mut stm = db_conn.prepare(sql)?;
+
+    let rows = stm.query_map(params![seller_id], |r| {
+        let v = match r.get_ref(1)? {
+            rusqlite::types::ValueRef::Real(f) => Value::from(f),
+            rusqlite::types::ValueRef::Integer(i) => Value::from(i as f64),
+            rusqlite::types::ValueRef::Text(t) => Value::from(String::from_utf8_lossy(t).into_owned()),
+            _ => Value::Null,
+        };
+
+        Ok((r.get::<_, String>(0)?, v))
+    })?
+    .collect::<rusqlite::Result<BTreeMap<String, Value>>>()?;
+
+    Ok(rows)
+}
+
+// Compare a field of the first rows found on both sides
+fn spot_check(found: &mut Vec<String>, what: &str, field: &str, db: &BTreeMap<String, Value>,
+    json: &serde_json::Map<String, Value>) {
+    let mut mismatches = vec![];
+
+    for (id, v) in db.iter().filter(|(id, _)| json.contains_key(*id)).take(SPOT_CHECKS) {
+        let j = json.get(id).and_then(|p| p.get(field)).unwrap_or(&Value::Null);
+
+        let same = match (v.as_f64(), j.as_f64()) {
+            (Some(a), Some(b)) => (a - b).abs() < 0.005,
+            _ => v.eq(j),
+        };
+
+        if !same {
+            mismatches.push(id);
+        }
+    }
+
+    if !mismatches.is_empty() {
+        found.push(format!("{}: '{}' differs between DB and JSON: {}", what, field, examples(&mismatches)));
+    }
+}
+
+// Rows of a table without a product of the category
+fn orphans(db_conn: &Connection, table: &str, k: &str) -> Result<Vec<String>> {
+    let mut stm = db_conn.prepare(&format!(
+        "select distinct t.seller_id || t.product_id from {table} t \
+            left join products_entries p on p.id = t.seller_id || t.product_id and p.category = '{k}' \
+            where p.id is null"
+    ))?;
+
+    let rows = stm.query_map([], |r| r.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    Ok(rows)
+}
+
+// Seller's manifest against the files in its dir: listed files exist with the
+// checksum and entry count of the manifest, files in the dir are listed.
+// Manifests written before checksums only have their files checked.
+fn check_manifest(found: &mut Vec<String>, seller_id: &str, dir: &Path) -> Result<()> {
+    let path = dir.join("manifest.json");
+
+    if !path.is_file() {
+        return Ok(())
+    }
+
+    let manifest: Value = serde_json::from_str(&read_to_string(&path)?)
+        .map_err(|e| anyhow!("Failed to parse {:?}: {}", path, e))?;
+
+    let listed = manifest["files"].as_array().map(|a| a.iter()
+        .filter_map(|f| f.as_str().map(String::from))
+        .collect::<BTreeSet<String>>())
+        .unwrap_or_default();
+
+    let what = format!("{} manifest", seller_id);
+
+    for f in listed.iter() {
+        let bytes = match read(dir.join(f)) {
+            Ok(b) => b,
+            Err(_) => {
+                found.push(format!("{}: listed file {} is missing", what, f));
+                continue
+            },
+        };
+
+        if let Some(s) = manifest["sha256"][f].as_str() {
+            if s.ne(&sha256_hex(&bytes)) {
+                found.push(format!("{}: checksum of {} differs", what, f));
+            }
+        }
+
+        if let Some(n) = manifest["entries"][f].as_u64() {
+            match entry_count(&bytes) {
+                Some(c) if c as u64 == n => (),
+                Some(c) => found.push(format!("{}: {} entries of {} listed, {} in file", what, n, f, c)),
+                None => found.push(format!("{}: {} entries of {} listed, file has no data", what, n, f)),
+            }
+        }
+    }
+
+    let mut files = vec![];
+    json_files(dir, dir, &mut files)?;
+
+    let unlisted = files.iter().filter(|f| !listed.contains(*f)).collect::<Vec<&String>>();
+
+    if !unlisted.is_empty() {
+        found.push(format!("{}: files not listed: {}", what, examples(&unlisted)));
+    }
+
+    Ok(())
+}
+
+fn table_exists(db_conn: &Connection, table: &str) -> Result<bool> {
+    let n: i64 = db_conn.query_row(
+        "select count(*) from sqlite_master where name = ?1", params![table], |r| r.get(0)
+    )?;
+
+    Ok(n > 0)
+}
+
+/// Cross check database against JSON outputs: row counts, ids and a sample of
+/// field values per seller and category, translation and search rows without a
+/// product. Sellers' JSON files are checked against their manifests. Discrepancies are printed, returns their count.
+pub fn verify(config: &Config, db_sellers: &Connection) -> Result<usize> {
+    let mut found = vec![];
+
+    for (k, _) in Category::mapper() {
+        for r in orphans(db_sellers, &format!("product_{k}_t"), k)? {
+            found.push(format!("product_{k}_t: translation without product {}", r));
+        }
+
+        for l in config.lang_codes.iter().filter(|_| config.import.search) {
+            let table = search_table(k, l);
+
+            if !table_exists(db_sellers, &table)? {
+                continue
+            }
+
+            let missing = orphans(db_sellers, &table, k)?;
+
+            if !missing.is_empty() {
+                found.push(format!("{table}: {} search rows without product: {}", missing.len(),
+                    examples(&missing.iter().collect::<Vec<&String>>())));
+            }
+        }
+    }
+
+    if config.import.json {
+        for s in config.seller.iter() {
+            check_manifest(&mut found, &s.id, &config.dir.join("sellers").join(&s.id))?;
+        }
+    }
+
+    if config.import.json && config.import.sqlite {
+        for s in config.seller.iter() {
+            let mut dir = config.dir.to_owned();
+            dir.push("sellers");
+            dir.push(&s.id);
+
+            for (k, _) in Category::mapper() {
+                debug!("Verifying {} {} against JSON files in {:?}", s.id, k, dir);
+
+                for l in config.lang_codes.iter() {
+                    let name = format!("{}.{}", k, l.to_name());
+                    let db = query_map(db_sellers, &format!("select t.product_id, t.name from product_{k}_t t \
+                        inner join products_entries p on p.id = t.seller_id || t.product_id \
+                        where p.category = '{k}' and t.seller_id = ?1 and t.lang = {}", l.to_index()), &s.id)?;
+
+                    // Languages the seller doesn't provide have neither. Products
+                    // may be in shards.
+                    let json = match read_map::<Value>(&dir.join("products"), &name)? {
+                        Some(m) => m.into_iter().collect::<serde_json::Map<String, Value>>(),
+                        None if db.is_empty() => continue,
+                        None => serde_json::Map::new(),
+                    };
+
+                    let what = format!("{} \"{}.json\"", s.id, name);
+
+                    compare_ids(&mut found, &what, &db.keys().cloned().collect(), &json.keys().cloned().collect());
+                    spot_check(&mut found, &what, "name", &db, &json);
+                }
+
+                let path = dir.join("prices").join(format!("{}.json", k));
+                let db = query_map(db_sellers, &format!("select product_id, price from prices_entries \
+                    where category = '{k}' and substr(id, 1, length(?1)) = ?1"), &s.id)?;
+
+                let json = match read_json(&path)? {
+                    Some(m) => m,
+                    None if db.is_empty() => continue,
+                    None => serde_json::Map::new(),
+                };
+
+                let what = format!("{} {:?}", s.id, path.file_name().unwrap_or_default());
+
+                compare_ids(&mut found, &what, &db.keys().cloned().collect(), &json.keys().cloned().collect());
+                spot_check(&mut found, &what, "price", &db, &json);
+            }
+        }
+    }
+
+    for f in found.iter() {
+        println!("{}", f);
+    }
+
+    info!("Verification finished with {} discrepancies", found.len());
+
+    Ok(found.len())
+}